@@ -0,0 +1,61 @@
+/*
+ * C API of the nes_rust emulator, link with librust_nes_ffi.
+ *
+ * Functions returning int return 0 on success and -1 on failure, nes_last_error tells why.
+ * A handle must only be used from one thread at a time.
+ */
+#ifndef RUST_NES_H
+#define RUST_NES_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define NES_API_VERSION 1
+
+#define NES_FRAME_WIDTH 256
+#define NES_FRAME_HEIGHT 240
+
+/* Bits of the buttons passed to nes_set_input */
+#define NES_BUTTON_A 0x01
+#define NES_BUTTON_B 0x02
+#define NES_BUTTON_SELECT 0x04
+#define NES_BUTTON_START 0x08
+#define NES_BUTTON_UP 0x10
+#define NES_BUTTON_DOWN 0x20
+#define NES_BUTTON_LEFT 0x40
+#define NES_BUTTON_RIGHT 0x80
+
+typedef struct Nes Nes;
+
+/* NES_API_VERSION of the library, to check it matches this header */
+int nes_api_version(void);
+
+Nes *nes_create(void);
+void nes_destroy(Nes *nes);
+/* Valid until the next call that fails */
+const char *nes_last_error(const Nes *nes);
+
+/* Loads the contents of an iNES file and powers on, the data is copied */
+int nes_load_rom(Nes *nes, const uint8_t *data, size_t size);
+int nes_run_frame(Nes *nes);
+/* NES_FRAME_WIDTH * NES_FRAME_HEIGHT RGB pixels of 3 bytes, valid as long as the handle */
+const uint8_t *nes_get_framebuffer(const Nes *nes);
+/* Only port 0 is connected */
+int nes_set_input(Nes *nes, int port, uint8_t buttons);
+int nes_reset(Nes *nes);
+int nes_power_cycle(Nes *nes);
+
+/* Bytes nes_save_state needs, 0 without a game */
+size_t nes_state_size(const Nes *nes);
+int nes_save_state(Nes *nes, uint8_t *buffer, size_t size);
+int nes_load_state(Nes *nes, const uint8_t *data, size_t size);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,250 @@
+//! A C API for embedding the emulator, declared in `include/rust_nes.h`.
+//!
+//! Every function takes the handle from `nes_create`. Functions that can fail return 0 on
+//! success and -1 on failure, with the reason in `nes_last_error`. A panic in the emulator
+//! is caught and reported the same way, after which the game has to be loaded again.
+
+use rust_nes::bus::Bus;
+use rust_nes::cartridge::Rom;
+use rust_nes::cpu::CPU;
+use rust_nes::render::{self, Frame};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+
+/// Bumped whenever a function changes in a way that breaks existing callers.
+pub const NES_API_VERSION: c_int = 1;
+
+/// An emulator with an optional game loaded, opaque to C.
+pub struct Nes {
+    cpu: Option<CPU<'static>>,
+    frame: Rc<RefCell<Frame>>,
+    last_error: CString,
+}
+
+impl Nes {
+    fn fail(&mut self, error: &str) -> c_int {
+        // Interior NULs would cut the message short, so drop them
+        self.last_error = CString::new(error.replace('\0', "")).unwrap_or_default();
+        -1
+    }
+
+    fn with_cpu(&mut self, f: impl FnOnce(&mut CPU<'static>) -> Result<(), String>) -> c_int {
+        let Some(cpu) = &mut self.cpu else {
+            return self.fail("No game is loaded");
+        };
+        match panic::catch_unwind(AssertUnwindSafe(|| f(cpu))) {
+            Ok(Ok(())) => 0,
+            Ok(Err(error)) => self.fail(&error),
+            Err(_) => {
+                self.cpu = None;
+                self.fail("The emulator crashed, load the game again")
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn nes_api_version() -> c_int {
+    NES_API_VERSION
+}
+
+/// Creates an emulator without a game, free it with `nes_destroy`.
+#[no_mangle]
+pub extern "C" fn nes_create() -> *mut Nes {
+    Box::into_raw(Box::new(Nes {
+        cpu: None,
+        frame: Rc::new(RefCell::new(Frame::new())),
+        last_error: CString::default(),
+    }))
+}
+
+/// # Safety
+///
+/// `nes` must come from `nes_create` and not be used afterwards. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn nes_destroy(nes: *mut Nes) {
+    if !nes.is_null() {
+        drop(Box::from_raw(nes));
+    }
+}
+
+/// Why the last failing call failed, valid until the next call that fails.
+///
+/// # Safety
+///
+/// `nes` must come from `nes_create`.
+#[no_mangle]
+pub unsafe extern "C" fn nes_last_error(nes: *const Nes) -> *const c_char {
+    (*nes).last_error.as_ptr()
+}
+
+/// Loads the contents of an iNES file and powers on, replacing any game.
+///
+/// # Safety
+///
+/// `nes` must come from `nes_create` and `data` point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nes_load_rom(nes: *mut Nes, data: *const u8, size: usize) -> c_int {
+    let nes = &mut *nes;
+    if data.is_null() {
+        return nes.fail("No ROM data");
+    }
+    let rom = match Rom::new(slice::from_raw_parts(data, size)) {
+        Ok(rom) => rom,
+        Err(error) => return nes.fail(&error),
+    };
+    let frame = Rc::clone(&nes.frame);
+    let mut cpu = CPU::new(Bus::new(rom, move |ppu, _| {
+        render::render(ppu, &mut frame.borrow_mut());
+    }));
+    cpu.power_cycle();
+    nes.cpu = Some(cpu);
+    0
+}
+
+/// Runs until the PPU finishes the next frame.
+///
+/// # Safety
+///
+/// `nes` must come from `nes_create`.
+#[no_mangle]
+pub unsafe extern "C" fn nes_run_frame(nes: *mut Nes) -> c_int {
+    (*nes).with_cpu(|cpu| {
+        let end = cpu.bus.frames() + 1;
+        while cpu.bus.frames() < end {
+            cpu.step();
+        }
+        Ok(())
+    })
+}
+
+/// The last finished frame as 256x240 RGB pixels, 3 bytes each, row by row. The pointer
+/// stays valid as long as the emulator, the contents change with every frame.
+///
+/// # Safety
+///
+/// `nes` must come from `nes_create`.
+#[no_mangle]
+pub unsafe extern "C" fn nes_get_framebuffer(nes: *const Nes) -> *const u8 {
+    (*(*nes).frame.as_ptr()).data.as_ptr()
+}
+
+/// Sets the buttons held on a controller, one `NES_BUTTON_*` bit each. Only port 0 is
+/// connected so far.
+///
+/// # Safety
+///
+/// `nes` must come from `nes_create`.
+#[no_mangle]
+pub unsafe extern "C" fn nes_set_input(nes: *mut Nes, port: c_int, buttons: u8) -> c_int {
+    if port != 0 {
+        return (*nes).fail("Only controller port 0 is connected");
+    }
+    (*nes).with_cpu(|cpu| {
+        cpu.bus.joypad_mut().set_buttons(buttons);
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// `nes` must come from `nes_create`.
+#[no_mangle]
+pub unsafe extern "C" fn nes_reset(nes: *mut Nes) -> c_int {
+    (*nes).with_cpu(|cpu| {
+        cpu.reset();
+        Ok(())
+    })
+}
+
+/// # Safety
+///
+/// `nes` must come from `nes_create`.
+#[no_mangle]
+pub unsafe extern "C" fn nes_power_cycle(nes: *mut Nes) -> c_int {
+    (*nes).with_cpu(|cpu| {
+        cpu.power_cycle();
+        Ok(())
+    })
+}
+
+/// Bytes `nes_save_state` needs for the loaded game, 0 without one.
+///
+/// # Safety
+///
+/// `nes` must come from `nes_create`.
+#[no_mangle]
+pub unsafe extern "C" fn nes_state_size(nes: *const Nes) -> usize {
+    (*nes)
+        .cpu
+        .as_ref()
+        .map_or(0, |cpu| cpu.state_to_bytes().len())
+}
+
+/// Writes the machine state to `buffer`, which needs at least `nes_state_size` bytes.
+///
+/// # Safety
+///
+/// `nes` must come from `nes_create` and `buffer` point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nes_save_state(nes: *mut Nes, buffer: *mut u8, size: usize) -> c_int {
+    (*nes).with_cpu(|cpu| {
+        let state = cpu.state_to_bytes();
+        if state.len() > size {
+            return Err(format!("The state needs {} bytes", state.len()));
+        }
+        ptr::copy_nonoverlapping(state.as_ptr(), buffer, state.len());
+        Ok(())
+    })
+}
+
+/// Restores a state from `nes_save_state` of the same game, leaving the machine untouched
+/// when it does not fit.
+///
+/// # Safety
+///
+/// `nes` must come from `nes_create` and `data` point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn nes_load_state(nes: *mut Nes, data: *const u8, size: usize) -> c_int {
+    let state = slice::from_raw_parts(data, size);
+    (*nes).with_cpu(|cpu| {
+        cpu.state_from_bytes(state)
+            .map_err(|error| error.to_string())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn test_embedding() {
+        let rom = std::fs::read("../nestest.nes").unwrap();
+        unsafe {
+            let nes = nes_create();
+            assert_eq!(nes_run_frame(nes), -1);
+            assert_eq!(
+                CStr::from_ptr(nes_last_error(nes)).to_str(),
+                Ok("No game is loaded")
+            );
+            assert_eq!(nes_load_rom(nes, b"NES".as_ptr(), 3), -1);
+
+            assert_eq!(nes_load_rom(nes, rom.as_ptr(), rom.len()), 0);
+            assert_eq!(nes_set_input(nes, 0, 0x08), 0);
+            assert_eq!(nes_run_frame(nes), 0);
+            let mut state = vec![0; nes_state_size(nes)];
+            assert_eq!(nes_save_state(nes, state.as_mut_ptr(), state.len()), 0);
+            assert_eq!(nes_save_state(nes, state.as_mut_ptr(), 1), -1);
+            assert_eq!(nes_run_frame(nes), 0);
+            assert_eq!(nes_load_state(nes, state.as_ptr(), state.len()), 0);
+            assert_eq!(nes_load_state(nes, state.as_ptr(), 1), -1);
+            assert!(!nes_get_framebuffer(nes).is_null());
+            nes_destroy(nes);
+        }
+    }
+}
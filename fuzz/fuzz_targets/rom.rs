@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_nes::cartridge::Rom;
+
+// Any bytes may come from a ROM file, parsing them must fail cleanly instead of panicking
+fuzz_target!(|data: &[u8]| {
+    let _ = Rom::new(data);
+});
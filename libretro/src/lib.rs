@@ -0,0 +1,322 @@
+//! The emulator as a libretro core, so RetroArch and other libretro frontends can run it.
+//!
+//! Frontends call every function from the same thread, so the core and the callbacks live in
+//! thread locals. There is no APU yet, so the audio is silence, and no battery-backed RAM, so
+//! there is no SRAM to save.
+
+mod api;
+
+use api::*;
+use rust_nes::bus::Bus;
+use rust_nes::cartridge::Rom;
+use rust_nes::cpu::CPU;
+use rust_nes::region::NTSC_FRAME_RATE;
+use rust_nes::joypad::{
+    JOYPAD_A, JOYPAD_B, JOYPAD_DOWN, JOYPAD_LEFT, JOYPAD_RIGHT, JOYPAD_SELECT, JOYPAD_START,
+    JOYPAD_UP,
+};
+use rust_nes::render::{self, PixelFormat};
+use rust_nes::scaling::{FRAME_HEIGHT, FRAME_WIDTH};
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_char, c_uint, c_void};
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+
+/// Rate of the silent audio, which frontends that sync to audio still need.
+const SAMPLE_RATE: f64 = 44100.0;
+
+/// Joypad buttons of libretro and the matching bits of the NES controller.
+const BUTTONS: [(c_uint, u8); 8] = [
+    (RETRO_DEVICE_ID_JOYPAD_A, JOYPAD_A),
+    (RETRO_DEVICE_ID_JOYPAD_B, JOYPAD_B),
+    (RETRO_DEVICE_ID_JOYPAD_SELECT, JOYPAD_SELECT),
+    (RETRO_DEVICE_ID_JOYPAD_START, JOYPAD_START),
+    (RETRO_DEVICE_ID_JOYPAD_UP, JOYPAD_UP),
+    (RETRO_DEVICE_ID_JOYPAD_DOWN, JOYPAD_DOWN),
+    (RETRO_DEVICE_ID_JOYPAD_LEFT, JOYPAD_LEFT),
+    (RETRO_DEVICE_ID_JOYPAD_RIGHT, JOYPAD_RIGHT),
+];
+
+#[derive(Clone, Copy, Default)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+struct Core {
+    cpu: CPU<'static>,
+    /// The frame as XRGB8888, the format asked for when loading, rendered straight into.
+    pixels: Rc<RefCell<Vec<u8>>>,
+    silence: Vec<i16>,
+}
+
+thread_local! {
+    static CALLBACKS: Cell<Callbacks> = Cell::new(Callbacks::default());
+    static CORE: RefCell<Option<Core>> = const { RefCell::new(None) };
+}
+
+fn set_callbacks(update: impl FnOnce(&mut Callbacks)) {
+    CALLBACKS.with(|callbacks| {
+        let mut current = callbacks.get();
+        update(&mut current);
+        callbacks.set(current);
+    });
+}
+
+fn callbacks() -> Callbacks {
+    CALLBACKS.with(Cell::get)
+}
+
+/// Runs `f` on the loaded game, does nothing without one.
+fn with_core<T>(f: impl FnOnce(&mut Core) -> T) -> Option<T> {
+    CORE.with(|core| core.borrow_mut().as_mut().map(f))
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    set_callbacks(|callbacks| callbacks.environment = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    set_callbacks(|callbacks| callbacks.video_refresh = Some(callback));
+}
+
+/// Audio goes out a frame at a time through the batch callback instead.
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    set_callbacks(|callbacks| callbacks.audio_sample_batch = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    set_callbacks(|callbacks| callbacks.input_poll = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    set_callbacks(|callbacks| callbacks.input_state = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    CORE.with(|core| core.borrow_mut().take());
+}
+
+/// # Safety
+///
+/// `info` must point to a `retro_system_info` the frontend owns.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    *info = RetroSystemInfo {
+        library_name: c"nes_rust".as_ptr(),
+        library_version: c"0.1.0".as_ptr(),
+        valid_extensions: c"nes".as_ptr(),
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+///
+/// `info` must point to a `retro_system_av_info` the frontend owns.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    *info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: FRAME_WIDTH,
+            base_height: FRAME_HEIGHT,
+            max_width: FRAME_WIDTH,
+            max_height: FRAME_HEIGHT,
+            aspect_ratio: 4.0 / 3.0,
+        },
+        timing: RetroSystemTiming {
+            fps: NTSC_FRAME_RATE,
+            sample_rate: SAMPLE_RATE,
+        },
+    };
+}
+
+/// Only the standard joypad is supported, on the first port.
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    with_core(|core| core.cpu.reset());
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = callbacks();
+    with_core(|core| {
+        let mut buttons = 0;
+        if let (Some(poll), Some(state)) = (callbacks.input_poll, callbacks.input_state) {
+            unsafe { poll() };
+            for (id, button) in BUTTONS {
+                if unsafe { state(0, RETRO_DEVICE_JOYPAD, 0, id) } != 0 {
+                    buttons |= button;
+                }
+            }
+        }
+        core.cpu.bus.joypad_mut().set_buttons(buttons);
+
+        let end = core.cpu.bus.frames() + 1;
+        while core.cpu.bus.frames() < end {
+            core.cpu.step();
+        }
+
+        let pixels = core.pixels.borrow();
+        if let Some(video_refresh) = callbacks.video_refresh {
+            unsafe {
+                video_refresh(
+                    pixels.as_ptr() as *const c_void,
+                    FRAME_WIDTH,
+                    FRAME_HEIGHT,
+                    PixelFormat::Xrgb8888.stride(),
+                )
+            };
+        }
+        if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+            unsafe { audio_sample_batch(core.silence.as_ptr(), core.silence.len() / 2) };
+        }
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    with_core(|core| core.cpu.state_to_bytes().len()).unwrap_or(0)
+}
+
+/// # Safety
+///
+/// `data` must point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    with_core(|core| {
+        let state = core.cpu.state_to_bytes();
+        if state.len() > size {
+            return false;
+        }
+        ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// # Safety
+///
+/// `data` must point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let state = slice::from_raw_parts(data as *const u8, size);
+    with_core(|core| core.cpu.state_from_bytes(state).is_ok()).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+/// # Safety
+///
+/// `game` must point to a `retro_game_info` with the contents of the ROM, since the core
+/// does not need the full path.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    let Some(game) = game.as_ref() else {
+        return false;
+    };
+    if game.data.is_null() {
+        return false;
+    }
+    let rom = match Rom::new(slice::from_raw_parts(game.data as *const u8, game.size)) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("Open failed: {}", error);
+            return false;
+        }
+    };
+
+    let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if let Some(environment) = callbacks().environment {
+        if !environment(
+            RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
+            &mut format as *mut c_uint as *mut c_void,
+        ) {
+            return false;
+        }
+    }
+
+    let pixels = Rc::new(RefCell::new(vec![0; PixelFormat::Xrgb8888.frame_size()]));
+    let bus_pixels = Rc::clone(&pixels);
+    let bus = Bus::new(rom, move |ppu, _| {
+        render::render_into(ppu, PixelFormat::Xrgb8888, &mut bus_pixels.borrow_mut());
+    });
+    let mut cpu = CPU::new(bus);
+    cpu.power_cycle();
+    let samples_per_frame = (SAMPLE_RATE / NTSC_FRAME_RATE).round() as usize;
+    let core = Core {
+        cpu,
+        pixels,
+        silence: vec![0; 2 * samples_per_frame],
+    };
+    CORE.with(|slot| *slot.borrow_mut() = Some(core));
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const RetroGameInfo,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    CORE.with(|core| core.borrow_mut().take());
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+/// CPU RAM for cheats and achievements. There is no battery-backed save RAM.
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match id {
+        RETRO_MEMORY_SYSTEM_RAM => {
+            with_core(|core| core.cpu.bus.ram_mut().as_mut_ptr() as *mut c_void)
+                .unwrap_or(ptr::null_mut())
+        }
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match id {
+        RETRO_MEMORY_SYSTEM_RAM => with_core(|core| core.cpu.bus.ram_mut().len()).unwrap_or(0),
+        RETRO_MEMORY_SAVE_RAM => 0,
+        _ => 0,
+    }
+}
@@ -0,0 +1,148 @@
+//! The `nes_rust` Python module, e.g.
+//!
+//! ```python
+//! import numpy as np
+//! from nes_rust import Nes, BUTTON_START
+//!
+//! nes = Nes.open("game.nes")
+//! nes.set_buttons(BUTTON_START)
+//! nes.step(60)
+//! screen = np.frombuffer(nes.framebuffer(), np.uint8).reshape(240, 256, 3)
+//! lives = nes.read(0x0075)
+//! ```
+
+use pyo3::exceptions::{PyIOError, PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use rust_nes::bus::Bus;
+use rust_nes::cartridge::Rom;
+use rust_nes::cpu::CPU;
+use rust_nes::joypad;
+use rust_nes::memory::MemoryRegion;
+use rust_nes::render::{self, Frame};
+use std::cell::RefCell;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+/// A console with a game inserted. It can only be used from the thread that created it.
+#[pyclass(unsendable)]
+struct Nes {
+    cpu: CPU<'static>,
+    frame: Rc<RefCell<Frame>>,
+}
+
+#[pymethods]
+impl Nes {
+    /// Powers on with the contents of an iNES file.
+    #[new]
+    fn new(rom: &[u8]) -> PyResult<Self> {
+        let rom = Rom::new(rom).map_err(PyValueError::new_err)?;
+        let frame = Rc::new(RefCell::new(Frame::new()));
+        let bus_frame = Rc::clone(&frame);
+        let mut cpu = CPU::new(Bus::new(rom, move |ppu, _| {
+            render::render(ppu, &mut bus_frame.borrow_mut());
+        }));
+        cpu.power_cycle();
+        Ok(Nes { cpu, frame })
+    }
+
+    /// Powers on with an iNES file.
+    #[staticmethod]
+    fn open(path: &str) -> PyResult<Self> {
+        let rom = fs::read(path).map_err(|error| PyIOError::new_err(error.to_string()))?;
+        Self::new(&rom)
+    }
+
+    /// Runs a number of frames. Raises RuntimeError when the emulator crashes, e.g. on an
+    /// opcode that is not implemented.
+    #[pyo3(signature = (frames = 1))]
+    fn step(&mut self, frames: u64) -> PyResult<()> {
+        let cpu = &mut self.cpu;
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let end = cpu.bus.frames() + frames;
+            while cpu.bus.frames() < end {
+                cpu.step();
+            }
+        }))
+        .map_err(|_| PyRuntimeError::new_err("The emulator crashed"))
+    }
+
+    /// Frames since power on.
+    #[getter]
+    fn frame(&self) -> u64 {
+        self.cpu.bus.frames()
+    }
+
+    /// The last finished frame as 256x240 RGB pixels, 3 bytes each, row by row.
+    fn framebuffer<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.frame.borrow().data)
+    }
+
+    /// Reads a byte of the CPU address space without side effects, `None` for I/O registers
+    /// and unmapped addresses.
+    fn read(&mut self, address: u16) -> Option<u8> {
+        MemoryRegion::Cpu.peek(&mut self.cpu, address)
+    }
+
+    /// Writes a byte the way the CPU would. Raises ValueError for ROM and read-only registers.
+    fn write(&mut self, address: u16, value: u8) -> PyResult<()> {
+        if MemoryRegion::Cpu.poke(&mut self.cpu, address, value) {
+            Ok(())
+        } else {
+            Err(PyValueError::new_err(format!(
+                "${:04X} is not writable",
+                address
+            )))
+        }
+    }
+
+    /// The 2K of CPU RAM.
+    fn ram<'py>(&mut self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.cpu.bus.ram_mut())
+    }
+
+    /// Sets the buttons held on the first controller, one `BUTTON_*` bit each.
+    fn set_buttons(&mut self, buttons: u8) {
+        self.cpu.bus.joypad_mut().set_buttons(buttons);
+    }
+
+    #[getter]
+    fn buttons(&mut self) -> u8 {
+        self.cpu.bus.joypad_mut().buttons()
+    }
+
+    fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    fn power_cycle(&mut self) {
+        self.cpu.power_cycle();
+    }
+
+    fn save_state<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.cpu.state_to_bytes())
+    }
+
+    /// Restores a state of the same game, raises ValueError and leaves the machine
+    /// untouched when it does not fit.
+    fn load_state(&mut self, state: &[u8]) -> PyResult<()> {
+        self.cpu
+            .state_from_bytes(state)
+            .map_err(|error| PyValueError::new_err(error.to_string()))
+    }
+}
+
+#[pymodule]
+fn nes_rust(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Nes>()?;
+    module.add("BUTTON_A", joypad::JOYPAD_A)?;
+    module.add("BUTTON_B", joypad::JOYPAD_B)?;
+    module.add("BUTTON_SELECT", joypad::JOYPAD_SELECT)?;
+    module.add("BUTTON_START", joypad::JOYPAD_START)?;
+    module.add("BUTTON_UP", joypad::JOYPAD_UP)?;
+    module.add("BUTTON_DOWN", joypad::JOYPAD_DOWN)?;
+    module.add("BUTTON_LEFT", joypad::JOYPAD_LEFT)?;
+    module.add("BUTTON_RIGHT", joypad::JOYPAD_RIGHT)?;
+    Ok(())
+}
@@ -0,0 +1,646 @@
+use crate::cpu::CPU;
+use crate::memory::MemoryRegion;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A range of the memory achievements read, inclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryBlock {
+    pub start: u32,
+    pub end: u32,
+    pub description: &'static str,
+}
+
+/// The NES memory map as RetroAchievements defines it, which is the CPU address space.
+pub const MEMORY_MAP: &[MemoryBlock] = &[
+    MemoryBlock {
+        start: 0x0000,
+        end: 0x07ff,
+        description: "System RAM",
+    },
+    MemoryBlock {
+        start: 0x0800,
+        end: 0x1fff,
+        description: "Mirrored RAM",
+    },
+    MemoryBlock {
+        start: 0x2000,
+        end: 0x2007,
+        description: "PPU registers",
+    },
+    MemoryBlock {
+        start: 0x2008,
+        end: 0x3fff,
+        description: "Mirrored PPU registers",
+    },
+    MemoryBlock {
+        start: 0x4000,
+        end: 0x4017,
+        description: "APU and I/O registers",
+    },
+    MemoryBlock {
+        start: 0x4018,
+        end: 0x401f,
+        description: "APU and I/O test registers",
+    },
+    MemoryBlock {
+        start: 0x4020,
+        end: 0x5fff,
+        description: "Cartridge expansion",
+    },
+    MemoryBlock {
+        start: 0x6000,
+        end: 0x7fff,
+        description: "Cartridge RAM",
+    },
+    MemoryBlock {
+        start: 0x8000,
+        end: 0xffff,
+        description: "Cartridge ROM",
+    },
+];
+
+/// Fills `buffer` with the memory from `address` on without side effects, like the memory
+/// callback of rcheevos. Registers read as 0, returns how many bytes are in the memory map.
+pub fn read_memory(cpu: &mut CPU, address: u32, buffer: &mut [u8]) -> usize {
+    let mut count = 0;
+    for (offset, byte) in buffer.iter_mut().enumerate() {
+        let Ok(address) = u16::try_from(address as usize + offset) else {
+            break;
+        };
+        *byte = MemoryRegion::Cpu.peek(cpu, address).unwrap_or(0);
+        count += 1;
+    }
+    count
+}
+
+/// How much of the memory at an address a value is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Size {
+    Bit(u8),
+    Lower4,
+    Upper4,
+    BitCount,
+    Bits8,
+    Bits16,
+    Bits24,
+    Bits32,
+}
+
+impl Size {
+    fn from_char(c: char) -> Option<Self> {
+        Some(match c.to_ascii_uppercase() {
+            'M' => Size::Bit(0),
+            'N' => Size::Bit(1),
+            'O' => Size::Bit(2),
+            'P' => Size::Bit(3),
+            'Q' => Size::Bit(4),
+            'R' => Size::Bit(5),
+            'S' => Size::Bit(6),
+            'T' => Size::Bit(7),
+            'L' => Size::Lower4,
+            'U' => Size::Upper4,
+            'K' => Size::BitCount,
+            'H' => Size::Bits8,
+            ' ' => Size::Bits16,
+            'W' => Size::Bits24,
+            'X' => Size::Bits32,
+            _ => return None,
+        })
+    }
+
+    fn read(self, cpu: &mut CPU, address: u16) -> u32 {
+        let mut bytes = [0; 4];
+        let length = match self {
+            Size::Bits16 => 2,
+            Size::Bits24 => 3,
+            Size::Bits32 => 4,
+            _ => 1,
+        };
+        read_memory(cpu, address as u32, &mut bytes[..length]);
+        let value = u32::from_le_bytes(bytes);
+        match self {
+            Size::Bit(bit) => (value >> bit) & 1,
+            Size::Lower4 => value & 0x0f,
+            Size::Upper4 => value >> 4,
+            Size::BitCount => value.count_ones(),
+            _ => value,
+        }
+    }
+}
+
+/// Which value of a memory reference a condition compares.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Value,
+    /// The value in the previous frame.
+    Delta,
+    /// The value before it last changed.
+    Prior,
+    /// The value read as binary coded decimal.
+    Bcd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    Number(u32),
+    Memory(Kind, Size, u16),
+}
+
+/// Values of an address over the frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MemoryReference {
+    value: u32,
+    previous: u32,
+    prior: u32,
+}
+
+type Memory = BTreeMap<(Size, u16), MemoryReference>;
+
+impl Operand {
+    fn value(self, memory: &Memory) -> u32 {
+        let (kind, reference) = match self {
+            Operand::Number(number) => return number,
+            Operand::Memory(kind, size, address) => (kind, memory[&(size, address)]),
+        };
+        match kind {
+            Kind::Value => reference.value,
+            Kind::Delta => reference.previous,
+            Kind::Prior => reference.prior,
+            Kind::Bcd => (0..8).rev().fold(0, |decimal, digit| {
+                decimal * 10 + ((reference.value >> (digit * 4)) & 0x0f)
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Flag {
+    None,
+    /// Clears the hits of the whole trigger while true.
+    ResetIf,
+    /// Stops the group it is in while true.
+    PauseIf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Requirement {
+    flag: Flag,
+    left: Operand,
+    comparison: Comparison,
+    right: Operand,
+    /// Frames it has to be true for, not necessarily in a row, 0 for just now.
+    required_hits: u32,
+    hits: u32,
+}
+
+impl Requirement {
+    fn test(&mut self, memory: &Memory) -> bool {
+        let (left, right) = (self.left.value(memory), self.right.value(memory));
+        let holds = match self.comparison {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        };
+        if self.required_hits == 0 {
+            return holds;
+        }
+        if holds && self.hits < self.required_hits {
+            self.hits += 1;
+        }
+        self.hits >= self.required_hits
+    }
+}
+
+/// Achievement logic in the RetroAchievements syntax, e.g. `0xH0075=0_d0xH0075=1.2.`.
+///
+/// Requirements are separated by `_` and all have to hold. `S` starts an alternative group,
+/// when there are any one of them has to hold as well. Supported are the memory sizes `0xH`
+/// (8 bits), `0x ` (16 bits), `0xW`, `0xX`, `0xL`, `0xU`, `0xK` and `0xM` to `0xT`, the
+/// prefixes `d` (delta), `p` (prior) and `b` (BCD), decimal and `h` hex numbers, hit counts
+/// and the `R:` (reset if) and `P:` (pause if) flags.
+#[derive(Debug, Clone, PartialEq)]
+struct Trigger {
+    core: Vec<Requirement>,
+    alternatives: Vec<Vec<Requirement>>,
+}
+
+impl Trigger {
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut groups = split_groups(text).into_iter().map(|group| match group {
+            // Sets with only alternatives leave the core empty
+            "" => Ok(vec![]),
+            group => group.split('_').map(parse_requirement).collect(),
+        });
+        // There is always a core group
+        let core = groups.next().unwrap()?;
+        let alternatives = groups.collect::<Result<Vec<_>, _>>()?;
+        Ok(Trigger { core, alternatives })
+    }
+
+    fn requirements(&self) -> impl Iterator<Item = &Requirement> {
+        self.core.iter().chain(self.alternatives.iter().flatten())
+    }
+
+    /// Tests the trigger for a new frame, counting hits.
+    fn test(&mut self, memory: &Memory) -> bool {
+        let mut reset = false;
+        let core = test_group(&mut self.core, memory, &mut reset);
+        let mut alternative = self.alternatives.is_empty();
+        for group in &mut self.alternatives {
+            alternative |= test_group(group, memory, &mut reset);
+        }
+        if reset {
+            self.reset();
+            return false;
+        }
+        core && alternative
+    }
+
+    fn reset(&mut self) {
+        for requirement in self
+            .core
+            .iter_mut()
+            .chain(self.alternatives.iter_mut().flatten())
+        {
+            requirement.hits = 0;
+        }
+    }
+}
+
+fn test_group(group: &mut [Requirement], memory: &Memory, reset: &mut bool) -> bool {
+    let mut paused = false;
+    for requirement in group.iter_mut() {
+        if requirement.flag == Flag::PauseIf {
+            paused |= requirement.test(memory);
+        }
+    }
+    // Nothing counts hits while paused, not even the reset requirements
+    if paused {
+        return false;
+    }
+    let mut holds = true;
+    for requirement in group.iter_mut() {
+        match requirement.flag {
+            Flag::None => holds &= requirement.test(memory),
+            Flag::ResetIf => *reset |= requirement.test(memory),
+            Flag::PauseIf => {}
+        }
+    }
+    holds
+}
+
+fn parse_requirement(text: &str) -> Result<Requirement, String> {
+    let mut text = text.trim();
+    let mut flag = Flag::None;
+    if let Some((name, rest)) = text.split_once(':') {
+        flag = match name {
+            "R" => Flag::ResetIf,
+            "P" => Flag::PauseIf,
+            _ => return Err(format!("Unsupported flag {:?}", name)),
+        };
+        text = rest;
+    }
+
+    let mut required_hits = 0;
+    if let Some((rest, hits)) = text
+        .strip_suffix('.')
+        .and_then(|text| text.rsplit_once('.'))
+    {
+        required_hits = hits
+            .parse()
+            .map_err(|_| format!("Invalid hit count {:?}", hits))?;
+        text = rest;
+    }
+
+    let (left, rest) = parse_operand(text)?;
+    let (comparison, length) = match rest.get(..2) {
+        Some("==") => (Comparison::Equal, 2),
+        Some("!=") => (Comparison::NotEqual, 2),
+        Some("<=") => (Comparison::LessOrEqual, 2),
+        Some(">=") => (Comparison::GreaterOrEqual, 2),
+        _ => match rest.chars().next() {
+            Some('=') => (Comparison::Equal, 1),
+            Some('<') => (Comparison::Less, 1),
+            Some('>') => (Comparison::Greater, 1),
+            _ => return Err(format!("Expected a comparison in {:?}", text)),
+        },
+    };
+    let (right, rest) = parse_operand(&rest[length..])?;
+    if !rest.is_empty() {
+        return Err(format!("Unexpected {:?}", rest));
+    }
+    Ok(Requirement {
+        flag,
+        left,
+        comparison,
+        right,
+        required_hits,
+        hits: 0,
+    })
+}
+
+/// Parses a memory reference or number from the start of `text`, returns it with the rest.
+fn parse_operand(text: &str) -> Result<(Operand, &str), String> {
+    let hex_length = |text: &str| text.chars().take_while(char::is_ascii_hexdigit).count();
+
+    let (kind, memory) = match text.get(..3) {
+        Some("d0x") => (Kind::Delta, &text[3..]),
+        Some("p0x") => (Kind::Prior, &text[3..]),
+        Some("b0x") => (Kind::Bcd, &text[3..]),
+        _ => match text.get(..2) {
+            Some("0x") | Some("0X") => (Kind::Value, &text[2..]),
+            _ => {
+                let (number, rest) = if let Some(hex) = text.strip_prefix(['h', 'H']) {
+                    let length = hex_length(hex);
+                    (u32::from_str_radix(&hex[..length], 16), &hex[length..])
+                } else {
+                    let length = text.chars().take_while(char::is_ascii_digit).count();
+                    (text[..length].parse(), &text[length..])
+                };
+                let number = number.map_err(|_| format!("Expected a value in {:?}", text))?;
+                return Ok((Operand::Number(number), rest));
+            }
+        },
+    };
+
+    // The 16 bit size has no letter, the space is optional
+    let (size, address) = match memory.chars().next().and_then(Size::from_char) {
+        Some(size) => (size, &memory[1..]),
+        None => (Size::Bits16, memory),
+    };
+    let length = hex_length(address);
+    let (address, rest) = address.split_at(length);
+    let address =
+        u16::from_str_radix(address, 16).map_err(|_| format!("Invalid address in {:?}", text))?;
+    Ok((Operand::Memory(kind, size, address), rest))
+}
+
+/// Splits the core group from the alternative groups at each `S`, except the one in `0xS`.
+fn split_groups(text: &str) -> Vec<&str> {
+    let mut groups = vec![];
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if c == 'S' && !text[..i].ends_with(['x', 'X']) {
+            groups.push(&text[start..i]);
+            start = i + 1;
+        }
+    }
+    groups.push(&text[start..]);
+    groups
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    /// Held back until the trigger is false once, so nothing unlocks just because the game
+    /// was already past it.
+    Waiting,
+    Active,
+    Unlocked,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Achievement {
+    pub id: u32,
+    pub title: String,
+    pub description: String,
+    pub points: u32,
+    trigger: Trigger,
+    state: State,
+}
+
+impl Achievement {
+    pub fn unlocked(&self) -> bool {
+        self.state == State::Unlocked
+    }
+}
+
+impl fmt::Display for Achievement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} points)", self.title, self.points)
+    }
+}
+
+/// The achievements of a game, evaluated once per frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AchievementSet {
+    achievements: Vec<Achievement>,
+    memory: Memory,
+}
+
+impl AchievementSet {
+    /// Parses the local achievements file of RetroAchievements, lines like
+    /// `1:"0xH0075=0":Title:Description::::Author:10`, which are the id, the logic, the title,
+    /// the description and the points as the 9th field. Fields may be quoted and other lines
+    /// are ignored, like the version and game title at the top.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut achievements = vec![];
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| format!("Line {}: {}", index + 1, message);
+            let fields = split_fields(line.trim());
+            let Ok(id) = fields[0].parse() else {
+                continue;
+            };
+            let field = |i: usize| fields.get(i).cloned().unwrap_or_default();
+            let points = match field(8).as_str() {
+                "" => 0,
+                points => points
+                    .parse()
+                    .map_err(|_| error(format!("Invalid points {:?}", points)))?,
+            };
+            achievements.push(Achievement {
+                id,
+                title: field(2),
+                description: field(3),
+                points,
+                trigger: Trigger::parse(&field(1)).map_err(error)?,
+                state: State::Waiting,
+            });
+        }
+
+        let mut memory = Memory::new();
+        for achievement in &achievements {
+            for requirement in achievement.trigger.requirements() {
+                for operand in [requirement.left, requirement.right] {
+                    if let Operand::Memory(_, size, address) = operand {
+                        memory.insert(
+                            (size, address),
+                            MemoryReference {
+                                value: 0,
+                                previous: 0,
+                                prior: 0,
+                            },
+                        );
+                    }
+                }
+            }
+        }
+        Ok(AchievementSet {
+            achievements,
+            memory,
+        })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    pub fn achievements(&self) -> &[Achievement] {
+        &self.achievements
+    }
+
+    /// Reads the memory the achievements use and tests them, once after every frame. Returns
+    /// the achievements that unlocked.
+    pub fn do_frame(&mut self, cpu: &mut CPU) -> Vec<&Achievement> {
+        for (&(size, address), reference) in &mut self.memory {
+            let value = size.read(cpu, address);
+            reference.previous = reference.value;
+            if value != reference.value {
+                reference.prior = reference.value;
+            }
+            reference.value = value;
+        }
+
+        let mut unlocked = vec![];
+        for (i, achievement) in self.achievements.iter_mut().enumerate() {
+            if achievement.state == State::Unlocked {
+                continue;
+            }
+            let holds = achievement.trigger.test(&self.memory);
+            achievement.state = match (achievement.state, holds) {
+                (State::Waiting, true) => {
+                    achievement.trigger.reset();
+                    State::Waiting
+                }
+                (State::Active, true) => {
+                    unlocked.push(i);
+                    State::Unlocked
+                }
+                _ => State::Active,
+            };
+        }
+        unlocked.iter().map(|&i| &self.achievements[i]).collect()
+    }
+}
+
+/// Splits at colons outside double quotes, unquoting the fields.
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => fields.last_mut().unwrap().extend(chars.next()),
+            ':' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::looping_rom;
+    use crate::cpu::Mem;
+
+    fn cpu() -> CPU<'static> {
+        let mut cpu = CPU::new(Bus::new(looping_rom(), |_, _| {}));
+        cpu.power_cycle();
+        cpu
+    }
+
+    #[test]
+    fn test_read_memory() {
+        let mut cpu = cpu();
+        cpu.write(0x0010, 0x34);
+        cpu.write(0x0011, 0x12);
+        let mut buffer = [0xff; 4];
+        assert_eq!(read_memory(&mut cpu, 0x0810, &mut buffer), 4);
+        assert_eq!(buffer, [0x34, 0x12, 0, 0]);
+        // The reset vector
+        assert_eq!(read_memory(&mut cpu, 0xfffc, &mut buffer), 4);
+        assert_eq!(buffer, [0x00, 0x80, 0x00, 0x00]);
+        assert_eq!(read_memory(&mut cpu, 0xfffe, &mut buffer), 2);
+        assert_eq!(MEMORY_MAP.last().unwrap().end, 0xffff);
+    }
+
+    #[test]
+    fn test_parse() {
+        let set = AchievementSet::parse(
+            "1.0\n\
+             Game\n\
+             7:\"0xH0010=3_0x 0011>h100\":Three:Reach level 3::::me:10\n\
+             8:\"0xS0010=1S0xH0012=1S0xH0013=1\":\"Six: or more\":\"Bit 6\"\n",
+        )
+        .unwrap();
+        let [three, six] = set.achievements() else {
+            panic!("{:?}", set.achievements());
+        };
+        assert_eq!((three.id, three.points), (7, 10));
+        assert_eq!(three.to_string(), "Three (10 points)");
+        assert_eq!(three.description, "Reach level 3");
+        assert_eq!(six.title, "Six: or more");
+        assert_eq!(
+            six.trigger.core[0].left,
+            Operand::Memory(Kind::Value, Size::Bit(6), 0x10)
+        );
+        assert_eq!(six.trigger.alternatives.len(), 2);
+
+        assert!(AchievementSet::parse("1:\"0xH0010\":Title").is_err());
+        assert!(AchievementSet::parse("1:\"A:0xH0010=1\":Title").is_err());
+        assert!(AchievementSet::parse("1:\"0xH0010=1.x.\":Title").is_err());
+        assert!(AchievementSet::parse("1:\"0xHFFFFF=1\":Title").is_err());
+    }
+
+    #[test]
+    fn test_do_frame() {
+        let mut cpu = cpu();
+        let mut set = AchievementSet::parse(
+            "1:\"0xH0010=1_d0xH0010=0\":Rising\n\
+             2:\"0xH0011=1.3._R:0xH0012=1_P:0xH0013=1\":Three frames\n\
+             3:\"0xH0014=5\":Already there\n",
+        )
+        .unwrap();
+        let mut frame = |cpu: &mut CPU, ram: [u8; 5]| {
+            for (i, value) in ram.into_iter().enumerate() {
+                cpu.write(0x10 + i as u16, value);
+            }
+            let unlocked = set.do_frame(cpu);
+            unlocked
+                .iter()
+                .map(|achievement| achievement.id)
+                .collect::<Vec<_>>()
+        };
+
+        // True from the start, so it waits until it is false once
+        assert!(frame(&mut cpu, [0, 1, 0, 0, 5]).is_empty());
+        // Paused frames do not count
+        assert_eq!(frame(&mut cpu, [1, 1, 0, 1, 0]), [1]);
+        // A reset clears the count
+        assert_eq!(frame(&mut cpu, [1, 1, 1, 0, 5]), [3]);
+        assert!(frame(&mut cpu, [1, 1, 0, 0, 5]).is_empty());
+        assert!(frame(&mut cpu, [1, 1, 0, 0, 5]).is_empty());
+        assert_eq!(frame(&mut cpu, [1, 1, 0, 0, 5]), [2]);
+        assert!(set.achievements().iter().all(Achievement::unlocked));
+    }
+}
@@ -0,0 +1,1767 @@
+//! Alternative frontend built on egui, with a menu bar, settings and debug panels.
+
+use eframe::egui;
+use rust_nes::cartridge::Rom;
+use rust_nes::cheats::{Cheat, CheatList};
+use rust_nes::clip::ClipBuffer;
+use rust_nes::condition::Condition;
+use rust_nes::config::{
+    Config, FastForward, CONFIG_PATH, MAX_FRAME_SKIP, MAX_OVERCLOCK, MAX_SPEED, MIN_SPEED,
+    SPEED_STEP,
+};
+use rust_nes::coverage::{bank_coverage, coverage_map, BankCoverage, COVERAGE_MAP_WIDTH};
+use rust_nes::debugger::{parse_address, DebugCommand, Registers};
+use rust_nes::emulation::{self, Command};
+use rust_nes::frontend::{
+    apply_cheats, file_name, load_achievements, load_cheats, load_labels, load_state_slot, notify,
+    offer_resume, open_rom, resume_autosave, save_autosave, save_cheats, save_clip,
+    save_screenshot, save_state_slot, start_netplay, toggle_code_data_log, toggle_event_log,
+    toggle_hash_log, toggle_profiler, toggle_recording, toggle_trace, window_title, HashRecording,
+    STATE_SLOTS,
+};
+use rust_nes::heatmap::HEATMAP_SIZE;
+use rust_nes::joypad::{
+    JOYPAD_A, JOYPAD_B, JOYPAD_DOWN, JOYPAD_LEFT, JOYPAD_RIGHT, JOYPAD_SELECT, JOYPAD_START,
+    JOYPAD_UP,
+};
+use rust_nes::labels::Labels;
+use rust_nes::logging;
+use rust_nes::memory::MemoryRegion;
+use rust_nes::menu::list_roms;
+use rust_nes::osd::Osd;
+use rust_nes::ramsearch::{parse_value, read_ram, RamSearch, SearchFilter};
+use rust_nes::ramwatch::{Watch, WatchFormat, WatchSize};
+use rust_nes::recorder::Recorder;
+use rust_nes::render::{self, show_tile_bank, Frame, FrameReceiver};
+use rust_nes::scaling::{self, ScaleMode, FRAME_HEIGHT, FRAME_WIDTH};
+use rust_nes::watcher::FileWatcher;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+const KEY_MAP: [(egui::Key, u8); 8] = [
+    (egui::Key::ArrowDown, JOYPAD_DOWN),
+    (egui::Key::ArrowUp, JOYPAD_UP),
+    (egui::Key::ArrowRight, JOYPAD_RIGHT),
+    (egui::Key::ArrowLeft, JOYPAD_LEFT),
+    (egui::Key::Space, JOYPAD_SELECT),
+    (egui::Key::Enter, JOYPAD_START),
+    (egui::Key::A, JOYPAD_A),
+    (egui::Key::S, JOYPAD_B),
+];
+
+const STATE_KEYS: [egui::Key; STATE_SLOTS as usize] = [
+    egui::Key::Num0,
+    egui::Key::Num1,
+    egui::Key::Num2,
+    egui::Key::Num3,
+    egui::Key::Num4,
+    egui::Key::Num5,
+    egui::Key::Num6,
+    egui::Key::Num7,
+    egui::Key::Num8,
+    egui::Key::Num9,
+];
+
+/// Bytes shown at once by the memory viewer, as rows of 16.
+const MEMORY_PAGE: usize = 0x100;
+
+/// Hex view of a memory region, the page is copied from the emulation thread every frame.
+struct MemoryViewer {
+    region: MemoryRegion,
+    start: u16,
+    page: Vec<Option<u8>>,
+    page_sender: Sender<Vec<Option<u8>>>,
+    pages: Receiver<Vec<Option<u8>>>,
+    selected: Option<u16>,
+    value: String,
+    go_to: String,
+}
+
+impl MemoryViewer {
+    fn new() -> Self {
+        let (page_sender, pages) = mpsc::channel();
+        MemoryViewer {
+            region: MemoryRegion::Cpu,
+            start: 0,
+            page: vec![],
+            page_sender,
+            pages,
+            selected: None,
+            value: String::new(),
+            go_to: String::new(),
+        }
+    }
+
+    /// Start of the last page of the region.
+    fn last_page(&self) -> u16 {
+        ((self.region.size() - 1) & !(MEMORY_PAGE - 1)) as u16
+    }
+
+    fn request_page(&self, emulation: &Sender<Command>) {
+        let (region, start) = (self.region, self.start);
+        let sender = self.page_sender.clone();
+        emulation
+            .send(Command::Inspect(Box::new(move |cpu| {
+                let _ = sender.send(region.peek_range(cpu, start, MEMORY_PAGE));
+            })))
+            .unwrap();
+    }
+
+    fn poke(&self, emulation: &Sender<Command>, notices: &Sender<String>, address: u16, value: u8) {
+        let region = self.region;
+        let notices = notices.clone();
+        emulation
+            .send(Command::Inspect(Box::new(move |cpu| {
+                if !region.poke(cpu, address, value) {
+                    let _ = notices.send(format!("${:04X} cannot be written", address));
+                }
+            })))
+            .unwrap();
+    }
+}
+
+/// How often the memory heatmap is redrawn.
+const HEATMAP_REFRESH: Duration = Duration::from_millis(500);
+
+/// RAM search results listed at most.
+const SEARCH_RESULTS: usize = 100;
+
+/// State of the RAM search window, RAM is copied from the emulation thread on every search.
+struct RamSearchWindow {
+    search: Option<RamSearch>,
+    filter: SearchFilter,
+    value: String,
+    ram_sender: Sender<Vec<u8>>,
+    rams: Receiver<Vec<u8>>,
+    /// The filter to apply once RAM arrives, `None` starts a new search.
+    pending: Option<Option<SearchFilter>>,
+}
+
+impl RamSearchWindow {
+    fn new() -> Self {
+        let (ram_sender, rams) = mpsc::channel();
+        RamSearchWindow {
+            search: None,
+            filter: SearchFilter::Equal(0),
+            value: String::new(),
+            ram_sender,
+            rams,
+            pending: None,
+        }
+    }
+
+    fn request_ram(&mut self, emulation: &Sender<Command>, filter: Option<SearchFilter>) {
+        let sender = self.ram_sender.clone();
+        emulation
+            .send(Command::Inspect(Box::new(move |cpu| {
+                let _ = sender.send(read_ram(cpu));
+            })))
+            .unwrap();
+        self.pending = Some(filter);
+    }
+}
+
+/// Named addresses shown in the RAM watch window, their values are copied every frame.
+struct RamWatchWindow {
+    watches: Vec<Watch>,
+    /// `None` for watches on I/O registers, which are not read.
+    values: Vec<Option<u16>>,
+    value_sender: Sender<Vec<Option<u16>>>,
+    value_receiver: Receiver<Vec<Option<u16>>>,
+    /// Text of the value field of every watch, used when it gets frozen.
+    freeze_text: Vec<String>,
+    name: String,
+    address: String,
+    size: WatchSize,
+    format: WatchFormat,
+}
+
+impl RamWatchWindow {
+    fn new() -> Self {
+        let (value_sender, value_receiver) = mpsc::channel();
+        RamWatchWindow {
+            watches: vec![],
+            values: vec![],
+            value_sender,
+            value_receiver,
+            freeze_text: vec![],
+            name: String::new(),
+            address: String::new(),
+            size: WatchSize::Byte,
+            format: WatchFormat::Unsigned,
+        }
+    }
+
+    fn add(&mut self, watch: Watch) {
+        self.watches.push(watch);
+        self.freeze_text.push(String::new());
+    }
+
+    fn request_values(&self, emulation: &Sender<Command>) {
+        let watches = self.watches.clone();
+        let sender = self.value_sender.clone();
+        emulation
+            .send(Command::Inspect(Box::new(move |cpu| {
+                let _ = sender.send(watches.iter().map(|watch| watch.read(cpu)).collect());
+            })))
+            .unwrap();
+    }
+
+    /// Freezes the watch at `value` or releases it.
+    fn freeze(&mut self, emulation: &Sender<Command>, index: usize, value: Option<u16>) {
+        let watch = &mut self.watches[index];
+        watch.frozen = value;
+        let bytes = watch.bytes(value.unwrap_or_default());
+        emulation
+            .send(Command::Inspect(Box::new(move |cpu| {
+                for (address, byte) in bytes {
+                    cpu.bus.freeze(address, value.map(|_| byte));
+                }
+            })))
+            .unwrap();
+    }
+}
+
+/// The cheat list of the game and the fields to add one.
+struct CheatsWindow {
+    list: CheatList,
+    code: String,
+    name: String,
+}
+
+/// Details of the loaded cartridge shown in the ROM info panel.
+struct RomInfo {
+    path: PathBuf,
+    title: String,
+    mapper_id: u8,
+    mirroring: String,
+    region: &'static str,
+    prg_size: usize,
+    chr_size: usize,
+    crc: u32,
+    frame_rate: f64,
+}
+
+impl RomInfo {
+    fn new(path: &Path, rom: &Rom) -> Self {
+        RomInfo {
+            path: path.to_path_buf(),
+            title: window_title(path, rom),
+            mapper_id: rom.mapper_id,
+            mirroring: format!("{:?}", rom.screen_mirroring),
+            region: rom.region.name(),
+            prg_size: rom.prg_rom.len(),
+            chr_size: rom.chr_rom.len(),
+            crc: rom.crc,
+            frame_rate: rom.region.frame_rate(),
+        }
+    }
+}
+
+struct App {
+    emulation: Sender<Command>,
+    frames: FrameReceiver,
+    notice_sender: Sender<String>,
+    notices: Receiver<String>,
+    frame: Box<Frame>,
+    display: Frame,
+    screen: egui::TextureHandle,
+    pattern_tables: Option<[egui::TextureHandle; 2]>,
+    rom: RomInfo,
+    watcher: FileWatcher,
+
+    config: Config,
+    osd: Osd,
+    clip: ClipBuffer,
+    recorder: Option<Recorder>,
+    hash_log: Option<HashRecording>,
+    code_data_log: bool,
+    profiling: bool,
+    event_log: bool,
+    tracing: bool,
+    scale_mode: ScaleMode,
+
+    paused: bool,
+    emulation_paused: bool,
+    fast_forward: bool,
+    frame_skip: u32,
+    rewinding: bool,
+    frame_rate: Option<f64>,
+    buttons: u8,
+
+    debug_stops: Receiver<Registers>,
+    stopped_at: Option<Registers>,
+    labels: Labels,
+    /// Breakpoint addresses with the text of their condition.
+    breakpoints: BTreeMap<u16, String>,
+    breakpoint_text: String,
+    condition_text: String,
+    break_on_nmi: bool,
+    /// PPU registers whose writes stop execution, bit n is $2000 + n.
+    ppu_write_breaks: u8,
+    history_sender: Sender<String>,
+    histories: Receiver<String>,
+    /// Instructions that ran before the last dump, oldest first.
+    history: String,
+    interrupt_sender: Sender<String>,
+    interrupt_logs: Receiver<String>,
+    /// NMIs and BRKs up to the last dump, oldest first.
+    interrupt_log: String,
+
+    fps: f64,
+    fps_frames: u32,
+    fps_start: Instant,
+
+    show_settings: bool,
+    show_rom_info: bool,
+    show_pattern_tables: bool,
+    show_coverage: bool,
+    show_heatmap: bool,
+    /// Whether the emulation thread is counting accesses for the heatmap.
+    heatmap_running: bool,
+    heatmap_sender: Sender<Vec<u8>>,
+    heatmap_images: Receiver<Vec<u8>>,
+    heatmap: Option<egui::TextureHandle>,
+    heatmap_requested: Instant,
+    /// PRG flags of the running code/data log, `None` when no log is running.
+    coverage_sender: Sender<Option<Vec<u8>>>,
+    coverages: Receiver<Option<Vec<u8>>>,
+    coverage: Option<(Vec<BankCoverage>, egui::TextureHandle)>,
+    show_debugger: bool,
+    show_memory: bool,
+    show_ram_search: bool,
+    ram_search: RamSearchWindow,
+    show_ram_watch: bool,
+    ram_watch: RamWatchWindow,
+    show_cheats: bool,
+    cheats: CheatsWindow,
+    memory: MemoryViewer,
+}
+
+impl App {
+    fn new(cc: &eframe::CreationContext, rom_path: PathBuf) -> Self {
+        let rom = open_rom(&rom_path).unwrap();
+        let mut config = Config::load(Path::new(CONFIG_PATH));
+        config.add_recent_rom(&rom_path);
+        config.apply_profile(rom.crc);
+        config.save();
+
+        let (emulation, commands) = mpsc::channel();
+        let (frame_sender, frames) = render::frame_channel();
+        let (notice_sender, notices) = mpsc::channel();
+        let info = RomInfo::new(&rom_path, &rom);
+        let rom_frame_rate = info.frame_rate;
+        let frame_rate = Some(rom_frame_rate * config.speed as f64 / 100.0);
+        let labels = load_labels(&rom_path, &rom);
+        let pattern_tables = pattern_tables(&cc.egui_ctx, &rom.chr_rom);
+        let (coverage_sender, coverages) = mpsc::channel();
+        let (heatmap_sender, heatmap_images) = mpsc::channel();
+        emulation::spawn(
+            rom,
+            config.ram_init,
+            frame_rate,
+            config.rewind_seconds,
+            config.state_dir.clone(),
+            commands,
+            frame_sender,
+        );
+        emulation
+            .send(Command::Overclock(config.overclock))
+            .unwrap();
+        load_achievements(&emulation, &notice_sender, &rom_path);
+        let cheats = load_cheats(
+            &emulation,
+            &notice_sender,
+            &config.cheat_dir,
+            info.crc,
+            &rom_path,
+        );
+        start_netplay(&emulation, &notice_sender, info.crc);
+        #[cfg(feature = "remote")]
+        rust_nes::frontend::start_remote_server(&emulation);
+        #[cfg(any(feature = "lua", feature = "rhai"))]
+        rust_nes::frontend::start_scripts(&emulation);
+        let (listener, debug_stops) = mpsc::channel();
+        let (history_sender, histories) = mpsc::channel();
+        let (interrupt_sender, interrupt_logs) = mpsc::channel();
+        emulation
+            .send(Command::Debug(DebugCommand::Attach(listener)))
+            .unwrap();
+
+        let frame = Box::new(Frame::new());
+        let screen =
+            cc.egui_ctx
+                .load_texture("screen", color_image(&frame), egui::TextureOptions::NEAREST);
+
+        let mut app = App {
+            emulation,
+            frames,
+            notice_sender,
+            notices,
+            frame,
+            display: Frame::new(),
+            screen,
+            pattern_tables,
+            watcher: FileWatcher::new(&rom_path),
+            rom: info,
+
+            clip: ClipBuffer::new(config.gif_seconds, rom_frame_rate),
+            config,
+            osd: Osd::new(),
+            recorder: None,
+            hash_log: None,
+            code_data_log: false,
+            profiling: false,
+            event_log: false,
+            tracing: false,
+            scale_mode: ScaleMode::Integer,
+
+            paused: false,
+            emulation_paused: false,
+            fast_forward: false,
+            frame_skip: 0,
+            rewinding: false,
+            frame_rate,
+            buttons: 0,
+
+            debug_stops,
+            stopped_at: None,
+            labels,
+            breakpoints: BTreeMap::new(),
+            breakpoint_text: String::new(),
+            condition_text: String::new(),
+            break_on_nmi: false,
+            ppu_write_breaks: 0,
+            history_sender,
+            histories,
+            history: String::new(),
+            interrupt_sender,
+            interrupt_logs,
+            interrupt_log: String::new(),
+
+            fps: 0.0,
+            fps_frames: 0,
+            fps_start: Instant::now(),
+
+            show_settings: false,
+            show_rom_info: false,
+            show_pattern_tables: false,
+            show_coverage: false,
+            show_heatmap: false,
+            heatmap_running: false,
+            heatmap_sender,
+            heatmap_images,
+            heatmap: None,
+            heatmap_requested: Instant::now(),
+            coverage_sender,
+            coverages,
+            coverage: None,
+            show_debugger: false,
+            show_memory: false,
+            show_ram_search: false,
+            ram_search: RamSearchWindow::new(),
+            show_ram_watch: false,
+            ram_watch: RamWatchWindow::new(),
+            show_cheats: false,
+            cheats: CheatsWindow {
+                list: cheats,
+                code: String::new(),
+                name: String::new(),
+            },
+            memory: MemoryViewer::new(),
+        };
+        offer_resume(&app.config, app.rom.crc, &mut app.osd);
+        app
+    }
+
+    fn load_rom(&mut self, ctx: &egui::Context, path: &Path, reload: bool) {
+        match open_rom(path) {
+            Ok(rom) => {
+                self.config.add_recent_rom(path);
+                self.config.apply_profile(rom.crc);
+                self.config.save();
+                if reload {
+                    self.osd.push(format!("Reloaded {}", file_name(path)));
+                } else {
+                    self.osd.push(format!("Loaded {}", file_name(path)));
+                    if self.config.has_profile() {
+                        self.osd.push("Game profile applied");
+                    }
+                    offer_resume(&self.config, rom.crc, &mut self.osd);
+                }
+                self.rom = RomInfo::new(path, &rom);
+                self.labels = load_labels(path, &rom);
+                self.pattern_tables = pattern_tables(ctx, &rom.chr_rom);
+                self.watcher = FileWatcher::new(path);
+                let command = if reload {
+                    Command::ReloadRom(rom, self.config.ram_init)
+                } else {
+                    Command::LoadRom(rom, self.config.ram_init)
+                };
+                self.emulation.send(command).unwrap();
+                self.emulation
+                    .send(Command::Overclock(self.config.overclock))
+                    .unwrap();
+                load_achievements(&self.emulation, &self.notice_sender, path);
+                // Loading the game released whatever was frozen
+                for watch in &mut self.ram_watch.watches {
+                    watch.frozen = None;
+                }
+                self.cheats.list = load_cheats(
+                    &self.emulation,
+                    &self.notice_sender,
+                    &self.config.cheat_dir,
+                    self.rom.crc,
+                    path,
+                );
+            }
+            Err(error) => notify(&mut self.osd, format!("Open failed: {}", error)),
+        }
+    }
+
+    /// Takes the frames the emulation thread finished since the last repaint.
+    fn receive_frames(&mut self) {
+        for new_frame in self.frames.try_iter() {
+            self.fps_frames += 1;
+            self.clip.push(&new_frame);
+            if let Some(running) = self.recorder.as_mut() {
+                if let Err(error) = running.write_frame(&new_frame) {
+                    notify(&mut self.osd, format!("Recording stopped: {}", error));
+                    self.recorder = None;
+                }
+            }
+            self.frames
+                .recycle(std::mem::replace(&mut self.frame, new_frame));
+        }
+        for message in self.notices.try_iter() {
+            notify(&mut self.osd, message);
+        }
+        if let Some(registers) = self.debug_stops.try_iter().last() {
+            self.stopped_at = Some(registers);
+        }
+        if let Some(history) = self.histories.try_iter().last() {
+            self.history = self.labels.substitute(&history);
+        }
+        if let Some(log) = self.interrupt_logs.try_iter().last() {
+            self.interrupt_log = self.labels.substitute(&log);
+        }
+
+        let elapsed = self.fps_start.elapsed().as_secs_f64();
+        if elapsed >= 1.0 {
+            self.fps = self.fps_frames as f64 / elapsed;
+            self.fps_frames = 0;
+            self.fps_start = Instant::now();
+        }
+    }
+
+    /// Sends the joypad buttons that changed, unless a text field has focus.
+    fn handle_input(&mut self, ctx: &egui::Context) {
+        let buttons = if ctx.wants_keyboard_input() {
+            0
+        } else {
+            ctx.input(|input| {
+                KEY_MAP
+                    .iter()
+                    .filter(|(key, _)| input.key_down(*key))
+                    .fold(0, |buttons, (_, button)| buttons | button)
+            })
+        };
+
+        for (_, button) in KEY_MAP {
+            if (buttons ^ self.buttons) & button != 0 {
+                self.emulation
+                    .send(Command::Button(button, buttons & button != 0))
+                    .unwrap();
+            }
+        }
+        self.buttons = buttons;
+        self.fast_forward = ctx.input(|input| input.key_down(egui::Key::Tab));
+
+        let rewinding =
+            !ctx.wants_keyboard_input() && ctx.input(|input| input.key_down(egui::Key::Backspace));
+        if rewinding != self.rewinding {
+            self.emulation.send(Command::Rewind(rewinding)).unwrap();
+            self.rewinding = rewinding;
+        }
+
+        if ctx.input(|input| input.key_pressed(egui::Key::F4)) {
+            self.resume();
+        }
+
+        if !ctx.wants_keyboard_input() && ctx.input(|input| input.key_pressed(egui::Key::T)) {
+            self.toggle_trace();
+        }
+
+        // Shift+number saves a state, the number alone loads it
+        for (slot, key) in STATE_KEYS.into_iter().enumerate() {
+            let (pressed, shift) =
+                ctx.input(|input| (input.key_pressed(key), input.modifiers.shift));
+            if pressed && !ctx.wants_keyboard_input() {
+                if shift {
+                    self.save_state(slot as u8);
+                } else {
+                    self.load_state(slot as u8);
+                }
+            }
+        }
+    }
+
+    fn save_state(&self, slot: u8) {
+        save_state_slot(
+            &self.emulation,
+            &self.notice_sender,
+            &self.config.state_dir,
+            self.rom.crc,
+            slot,
+        );
+    }
+
+    fn load_state(&self, slot: u8) {
+        load_state_slot(
+            &self.emulation,
+            &self.notice_sender,
+            &self.config.state_dir,
+            self.rom.crc,
+            slot,
+        );
+    }
+
+    fn toggle_trace(&mut self) {
+        toggle_trace(
+            &mut self.tracing,
+            &self.emulation,
+            &self.notice_sender,
+            &self.rom.path,
+            self.config.trace_format,
+            &self.labels,
+        );
+    }
+
+    fn resume(&self) {
+        resume_autosave(
+            &self.emulation,
+            &self.notice_sender,
+            &self.config.state_dir,
+            self.rom.crc,
+        );
+    }
+
+    /// Only tells the emulation thread about changes.
+    fn sync_emulation(&mut self) {
+        if self.paused != self.emulation_paused {
+            self.emulation.send(Command::Pause(self.paused)).unwrap();
+            self.emulation_paused = self.paused;
+        }
+
+        let speed = self.config.speed as f64 / 100.0;
+        let multiplier = if self.fast_forward {
+            self.config.fast_forward.multiplier()
+        } else {
+            Some(1.0)
+        };
+        let rate = multiplier.map(|multiplier| self.rom.frame_rate * speed * multiplier);
+        if rate != self.frame_rate {
+            self.emulation.send(Command::FrameRate(rate)).unwrap();
+            self.frame_rate = rate;
+        }
+        let skip = if self.fast_forward {
+            self.config.frame_skip
+        } else {
+            0
+        };
+        if skip != self.frame_skip {
+            self.emulation.send(Command::FrameSkip(skip)).unwrap();
+            self.frame_skip = skip;
+        }
+    }
+
+    fn menu_bar(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
+        egui::MenuBar::new().ui(ui, |ui| {
+            ui.menu_button("File", |ui| {
+                ui.menu_button("Open ROM", |ui| {
+                    let dir = self
+                        .rom
+                        .path
+                        .parent()
+                        .filter(|dir| !dir.as_os_str().is_empty())
+                        .unwrap_or(Path::new("."))
+                        .to_path_buf();
+                    let mut roms = self.config.recent_roms.clone();
+                    for rom in list_roms(&dir) {
+                        if !roms.contains(&rom) {
+                            roms.push(rom);
+                        }
+                    }
+
+                    if roms.is_empty() {
+                        ui.label("No ROMs found");
+                    }
+                    for path in roms {
+                        if ui.button(file_name(&path)).clicked() {
+                            self.load_rom(ctx, &path, false);
+                            ui.close();
+                        }
+                    }
+                });
+                ui.separator();
+                if ui.button("Screenshot").clicked() {
+                    save_screenshot(&self.frame, &self.config.screenshot_dir, &mut self.osd);
+                    ui.close();
+                }
+                if ui.button("Save GIF clip").clicked() {
+                    save_clip(&self.clip, &self.config.screenshot_dir, &mut self.osd);
+                    ui.close();
+                }
+                let recording = if self.recorder.is_some() {
+                    "Stop recording"
+                } else {
+                    "Start recording"
+                };
+                if ui.button(recording).clicked() {
+                    toggle_recording(&mut self.recorder, &self.config, &mut self.osd);
+                    ui.close();
+                }
+                let hash_log = if self.hash_log.is_some() {
+                    "Stop state hash log"
+                } else {
+                    "Start state hash log"
+                };
+                if ui.button(hash_log).clicked() {
+                    toggle_hash_log(
+                        &mut self.hash_log,
+                        &self.emulation,
+                        &self.config.state_dir,
+                        self.rom.crc,
+                        &mut self.osd,
+                    );
+                    ui.close();
+                }
+                let code_data_log = if self.code_data_log {
+                    "Save code/data log"
+                } else {
+                    "Start code/data log"
+                };
+                if ui.button(code_data_log).clicked() {
+                    toggle_code_data_log(
+                        &mut self.code_data_log,
+                        &self.emulation,
+                        &self.notice_sender,
+                        &self.rom.path,
+                    );
+                    ui.close();
+                }
+                let profiler = if self.profiling {
+                    "Save profile"
+                } else {
+                    "Start profiler"
+                };
+                if ui.button(profiler).clicked() {
+                    toggle_profiler(
+                        &mut self.profiling,
+                        &self.emulation,
+                        &self.notice_sender,
+                        &self.rom.path,
+                        &self.labels,
+                    );
+                    ui.close();
+                }
+                let event_log = if self.event_log {
+                    "Save event log"
+                } else {
+                    "Start event log"
+                };
+                if ui.button(event_log).clicked() {
+                    toggle_event_log(
+                        &mut self.event_log,
+                        &self.emulation,
+                        &self.notice_sender,
+                        &self.rom.path,
+                    );
+                    ui.close();
+                }
+                let trace = if self.tracing {
+                    "Pause trace"
+                } else {
+                    "Start trace"
+                };
+                if ui.button(trace).clicked() {
+                    self.toggle_trace();
+                    ui.close();
+                }
+                ui.separator();
+                if ui.button("Quit").clicked() {
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                }
+            });
+
+            ui.menu_button("Emulation", |ui| {
+                if ui.button("Reset").clicked() {
+                    self.emulation.send(Command::Reset).unwrap();
+                    self.osd.push("Reset");
+                    ui.close();
+                }
+                if ui.button("Power cycle").clicked() {
+                    self.emulation.send(Command::PowerCycle).unwrap();
+                    self.osd.push("Power cycle");
+                    ui.close();
+                }
+                ui.separator();
+                ui.checkbox(&mut self.paused, "Pause");
+                if ui.button("Frame advance").clicked() {
+                    // The emulation thread pauses again by itself
+                    self.paused = true;
+                    self.emulation_paused = true;
+                    self.emulation.send(Command::FrameAdvance).unwrap();
+                }
+                ui.separator();
+                ui.menu_button("Save state", |ui| {
+                    for slot in 0..STATE_SLOTS {
+                        if ui.button(format!("Slot {}", slot)).clicked() {
+                            self.save_state(slot);
+                            ui.close();
+                        }
+                    }
+                });
+                if ui.button("Resume autosave").clicked() {
+                    self.resume();
+                    ui.close();
+                }
+                ui.menu_button("Load state", |ui| {
+                    for slot in 0..STATE_SLOTS {
+                        if ui.button(format!("Slot {}", slot)).clicked() {
+                            self.load_state(slot);
+                            ui.close();
+                        }
+                    }
+                });
+            });
+
+            ui.menu_button("View", |ui| {
+                ui.checkbox(&mut self.show_settings, "Settings");
+                ui.separator();
+                ui.checkbox(&mut self.show_rom_info, "ROM info");
+                ui.checkbox(&mut self.show_pattern_tables, "Pattern tables");
+                ui.checkbox(&mut self.show_coverage, "PRG coverage");
+                ui.checkbox(&mut self.show_heatmap, "Memory heatmap");
+                ui.checkbox(&mut self.show_debugger, "Debugger");
+                ui.checkbox(&mut self.show_memory, "Memory");
+                ui.checkbox(&mut self.show_ram_search, "RAM search");
+                ui.checkbox(&mut self.show_ram_watch, "RAM watch");
+                ui.checkbox(&mut self.show_cheats, "Cheats");
+            });
+        });
+    }
+
+    fn settings(&mut self, ctx: &egui::Context) {
+        let mut changed = false;
+        egui::Window::new("Settings")
+            .open(&mut self.show_settings)
+            .resizable(false)
+            .show(ctx, |ui| {
+                changed |= ui
+                    .add(
+                        egui::Slider::new(&mut self.config.speed, MIN_SPEED..=MAX_SPEED)
+                            .step_by(SPEED_STEP as f64)
+                            .suffix("%")
+                            .text("Speed"),
+                    )
+                    .changed();
+
+                egui::ComboBox::from_label("Fast-forward")
+                    .selected_text(self.config.fast_forward.name())
+                    .show_ui(ui, |ui| {
+                        for mode in [
+                            FastForward::Double,
+                            FastForward::Quadruple,
+                            FastForward::Uncapped,
+                        ] {
+                            changed |= ui
+                                .selectable_value(&mut self.config.fast_forward, mode, mode.name())
+                                .changed();
+                        }
+                    });
+
+                changed |= ui
+                    .add(
+                        egui::Slider::new(&mut self.config.frame_skip, 0..=MAX_FRAME_SKIP)
+                            .text("Fast-forward frame skip"),
+                    )
+                    .on_hover_text("Frames not drawn between the shown ones while fast-forwarding")
+                    .changed();
+
+                let overclock = ui
+                    .add(
+                        egui::Slider::new(&mut self.config.overclock, 0..=MAX_OVERCLOCK)
+                            .suffix(" scanlines")
+                            .text("Overclock"),
+                    )
+                    .on_hover_text("Extra CPU time per frame against slowdown and flicker");
+                if overclock.changed() {
+                    self.emulation
+                        .send(Command::Overclock(self.config.overclock))
+                        .unwrap();
+                    changed = true;
+                }
+
+                egui::ComboBox::from_label("Scaling")
+                    .selected_text(format!("{:?}", self.scale_mode))
+                    .show_ui(ui, |ui| {
+                        for mode in [
+                            ScaleMode::Integer,
+                            ScaleMode::Stretch,
+                            ScaleMode::AspectCorrected,
+                        ] {
+                            ui.selectable_value(&mut self.scale_mode, mode, format!("{:?}", mode));
+                        }
+                    });
+
+                changed |= ui
+                    .checkbox(&mut self.config.hot_reload, "Reload ROM when it changes")
+                    .changed();
+                changed |= ui
+                    .checkbox(&mut self.config.autosave, "Save state on quit")
+                    .changed();
+            });
+
+        if changed {
+            self.config.save();
+        }
+    }
+
+    fn memory_viewer(&mut self, ctx: &egui::Context) {
+        if !self.show_memory {
+            return;
+        }
+        let viewer = &mut self.memory;
+        if let Some(page) = viewer.pages.try_iter().last() {
+            viewer.page = page;
+        }
+
+        let mut poke = None;
+        let mut invalid = false;
+        egui::Window::new("Memory")
+            .open(&mut self.show_memory)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_salt("memory_region")
+                        .selected_text(viewer.region.name())
+                        .show_ui(ui, |ui| {
+                            for region in MemoryRegion::ALL {
+                                if ui
+                                    .selectable_value(&mut viewer.region, region, region.name())
+                                    .changed()
+                                {
+                                    viewer.start = 0;
+                                    viewer.selected = None;
+                                }
+                            }
+                        });
+                    if ui.button("<").clicked() {
+                        viewer.start = viewer.start.saturating_sub(MEMORY_PAGE as u16);
+                    }
+                    if ui.button(">").clicked() {
+                        viewer.start = viewer
+                            .start
+                            .saturating_add(MEMORY_PAGE as u16)
+                            .min(viewer.last_page());
+                    }
+                    ui.add(
+                        egui::TextEdit::singleline(&mut viewer.go_to)
+                            .hint_text("Address or label")
+                            .desired_width(50.0),
+                    );
+                    if ui.button("Go").clicked() {
+                        match parse_address(&viewer.go_to) {
+                            Some(address) => {
+                                viewer.start =
+                                    (address & !(MEMORY_PAGE as u16 - 1)).min(viewer.last_page());
+                                viewer.selected = Some(address);
+                            }
+                            None => invalid = true,
+                        }
+                    }
+                });
+
+                egui::Grid::new("memory_grid")
+                    .spacing([2.0, 2.0])
+                    .show(ui, |ui| {
+                        for row in 0..MEMORY_PAGE / 16 {
+                            let row_address = viewer.start as usize + row * 16;
+                            if row_address >= viewer.region.size() {
+                                break;
+                            }
+                            ui.monospace(format!("{:04X}", row_address));
+                            for column in 0..16 {
+                                let address = (row_address + column) as u16;
+                                let byte = viewer.page.get(row * 16 + column).copied().flatten();
+                                let text = match byte {
+                                    Some(byte) => format!("{:02X}", byte),
+                                    None => "--".to_string(),
+                                };
+                                let selected = viewer.selected == Some(address);
+                                let label = egui::RichText::new(&text).monospace();
+                                if ui.selectable_label(selected, label).clicked() {
+                                    viewer.selected = Some(address);
+                                    viewer.value = byte.map(|_| text).unwrap_or_default();
+                                }
+                            }
+                            ui.end_row();
+                        }
+                    });
+
+                if let Some(address) = viewer.selected {
+                    ui.horizontal(|ui| {
+                        ui.monospace(format!("${:04X} =", address));
+                        let response = ui
+                            .add(egui::TextEdit::singleline(&mut viewer.value).desired_width(30.0));
+                        let entered = response.lost_focus()
+                            && ui.input(|input| input.key_pressed(egui::Key::Enter));
+                        if ui.button("Write").clicked() || entered {
+                            match u8::from_str_radix(
+                                viewer.value.trim().trim_start_matches('$'),
+                                16,
+                            ) {
+                                Ok(value) => poke = Some((address, value)),
+                                Err(_) => invalid = true,
+                            }
+                        }
+                    });
+                }
+            });
+
+        if invalid {
+            notify(&mut self.osd, "Invalid hex value".to_string());
+        }
+        if let Some((address, value)) = poke {
+            self.memory
+                .poke(&self.emulation, &self.notice_sender, address, value);
+        }
+        self.memory.request_page(&self.emulation);
+    }
+
+    fn ram_search(&mut self, ctx: &egui::Context) {
+        let window = &mut self.ram_search;
+        if let Some(ram) = window.rams.try_iter().last() {
+            match (window.pending.take(), &mut window.search) {
+                (Some(Some(filter)), Some(search)) => search.filter(filter, &ram),
+                _ => window.search = Some(RamSearch::new(&ram)),
+            }
+        }
+
+        let mut request = None;
+        let mut show = None;
+        let mut watch = None;
+        let mut cheat = None;
+        egui::Window::new("RAM search")
+            .open(&mut self.show_ram_search)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_salt("search_filter")
+                        .selected_text(window.filter.name())
+                        .show_ui(ui, |ui| {
+                            for filter in SearchFilter::ALL {
+                                ui.selectable_value(&mut window.filter, filter, filter.name());
+                            }
+                        });
+                    if window.filter.takes_value() {
+                        ui.add(
+                            egui::TextEdit::singleline(&mut window.value)
+                                .hint_text("Value")
+                                .desired_width(40.0),
+                        );
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("New search").clicked() {
+                        request = Some(Ok(None));
+                    }
+                    let searching = window.search.is_some();
+                    if ui
+                        .add_enabled(searching, egui::Button::new("Search"))
+                        .clicked()
+                    {
+                        request = Some(if window.filter.takes_value() {
+                            parse_value(window.value.trim())
+                                .map(|value| Some(window.filter.with_value(value)))
+                        } else {
+                            Ok(Some(window.filter))
+                        });
+                    }
+                });
+
+                let Some(search) = &window.search else {
+                    ui.label("Start a new search, then narrow it down while playing");
+                    return;
+                };
+                ui.label(format!("{} addresses left", search.candidates().len()));
+                egui::ScrollArea::vertical()
+                    .max_height(300.0)
+                    .show(ui, |ui| {
+                        egui::Grid::new("search_results").show(ui, |ui| {
+                            for &address in search.candidates().iter().take(SEARCH_RESULTS) {
+                                ui.monospace(format!("${:04X}", address));
+                                ui.monospace(format!("{:3}", search.value(address)));
+                                ui.label(self.labels.get(address).unwrap_or_default());
+                                if ui.button("Show").clicked() {
+                                    show = Some(address);
+                                }
+                                if ui.button("Watch").clicked() {
+                                    watch = Some(address);
+                                }
+                                if ui.button("Cheat").clicked() {
+                                    cheat = Some((address, search.value(address)));
+                                }
+                                ui.end_row();
+                            }
+                        });
+                    });
+            });
+
+        match request {
+            Some(Ok(filter)) => window.request_ram(&self.emulation, filter),
+            Some(Err(error)) => notify(&mut self.osd, error),
+            None => { /* do nothing */ }
+        }
+        if let Some(address) = show {
+            let viewer = &mut self.memory;
+            viewer.region = MemoryRegion::Cpu;
+            viewer.start = address & !(MEMORY_PAGE as u16 - 1);
+            viewer.selected = Some(address);
+            viewer.value = format!("{:02X}", window.search.as_ref().unwrap().value(address));
+            self.show_memory = true;
+        }
+        if let Some(address) = watch {
+            let name = self.labels.get(address).unwrap_or_default();
+            self.ram_watch.add(Watch::new(
+                name,
+                address,
+                WatchSize::Byte,
+                WatchFormat::Unsigned,
+            ));
+            self.show_ram_watch = true;
+        }
+        if let Some((address, value)) = cheat {
+            let name = self.labels.get(address).unwrap_or_default();
+            self.cheats
+                .list
+                .cheats
+                .push(Cheat::new(address, value, name));
+            self.save_cheats(None);
+            self.show_cheats = true;
+        }
+    }
+
+    /// Applies and saves the cheat list after a change, releasing a removed cheat.
+    fn save_cheats(&mut self, removed: Option<Cheat>) {
+        let mut list = self.cheats.list.clone();
+        list.cheats.extend(removed.map(|cheat| Cheat {
+            enabled: false,
+            ..cheat
+        }));
+        apply_cheats(&self.emulation, &list);
+        if let Err(error) = save_cheats(&self.cheats.list, &self.config.cheat_dir, self.rom.crc) {
+            notify(&mut self.osd, format!("Saving cheats failed: {}", error));
+        }
+    }
+
+    fn cheats(&mut self, ctx: &egui::Context) {
+        let window = &mut self.cheats;
+        let mut changed = false;
+        let mut add = false;
+        let mut remove = None;
+        egui::Window::new("Cheats")
+            .open(&mut self.show_cheats)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("cheats").show(ui, |ui| {
+                    for (i, cheat) in window.list.cheats.iter_mut().enumerate() {
+                        changed |= ui.checkbox(&mut cheat.enabled, &cheat.name).changed();
+                        ui.monospace(cheat.code());
+                        if ui.button("Remove").clicked() {
+                            remove = Some(i);
+                        }
+                        ui.end_row();
+                    }
+                });
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::TextEdit::singleline(&mut window.code)
+                            .hint_text("AAAA:VV")
+                            .desired_width(60.0),
+                    );
+                    ui.add(
+                        egui::TextEdit::singleline(&mut window.name)
+                            .hint_text("Name")
+                            .desired_width(120.0),
+                    );
+                    add = ui.button("Add").clicked();
+                });
+            });
+
+        if add {
+            match Cheat::parse(&format!("{} {}", window.code.trim(), window.name.trim())) {
+                Ok(cheat) => {
+                    window.list.cheats.push(cheat);
+                    window.code.clear();
+                    window.name.clear();
+                    changed = true;
+                }
+                Err(error) => notify(&mut self.osd, error),
+            }
+        }
+        let removed = remove.map(|i| window.list.cheats.remove(i));
+        if changed || removed.is_some() {
+            self.save_cheats(removed);
+        }
+    }
+
+    /// Counts memory accesses while the window is open, the image is refreshed periodically.
+    fn heatmap(&mut self, ctx: &egui::Context) {
+        if self.show_heatmap != self.heatmap_running {
+            let start = self.show_heatmap;
+            self.emulation
+                .send(Command::Inspect(Box::new(move |cpu| {
+                    if start {
+                        cpu.bus.start_heatmap();
+                    } else {
+                        cpu.bus.stop_heatmap();
+                    }
+                })))
+                .unwrap();
+            self.heatmap_running = start;
+            self.heatmap = None;
+        }
+        if !self.show_heatmap {
+            return;
+        }
+
+        if let Some(image) = self.heatmap_images.try_iter().last() {
+            let image = egui::ColorImage::from_rgb([HEATMAP_SIZE, HEATMAP_SIZE], &image);
+            self.heatmap = Some(ctx.load_texture("heatmap", image, egui::TextureOptions::NEAREST));
+        }
+        if self.heatmap_requested.elapsed() >= HEATMAP_REFRESH {
+            self.heatmap_requested = Instant::now();
+            let sender = self.heatmap_sender.clone();
+            self.emulation
+                .send(Command::Inspect(Box::new(move |cpu| {
+                    if let Some(heatmap) = cpu.bus.heatmap() {
+                        let _ = sender.send(heatmap.image());
+                    }
+                })))
+                .unwrap();
+        }
+
+        let mut clear = false;
+        egui::Window::new("Memory heatmap")
+            .open(&mut self.show_heatmap)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Reads are green, writes red, a row is a page");
+                    clear = ui.button("Clear").clicked();
+                });
+                if let Some(heatmap) = &self.heatmap {
+                    let response = ui.add(
+                        egui::Image::new(heatmap)
+                            .fit_to_exact_size(egui::vec2(512.0, 512.0))
+                            .sense(egui::Sense::hover()),
+                    );
+                    if let Some(position) = response.hover_pos() {
+                        let offset = (position - response.rect.min) / 2.0;
+                        let address =
+                            (offset.y as u16).min(0xff) << 8 | (offset.x as u16).min(0xff);
+                        let name = self.labels.get(address).unwrap_or_default();
+                        response.on_hover_text(format!("${:04X} {}", address, name));
+                    }
+                }
+            });
+
+        if clear {
+            self.emulation
+                .send(Command::Inspect(Box::new(|cpu| {
+                    if let Some(heatmap) = cpu.bus.heatmap() {
+                        heatmap.clear();
+                    }
+                })))
+                .unwrap();
+        }
+    }
+
+    fn ram_watch(&mut self, ctx: &egui::Context) {
+        if !self.show_ram_watch {
+            return;
+        }
+        let window = &mut self.ram_watch;
+        if let Some(values) = window.value_receiver.try_iter().last() {
+            window.values = values;
+        }
+
+        let mut add = false;
+        let mut remove = None;
+        let mut freeze = None;
+        egui::Window::new("RAM watch")
+            .open(&mut self.show_ram_watch)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("watches").show(ui, |ui| {
+                    for (i, watch) in window.watches.iter().enumerate() {
+                        ui.label(&watch.name);
+                        ui.monospace(format!("${:04X}", watch.address));
+                        match window.values.get(i) {
+                            Some(&Some(value)) => ui.monospace(watch.format_value(value)),
+                            _ => ui.monospace("--"),
+                        };
+                        ui.add(
+                            egui::TextEdit::singleline(&mut window.freeze_text[i])
+                                .hint_text("Value")
+                                .desired_width(50.0),
+                        );
+                        let mut frozen = watch.frozen.is_some();
+                        if ui.checkbox(&mut frozen, "Freeze").changed() {
+                            freeze = Some((i, frozen));
+                        }
+                        if ui.button("Remove").clicked() {
+                            remove = Some(i);
+                        }
+                        ui.end_row();
+                    }
+                });
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::TextEdit::singleline(&mut window.name)
+                            .hint_text("Name")
+                            .desired_width(80.0),
+                    );
+                    ui.add(
+                        egui::TextEdit::singleline(&mut window.address)
+                            .hint_text("Address or label")
+                            .desired_width(60.0),
+                    );
+                    egui::ComboBox::from_id_salt("watch_size")
+                        .selected_text(window.size.name())
+                        .show_ui(ui, |ui| {
+                            for size in WatchSize::ALL {
+                                ui.selectable_value(&mut window.size, size, size.name());
+                            }
+                        });
+                    egui::ComboBox::from_id_salt("watch_format")
+                        .selected_text(window.format.name())
+                        .show_ui(ui, |ui| {
+                            for format in WatchFormat::ALL {
+                                ui.selectable_value(&mut window.format, format, format.name());
+                            }
+                        });
+                    add = ui.button("Add").clicked();
+                });
+            });
+
+        if add {
+            match self.labels.parse_address(&window.address) {
+                Some(address) => {
+                    let name = match window.name.trim() {
+                        "" => self.labels.get(address).unwrap_or_default(),
+                        name => name,
+                    };
+                    let watch = Watch::new(name, address, window.size, window.format);
+                    window.add(watch);
+                    window.name.clear();
+                    window.address.clear();
+                }
+                None => notify(&mut self.osd, "Invalid address".to_string()),
+            }
+        }
+        if let Some((i, frozen)) = freeze {
+            let watch = &window.watches[i];
+            let value = match window.freeze_text[i].trim() {
+                "" => window
+                    .values
+                    .get(i)
+                    .copied()
+                    .flatten()
+                    .ok_or("No value to freeze yet".to_string()),
+                text => watch.parse_value(text),
+            };
+            match (frozen, value) {
+                (false, _) => window.freeze(&self.emulation, i, None),
+                (true, Ok(value)) => window.freeze(&self.emulation, i, Some(value)),
+                (true, Err(error)) => notify(&mut self.osd, error),
+            }
+        }
+        if let Some(i) = remove {
+            window.freeze(&self.emulation, i, None);
+            window.watches.remove(i);
+            window.freeze_text.remove(i);
+            window.values.clear();
+        }
+        window.request_values(&self.emulation);
+    }
+
+    fn debugger(&mut self, ctx: &egui::Context) {
+        let mut commands = vec![];
+        egui::Window::new("Debugger")
+            .open(&mut self.show_debugger)
+            .resizable(false)
+            .show(ctx, |ui| {
+                match &self.stopped_at {
+                    Some(registers) => {
+                        let labels = &self.labels;
+                        ui.monospace(labels.substitute(&format!("Stopped at {}", registers)));
+                        for frame in registers.call_stack.iter().rev() {
+                            ui.monospace(labels.substitute(&format!("  in {}", frame)));
+                        }
+                    }
+                    None => {
+                        ui.label("Running");
+                    }
+                };
+                ui.horizontal(|ui| {
+                    if ui.button("Break").clicked() {
+                        commands.push(DebugCommand::Break);
+                    }
+                    if ui.button("Continue").clicked() {
+                        commands.push(DebugCommand::Continue);
+                    }
+                    if ui.button("Step into").clicked() {
+                        commands.push(DebugCommand::Step);
+                    }
+                    if ui.button("Step over").clicked() {
+                        commands.push(DebugCommand::StepOver);
+                    }
+                    if ui.button("Step out").clicked() {
+                        commands.push(DebugCommand::StepOut);
+                    }
+                    if ui.button("History").clicked() {
+                        let _ = self
+                            .emulation
+                            .send(Command::History(self.history_sender.clone()));
+                    }
+                    if ui.button("Interrupts").clicked() {
+                        let sender = self.interrupt_sender.clone();
+                        let _ = self.emulation.send(Command::Inspect(Box::new(move |cpu| {
+                            let _ = sender.send(cpu.interrupts.lines());
+                        })));
+                    }
+                });
+                if !self.history.is_empty() {
+                    egui::CollapsingHeader::new("Instruction history").show(ui, |ui| {
+                        egui::ScrollArea::vertical()
+                            .max_height(200.0)
+                            .stick_to_bottom(true)
+                            .show(ui, |ui| ui.monospace(&self.history));
+                    });
+                }
+                if !self.interrupt_log.is_empty() {
+                    egui::CollapsingHeader::new("Interrupt log").show(ui, |ui| {
+                        egui::ScrollArea::vertical()
+                            .max_height(200.0)
+                            .stick_to_bottom(true)
+                            .show(ui, |ui| ui.monospace(&self.interrupt_log));
+                    });
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.breakpoint_text)
+                            .hint_text("Address")
+                            .desired_width(60.0),
+                    );
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.condition_text)
+                            .hint_text("Condition, e.g. A == $3F")
+                            .desired_width(160.0),
+                    );
+                });
+                if ui.button("Add breakpoint").clicked() {
+                    let condition = if self.condition_text.trim().is_empty() {
+                        Ok(None)
+                    } else {
+                        Condition::parse(&self.condition_text).map(Some)
+                    };
+                    match (self.labels.parse_address(&self.breakpoint_text), condition) {
+                        (Some(address), Ok(condition)) => {
+                            let text = condition
+                                .as_ref()
+                                .map(|condition| condition.to_string())
+                                .unwrap_or_default();
+                            self.breakpoints.insert(address, text);
+                            commands.push(DebugCommand::SetBreakpoint(address, condition));
+                            self.breakpoint_text.clear();
+                            self.condition_text.clear();
+                        }
+                        (None, _) => notify(&mut self.osd, "Invalid address".to_string()),
+                        (_, Err(error)) => notify(&mut self.osd, error),
+                    }
+                }
+                for (&address, condition) in &self.breakpoints {
+                    ui.horizontal(|ui| {
+                        let name = self.labels.get(address).unwrap_or_default();
+                        ui.monospace(format!("${:04X} {} {}", address, name, condition));
+                        if ui.button("Remove").clicked() {
+                            commands.push(DebugCommand::ClearBreakpoint(address));
+                        }
+                    });
+                }
+
+                ui.separator();
+                if ui
+                    .checkbox(&mut self.break_on_nmi, "Break on NMI")
+                    .changed()
+                {
+                    commands.push(DebugCommand::BreakOnNmi(self.break_on_nmi));
+                }
+                ui.label("Break on PPU register writes");
+                ui.horizontal_wrapped(|ui| {
+                    for register in [0x2000, 0x2001, 0x2003, 0x2004, 0x2005, 0x2006, 0x2007] {
+                        let bit = 1 << (register & 7);
+                        let mut enabled = self.ppu_write_breaks & bit != 0;
+                        if ui
+                            .checkbox(&mut enabled, format!("${:04X}", register))
+                            .changed()
+                        {
+                            self.ppu_write_breaks ^= bit;
+                            commands.push(DebugCommand::BreakOnPpuWrite(register, enabled));
+                        }
+                    }
+                });
+            });
+
+        for command in commands {
+            match command {
+                DebugCommand::Continue
+                | DebugCommand::Step
+                | DebugCommand::StepOver
+                | DebugCommand::StepOut => self.stopped_at = None,
+                DebugCommand::ClearBreakpoint(address) => {
+                    self.breakpoints.remove(&address);
+                }
+                _ => { /* do nothing */ }
+            }
+            self.emulation.send(Command::Debug(command)).unwrap();
+        }
+    }
+
+    fn debug_panels(&mut self, ctx: &egui::Context) {
+        if self.show_rom_info {
+            egui::SidePanel::right("rom_info")
+                .resizable(true)
+                .show(ctx, |ui| {
+                    ui.heading("ROM info");
+                    egui::Grid::new("rom_info_grid").show(ui, |ui| {
+                        ui.label("File");
+                        ui.label(file_name(&self.rom.path));
+                        ui.end_row();
+                        ui.label("Mapper");
+                        ui.label(self.rom.mapper_id.to_string());
+                        ui.end_row();
+                        ui.label("Mirroring");
+                        ui.label(&self.rom.mirroring);
+                        ui.end_row();
+                        ui.label("Region");
+                        ui.label(self.rom.region);
+                        ui.end_row();
+                        ui.label("PRG ROM");
+                        ui.label(format!("{} KiB", self.rom.prg_size / 1024));
+                        ui.end_row();
+                        ui.label("CHR ROM");
+                        ui.label(format!("{} KiB", self.rom.chr_size / 1024));
+                        ui.end_row();
+                        ui.label("CRC32");
+                        ui.label(format!("{:08X}", self.rom.crc));
+                        ui.end_row();
+                    });
+                });
+        }
+
+        if self.show_pattern_tables {
+            egui::SidePanel::right("pattern_tables")
+                .resizable(true)
+                .show(ctx, |ui| {
+                    ui.heading("Pattern tables");
+                    match &self.pattern_tables {
+                        Some(tables) => {
+                            for table in tables {
+                                let width = ui.available_width();
+                                ui.add(
+                                    egui::Image::new(table)
+                                        .fit_to_exact_size(egui::vec2(width, width / 4.0)),
+                                );
+                            }
+                        }
+                        None => {
+                            ui.label("The cartridge uses CHR RAM");
+                        }
+                    }
+                });
+        }
+
+        if self.show_coverage {
+            self.coverage_panel(ctx);
+        }
+    }
+
+    fn coverage_panel(&mut self, ctx: &egui::Context) {
+        if let Some(flags) = self.coverages.try_iter().last() {
+            self.coverage = flags.map(|flags| {
+                let image = egui::ColorImage::from_rgb(
+                    [COVERAGE_MAP_WIDTH, flags.len() / COVERAGE_MAP_WIDTH],
+                    &coverage_map(&flags),
+                );
+                let texture = ctx.load_texture("coverage", image, egui::TextureOptions::NEAREST);
+                (bank_coverage(&flags), texture)
+            });
+        }
+
+        egui::SidePanel::right("coverage")
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.heading("PRG coverage");
+                let Some((banks, map)) = &self.coverage else {
+                    ui.label("Start a code/data log from the File menu to track coverage");
+                    return;
+                };
+                for bank in banks {
+                    ui.label(format!("Bank {}", bank.bank));
+                    ui.add(
+                        egui::ProgressBar::new(bank.code_percent() as f32 / 100.0).text(format!(
+                            "{:.1}% code, {:.1}% data",
+                            bank.code_percent(),
+                            bank.data_percent()
+                        )),
+                    );
+                }
+                ui.label("Green ran as code, blue was read as data");
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    let width = ui.available_width();
+                    let size = map.size_vec2();
+                    ui.add(
+                        egui::Image::new(map)
+                            .fit_to_exact_size(egui::vec2(width, width * size.y / size.x)),
+                    );
+                });
+            });
+
+        let sender = self.coverage_sender.clone();
+        self.emulation
+            .send(Command::Inspect(Box::new(move |cpu| {
+                let flags = cpu.bus.code_data_log().map(|log| log.prg().to_vec());
+                let _ = sender.send(flags);
+            })))
+            .unwrap();
+    }
+
+    fn screen(&mut self, ctx: &egui::Context) {
+        // Draw the messages on a copy, so recordings and a paused frame stay clean
+        self.display.data = self.frame.data;
+        self.osd.draw(&mut self.display);
+        self.osd.tick();
+        self.screen
+            .set(color_image(&self.display), egui::TextureOptions::NEAREST);
+
+        egui::CentralPanel::default()
+            .frame(egui::Frame::NONE.fill(egui::Color32::BLACK))
+            .show(ctx, |ui| {
+                let rect = ui.max_rect();
+                let viewport =
+                    scaling::viewport(self.scale_mode, rect.width() as u32, rect.height() as u32);
+                let screen_rect = egui::Rect::from_min_size(
+                    rect.min + egui::vec2(viewport.x as f32, viewport.y as f32),
+                    egui::vec2(viewport.width as f32, viewport.height as f32),
+                );
+                ui.put(
+                    screen_rect,
+                    egui::Image::new(&self.screen).fit_to_exact_size(screen_rect.size()),
+                );
+            });
+    }
+}
+
+impl eframe::App for App {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.receive_frames();
+        self.handle_input(ctx);
+        if self.config.hot_reload && self.watcher.changed() {
+            let path = self.watcher.path().to_path_buf();
+            self.load_rom(ctx, &path, true);
+        }
+
+        egui::TopBottomPanel::top("menu_bar").show(ctx, |ui| self.menu_bar(ctx, ui));
+        self.settings(ctx);
+        self.debugger(ctx);
+        self.memory_viewer(ctx);
+        self.ram_search(ctx);
+        self.ram_watch(ctx);
+        self.cheats(ctx);
+        self.heatmap(ctx);
+        self.debug_panels(ctx);
+        self.screen(ctx);
+        self.sync_emulation();
+
+        ctx.send_viewport_cmd(egui::ViewportCommand::Title(format!(
+            "{} - {:.1} FPS",
+            self.rom.title, self.fps
+        )));
+        ctx.request_repaint();
+    }
+
+    fn on_exit(&mut self) {
+        if self.recorder.is_some() {
+            toggle_recording(&mut self.recorder, &self.config, &mut self.osd);
+        }
+        if self.config.autosave {
+            if let Err(error) = save_autosave(&self.emulation, &self.config.state_dir, self.rom.crc)
+            {
+                println!("Autosave failed: {}", error);
+            }
+        }
+    }
+}
+
+fn color_image(frame: &Frame) -> egui::ColorImage {
+    egui::ColorImage::from_rgb([FRAME_WIDTH as usize, FRAME_HEIGHT as usize], &frame.data)
+}
+
+/// Renders both CHR banks, cartridges with CHR RAM have nothing to show.
+fn pattern_tables(ctx: &egui::Context, chr_rom: &[u8]) -> Option<[egui::TextureHandle; 2]> {
+    if chr_rom.len() < 0x2000 {
+        return None;
+    }
+    Some([0, 1].map(|bank| {
+        // A bank is 32 by 8 tiles, only the top of the frame is used
+        let frame = show_tile_bank(chr_rom, bank);
+        let image = egui::ColorImage::from_rgb([256, 64], &frame.data[..256 * 64 * 3]);
+        ctx.load_texture(
+            format!("pattern_table_{}", bank),
+            image,
+            egui::TextureOptions::NEAREST,
+        )
+    }))
+}
+
+fn main() -> eframe::Result {
+    let args = logging::init(std::env::args().collect());
+    let rom_path = args
+        .get(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("pacman.nes"));
+
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default()
+            .with_title("nes_rust")
+            .with_inner_size([(FRAME_WIDTH * 3) as f32, (FRAME_HEIGHT * 3 + 24) as f32]),
+        ..Default::default()
+    };
+    eframe::run_native(
+        "nes_rust",
+        options,
+        Box::new(|cc| Ok(Box::new(App::new(cc, rom_path)))),
+    )
+}
@@ -0,0 +1,171 @@
+use rust_nes::bus::RamInit;
+use rust_nes::cartridge::Rom;
+use rust_nes::compat::{self, COMPAT_FRAMES};
+#[cfg(feature = "remote")]
+use rust_nes::emulation::{self, Command};
+use rust_nes::frontend::open_rom;
+use rust_nes::gametest::TestScript;
+use rust_nes::logging;
+use rust_nes::nestest;
+use std::fs;
+use std::path::Path;
+use std::process::ExitCode;
+
+/// Runs a game test script on a ROM without any window, e.g.
+/// `rust_nes_test game.nes lives.test`, and exits with a failure when a check fails.
+///
+/// `rust_nes_test --nestest nestest.nes nestest.log` compares the CPU trace of nestest with a
+/// reference log instead, `rust_nes_test --compat roms report.html` runs every ROM of a
+/// directory for a few seconds and writes a compatibility report as HTML or CSV, and with the
+/// `conformance` feature
+/// `rust_nes_test --single-step nes6502/v1` runs the single-step CPU tests of a directory.
+/// With the `remote` feature `rust_nes_test --serve game.nes 127.0.0.1:6502` runs a game
+/// for remote clients, paused until they press buttons for some frames.
+fn main() -> ExitCode {
+    let args = logging::init(std::env::args().collect());
+    let passed = match args.len() {
+        #[cfg(feature = "conformance")]
+        3 if args[1] == "--single-step" => run_single_step(&args[2]),
+        3 => run_script(&args[1], &args[2]),
+        4 if args[1] == "--nestest" => run_nestest(&args[2], &args[3]),
+        4 if args[1] == "--compat" => run_compat(&args[2], &args[3]),
+        #[cfg(feature = "remote")]
+        4 if args[1] == "--serve" => run_server(&args[2], &args[3]),
+        _ => {
+            println!("Usage: rust_nes_test <rom> <script>");
+            println!("       rust_nes_test --nestest <rom> <reference log>");
+            println!("       rust_nes_test --compat <rom directory> <report.html|report.csv>");
+            #[cfg(feature = "conformance")]
+            println!("       rust_nes_test --single-step <test directory>");
+            #[cfg(feature = "remote")]
+            println!("       rust_nes_test --serve <rom> <address>");
+            false
+        }
+    };
+    if passed {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn load_rom(path: &str) -> Option<Rom> {
+    match open_rom(Path::new(path)) {
+        Ok(rom) => Some(rom),
+        Err(error) => {
+            println!("Open failed: {}", error);
+            None
+        }
+    }
+}
+
+fn run_script(rom_path: &str, script_path: &str) -> bool {
+    let Some(rom) = load_rom(rom_path) else {
+        return false;
+    };
+    let script = match TestScript::load(Path::new(script_path)) {
+        Ok(script) => script,
+        Err(error) => {
+            println!("Reading {} failed: {}", script_path, error);
+            return false;
+        }
+    };
+
+    // RAM starts zeroed whatever the configuration says, so results are the same everywhere
+    let report = script.run(rom, RamInit::Zero);
+    println!("{}", report);
+    report.passed()
+}
+
+fn run_nestest(rom_path: &str, log: &str) -> bool {
+    let Some(rom) = load_rom(rom_path) else {
+        return false;
+    };
+    let reference = match fs::read_to_string(log) {
+        Ok(reference) => reference,
+        Err(error) => {
+            println!("Reading {} failed: {}", log, error);
+            return false;
+        }
+    };
+
+    match nestest::compare(rom, &reference) {
+        Ok(lines) => {
+            println!("PASS, all {} lines match", lines);
+            true
+        }
+        Err(divergence) => {
+            println!("{}", divergence);
+            false
+        }
+    }
+}
+
+fn run_compat(dir: &str, report_path: &str) -> bool {
+    let reports = compat::check_dir(Path::new(dir), COMPAT_FRAMES);
+    let report = if report_path.ends_with(".csv") {
+        compat::to_csv(&reports)
+    } else {
+        compat::to_html(&reports)
+    };
+    if let Err(error) = fs::write(report_path, report) {
+        println!("Writing {} failed: {}", report_path, error);
+        return false;
+    }
+    println!(
+        "{} of {} ROMs ran without problems, wrote {}",
+        compat::passed(&reports),
+        reports.len(),
+        report_path
+    );
+    true
+}
+
+#[cfg(feature = "remote")]
+fn run_server(rom_path: &str, address: &str) -> bool {
+    let Some(rom) = load_rom(rom_path) else {
+        return false;
+    };
+    let (emulation, commands) = std::sync::mpsc::channel();
+    // Nobody shows the frames, clients ask for them
+    let (frames, _) = rust_nes::render::frame_channel();
+    emulation.send(Command::Pause(true)).unwrap();
+    let thread = emulation::spawn(
+        rom,
+        RamInit::Zero,
+        None,
+        0,
+        std::env::temp_dir(),
+        commands,
+        frames,
+    );
+    if let Err(error) = rust_nes::remote::spawn_server(address, emulation) {
+        println!("Remote server failed: {}", error);
+        return false;
+    }
+    thread.join().is_ok()
+}
+
+#[cfg(feature = "conformance")]
+fn run_single_step(dir: &str) -> bool {
+    let reports = match rust_nes::singlestep::run_dir(Path::new(dir)) {
+        Ok(reports) => reports,
+        Err(error) => {
+            println!("Reading {} failed: {}", dir, error);
+            return false;
+        }
+    };
+    let mut failed_files = 0;
+    for report in &reports {
+        if report.passed < report.cases {
+            failed_files += 1;
+            println!("{}", report);
+        }
+    }
+    println!(
+        "{} of {} opcode files passed",
+        reports.len() - failed_files,
+        reports.len()
+    );
+    failed_files == 0
+}
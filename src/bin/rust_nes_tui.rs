@@ -0,0 +1,50 @@
+use rust_nes::config::{Config, CONFIG_PATH};
+use rust_nes::emulation;
+use rust_nes::frontend::{load_labels, open_rom};
+use rust_nes::logging;
+use rust_nes::render;
+use rust_nes::tui;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+
+/// Runs a game without a window, controlled from the terminal debugger.
+fn main() {
+    let args = logging::init(std::env::args().collect());
+    let rom_path = args
+        .get(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("pacman.nes"));
+    let rom = match open_rom(&rom_path) {
+        Ok(rom) => rom,
+        Err(error) => {
+            println!("Open failed: {}", error);
+            return;
+        }
+    };
+
+    let mut config = Config::load(Path::new(CONFIG_PATH));
+    config.apply_profile(rom.crc);
+    let labels = load_labels(&rom_path, &rom);
+
+    // Nothing shows the frames, dropping the receiver makes sending them a no-op
+    let (emulation, commands) = mpsc::channel();
+    let (frame_sender, _) = render::frame_channel();
+    let frame_rate = rom.region.frame_rate() * config.speed as f64 / 100.0;
+    emulation::spawn(
+        rom,
+        config.ram_init,
+        Some(frame_rate),
+        config.rewind_seconds,
+        config.state_dir.clone(),
+        commands,
+        frame_sender,
+    );
+
+    #[cfg(feature = "remote")]
+    rust_nes::frontend::start_remote_server(&emulation);
+    #[cfg(any(feature = "lua", feature = "rhai"))]
+    rust_nes::frontend::start_scripts(&emulation);
+    if let Err(error) = tui::run(emulation, labels) {
+        println!("Terminal debugger failed: {}", error);
+    }
+}
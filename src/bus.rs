@@ -1,15 +1,84 @@
-use crate::cartridge::Rom;
+use crate::callstack::CallFrame;
+use crate::cartridge::{Region, Rom};
+use crate::cdl::CodeDataLog;
 use crate::cpu::Mem;
+use crate::events::{EventKind, EventLog};
+use crate::heatmap::AccessHeatmap;
 use crate::joypad::Joypad;
+use crate::logging;
+use crate::opcodes;
 use crate::ppu::PPU;
+use crate::profiler::Profiler;
+use crate::state::{StateChunks, StateWriter};
+use crate::unchecked;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+/// Cycles the CPU spends on the reset sequence before the first instruction, which is where
+/// nestest.log starts counting. The PPU starts 21 dots in to match.
+pub const RESET_CYCLES: u64 = 7;
+
+/// Contents of CPU RAM after power-on, which differ between consoles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RamInit {
+    Zero,
+    Ones,
+    /// Alternating blocks of four $00 and four $FF bytes.
+    Stripes,
+    /// Random bytes from the given seed, so runs can be reproduced.
+    Random(u64),
+}
+
+impl RamInit {
+    fn fill(self, ram: &mut [u8]) {
+        match self {
+            RamInit::Zero => ram.fill(0x00),
+            RamInit::Ones => ram.fill(0xff),
+            RamInit::Stripes => {
+                for (i, byte) in ram.iter_mut().enumerate() {
+                    *byte = if i & 0b100 == 0 { 0x00 } else { 0xff };
+                }
+            }
+            RamInit::Random(seed) => StdRng::seed_from_u64(seed).fill(ram),
+        }
+    }
+}
+
+type Callback<'call> = Box<dyn FnMut(&PPU, &mut Joypad) + 'call>;
 
 pub struct Bus<'call> {
     cpu_ram: [u8; 0x0800],
+    ram_init: RamInit,
     prg_rom: Vec<u8>,
+    rom_crc: u32,
     pub ppu: PPU,
     joypad_1: Joypad,
+    joypad_2: Joypad,
+    cycles: u64,
+    frames: u64,
+    /// Fifths of a dot the PAL PPU still owes, it runs 3.2 dots per CPU cycle.
+    pal_dot_fifths: u16,
+    cdl: Option<CodeDataLog>,
+    profiler: Option<Profiler>,
+    heatmap: Option<AccessHeatmap>,
+    events: Option<EventLog>,
+    /// RAM bytes written back at the end of every frame.
+    frozen: BTreeMap<u16, u8>,
+    /// Set while the CPU hands control to its callback, whose accesses are not the game's.
+    in_callback: bool,
+    /// PPU registers whose writes stop the debugger, bit n is $2000 + n.
+    ppu_write_watch: u8,
+    ppu_write_hit: Option<(u16, u8)>,
+    /// Addresses whose writes are collected for scripts, RAM without its mirrors.
+    write_watch: BTreeSet<u16>,
+    watched_writes: Vec<(u16, u8)>,
+    /// 64K of plain RAM in place of every device, for CPU tests written for a bare 6502.
+    #[cfg(any(test, feature = "conformance"))]
+    flat_memory: Option<Vec<u8>>,
 
-    callback: Box<dyn FnMut(&PPU, &mut Joypad) + 'call>,
+    callback: Callback<'call>,
 }
 
 impl<'a> Bus<'a> {
@@ -17,25 +86,341 @@ impl<'a> Bus<'a> {
     where
         F: FnMut(&PPU, &mut Joypad) + 'call,
     {
-        let ppu = PPU::new(rom.chr_rom, rom.screen_mirroring);
+        if rom.mapper_id != 0 {
+            log::warn!(
+                target: logging::MAPPER,
+                "Mapper {} is not supported, running the game as NROM",
+                rom.mapper_id
+            );
+        }
+        let mut ppu = PPU::new(rom.chr_rom, rom.screen_mirroring);
+        ppu.region = rom.region;
 
         Bus {
             cpu_ram: [0; 0x0800],
+            ram_init: RamInit::Zero,
             prg_rom: rom.prg_rom,
+            rom_crc: rom.crc,
             ppu,
             joypad_1: Joypad::new(),
+            joypad_2: Joypad::new(),
+            cycles: RESET_CYCLES,
+            frames: 0,
+            pal_dot_fifths: 0,
+            cdl: None,
+            profiler: None,
+            heatmap: None,
+            events: None,
+            frozen: BTreeMap::new(),
+            in_callback: false,
+            ppu_write_watch: 0,
+            ppu_write_hit: None,
+            write_watch: BTreeSet::new(),
+            watched_writes: Vec::new(),
+            #[cfg(any(test, feature = "conformance"))]
+            flat_memory: None,
 
             callback: Box::from(callback),
         }
     }
 
     pub fn tick(&mut self, cycles: u8) {
-        //self.cycles += cycles;
-        if self.ppu.tick(3 * cycles) {
+        self.cycles += cycles as u64;
+        let dots = match self.ppu.region {
+            Region::Ntsc => 3 * cycles,
+            Region::Pal => {
+                let fifths = self.pal_dot_fifths + 16 * cycles as u16;
+                self.pal_dot_fifths = fifths % 5;
+                (fifths / 5) as u8
+            }
+        };
+        if self.ppu.tick(dots) {
+            self.frames += 1;
+            if let Some(cdl) = &mut self.cdl {
+                cdl.log_frame(&self.ppu);
+            }
+            if let Some(profiler) = &mut self.profiler {
+                profiler.end_frame();
+            }
+            for (&adr, &data) in &self.frozen {
+                self.cpu_ram[adr as usize] = data;
+            }
             (self.callback)(&self.ppu, &mut self.joypad_1);
         }
     }
 
+    /// CPU cycles since power-on, not part of save states.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Frames finished since power-on, not part of save states.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Starts logging which ROM bytes are code and data, dropping any running log.
+    pub fn start_code_data_log(&mut self) {
+        self.cdl = Some(CodeDataLog::new(self.prg_rom.len(), self.ppu.chr_rom.len()));
+    }
+
+    pub fn code_data_log(&self) -> Option<&CodeDataLog> {
+        self.cdl.as_ref()
+    }
+
+    /// Stops logging and returns the log.
+    pub fn take_code_data_log(&mut self) -> Option<CodeDataLog> {
+        self.cdl.take()
+    }
+
+    /// Keeps a RAM byte at `data` by writing it at the end of every frame, `None` releases it.
+    /// Addresses outside $0000-$1FFF are ignored.
+    pub fn freeze(&mut self, adr: u16, data: Option<u8>) {
+        if adr >= 0x2000 {
+            return;
+        }
+        match data {
+            Some(data) => self.frozen.insert(adr & 0x07ff, data),
+            None => self.frozen.remove(&(adr & 0x07ff)),
+        };
+    }
+
+    /// Starts counting reads and writes per address, dropping any running counts.
+    pub fn start_heatmap(&mut self) {
+        self.heatmap = Some(AccessHeatmap::new());
+    }
+
+    pub fn stop_heatmap(&mut self) {
+        self.heatmap = None;
+    }
+
+    pub fn heatmap(&mut self) -> Option<&mut AccessHeatmap> {
+        self.heatmap.as_mut()
+    }
+
+    /// Starts recording frame times, resets and state loads, dropping any running log.
+    pub fn start_event_log(&mut self) {
+        self.events = Some(EventLog::default());
+    }
+
+    pub fn take_event_log(&mut self) -> Option<EventLog> {
+        self.events.take()
+    }
+
+    pub fn event_log(&self) -> Option<&EventLog> {
+        self.events.as_ref()
+    }
+
+    /// Records an event at the current frame when the event log is running.
+    pub fn log_event(&mut self, kind: EventKind) {
+        if let Some(events) = &mut self.events {
+            events.record(self.frames, kind);
+        }
+    }
+
+    /// Starts counting cycles per routine, dropping any running profile.
+    pub fn start_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    /// Stops profiling and returns the profile.
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Counts the cycles of the instruction at `adr` that just finished.
+    pub fn profile(&mut self, adr: u16, call_stack: &[CallFrame]) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(adr, self.cycles, call_stack);
+        }
+    }
+
+    /// Marks the accesses that follow as made by tooling rather than the game, so they are
+    /// neither logged nor trip watches.
+    pub fn set_in_callback(&mut self, in_callback: bool) {
+        self.in_callback = in_callback;
+    }
+
+    /// Watches writes to a PPU register, `register` is one of $2000-$2007.
+    pub fn watch_ppu_write(&mut self, register: u16, enabled: bool) {
+        let bit = 1 << (register & 7);
+        if enabled {
+            self.ppu_write_watch |= bit;
+        } else {
+            self.ppu_write_watch &= !bit;
+        }
+    }
+
+    /// The last watched PPU register write since the previous call, with the value written.
+    pub fn take_ppu_write(&mut self) -> Option<(u16, u8)> {
+        self.ppu_write_hit.take()
+    }
+
+    /// Collects the writes to `adr` for `take_watched_writes`, RAM mirrors count as the same
+    /// address.
+    pub fn watch_write(&mut self, adr: u16, enabled: bool) {
+        let adr = unmirrored(adr);
+        if enabled {
+            self.write_watch.insert(adr);
+        } else {
+            self.write_watch.remove(&adr);
+        }
+    }
+
+    /// Stops collecting writes and drops the ones not taken yet.
+    pub fn clear_write_watches(&mut self) {
+        self.write_watch.clear();
+        self.watched_writes.clear();
+    }
+
+    /// The watched writes since the previous call, oldest first.
+    pub fn take_watched_writes(&mut self) -> Vec<(u16, u8)> {
+        std::mem::take(&mut self.watched_writes)
+    }
+
+    /// Replaces RAM, the PPU, I/O and the cartridge with 64K of plain memory, or puts them back.
+    #[cfg(any(test, feature = "conformance"))]
+    pub fn set_flat_memory(&mut self, memory: Option<Vec<u8>>) {
+        assert!(memory.as_ref().is_none_or(|memory| memory.len() == 0x10000));
+        self.flat_memory = memory;
+    }
+
+    /// Reads RAM or PRG ROM without logging the access, other addresses read as `None`.
+    pub fn peek(&self, adr: u16) -> Option<u8> {
+        #[cfg(any(test, feature = "conformance"))]
+        if let Some(memory) = &self.flat_memory {
+            return Some(memory[adr as usize]);
+        }
+        match adr {
+            0x0000..=0x1fff => Some(self.cpu_ram[adr as usize & 0x07ff]),
+            0x8000..=0xffff => Some(self.prg_rom[self.prg_offset(adr)]),
+            _ => None,
+        }
+    }
+
+    /// Marks the instruction at `adr` as code when it is in ROM.
+    pub fn log_code(&mut self, adr: u16) {
+        if adr < 0x8000 {
+            return;
+        }
+        let offset = self.prg_offset(adr);
+        if let Some(cdl) = &mut self.cdl {
+            let length = opcodes::OPCODES_MAP
+                .get(&self.prg_rom[offset])
+                .map_or(1, |opcode| opcode.len);
+            cdl.log_code(offset, adr, length);
+        }
+    }
+
+    /// The 16K PRG ROM bank the CPU sees at `adr`, which is $8000 or up.
+    pub fn prg_bank(&self, adr: u16) -> usize {
+        self.prg_offset(adr) / 0x4000
+    }
+
+    /// Always within PRG ROM, which is one 16K bank mirrored or at least two.
+    fn prg_offset(&self, adr: u16) -> usize {
+        if self.prg_rom.len() == 0x4000 {
+            adr as usize & 0x3fff
+        } else {
+            adr as usize - 0x8000
+        }
+    }
+
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad_1
+    }
+
+    /// The controller in the second port, for a second player.
+    pub fn joypad_2_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad_2
+    }
+
+    /// The 2K of CPU RAM without its mirrors, for frontends that expose it directly.
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.cpu_ram
+    }
+
+    /// CRC32 of the loaded cartridge, identifies the game.
+    pub fn rom_crc(&self) -> u32 {
+        self.rom_crc
+    }
+
+    /// Replaces the cartridge, the system should be power cycled afterwards.
+    pub fn load_rom(&mut self, rom: Rom) {
+        self.prg_rom = rom.prg_rom;
+        self.rom_crc = rom.crc;
+        self.ppu.chr_rom = rom.chr_rom;
+        self.ppu.mirroring = rom.screen_mirroring;
+        self.ppu.region = rom.region;
+        self.ppu.mark_palette_dirty();
+        self.ppu.mark_background_dirty();
+        // Frozen bytes and cheats belong to the old game
+        self.frozen.clear();
+        if self.cdl.is_some() {
+            self.start_code_data_log();
+        }
+        self.log_event(EventKind::RomLoad);
+    }
+
+    /// Resets the devices that are connected to the reset line, RAM is left untouched.
+    pub fn reset(&mut self) {
+        // todo reset APU and mapper once they are implemented
+        self.ppu.reset();
+        self.joypad_1.write(0);
+        self.joypad_2.write(0);
+        self.log_event(EventKind::Reset);
+    }
+
+    /// Sets the RAM contents used by the next power cycle.
+    pub fn set_ram_init(&mut self, ram_init: RamInit) {
+        self.ram_init = ram_init;
+    }
+
+    /// Adds idle scanlines to every frame after vertical blank, running the CPU that much
+    /// longer per frame.
+    pub fn set_overclock(&mut self, scanlines: u16) {
+        self.ppu.extra_scanlines = scanlines;
+    }
+
+    /// Returns all devices to their power-on state.
+    pub fn power_cycle(&mut self) {
+        self.ram_init.fill(&mut self.cpu_ram);
+        self.ppu.power_cycle();
+        self.joypad_1 = Joypad::new();
+        self.joypad_2 = Joypad::new();
+        self.cycles = RESET_CYCLES;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.restart_timing();
+        }
+        self.pal_dot_fifths = 0;
+        self.frames = 0;
+        self.log_event(EventKind::PowerCycle);
+    }
+
+    /// Writes RAM and the state of the connected devices, each in its own chunk.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        // todo save APU and mapper state once they are implemented
+        writer.write_chunk(*b"RAM ", |writer| writer.write_bytes(&self.cpu_ram));
+        writer.write_chunk(*b"PPU ", |writer| self.ppu.save_state(writer));
+        writer.write_chunk(*b"JOY1", |writer| self.joypad_1.save_state(writer));
+        writer.write_chunk(*b"JOY2", |writer| self.joypad_2.save_state(writer));
+    }
+
+    pub fn load_state(&mut self, chunks: &StateChunks) -> io::Result<()> {
+        chunks.reader(*b"RAM ")?.read_bytes(&mut self.cpu_ram)?;
+        self.ppu.load_state(&mut chunks.reader(*b"PPU ")?)?;
+        self.joypad_1.load_state(&mut chunks.reader(*b"JOY1")?)?;
+        // States from before the second controller leave it as it is
+        match chunks.reader(*b"JOY2") {
+            Ok(mut reader) => self.joypad_2.load_state(&mut reader),
+            Err(_) => Ok(()),
+        }
+    }
+
     pub fn get_nmi(&mut self) -> bool {
         self.ppu.get_nmi()
     }
@@ -43,12 +428,27 @@ impl<'a> Bus<'a> {
 
 impl Mem for Bus<'_> {
     fn read(&mut self, adr: u16) -> u8 {
+        #[cfg(any(test, feature = "conformance"))]
+        if let Some(memory) = &self.flat_memory {
+            return unchecked::read(memory, adr as usize);
+        }
+        if let (Some(heatmap), false) = (&mut self.heatmap, self.in_callback) {
+            heatmap.log_read(adr);
+        }
         match adr {
-            0x0000..=0x1fff => self.cpu_ram[adr as usize & 0x07ff],
+            0x0000..=0x1fff => unchecked::read(&self.cpu_ram, adr as usize & 0x07ff),
             0x2000..=0x3fff => match adr & 0x2007 {
                 0x2002 => self.ppu.read_status(),
                 0x2004 => self.ppu.read_oam_data(),
-                0x2007 => self.ppu.read_data(),
+                0x2007 => {
+                    let address = self.ppu.register_address.get();
+                    if let (Some(cdl), false) = (&mut self.cdl, self.in_callback) {
+                        if address < 0x2000 {
+                            cdl.log_chr_read(address);
+                        }
+                    }
+                    self.ppu.read_data()
+                }
                 _ => panic!("Attempted to read from write-only PPU register {:x}", adr),
             },
             0x4000..=0x4015 => {
@@ -56,40 +456,57 @@ impl Mem for Bus<'_> {
                 0
             }
             0x4016 => self.joypad_1.read(),
-            0x4017 => {
-                // todo implement joy pad 2 read, return 0 for now
-                0
-            }
+            0x4017 => self.joypad_2.read(),
             0x8000..=0xffff => {
-                if self.prg_rom.len() == 0x4000 {
-                    self.prg_rom[adr as usize & 0x3fff]
-                } else {
-                    self.prg_rom[adr as usize - 0x8000]
+                let offset = self.prg_offset(adr);
+                if let (Some(cdl), false) = (&mut self.cdl, self.in_callback) {
+                    cdl.log_data(offset, adr);
                 }
+                unchecked::read(&self.prg_rom, offset)
             }
             _ => {
-                println!("Ignoring mem access at {:#x}", adr);
+                log::debug!(target: logging::BUS, "Ignoring mem access at {:#x}", adr);
                 0
             }
         }
     }
 
     fn write(&mut self, adr: u16, data: u8) {
+        #[cfg(any(test, feature = "conformance"))]
+        if let Some(memory) = &mut self.flat_memory {
+            unchecked::write(memory, adr as usize, data);
+            return;
+        }
+        if let (Some(heatmap), false) = (&mut self.heatmap, self.in_callback) {
+            heatmap.log_write(adr);
+        }
+        if !self.write_watch.is_empty()
+            && !self.in_callback
+            && self.write_watch.contains(&unmirrored(adr))
+        {
+            self.watched_writes.push((unmirrored(adr), data));
+        }
         match adr {
             0x0000..=0x1fff => {
-                self.cpu_ram[adr as usize & 0x07ff] = data;
+                unchecked::write(&mut self.cpu_ram, adr as usize & 0x07ff, data);
+            }
+            0x2000..=0x3fff => {
+                let register = adr & 0x2007;
+                if self.ppu_write_watch & 1 << (register & 7) != 0 && !self.in_callback {
+                    self.ppu_write_hit = Some((register, data));
+                }
+                match register {
+                    0x2000 => self.ppu.write_control(data),
+                    0x2001 => self.ppu.write_mask(data),
+                    0x2002 => panic!("Attempted to write to PPU status register"),
+                    0x2003 => self.ppu.write_oam_address(data),
+                    0x2004 => self.ppu.write_oam_data(data),
+                    0x2005 => self.ppu.write_scroll(data),
+                    0x2006 => self.ppu.write_address(data),
+                    0x2007 => self.ppu.write_data(data),
+                    _ => unreachable!(),
+                }
             }
-            0x2000..=0x3fff => match adr & 0x2007 {
-                0x2000 => self.ppu.write_control(data),
-                0x2001 => self.ppu.write_mask(data),
-                0x2002 => panic!("Attempted to write to PPU status register"),
-                0x2003 => self.ppu.write_oam_address(data),
-                0x2004 => self.ppu.write_oam_data(data),
-                0x2005 => self.ppu.write_scroll(data),
-                0x2006 => self.ppu.write_address(data),
-                0x2007 => self.ppu.write_data(data),
-                _ => unreachable!(),
-            },
             0x4000..=0x4013 | 0x4015 => {
                 // ignore APU
             }
@@ -102,24 +519,92 @@ impl Mem for Bus<'_> {
 
                 self.ppu.write_oam_dma(&buffer);
             }
-            0x4016 => self.joypad_1.write(data),
+            // The strobe goes to both controllers
+            0x4016 => {
+                self.joypad_1.write(data);
+                self.joypad_2.write(data);
+            }
             0x4017 => {
-                // ignore joy pad 2
+                // todo APU frame counter
             }
             0x8000..=0xffff => {
                 panic!("Attempted to write to Cartridge ROM space")
             }
             _ => {
-                println!("Ignoring mem write-access at {:#x}", adr);
+                log::debug!(target: logging::BUS, "Ignoring mem write-access at {:#x}", adr);
             }
         }
     }
 }
 
+/// RAM addresses without their mirrors, other addresses as they are.
+fn unmirrored(adr: u16) -> u16 {
+    if adr < 0x2000 {
+        adr & 0x07ff
+    } else {
+        adr
+    }
+}
+
 #[cfg(test)]
-mod test {
+pub mod test {
     use super::*;
     use crate::cartridge::test::test_rom;
+    use crate::cpu::CPU;
+
+    /// 64K of flat memory to set up a CPU test in, with code anywhere including RAM.
+    pub struct TestBus {
+        memory: Vec<u8>,
+    }
+
+    impl Mem for TestBus {
+        fn read(&mut self, adr: u16) -> u8 {
+            self.memory[adr as usize]
+        }
+
+        fn write(&mut self, adr: u16, val: u8) {
+            self.memory[adr as usize] = val;
+        }
+    }
+
+    impl Default for TestBus {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl TestBus {
+        /// Zeroed memory with the reset vector pointing at $8000.
+        pub fn new() -> Self {
+            let mut bus = TestBus {
+                memory: vec![0; 0x10000],
+            };
+            bus.set_vectors(0x0000, 0x8000, 0x0000);
+            bus
+        }
+
+        /// Places code or data at `adr`.
+        pub fn load(&mut self, adr: u16, bytes: &[u8]) {
+            let start = adr as usize;
+            self.memory[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+
+        pub fn set_vectors(&mut self, nmi: u16, reset: u16, irq: u16) {
+            self.write_address(0xfffa, nmi);
+            self.write_address(0xfffc, reset);
+            self.write_address(0xfffe, irq);
+        }
+
+        /// A powered on CPU on this memory, about to run from the reset vector. The PPU still
+        /// ticks, but none of its registers are mapped.
+        pub fn into_cpu(self) -> CPU<'static> {
+            let mut bus = Bus::new(test_rom(vec![0; 0x8000]), |_, _| {});
+            bus.set_flat_memory(Some(self.memory));
+            let mut cpu = CPU::new(bus);
+            cpu.power_cycle();
+            cpu
+        }
+    }
 
     #[test]
     fn test_read_write_ram() {
@@ -127,4 +612,101 @@ mod test {
         bus.write(0x01, 0x55);
         assert_eq!(bus.read(0x01), 0x55);
     }
+
+    #[test]
+    fn test_power_cycle_while_profiling() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        cpu.power_cycle();
+        cpu.bus.start_profiler();
+        for _ in 0..1000 {
+            cpu.step();
+        }
+        let profiled = |cpu: &CPU| -> u64 {
+            let profiler = cpu.bus.profiler().unwrap();
+            let addresses = profiler.hottest_addresses(0x10000);
+            addresses.iter().map(|(_, cycles)| cycles).sum()
+        };
+        let before = profiled(&cpu);
+        // The cycle count starts over, the first instruction after it has nothing to count
+        cpu.power_cycle();
+        cpu.step();
+        assert_eq!(profiled(&cpu), before);
+    }
+
+    #[test]
+    fn test_region_timing() {
+        let two_frames = |region| {
+            let mut rom = test_rom(vec![0; 0x8000]);
+            rom.region = region;
+            let mut bus = Bus::new(rom, |_, _| {});
+            while bus.frames() < 1 {
+                bus.tick(1);
+            }
+            let start = bus.cycles();
+            while bus.frames() < 3 {
+                bus.tick(1);
+            }
+            bus.cycles() - start
+        };
+        // An NTSC frame is 29780.67 CPU cycles, a PAL frame 33247.5
+        assert!((59561..=59562).contains(&two_frames(Region::Ntsc)));
+        assert!((66495..=66496).contains(&two_frames(Region::Pal)));
+    }
+
+    #[test]
+    fn test_frozen_ram() {
+        let mut bus = Bus::new(test_rom(vec![0; 0x8000]), |_, _| {});
+        bus.freeze(0x0801, Some(3));
+        bus.freeze(0x2000, Some(3));
+        bus.write(0x01, 0x55);
+        // A frame is 29780.5 CPU cycles
+        for _ in 0..29781 / 3 + 1 {
+            bus.tick(3);
+        }
+        assert_eq!(bus.read(0x01), 3);
+
+        bus.freeze(0x0801, None);
+        bus.write(0x01, 0x55);
+        for _ in 0..29781 / 3 + 1 {
+            bus.tick(3);
+        }
+        assert_eq!(bus.read(0x01), 0x55);
+    }
+
+    #[test]
+    fn test_write_watch() {
+        let mut bus = Bus::new(test_rom(vec![0; 0x8000]), |_, _| {});
+        bus.watch_write(0x0875, true);
+        bus.write(0x0075, 1);
+        bus.write(0x0076, 2);
+        bus.write(0x1075, 3);
+        assert_eq!(bus.take_watched_writes(), [(0x0075, 1), (0x0075, 3)]);
+        assert!(bus.take_watched_writes().is_empty());
+
+        bus.watch_write(0x0075, false);
+        bus.write(0x0075, 4);
+        assert!(bus.take_watched_writes().is_empty());
+    }
+
+    #[test]
+    fn test_ram_init_patterns() {
+        let mut bus = Bus::new(test_rom(vec![0; 0x8000]), |_, _| {});
+
+        bus.set_ram_init(RamInit::Ones);
+        bus.power_cycle();
+        assert_eq!(bus.read(0x07ff), 0xff);
+
+        bus.set_ram_init(RamInit::Stripes);
+        bus.power_cycle();
+        assert_eq!(bus.read(0x0003), 0x00);
+        assert_eq!(bus.read(0x0004), 0xff);
+        assert_eq!(bus.read(0x0008), 0x00);
+
+        bus.set_ram_init(RamInit::Random(42));
+        bus.power_cycle();
+        let first: Vec<u8> = (0..0x0800).map(|adr| bus.read(adr)).collect();
+        bus.power_cycle();
+        let second: Vec<u8> = (0..0x0800).map(|adr| bus.read(adr)).collect();
+        assert_eq!(first, second);
+    }
 }
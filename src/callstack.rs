@@ -0,0 +1,135 @@
+use std::fmt;
+use std::fmt::Write as _;
+
+/// Calls kept at most, the 256 byte stack only holds 128 return addresses.
+pub const CALL_STACK_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallKind {
+    Subroutine,
+    Nmi,
+    Brk,
+}
+
+/// A subroutine call or interrupt that has not returned yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    /// Address of the JSR or BRK, or of the instruction the NMI interrupted.
+    pub from: u16,
+    /// Start of the subroutine or interrupt handler.
+    pub target: u16,
+    /// Where the RTS or RTI continues.
+    pub return_address: u16,
+    /// Stack pointer after the return address was pushed.
+    pub s: u8,
+}
+
+impl fmt::Display for CallFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            CallKind::Subroutine => "JSR",
+            CallKind::Nmi => "NMI",
+            CallKind::Brk => "BRK",
+        };
+        write!(
+            f,
+            "{} ${:04X} from ${:04X}, returns to ${:04X}",
+            kind, self.target, self.from, self.return_address
+        )
+    }
+}
+
+/// Shadow of the calls on the hardware stack, kept up to date by the CPU.
+///
+/// Games sometimes drop a return address with PLA or jump through an RTS, so returns are
+/// matched on the stack pointer rather than on the order of the calls.
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn call(&mut self, frame: CallFrame) {
+        if self.frames.len() == CALL_STACK_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    /// Handles an RTS or RTI with stack pointer `s`, before the return address is pulled.
+    pub fn ret(&mut self, s: u8) -> Option<CallFrame> {
+        // Calls whose return address was already pulled some other way are gone
+        while self.frames.last().is_some_and(|frame| frame.s < s) {
+            self.frames.pop();
+        }
+        match self.frames.last() {
+            Some(frame) if frame.s == s => self.frames.pop(),
+            _ => None,
+        }
+    }
+
+    /// Outermost call first.
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// One call per line, innermost first like a backtrace.
+    pub fn lines(&self) -> String {
+        let mut text = String::new();
+        for frame in self.frames.iter().rev() {
+            writeln!(text, "{}", frame).unwrap();
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(target: u16, s: u8) -> CallFrame {
+        CallFrame {
+            kind: CallKind::Subroutine,
+            from: 0x8000,
+            target,
+            return_address: 0x8003,
+            s,
+        }
+    }
+
+    #[test]
+    fn test_returns_match_stack_pointer() {
+        let mut stack = CallStack::new();
+        stack.call(frame(0xc000, 0xfb));
+        stack.call(frame(0xc100, 0xf9));
+        stack.call(frame(0xc200, 0xf7));
+
+        // The innermost return address was pulled with PLA, so its frame is dropped too
+        assert_eq!(stack.ret(0xf9), Some(frame(0xc100, 0xf9)));
+        assert_eq!(stack.frames(), [frame(0xc000, 0xfb)]);
+
+        // An RTS used as a jump does not match a call
+        assert_eq!(stack.ret(0xf9), None);
+        assert_eq!(stack.frames().len(), 1);
+        assert_eq!(stack.lines(), "JSR $C000 from $8000, returns to $8003\n");
+    }
+
+    #[test]
+    fn test_depth_is_limited() {
+        let mut stack = CallStack::new();
+        for i in 0..CALL_STACK_DEPTH + 1 {
+            stack.call(frame(i as u16, 0));
+        }
+        assert_eq!(stack.frames().len(), CALL_STACK_DEPTH);
+        assert_eq!(stack.frames()[0].target, 1);
+    }
+}
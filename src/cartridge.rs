@@ -1,3 +1,5 @@
+use crate::region::{NTSC_FRAME_RATE, PAL_FRAME_RATE};
+
 #[derive(Debug, PartialEq)]
 pub enum Mirroring {
     Vertical,
@@ -5,24 +7,103 @@ pub enum Mirroring {
     FourScreen,
 }
 
+/// Timing of the console the game was made for. Both use the same palette, PAL only swaps the
+/// red and green emphasis bits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    pub fn name(self) -> &'static str {
+        match self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+        }
+    }
+
+    /// Parses `ntsc` or `pal` in any case.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            _ => None,
+        }
+    }
+
+    pub fn frame_rate(self) -> f64 {
+        match self {
+            Region::Ntsc => NTSC_FRAME_RATE,
+            Region::Pal => PAL_FRAME_RATE,
+        }
+    }
+
+    /// Scanlines per frame, vertical blank starts at scanline 241 in both regions.
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal => 312,
+        }
+    }
+}
+
+/// Region from the header: the NES 2.0 timing field, or the rarely set PAL bit of iNES flags 9.
+/// `None` when the header doesn't say or the game runs on both.
+pub fn header_region(bytes: &[u8]) -> Option<Region> {
+    if bytes.len() < 16 {
+        return None;
+    }
+    if (bytes[7] >> 2) & 0b0000_0011 == 2 {
+        // Dendy clones run at 50 Hz like PAL consoles
+        return match bytes[12] & 0b0000_0011 {
+            0 => Some(Region::Ntsc),
+            1 | 3 => Some(Region::Pal),
+            _ => None,
+        };
+    }
+    (bytes[9] & 0b0000_0001 != 0).then_some(Region::Pal)
+}
+
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
     pub mapper_id: u8,
     pub screen_mirroring: Mirroring,
+    pub region: Region,
+    /// CRC32 of the PRG and CHR data without header, identifies the game.
+    pub crc: u32,
 }
 
 impl Rom {
-    pub fn new(bytes: &[u8]) -> Rom {
-        if bytes[0..4] != [0x4E, 0x45, 0x53, 0x1A] {
-            panic!("File is not in iNES file format");
+    /// Reads an iNES file, malformed or truncated files are an error rather than a panic.
+    pub fn new(bytes: &[u8]) -> Result<Rom, String> {
+        if bytes.len() < 16 || bytes[0..4] != [0x4E, 0x45, 0x53, 0x1A] {
+            return Err("File is not in iNES file format".to_string());
         }
 
         let mapper = (bytes[7] & 0b1111_0000) | (bytes[6] >> 4);
 
-        if (bytes[7] >> 2) & 0b0000_0011 != 0 {
-            panic!("NES2.0 format is not supported");
-        }
+        let nes2 = match (bytes[7] >> 2) & 0b0000_0011 {
+            0 => false,
+            2 => true,
+            _ => return Err("The header format is not supported".to_string()),
+        };
+        // NES 2.0 keeps the high bits of the mapper and the ROM sizes in bytes 8 and 9
+        let (prg_rom_banks, chr_rom_banks) = if nes2 {
+            if bytes[8] & 0b0000_1111 != 0 {
+                return Err("Mappers above 255 are not supported".to_string());
+            }
+            if bytes[9] & 0b0000_1111 == 0xf || bytes[9] >> 4 == 0xf {
+                return Err("Exponent ROM sizes are not supported".to_string());
+            }
+            (
+                (bytes[9] as usize & 0b0000_1111) << 8 | bytes[4] as usize,
+                (bytes[9] as usize >> 4) << 8 | bytes[5] as usize,
+            )
+        } else {
+            (bytes[4] as usize, bytes[5] as usize)
+        };
 
         let screen_mirroring;
         if bytes[6] & 0b0000_1000 != 0 {
@@ -33,26 +114,62 @@ impl Rom {
             screen_mirroring = Mirroring::Horizontal;
         }
 
-        let prg_rom_size = bytes[4] as usize * 0x4000;
-        let chr_rom_size = bytes[5] as usize * 0x2000;
+        // Flags 9 is rarely set by dumps, so this mostly reports NTSC, see `region::detect`
+        let region = header_region(bytes).unwrap_or(Region::Ntsc);
+
+        let prg_rom_size = prg_rom_banks * 0x4000;
+        let chr_rom_size = chr_rom_banks * 0x2000;
+        if prg_rom_size == 0 {
+            return Err("The header has no PRG ROM".to_string());
+        }
 
         // check if rom contains a trainer so that we can skip it later
         let has_trainer = bytes[6] & 0b0000_0100 != 0;
 
         let prg_rom_start = 16 + if has_trainer { 512 } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        let size = chr_rom_start + chr_rom_size;
+        if bytes.len() < size {
+            return Err(format!(
+                "File is truncated, the header needs {} bytes but there are {}",
+                size,
+                bytes.len()
+            ));
+        }
+
+        let prg_rom = &bytes[prg_rom_start..chr_rom_start];
+        let chr_rom = &bytes[chr_rom_start..size];
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(prg_rom);
+        hasher.update(chr_rom);
 
-        Rom {
-            prg_rom: bytes[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: bytes[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+        // Carts without CHR ROM have 8K of CHR RAM instead, which starts out blank
+        let chr_rom = if chr_rom.is_empty() {
+            vec![0; 0x2000]
+        } else {
+            chr_rom.to_vec()
+        };
+
+        Ok(Rom {
+            prg_rom: prg_rom.to_vec(),
+            chr_rom,
             mapper_id: mapper,
             screen_mirroring,
-        }
+            region,
+            crc: hasher.finalize(),
+        })
     }
 }
 
+#[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::cartridge::Mirroring::Vertical;
+    use crate::ppu::PPU;
+    use crate::render::{render, Frame, PALETTE};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     struct TestRom {
         header: Vec<u8>,
@@ -89,7 +206,15 @@ pub mod test {
             chr_rom: vec![0; 0x2000],
         });
 
-        Rom::new(&test_rom)
+        Rom::new(&test_rom).unwrap()
+    }
+
+    /// A test rom that runs JMP $8000 forever, with the reset vector pointing at it.
+    pub fn looping_rom() -> Rom {
+        let mut program = vec![0x4c, 0x00, 0x80];
+        program.resize(2 * 0x4000 - 4, 0);
+        program.extend([0x00, 0x80, 0x00, 0x00]);
+        test_rom(program)
     }
 
     #[test]
@@ -100,15 +225,17 @@ pub mod test {
             ],
             trainer: None,
             prg_rom: vec![1; 2 * 0x4000],
-            chr_rom: vec![2; 1 * 0x2000],
+            chr_rom: vec![2; 0x2000],
         });
 
-        let rom: Rom = Rom::new(&test_rom);
+        let rom: Rom = Rom::new(&test_rom).unwrap();
 
-        assert_eq!(rom.chr_rom, vec!(2; 1 * 0x2000));
+        assert_eq!(rom.chr_rom, vec!(2; 0x2000));
         assert_eq!(rom.prg_rom, vec!(1; 2 * 0x4000));
         assert_eq!(rom.mapper_id, 3);
         assert_eq!(rom.screen_mirroring, Vertical);
+        assert_eq!(rom.region, Region::Ntsc);
+        assert_eq!(rom.crc, crc32fast::hash(&test_rom[16..]));
     }
 
     #[test]
@@ -134,14 +261,98 @@ pub mod test {
             ],
             trainer: Some(vec![0; 512]),
             prg_rom: vec![1; 2 * 0x4000],
-            chr_rom: vec![2; 1 * 0x2000],
+            chr_rom: vec![2; 0x2000],
         });
 
-        let rom: Rom = Rom::new(&test_rom);
+        let rom: Rom = Rom::new(&test_rom).unwrap();
 
-        assert_eq!(rom.chr_rom, vec!(2; 1 * 0x2000));
+        assert_eq!(rom.chr_rom, vec!(2; 0x2000));
         assert_eq!(rom.prg_rom, vec!(1; 2 * 0x4000));
         assert_eq!(rom.mapper_id, 3);
         assert_eq!(rom.screen_mirroring, Vertical);
     }
+
+    #[test]
+    fn test_nes2() {
+        let mut header = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 0x08, 00, 00, 00, 00, 0x01, 00, 00, 00,
+        ];
+        let rom = Rom::new(&create_rom(TestRom {
+            header: header.clone(),
+            trainer: None,
+            prg_rom: vec![1; 2 * 0x4000],
+            chr_rom: vec![2; 0x2000],
+        }))
+        .unwrap();
+        assert_eq!(rom.mapper_id, 3);
+        assert_eq!(rom.region, Region::Pal);
+
+        header[12] = 0x02;
+        assert_eq!(header_region(&header), None);
+        header[12] = 0x00;
+        assert_eq!(header_region(&header), Some(Region::Ntsc));
+        header[7] = 0x00;
+        assert_eq!(header_region(&header), None);
+        header[9] = 0x01;
+        assert_eq!(header_region(&header), Some(Region::Pal));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let bytes = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; 0x4000],
+            chr_rom: vec![2; 0x2000],
+        });
+        assert!(Rom::new(&bytes).is_ok());
+
+        // Every truncation is an error
+        for length in 0..bytes.len() {
+            assert!(Rom::new(&bytes[..length]).is_err());
+        }
+        assert_eq!(
+            Rom::new(&bytes[..0x100]).err().unwrap(),
+            "File is truncated, the header needs 24592 bytes but there are 256"
+        );
+
+        // Random headers and contents never panic
+        let mut rng = StdRng::seed_from_u64(0x1436);
+        for _ in 0..1000 {
+            let mut random = bytes[..4].to_vec();
+            let length = rng.gen_range(4, 0x200);
+            random.extend((4..length).map(|_| rng.gen::<u8>()));
+            let _ = Rom::new(&random);
+        }
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let bytes = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x00, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; 0x4000],
+            chr_rom: vec![],
+        });
+        let rom = Rom::new(&bytes).unwrap();
+        assert_eq!(rom.chr_rom, vec![0; 0x2000]);
+        assert_eq!(rom.crc, crc32fast::hash(&bytes[16..]));
+
+        // Blank patterns draw the backdrop everywhere
+        let mut ppu = PPU::new(rom.chr_rom, rom.screen_mirroring);
+        ppu.palette_table[0] = 0x21;
+        ppu.write_mask(0b0001_1110);
+        ppu.latch_lines();
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        for y in 0..240 {
+            for x in 0..256 {
+                assert_eq!(frame.get_pixel(x, y), PALETTE[0x21]);
+            }
+        }
+    }
 }
@@ -0,0 +1,134 @@
+use crate::ppu::PPU;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// PRG byte ran as an opcode or operand.
+pub const CDL_CODE: u8 = 0x01;
+/// PRG byte read by an instruction, or CHR byte read through $2007.
+pub const CDL_DATA: u8 = 0x02;
+/// CHR byte drawn on screen.
+pub const CDL_DRAWN: u8 = 0x01;
+
+/// Which ROM bytes ran as code, were read as data or were drawn, in FCEUX's .cdl layout:
+/// one flag byte per PRG byte followed by one per CHR byte.
+///
+/// PRG flags also hold the 8K window the byte was mapped into in bits 2 and 3. Bytes that
+/// ran as code are not marked as data when fetched, so an operand only counts as data when
+/// an instruction reads it before it runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeDataLog {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(prg_size: usize, chr_size: usize) -> Self {
+        CodeDataLog {
+            prg: vec![0; prg_size],
+            chr: vec![0; chr_size],
+        }
+    }
+
+    fn mark_prg(&mut self, offset: usize, address: u16, flag: u8) {
+        if let Some(byte) = self.prg.get_mut(offset) {
+            *byte |= flag | ((address >> 13 & 0b11) as u8) << 2;
+        }
+    }
+
+    /// Marks the bytes of the instruction at `address`, `offset` is where it starts in PRG ROM.
+    pub fn log_code(&mut self, offset: usize, address: u16, length: u8) {
+        for i in 0..length as usize {
+            self.mark_prg(offset + i, address.wrapping_add(i as u16), CDL_CODE);
+        }
+    }
+
+    pub fn log_data(&mut self, offset: usize, address: u16) {
+        if self
+            .prg
+            .get(offset)
+            .is_some_and(|flags| flags & CDL_CODE == 0)
+        {
+            self.mark_prg(offset, address, CDL_DATA);
+        }
+    }
+
+    pub fn log_chr_read(&mut self, address: u16) {
+        if let Some(byte) = self.chr.get_mut(address as usize) {
+            *byte |= CDL_DATA;
+        }
+    }
+
+    /// Marks the tiles of a finished frame, the same tiles the renderer draws.
+    pub fn log_frame(&mut self, ppu: &PPU) {
+        let background = ppu.register_control.background_pattern_address() as usize;
+        for &tile in &ppu.vram[..0x3c0] {
+            self.mark_tile(background + tile as usize * 16);
+        }
+
+        let sprites = ppu.register_control.sprite_pattern_address() as usize;
+        for sprite in ppu.oam_data.chunks(4) {
+            self.mark_tile(sprites + sprite[1] as usize * 16);
+        }
+    }
+
+    fn mark_tile(&mut self, start: usize) {
+        if let Some(tile) = self.chr.get_mut(start..start + 16) {
+            for byte in tile {
+                *byte |= CDL_DRAWN;
+            }
+        }
+    }
+
+    pub fn prg(&self) -> &[u8] {
+        &self.prg
+    }
+
+    pub fn chr(&self) -> &[u8] {
+        &self.chr
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.prg.as_slice(), self.chr.as_slice()].concat()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_code_and_data() {
+        let mut log = CodeDataLog::new(0x8000, 0x2000);
+        log.log_code(0x4000, 0xc000, 3);
+        log.log_data(0x4001, 0xc001);
+        log.log_data(0x0010, 0x8010);
+        log.log_chr_read(0x1000);
+
+        assert_eq!(&log.prg()[0x4000..0x4004], [0x09, 0x09, 0x09, 0x00]);
+        assert_eq!(log.prg()[0x0010], CDL_DATA);
+        assert_eq!(log.chr()[0x1000], CDL_DATA);
+
+        let bytes = log.to_bytes();
+        assert_eq!(bytes.len(), 0xa000);
+        assert_eq!(bytes[0x9000], CDL_DATA);
+    }
+
+    #[test]
+    fn test_frame_marks_tiles() {
+        let mut ppu = PPU::new(vec![0; 0x2000], crate::cartridge::Mirroring::Horizontal);
+        ppu.vram[0] = 2;
+        let mut log = CodeDataLog::new(0x4000, 0x2000);
+        log.log_frame(&ppu);
+
+        // Tile 0 is everywhere else and in all the empty sprites
+        assert_eq!(log.chr()[0x00], CDL_DRAWN);
+        assert_eq!(log.chr()[0x10], 0);
+        assert_eq!(log.chr()[0x2f], CDL_DRAWN);
+        assert_eq!(log.chr()[0x30], 0);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::bus::Bus;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A Pro Action Replay style cheat, which keeps a RAM byte at a value by writing it every
+/// frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cheat {
+    pub address: u16,
+    pub value: u8,
+    pub name: String,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    pub fn new(address: u16, value: u8, name: &str) -> Self {
+        Cheat {
+            address,
+            value,
+            name: name.to_string(),
+            enabled: true,
+        }
+    }
+
+    /// Parses `AAAA:VV` in hex with an optional name after it, like `0075:09 Infinite lives`.
+    /// A leading `-` disables the cheat.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let (enabled, text) = match text.strip_prefix('-') {
+            Some(text) => (false, text.trim_start()),
+            None => (true, text),
+        };
+        let (code, name) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let (address, value) = code
+            .split_once(':')
+            .ok_or_else(|| format!("Expected AAAA:VV in {:?}", code))?;
+        let address = u16::from_str_radix(address.trim_start_matches('$'), 16)
+            .map_err(|_| format!("Invalid address {:?}", address))?;
+        if address >= 0x2000 {
+            return Err(format!("${:04X} is not in RAM", address));
+        }
+        let value = u8::from_str_radix(value.trim_start_matches('$'), 16)
+            .map_err(|_| format!("Invalid value {:?}", value))?;
+        Ok(Cheat {
+            address,
+            value,
+            name: name.trim().to_string(),
+            enabled,
+        })
+    }
+
+    /// Freezes the byte while enabled, releases it otherwise.
+    pub fn apply(&self, bus: &mut Bus) {
+        bus.freeze(self.address, self.enabled.then_some(self.value));
+    }
+
+    /// Parses a line of an FCEUX `.cht` file, `[:]aaaa:vv:Name` in hex where a leading `:`
+    /// disables the cheat. Returns `None` for cheats this emulator can't apply: substitute (`S`)
+    /// and compare (`C`) cheats and addresses outside of RAM.
+    pub fn parse_cht(line: &str) -> Result<Option<Self>, String> {
+        let flags = line.len() - line.trim_start_matches(['S', 'C']).len();
+        let (enabled, code) = match line[flags..].strip_prefix(':') {
+            Some(code) => (false, code),
+            None => (true, &line[flags..]),
+        };
+        let mut fields = code.splitn(3, ':');
+        let (Some(address), Some(value), Some(name)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(format!("Expected aaaa:vv:Name in {:?}", line));
+        };
+        let address = u16::from_str_radix(address, 16)
+            .map_err(|_| format!("Invalid address {:?}", address))?;
+        if flags > 0 || address >= 0x2000 {
+            return Ok(None);
+        }
+        let value =
+            u8::from_str_radix(value, 16).map_err(|_| format!("Invalid value {:?}", value))?;
+        Ok(Some(Cheat {
+            address,
+            value,
+            name: name.to_string(),
+            enabled,
+        }))
+    }
+
+    /// The cheat as a line of an FCEUX `.cht` file.
+    pub fn to_cht(&self) -> String {
+        let disabled = if self.enabled { "" } else { ":" };
+        format!(
+            "{}{:04x}:{:02x}:{}",
+            disabled, self.address, self.value, self.name
+        )
+    }
+
+    /// The code without the name, like `0075:09`.
+    pub fn code(&self) -> String {
+        format!("{:04X}:{:02X}", self.address, self.value)
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.enabled {
+            f.write_str("-")?;
+        }
+        f.write_str(&self.code())?;
+        if !self.name.is_empty() {
+            write!(f, " {}", self.name)?;
+        }
+        Ok(())
+    }
+}
+
+/// The cheats of a game in the FCEUX `.cht` format, one per line. Blank lines are skipped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CheatList {
+    pub cheats: Vec<Cheat>,
+    /// Lines with cheats that can't be applied, kept so that saving doesn't lose them.
+    pub unsupported: Vec<String>,
+}
+
+impl CheatList {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut list = CheatList::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match Cheat::parse_cht(line)
+                .map_err(|error| format!("Line {}: {}", index + 1, error))?
+            {
+                Some(cheat) => list.cheats.push(cheat),
+                None => list.unsupported.push(line.to_string()),
+            }
+        }
+        Ok(list)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    /// Freezes the bytes of the enabled cheats and releases the others.
+    pub fn apply(&self, bus: &mut Bus) {
+        for cheat in &self.cheats {
+            cheat.apply(bus);
+        }
+    }
+}
+
+impl fmt::Display for CheatList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for cheat in &self.cheats {
+            writeln!(f, "{}", cheat.to_cht())?;
+        }
+        for line in &self.unsupported {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Cheat::parse("0075:09 Infinite lives"),
+            Ok(Cheat::new(0x0075, 0x09, "Infinite lives"))
+        );
+        assert_eq!(
+            Cheat::parse("-$07A0:$FF"),
+            Ok(Cheat {
+                enabled: false,
+                ..Cheat::new(0x07a0, 0xff, "")
+            })
+        );
+        assert!(Cheat::parse("0075").is_err());
+        assert!(Cheat::parse("0075:100").is_err());
+        assert!(Cheat::parse("8000:01").is_err());
+    }
+
+    #[test]
+    fn test_parse_cht() {
+        let text = "0075:09:Infinite lives\n\n:07a0:ff:\nSC8000:01:02:Patch\n";
+        let list = CheatList::parse(text).unwrap();
+        assert_eq!(
+            list.cheats,
+            [
+                Cheat::new(0x0075, 0x09, "Infinite lives"),
+                Cheat {
+                    enabled: false,
+                    ..Cheat::new(0x07a0, 0xff, "")
+                },
+            ]
+        );
+        assert_eq!(list.unsupported, ["SC8000:01:02:Patch"]);
+        assert_eq!(
+            list.to_string(),
+            "0075:09:Infinite lives\n:07a0:ff:\nSC8000:01:02:Patch\n"
+        );
+        assert_eq!(CheatList::parse(&list.to_string()), Ok(list));
+
+        assert_eq!(
+            CheatList::parse("0075:09:\nxyz"),
+            Err("Line 2: Expected aaaa:vv:Name in \"xyz\"".to_string())
+        );
+        assert!(CheatList::parse("0075:100:").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut bus = Bus::new(test_rom(vec![0; 0x8000]), |_, _| {});
+        let run_frame = |bus: &mut Bus| {
+            let end = bus.frames() + 1;
+            while bus.frames() < end {
+                bus.tick(3);
+            }
+        };
+        let mut list = CheatList::parse("0010:05:\n:0011:06:").unwrap();
+        list.apply(&mut bus);
+        run_frame(&mut bus);
+        assert_eq!((bus.read(0x0010), bus.read(0x0011)), (5, 0));
+
+        list.cheats[0].enabled = false;
+        list.apply(&mut bus);
+        bus.write(0x0010, 1);
+        run_frame(&mut bus);
+        assert_eq!(bus.read(0x0010), 1);
+    }
+}
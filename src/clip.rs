@@ -0,0 +1,146 @@
+use crate::render::{Frame, PALETTE};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const WIDTH: u16 = 256;
+const HEIGHT: u16 = 240;
+
+/// Only every other frame is kept, GIF delays are too coarse for 60 FPS.
+const FRAME_INTERVAL: u64 = 2;
+
+/// Keeps the last few seconds of frames so they can be saved as an animated GIF.
+pub struct ClipBuffer {
+    frames: VecDeque<Vec<u8>>,
+    capacity: usize,
+    frame_rate: f64,
+    frame_count: u64,
+}
+
+impl ClipBuffer {
+    pub fn new(seconds: u32, frame_rate: f64) -> Self {
+        let capacity = (seconds as f64 * frame_rate / FRAME_INTERVAL as f64).ceil() as usize;
+        ClipBuffer {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            frame_rate,
+            frame_count: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Stores the frame as palette indices, dropping the oldest frame when full.
+    pub fn push(&mut self, frame: &Frame) {
+        self.frame_count += 1;
+        if self.capacity == 0 || !self.frame_count.is_multiple_of(FRAME_INTERVAL) {
+            return;
+        }
+
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        // Neighbouring pixels mostly share a color, so remember the last lookup
+        let mut last = (PALETTE[0], 0);
+        let indices = frame
+            .data
+            .chunks_exact(3)
+            .map(|rgb| {
+                let rgb = (rgb[0], rgb[1], rgb[2]);
+                if rgb != last.0 {
+                    last = (rgb, palette_index(rgb));
+                }
+                last.1
+            })
+            .collect();
+        self.frames.push_back(indices);
+    }
+
+    /// Encodes the buffered frames as a looping GIF.
+    pub fn to_gif(&self) -> Result<Vec<u8>, gif::EncodingError> {
+        let palette: Vec<u8> = PALETTE.iter().flat_map(|&(r, g, b)| [r, g, b]).collect();
+
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut bytes, WIDTH, HEIGHT, &palette)?;
+            encoder.set_repeat(gif::Repeat::Infinite)?;
+
+            // Round the delays on the running time, so they do not drift from the real speed
+            let centiseconds = |frame: usize| {
+                (frame as f64 * FRAME_INTERVAL as f64 * 100.0 / self.frame_rate).round() as u16
+            };
+            for (i, indices) in self.frames.iter().enumerate() {
+                let mut gif_frame =
+                    gif::Frame::from_indexed_pixels(WIDTH, HEIGHT, indices.as_slice(), None);
+                gif_frame.delay = centiseconds(i + 1) - centiseconds(i);
+                encoder.write_frame(&gif_frame)?;
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub fn save_gif(&self, path: &Path) -> io::Result<()> {
+        let bytes = self.to_gif().map_err(io::Error::other)?;
+        fs::write(path, bytes)
+    }
+}
+
+/// Finds the palette entry closest to the given color.
+fn palette_index(rgb: (u8, u8, u8)) -> u8 {
+    let distance = |color: &(u8, u8, u8)| {
+        let dr = color.0 as i32 - rgb.0 as i32;
+        let dg = color.1 as i32 - rgb.1 as i32;
+        let db = color.2 as i32 - rgb.2 as i32;
+        dr * dr + dg * dg + db * db
+    };
+    PALETTE
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, color)| distance(color))
+        .map(|(i, _)| i as u8)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffer_keeps_last_frames() {
+        let mut clip = ClipBuffer::new(1, 10.0);
+        let frame = Frame::new();
+        for _ in 0..30 {
+            clip.push(&frame);
+        }
+        assert_eq!(clip.len(), 5);
+    }
+
+    #[test]
+    fn test_gif_decodes() {
+        let mut clip = ClipBuffer::new(1, 60.0);
+        let mut frame = Frame::new();
+        frame.set_pixel(10, 10, PALETTE[0x16]);
+        for _ in 0..4 {
+            clip.push(&frame);
+        }
+
+        let bytes = clip.to_gif().unwrap();
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(bytes.as_slice()).unwrap();
+
+        let mut frames = 0;
+        while let Some(gif_frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!(gif_frame.buffer[10 * 256 + 10], 0x16);
+            frames += 1;
+        }
+        assert_eq!(frames, 2);
+    }
+}
@@ -0,0 +1,267 @@
+use crate::bus::{Bus, RamInit};
+use crate::cartridge::Rom;
+use crate::config::parse_ram_init;
+use crate::cpu::CPU;
+use crate::headless::run_frame;
+use crate::movie::{Movie, MovieFrame};
+use crate::render::{self, Frame};
+use crate::statediff::{diff_states, Difference};
+use crate::statehash::hash_state;
+use std::cell::{Ref, RefCell};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Both screens next to each other.
+pub const COMPARE_WIDTH: usize = 2 * 256;
+
+/// How one of the two instances of `--compare` runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SideOptions {
+    pub ram_init: RamInit,
+    /// Input for this side instead of the keyboard, e.g. a reference run.
+    pub movie: Option<PathBuf>,
+}
+
+impl Default for SideOptions {
+    fn default() -> Self {
+        SideOptions {
+            ram_init: RamInit::Zero,
+            movie: None,
+        }
+    }
+}
+
+/// What to run for `--compare`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompareOptions {
+    pub rom: PathBuf,
+    pub sides: [SideOptions; 2],
+}
+
+impl CompareOptions {
+    /// Reads `<rom> --compare [--movie-a file.fm2] [--movie-b file.fm2] [--ram-a init]
+    /// [--ram-b init]`, flags in any order. Returns `None` without `--compare`.
+    pub fn parse(args: &[String]) -> Option<Result<Self, String>> {
+        if !args.iter().any(|arg| arg == "--compare") {
+            return None;
+        }
+        Some(Self::parse_flags(args))
+    }
+
+    fn parse_flags(args: &[String]) -> Result<Self, String> {
+        let mut rom = None;
+        let mut sides = [SideOptions::default(), SideOptions::default()];
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--compare" => {}
+                "--movie-a" | "--movie-b" => {
+                    let path = args.next().ok_or(format!("{} needs a file", arg))?;
+                    sides[side(arg)].movie = Some(PathBuf::from(path));
+                }
+                "--ram-a" | "--ram-b" => {
+                    let value = args.next().ok_or(format!("{} needs a value", arg))?;
+                    sides[side(arg)].ram_init =
+                        parse_ram_init(value).ok_or(format!("Invalid RAM contents {:?}", value))?;
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => rom = Some(PathBuf::from(arg)),
+            }
+        }
+        Ok(CompareOptions {
+            rom: rom.ok_or("No ROM given")?,
+            sides,
+        })
+    }
+}
+
+/// The side a `-a` or `-b` flag is for.
+fn side(flag: &str) -> usize {
+    if flag.ends_with('a') {
+        0
+    } else {
+        1
+    }
+}
+
+/// The first frame after which the two instances were not in the same state.
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    pub frame: u64,
+    pub differences: Vec<Difference>,
+}
+
+/// Two instances of a game run in lockstep with the same input, to find where e.g. a different
+/// RAM init or a reference movie makes them go apart.
+pub struct DualInstance {
+    instances: [CPU<'static>; 2],
+    screens: [Rc<RefCell<Frame>>; 2],
+    movies: [Option<Movie>; 2],
+    frame: u64,
+    divergence: Option<Divergence>,
+}
+
+impl DualInstance {
+    /// Powers on both instances, each needs its own copy of the ROM.
+    pub fn new(roms: [Rom; 2], ram_inits: [RamInit; 2], movies: [Option<Movie>; 2]) -> Self {
+        let screens = [
+            Rc::new(RefCell::new(Frame::new())),
+            Rc::new(RefCell::new(Frame::new())),
+        ];
+        let [first, second] = roms;
+        let instances = [
+            instance(first, ram_inits[0], Rc::clone(&screens[0])),
+            instance(second, ram_inits[1], Rc::clone(&screens[1])),
+        ];
+        DualInstance {
+            instances,
+            screens,
+            movies,
+            frame: 0,
+            divergence: None,
+        }
+    }
+
+    /// Runs a frame on both with the buttons held, a side with a movie takes its input from
+    /// it. Returns true on the frame they first differ.
+    pub fn run_frame(&mut self, buttons: u8) -> bool {
+        let frame = self.frame;
+        for (cpu, movie) in self.instances.iter_mut().zip(&self.movies) {
+            let input = match movie {
+                Some(movie) => movie.frame(frame),
+                None => MovieFrame {
+                    buttons,
+                    ..MovieFrame::default()
+                },
+            };
+            run_frame(cpu, Some(input));
+        }
+        self.frame += 1;
+
+        if self.divergence.is_some() {
+            return false;
+        }
+        let [first, second] = &self.instances;
+        let (first, second) = (first.state_to_bytes(), second.state_to_bytes());
+        if hash_state(&first) == hash_state(&second) {
+            return false;
+        }
+        self.divergence = Some(Divergence {
+            frame,
+            // Both run the same game, so the states can always be compared
+            differences: diff_states(&first, &second).unwrap_or_default(),
+        });
+        true
+    }
+
+    /// Frames run so far.
+    pub fn frames(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn divergence(&self) -> Option<&Divergence> {
+        self.divergence.as_ref()
+    }
+
+    /// The last frame of one side, 0 or 1.
+    pub fn screen(&self, side: usize) -> Ref<'_, Frame> {
+        self.screens[side].borrow()
+    }
+
+    /// Both screens as one `COMPARE_WIDTH` wide RGB24 image, the first on the left.
+    pub fn side_by_side(&self, buffer: &mut [u8]) {
+        let screens = [self.screen(0), self.screen(1)];
+        for (y, row) in buffer.chunks_exact_mut(COMPARE_WIDTH * 3).enumerate() {
+            let (left, right) = row.split_at_mut(256 * 3);
+            left.copy_from_slice(&screens[0].data[y * 256 * 3..(y + 1) * 256 * 3]);
+            right.copy_from_slice(&screens[1].data[y * 256 * 3..(y + 1) * 256 * 3]);
+        }
+    }
+}
+
+fn instance(rom: Rom, ram_init: RamInit, screen: Rc<RefCell<Frame>>) -> CPU<'static> {
+    let mut bus = Bus::new(rom, move |ppu, _| {
+        render::render(ppu, &mut screen.borrow_mut());
+    });
+    bus.set_ram_init(ram_init);
+    let mut cpu = CPU::new(bus);
+    cpu.power_cycle();
+    cpu
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(CompareOptions::parse(&args("game.nes")), None);
+        assert_eq!(
+            CompareOptions::parse(&args("game.nes --compare --movie-b run.fm2 --ram-a ones")),
+            Some(Ok(CompareOptions {
+                rom: PathBuf::from("game.nes"),
+                sides: [
+                    SideOptions {
+                        ram_init: RamInit::Ones,
+                        movie: None,
+                    },
+                    SideOptions {
+                        ram_init: RamInit::Zero,
+                        movie: Some(PathBuf::from("run.fm2")),
+                    },
+                ],
+            }))
+        );
+        assert!(
+            CompareOptions::parse(&args("game.nes --compare --ram-b lots"))
+                .unwrap()
+                .is_err()
+        );
+        assert!(CompareOptions::parse(&args("--compare")).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_dual_instance() {
+        // Copy the controller bits to $10 forever
+        let mut program = vec![
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, // strobe
+            0xad, 0x16, 0x40, 0x85, 0x10, 0x4c, 0x00, 0x80, // LDA $4016, STA $10, JMP $8000
+        ];
+        program.resize(2 * 0x4000 - 4, 0);
+        program.extend([0x00, 0x80, 0x00, 0x00]);
+
+        // The movie presses nothing for two frames and then A, the other side holds nothing
+        let movie = Movie::parse_fm2("|0|........|||\n|0|........|||\n|0|.......A|||").unwrap();
+        let mut dual = DualInstance::new(
+            [test_rom(program.clone()), test_rom(program)],
+            [RamInit::Zero, RamInit::Zero],
+            [None, Some(movie)],
+        );
+        assert!(!dual.run_frame(0));
+        assert!(!dual.run_frame(0));
+        assert!(dual.divergence().is_none());
+        assert!(dual.run_frame(0));
+        assert!(!dual.run_frame(0));
+        assert_eq!(dual.frames(), 4);
+
+        let divergence = dual.divergence().unwrap();
+        assert_eq!(divergence.frame, 2);
+        assert!(divergence
+            .differences
+            .iter()
+            .any(|difference| difference.to_string() == "JOY1 buttons: $00 -> $01"));
+
+        let mut buffer = vec![0; COMPARE_WIDTH * 240 * 3];
+        dual.side_by_side(&mut buffer);
+        assert_eq!(buffer[..256 * 3], dual.screen(0).data[..256 * 3]);
+        assert_eq!(
+            buffer[COMPARE_WIDTH * 3 + 256 * 3..COMPARE_WIDTH * 3 * 2],
+            dual.screen(1).data[256 * 3..256 * 3 * 2]
+        );
+    }
+}
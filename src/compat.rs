@@ -0,0 +1,248 @@
+use crate::bus::RamInit;
+use crate::frontend::open_rom;
+use crate::headless;
+use crate::menu::list_roms;
+use std::any::Any;
+use std::fmt::Write as _;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+/// Frames every ROM runs for, five seconds of NTSC.
+pub const COMPAT_FRAMES: u64 = 300;
+
+/// How a ROM did, from best to worst.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Compatibility {
+    /// Ran every frame without crashing.
+    Ran,
+    /// Ran every frame, but as NROM since its mapper is not implemented.
+    UnsupportedMapper,
+    /// Emulation panicked, e.g. on an opcode that is not implemented.
+    Crashed(String),
+    /// The file is not a ROM the emulator can load.
+    Unreadable(String),
+}
+
+impl Compatibility {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compatibility::Ran => "ok",
+            Compatibility::UnsupportedMapper => "unsupported mapper",
+            Compatibility::Crashed(_) => "crashed",
+            Compatibility::Unreadable(_) => "unreadable",
+        }
+    }
+
+    /// What went wrong, empty when nothing did.
+    pub fn detail(&self) -> &str {
+        match self {
+            Compatibility::Crashed(detail) | Compatibility::Unreadable(detail) => detail,
+            _ => "",
+        }
+    }
+}
+
+/// The result for one ROM of a directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomReport {
+    pub name: String,
+    pub mapper: Option<u8>,
+    pub compatibility: Compatibility,
+    /// State hash after the last frame, to notice when a change makes a game behave differently.
+    pub hash: Option<u32>,
+}
+
+/// Loads a ROM and runs it headlessly for a number of frames, catching any panic.
+pub fn check_rom(path: &Path, frames: u64) -> RomReport {
+    let mut report = RomReport {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        mapper: None,
+        compatibility: Compatibility::Ran,
+        hash: None,
+    };
+    let rom = match open_rom(path) {
+        Ok(rom) => rom,
+        Err(error) => {
+            report.compatibility = Compatibility::Unreadable(error);
+            return report;
+        }
+    };
+    report.mapper = Some(rom.mapper_id);
+
+    match panic::catch_unwind(AssertUnwindSafe(|| {
+        headless::run(rom, RamInit::Zero, frames, None, false)
+    })) {
+        Ok(run) => {
+            report.hash = Some(run.hash);
+            if report.mapper != Some(0) {
+                report.compatibility = Compatibility::UnsupportedMapper;
+            }
+        }
+        Err(payload) => report.compatibility = Compatibility::Crashed(panic_message(&*payload)),
+    }
+    report
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panicked".to_string()
+    }
+}
+
+/// Checks every iNES file of a directory in name order.
+pub fn check_dir(dir: &Path, frames: u64) -> Vec<RomReport> {
+    list_roms(dir)
+        .iter()
+        .map(|path| check_rom(path, frames))
+        .collect()
+}
+
+/// Number of ROMs that ran without any problem.
+pub fn passed(reports: &[RomReport]) -> usize {
+    reports
+        .iter()
+        .filter(|report| report.compatibility == Compatibility::Ran)
+        .count()
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub fn to_csv(reports: &[RomReport]) -> String {
+    let mut csv = "rom,mapper,status,detail,hash\n".to_string();
+    for report in reports {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{}",
+            csv_field(&report.name),
+            report
+                .mapper
+                .map(|mapper| mapper.to_string())
+                .unwrap_or_default(),
+            report.compatibility.name(),
+            csv_field(report.compatibility.detail()),
+            report
+                .hash
+                .map(|hash| format!("{:08X}", hash))
+                .unwrap_or_default()
+        );
+    }
+    csv
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A standalone page with one table row per ROM, colored by how it did.
+pub fn to_html(reports: &[RomReport]) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Compatibility report</title>\n<style>\n\
+         body { font-family: sans-serif; }\n\
+         td, th { padding: 2px 8px; text-align: left; }\n\
+         .ok { background: #cfc; }\n\
+         .unsupported { background: #ffc; }\n\
+         .crashed, .unreadable { background: #fcc; }\n\
+         </style>\n</head>\n<body>\n",
+    );
+    let _ = writeln!(
+        html,
+        "<h1>Compatibility report</h1>\n<p>{} of {} ROMs ran without problems.</p>",
+        passed(reports),
+        reports.len()
+    );
+    html.push_str("<table>\n<tr><th>ROM</th><th>Mapper</th><th>Status</th><th>Detail</th></tr>\n");
+    for report in reports {
+        let class = match report.compatibility {
+            Compatibility::Ran => "ok",
+            Compatibility::UnsupportedMapper => "unsupported",
+            Compatibility::Crashed(_) => "crashed",
+            Compatibility::Unreadable(_) => "unreadable",
+        };
+        let _ = writeln!(
+            html,
+            "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            class,
+            escape_html(&report.name),
+            report
+                .mapper
+                .map(|mapper| mapper.to_string())
+                .unwrap_or_default(),
+            report.compatibility.name(),
+            escape_html(report.compatibility.detail())
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    /// An iNES file with 32K of PRG ROM that resets to `program` at $8000.
+    fn ines(mapper: u8, program: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x4e, 0x45, 0x53, 0x1a, 0x02, 0x01, mapper << 4, 0x00];
+        bytes.resize(16, 0);
+        let mut prg = program.to_vec();
+        prg.resize(2 * 0x4000 - 4, 0);
+        prg.extend([0x00, 0x80, 0x00, 0x00]);
+        bytes.extend(prg);
+        bytes.extend(vec![0; 0x2000]);
+        bytes
+    }
+
+    #[test]
+    fn test_check_dir() {
+        let dir = std::env::temp_dir().join("nes_rust_test_compat");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // JMP $8000 forever, and an opcode that is not implemented
+        fs::write(dir.join("a_loop.nes"), ines(0, &[0x4c, 0x00, 0x80])).unwrap();
+        fs::write(dir.join("b_crash.nes"), ines(0, &[0x02])).unwrap();
+        fs::write(dir.join("c_mapper.nes"), ines(1, &[0x4c, 0x00, 0x80])).unwrap();
+        fs::write(dir.join("d_broken.nes"), b"not a rom").unwrap();
+        fs::write(dir.join("notes.txt"), b"skipped").unwrap();
+
+        let reports = check_dir(&dir, 2);
+        fs::remove_dir_all(&dir).unwrap();
+        let statuses: Vec<&str> = reports
+            .iter()
+            .map(|report| report.compatibility.name())
+            .collect();
+        assert_eq!(
+            statuses,
+            ["ok", "crashed", "unsupported mapper", "unreadable"]
+        );
+        assert!(reports[0].hash.is_some());
+        assert_eq!(reports[2].mapper, Some(1));
+        assert!(reports[1]
+            .compatibility
+            .detail()
+            .contains("is not recognized"));
+        assert_eq!(passed(&reports), 1);
+
+        let csv = to_csv(&reports);
+        assert!(csv.starts_with("rom,mapper,status,detail,hash\na_loop.nes,0,ok,,"));
+        let html = to_html(&reports);
+        assert!(html.contains("<p>1 of 4 ROMs ran without problems.</p>"));
+        assert!(html.contains("<tr class=\"crashed\"><td>b_crash.nes</td>"));
+    }
+}
@@ -0,0 +1,306 @@
+use crate::cpu::CPU;
+use crate::memory::MemoryRegion;
+use std::fmt;
+
+/// A breakpoint condition like `A == 0x3F && X > 2` or `[$00FE] != 0`.
+///
+/// Values are registers (`A`, `X`, `Y`, `P`, `SP`, `PC`), numbers (`$3F`, `0x3F` or `63`) and
+/// memory reads (`[address]`), combined with comparisons, `&&`, `||` and parentheses. A value
+/// on its own is true when it is not zero.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Register {
+    A,
+    X,
+    Y,
+    P,
+    S,
+    Pc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(u16),
+    Register(Register),
+    Memory(Box<Expr>),
+    Compare(Box<Expr>, Comparison, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u16),
+    Register(Register),
+    Comparison(Comparison),
+    And,
+    Or,
+    Open,
+    Close,
+    OpenBracket,
+    CloseBracket,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+        };
+        let expr = parser.or()?;
+        if parser.position != parser.tokens.len() {
+            return Err(format!("Unexpected {:?}", parser.tokens[parser.position]));
+        }
+
+        Ok(Condition {
+            text: text.trim().to_string(),
+            expr,
+        })
+    }
+
+    /// Evaluates the condition, memory is read through the bus like the CPU would.
+    pub fn holds(&self, cpu: &mut CPU) -> bool {
+        evaluate(&self.expr, cpu) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+fn evaluate(expr: &Expr, cpu: &mut CPU) -> u16 {
+    match expr {
+        Expr::Number(number) => *number,
+        Expr::Register(register) => match register {
+            Register::A => cpu.a as u16,
+            Register::X => cpu.x as u16,
+            Register::Y => cpu.y as u16,
+            Register::P => cpu.p as u16,
+            Register::S => cpu.s as u16,
+            Register::Pc => cpu.pc,
+        },
+        Expr::Memory(address) => {
+            // Reading I/O registers would change the game, they read as 0
+            let address = evaluate(address, cpu);
+            MemoryRegion::Cpu.peek(cpu, address).unwrap_or(0) as u16
+        }
+        Expr::Compare(left, comparison, right) => {
+            let (left, right) = (evaluate(left, cpu), evaluate(right, cpu));
+            let result = match comparison {
+                Comparison::Equal => left == right,
+                Comparison::NotEqual => left != right,
+                Comparison::Less => left < right,
+                Comparison::LessOrEqual => left <= right,
+                Comparison::Greater => left > right,
+                Comparison::GreaterOrEqual => left >= right,
+            };
+            result as u16
+        }
+        Expr::And(left, right) => (evaluate(left, cpu) != 0 && evaluate(right, cpu) != 0) as u16,
+        Expr::Or(left, right) => (evaluate(left, cpu) != 0 || evaluate(right, cpu) != 0) as u16,
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let rest: String = chars[i..].iter().take(2).collect();
+        let (token, length) = match chars[i] {
+            ' ' | '\t' => {
+                i += 1;
+                continue;
+            }
+            '(' => (Token::Open, 1),
+            ')' => (Token::Close, 1),
+            '[' => (Token::OpenBracket, 1),
+            ']' => (Token::CloseBracket, 1),
+            _ if rest == "&&" => (Token::And, 2),
+            _ if rest == "||" => (Token::Or, 2),
+            _ if rest == "==" => (Token::Comparison(Comparison::Equal), 2),
+            _ if rest == "!=" => (Token::Comparison(Comparison::NotEqual), 2),
+            _ if rest == "<=" => (Token::Comparison(Comparison::LessOrEqual), 2),
+            _ if rest == ">=" => (Token::Comparison(Comparison::GreaterOrEqual), 2),
+            '<' => (Token::Comparison(Comparison::Less), 1),
+            '>' => (Token::Comparison(Comparison::Greater), 1),
+            '$' | '0'..='9' | 'a'..='z' | 'A'..='Z' => {
+                let length = chars[i + 1..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric())
+                    .count()
+                    + 1;
+                let word: String = chars[i..i + length].iter().collect();
+                (word_token(&word)?, length)
+            }
+            c => return Err(format!("Unexpected {:?}", c)),
+        };
+        tokens.push(token);
+        i += length;
+    }
+    Ok(tokens)
+}
+
+fn word_token(word: &str) -> Result<Token, String> {
+    let register = match word.to_ascii_uppercase().as_str() {
+        "A" => Some(Register::A),
+        "X" => Some(Register::X),
+        "Y" => Some(Register::Y),
+        "P" => Some(Register::P),
+        "S" | "SP" => Some(Register::S),
+        "PC" => Some(Register::Pc),
+        _ => None,
+    };
+    if let Some(register) = register {
+        return Ok(Token::Register(register));
+    }
+
+    let number = if let Some(hex) = word.strip_prefix('$') {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(hex) = word.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16)
+    } else {
+        word.parse()
+    };
+    number
+        .map(Token::Number)
+        .map_err(|_| format!("Unknown value {:?}", word))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.position += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.comparison()?;
+        while self.peek() == Some(&Token::And) {
+            self.position += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.value()?;
+        match self.peek() {
+            Some(&Token::Comparison(comparison)) => {
+                self.position += 1;
+                let right = self.value()?;
+                Ok(Expr::Compare(Box::new(left), comparison, Box::new(right)))
+            }
+            _ => Ok(left),
+        }
+    }
+
+    fn value(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Register(register)) => Ok(Expr::Register(register)),
+            Some(Token::OpenBracket) => {
+                let address = self.or()?;
+                self.expect(Token::CloseBracket)?;
+                Ok(Expr::Memory(Box::new(address)))
+            }
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                self.expect(Token::Close)?;
+                Ok(expr)
+            }
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+            None => Err("Condition ends too early".to_string()),
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            _ => Err(format!("Expected {:?}", expected)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+
+    fn holds(text: &str, cpu: &mut CPU) -> bool {
+        Condition::parse(text).unwrap().holds(cpu)
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        cpu.a = 0x3f;
+        cpu.x = 3;
+        cpu.pc = 0x8123;
+        cpu.write(0x00fe, 7);
+
+        assert!(holds("A == 0x3F && X > 2", &mut cpu));
+        assert!(!holds("A == 0x3F && X > 3", &mut cpu));
+        assert!(holds("[$00FE] != 0", &mut cpu));
+        assert!(holds("[$00FE] == 7 || pc == 0", &mut cpu));
+        assert!(holds("(X >= 3 || Y) && PC == $8123", &mut cpu));
+        assert!(!holds("Y", &mut cpu));
+    }
+
+    #[test]
+    fn test_io_registers_untouched() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        cpu.bus.ppu.register_status.set_vertical_blank(true);
+        assert!(holds("[$2002] == 0", &mut cpu));
+        assert!(cpu.bus.ppu.register_status.get_vertical_blank());
+        // Write-only, reading it through the bus panics
+        assert!(holds("[$2000] == 0", &mut cpu));
+    }
+
+    #[test]
+    fn test_invalid_conditions() {
+        assert!(Condition::parse("A ==").is_err());
+        assert!(Condition::parse("A = 3").is_err());
+        assert!(Condition::parse("[$00FE").is_err());
+        assert!(Condition::parse("Q > 1").is_err());
+        assert!(Condition::parse("A 1").is_err());
+    }
+}
@@ -0,0 +1,539 @@
+use crate::bus::RamInit;
+use crate::filter::VideoFilter;
+use crate::recorder::RecordingFormat;
+use crate::trace::TraceFormat;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_PATH: &str = "nes_rust.cfg";
+
+pub const MIN_SPEED: u32 = 25;
+pub const MAX_SPEED: u32 = 400;
+pub const SPEED_STEP: u32 = 25;
+
+pub const MAX_GIF_SECONDS: u32 = 60;
+pub const MAX_REWIND_SECONDS: u32 = 120;
+pub const MAX_OVERCLOCK: u16 = 262;
+pub const MAX_FRAME_SKIP: u32 = 9;
+
+pub const MAX_RECENT_ROMS: usize = 10;
+
+/// Directory next to the global config that holds the per-game profiles.
+const PROFILE_DIR: &str = "profiles";
+
+/// Keys that only make sense globally and can not be overridden by a profile.
+const GLOBAL_KEYS: [&str; 1] = ["recent_rom"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FastForward {
+    Uncapped,
+    Double,
+    Quadruple,
+}
+
+impl FastForward {
+    /// Speed multiplier while fast-forwarding, `None` if the frame limiter is disabled.
+    pub fn multiplier(self) -> Option<f64> {
+        match self {
+            FastForward::Uncapped => None,
+            FastForward::Double => Some(2.0),
+            FastForward::Quadruple => Some(4.0),
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "uncapped" => Some(FastForward::Uncapped),
+            "2x" => Some(FastForward::Double),
+            "4x" => Some(FastForward::Quadruple),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FastForward::Uncapped => "uncapped",
+            FastForward::Double => "2x",
+            FastForward::Quadruple => "4x",
+        }
+    }
+}
+
+/// Per-game overrides of the global config, stored in the same format.
+#[derive(Debug, Clone, PartialEq)]
+struct Profile {
+    path: PathBuf,
+    /// Keys that are set by the profile.
+    keys: Vec<String>,
+    /// Global config lines of the overridden keys, so they can be saved and restored.
+    global_lines: Vec<String>,
+}
+
+/// Settings that persist between runs, stored as `key = value` lines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    path: PathBuf,
+    profile: Option<Profile>,
+    /// Emulation speed in percent of the native frame rate.
+    pub speed: u32,
+    pub fast_forward: FastForward,
+    /// Frames not drawn between the shown ones while fast-forwarding.
+    pub frame_skip: u32,
+    pub ram_init: RamInit,
+    /// Extra scanlines after vertical blank that every frame gives the CPU, 0 runs at the
+    /// native speed.
+    pub overclock: u16,
+    pub screenshot_dir: PathBuf,
+    pub recording_dir: PathBuf,
+    pub recording_format: RecordingFormat,
+    pub state_dir: PathBuf,
+    /// Cheats of each game, as FCEUX `.cht` files named after the ROM CRC.
+    pub cheat_dir: PathBuf,
+    /// Save the state on quit and offer to resume it when the game is loaded again.
+    pub autosave: bool,
+    /// Length of the GIF clips that can be saved at any time, 0 disables the buffer.
+    pub gif_seconds: u32,
+    /// How far back the rewind key can go, 0 disables rewinding.
+    pub rewind_seconds: u32,
+    /// Most recently opened ROM first.
+    pub recent_roms: Vec<PathBuf>,
+    /// Reload the ROM whenever its file changes, keeping the running game.
+    pub hot_reload: bool,
+    /// Sync presenting to the display, emulation keeps its own pace either way.
+    pub vsync: bool,
+    pub video_filter: VideoFilter,
+    pub trace_format: TraceFormat,
+}
+
+impl Config {
+    pub fn new(path: &Path) -> Self {
+        Config {
+            path: path.to_path_buf(),
+            profile: None,
+            speed: 100,
+            fast_forward: FastForward::Uncapped,
+            frame_skip: 3,
+            ram_init: RamInit::Zero,
+            screenshot_dir: PathBuf::from("screenshots"),
+            recording_dir: PathBuf::from("recordings"),
+            recording_format: RecordingFormat::Ffmpeg,
+            state_dir: PathBuf::from("states"),
+            cheat_dir: PathBuf::from("cheats"),
+            autosave: false,
+            overclock: 0,
+            gif_seconds: 5,
+            rewind_seconds: 10,
+            recent_roms: vec![],
+            hot_reload: false,
+            vsync: false,
+            video_filter: VideoFilter::None,
+            trace_format: TraceFormat::Nestest,
+        }
+    }
+
+    /// Loads the config from the given path, missing files and invalid values fall back to defaults.
+    pub fn load(path: &Path) -> Self {
+        let mut config = Config::new(path);
+        if let Ok(text) = fs::read_to_string(path) {
+            config.parse(&text);
+        }
+        config
+    }
+
+    /// Saves the settings, values that are overridden by a profile are saved to the profile.
+    pub fn save(&self) {
+        let text = self.serialize();
+        let (global, profile) = match &self.profile {
+            None => (text, None),
+            Some(profile) => {
+                let (profile_lines, mut global_lines): (Vec<&str>, Vec<&str>) = text
+                    .lines()
+                    .partition(|line| profile.keys.iter().any(|key| *key == line_key(line)));
+                global_lines.extend(profile.global_lines.iter().map(String::as_str));
+                (
+                    global_lines.join("\n") + "\n",
+                    Some((&profile.path, profile_lines.join("\n") + "\n")),
+                )
+            }
+        };
+
+        if let Err(error) = fs::write(&self.path, global) {
+            println!("Failed to save config to {:?}: {}", self.path, error);
+        }
+        if let Some((path, text)) = profile {
+            if let Err(error) = fs::write(path, text) {
+                println!("Failed to save profile to {:?}: {}", path, error);
+            }
+        }
+    }
+
+    /// Applies the profile of the game with the given CRC on top of the global settings,
+    /// replacing the profile of the previous game.
+    pub fn apply_profile(&mut self, crc: u32) {
+        self.clear_profile();
+
+        let dir = self.path.with_file_name(PROFILE_DIR);
+        let path = dir.join(format!("{:08X}.cfg", crc));
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) => return,
+        };
+
+        let keys: Vec<String> = text
+            .lines()
+            .map(line_key)
+            .filter(|key| !key.is_empty() && !key.starts_with('#'))
+            .filter(|key| !GLOBAL_KEYS.contains(key))
+            .map(str::to_string)
+            .collect();
+        let global_lines = self
+            .serialize()
+            .lines()
+            .filter(|line| keys.iter().any(|key| key == line_key(line)))
+            .map(str::to_string)
+            .collect();
+
+        let overrides: Vec<&str> = text
+            .lines()
+            .filter(|line| !GLOBAL_KEYS.contains(&line_key(line)))
+            .collect();
+        self.parse(&overrides.join("\n"));
+        self.profile = Some(Profile {
+            path,
+            keys,
+            global_lines,
+        });
+    }
+
+    /// Restores the global values of the settings overridden by the current profile.
+    pub fn clear_profile(&mut self) {
+        if let Some(profile) = self.profile.take() {
+            self.parse(&profile.global_lines.join("\n"));
+        }
+    }
+
+    pub fn has_profile(&self) -> bool {
+        self.profile.is_some()
+    }
+
+    fn parse(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    println!("Ignoring malformed config line {:?}", line);
+                    continue;
+                }
+            };
+
+            let valid = match key {
+                "speed" => value
+                    .parse()
+                    .ok()
+                    .filter(|speed| (MIN_SPEED..=MAX_SPEED).contains(speed))
+                    .map(|speed| self.speed = speed)
+                    .is_some(),
+                "fast_forward" => FastForward::parse(value)
+                    .map(|fast_forward| self.fast_forward = fast_forward)
+                    .is_some(),
+                "frame_skip" => value
+                    .parse()
+                    .ok()
+                    .filter(|frames| *frames <= MAX_FRAME_SKIP)
+                    .map(|frames| self.frame_skip = frames)
+                    .is_some(),
+                "ram_init" => parse_ram_init(value)
+                    .map(|ram_init| self.ram_init = ram_init)
+                    .is_some(),
+                "screenshot_dir" => {
+                    self.screenshot_dir = PathBuf::from(value);
+                    !value.is_empty()
+                }
+                "recording_dir" => {
+                    self.recording_dir = PathBuf::from(value);
+                    !value.is_empty()
+                }
+                "recording_format" => parse_recording_format(value)
+                    .map(|format| self.recording_format = format)
+                    .is_some(),
+                "state_dir" => {
+                    self.state_dir = PathBuf::from(value);
+                    !value.is_empty()
+                }
+                "cheat_dir" => {
+                    self.cheat_dir = PathBuf::from(value);
+                    !value.is_empty()
+                }
+                "autosave" => value
+                    .parse()
+                    .map(|autosave| self.autosave = autosave)
+                    .is_ok(),
+                "overclock" => value
+                    .parse()
+                    .ok()
+                    .filter(|scanlines| *scanlines <= MAX_OVERCLOCK)
+                    .map(|scanlines| self.overclock = scanlines)
+                    .is_some(),
+                "gif_seconds" => value
+                    .parse()
+                    .ok()
+                    .filter(|seconds| *seconds <= MAX_GIF_SECONDS)
+                    .map(|seconds| self.gif_seconds = seconds)
+                    .is_some(),
+                "rewind_seconds" => value
+                    .parse()
+                    .ok()
+                    .filter(|seconds| *seconds <= MAX_REWIND_SECONDS)
+                    .map(|seconds| self.rewind_seconds = seconds)
+                    .is_some(),
+                "recent_rom" => {
+                    if self.recent_roms.len() < MAX_RECENT_ROMS {
+                        self.recent_roms.push(PathBuf::from(value));
+                    }
+                    !value.is_empty()
+                }
+                "hot_reload" => value
+                    .parse()
+                    .map(|hot_reload| self.hot_reload = hot_reload)
+                    .is_ok(),
+                "vsync" => value.parse().map(|vsync| self.vsync = vsync).is_ok(),
+                "video_filter" => VideoFilter::parse(value)
+                    .map(|filter| self.video_filter = filter)
+                    .is_some(),
+                "trace_format" => TraceFormat::parse(value)
+                    .map(|format| self.trace_format = format)
+                    .is_some(),
+                _ => false,
+            };
+            if !valid {
+                println!("Ignoring config entry {:?}", line);
+            }
+        }
+    }
+
+    fn serialize(&self) -> String {
+        let mut text = String::new();
+        writeln!(text, "speed = {}", self.speed).unwrap();
+        writeln!(text, "fast_forward = {}", self.fast_forward.name()).unwrap();
+        writeln!(text, "frame_skip = {}", self.frame_skip).unwrap();
+        writeln!(text, "ram_init = {}", ram_init_name(self.ram_init)).unwrap();
+        writeln!(text, "screenshot_dir = {}", self.screenshot_dir.display()).unwrap();
+        writeln!(text, "recording_dir = {}", self.recording_dir.display()).unwrap();
+        writeln!(
+            text,
+            "recording_format = {}",
+            recording_format_name(self.recording_format)
+        )
+        .unwrap();
+        writeln!(text, "state_dir = {}", self.state_dir.display()).unwrap();
+        writeln!(text, "cheat_dir = {}", self.cheat_dir.display()).unwrap();
+        writeln!(text, "autosave = {}", self.autosave).unwrap();
+        writeln!(text, "overclock = {}", self.overclock).unwrap();
+        writeln!(text, "gif_seconds = {}", self.gif_seconds).unwrap();
+        writeln!(text, "rewind_seconds = {}", self.rewind_seconds).unwrap();
+        writeln!(text, "hot_reload = {}", self.hot_reload).unwrap();
+        writeln!(text, "vsync = {}", self.vsync).unwrap();
+        writeln!(text, "video_filter = {}", self.video_filter.name()).unwrap();
+        writeln!(text, "trace_format = {}", self.trace_format.name()).unwrap();
+        for rom in &self.recent_roms {
+            writeln!(text, "recent_rom = {}", rom.display()).unwrap();
+        }
+        text
+    }
+
+    /// Moves the ROM to the front of the recent ROMs, dropping the oldest if the list is full.
+    pub fn add_recent_rom(&mut self, path: &Path) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.recent_roms.retain(|rom| *rom != path);
+        self.recent_roms.insert(0, path);
+        self.recent_roms.truncate(MAX_RECENT_ROMS);
+    }
+
+    pub fn increase_speed(&mut self) {
+        self.speed = (self.speed + SPEED_STEP).min(MAX_SPEED);
+    }
+
+    pub fn decrease_speed(&mut self) {
+        self.speed = self.speed.saturating_sub(SPEED_STEP).max(MIN_SPEED);
+    }
+}
+
+fn line_key(line: &str) -> &str {
+    line.split_once('=')
+        .map(|(key, _)| key)
+        .unwrap_or(line)
+        .trim()
+}
+
+/// Parses `zero`, `ones`, `stripes` or `random` with an optional seed like `random 1234`.
+/// A bare `random` draws a fresh seed, which is saved with the config so the run can be
+/// reproduced.
+pub(crate) fn parse_ram_init(value: &str) -> Option<RamInit> {
+    let mut words = value.split_whitespace();
+    let ram_init = match (words.next()?, words.next()) {
+        ("zero", None) => RamInit::Zero,
+        ("ones", None) => RamInit::Ones,
+        ("stripes", None) => RamInit::Stripes,
+        ("random", None) => RamInit::Random(rand::random()),
+        ("random", Some(seed)) => RamInit::Random(seed.parse().ok()?),
+        _ => return None,
+    };
+    match words.next() {
+        Some(_) => None,
+        None => Some(ram_init),
+    }
+}
+
+fn ram_init_name(ram_init: RamInit) -> String {
+    match ram_init {
+        RamInit::Zero => "zero".to_string(),
+        RamInit::Ones => "ones".to_string(),
+        RamInit::Stripes => "stripes".to_string(),
+        RamInit::Random(seed) => format!("random {}", seed),
+    }
+}
+
+fn parse_recording_format(value: &str) -> Option<RecordingFormat> {
+    match value {
+        "raw" => Some(RecordingFormat::Raw),
+        "ffmpeg" => Some(RecordingFormat::Ffmpeg),
+        _ => None,
+    }
+}
+
+fn recording_format_name(format: RecordingFormat) -> &'static str {
+    match format {
+        RecordingFormat::Raw => "raw",
+        RecordingFormat::Ffmpeg => "ffmpeg",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_config() -> Config {
+        Config::new(Path::new("test.cfg"))
+    }
+
+    #[test]
+    fn test_profile_overrides_and_saves_separately() {
+        let dir = std::env::temp_dir().join("nes_rust_test_profile");
+        fs::create_dir_all(dir.join(PROFILE_DIR)).unwrap();
+        let path = dir.join("test.cfg");
+        fs::write(&path, "speed = 150\ngif_seconds = 3\n").unwrap();
+        fs::write(
+            dir.join(PROFILE_DIR).join("DEADBEEF.cfg"),
+            "speed = 50\nrecent_rom = ignored.nes\n",
+        )
+        .unwrap();
+
+        let mut config = Config::load(&path);
+        config.apply_profile(0xdeadbeef);
+        assert!(config.has_profile());
+        assert_eq!(config.speed, 50);
+        assert_eq!(config.gif_seconds, 3);
+        assert!(config.recent_roms.is_empty());
+
+        config.increase_speed();
+        config.gif_seconds = 4;
+        config.save();
+
+        let global = Config::load(&path);
+        let profile = fs::read_to_string(dir.join(PROFILE_DIR).join("DEADBEEF.cfg")).unwrap();
+        config.clear_profile();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(global.speed, 150);
+        assert_eq!(global.gif_seconds, 4);
+        assert_eq!(profile, "speed = 75\n");
+        assert_eq!(config.speed, 150);
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut config = test_config();
+        config.parse("# comment\nspeed = 150\nfast_forward = 4x\n");
+        assert_eq!(config.speed, 150);
+        assert_eq!(config.fast_forward, FastForward::Quadruple);
+    }
+
+    #[test]
+    fn test_parse_invalid_values() {
+        let mut config = test_config();
+        config.parse(
+            "speed = 1000\nfast_forward = 3x\nram_init = random x\noverclock = 300\nframe_skip = 10\nvideo_filter = blur\nunknown\n",
+        );
+        assert_eq!(config, test_config());
+    }
+
+    #[test]
+    fn test_parse_random_seed() {
+        assert_eq!(parse_ram_init("random 42"), Some(RamInit::Random(42)));
+        let seeds: Vec<_> = (0..4).map(|_| parse_ram_init("random")).collect();
+        assert!(seeds
+            .iter()
+            .all(|seed| matches!(seed, Some(RamInit::Random(_)))));
+        assert!(seeds.iter().any(|seed| *seed != seeds[0]));
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let mut config = test_config();
+        config.speed = 75;
+        config.fast_forward = FastForward::Double;
+        config.frame_skip = 1;
+        config.ram_init = RamInit::Random(1234);
+        config.screenshot_dir = PathBuf::from("my shots");
+        config.recording_format = RecordingFormat::Raw;
+        config.state_dir = PathBuf::from("my states");
+        config.cheat_dir = PathBuf::from("my cheats");
+        config.autosave = true;
+        config.gif_seconds = 10;
+        config.overclock = 50;
+        config.rewind_seconds = 30;
+        config.hot_reload = true;
+        config.vsync = true;
+        config.video_filter = VideoFilter::Scanlines;
+        config.trace_format = TraceFormat::Mesen;
+        config.add_recent_rom(Path::new("b.nes"));
+        config.add_recent_rom(Path::new("a.nes"));
+
+        let mut parsed = test_config();
+        parsed.parse(&config.serialize());
+        assert_eq!(parsed, config);
+    }
+
+    #[test]
+    fn test_recent_roms() {
+        let mut config = test_config();
+        for i in 0..MAX_RECENT_ROMS + 2 {
+            config.add_recent_rom(Path::new(&format!("{}.nes", i)));
+        }
+        config.add_recent_rom(Path::new("5.nes"));
+
+        assert_eq!(config.recent_roms.len(), MAX_RECENT_ROMS);
+        assert_eq!(config.recent_roms[0], PathBuf::from("5.nes"));
+        assert_eq!(config.recent_roms[1], PathBuf::from("11.nes"));
+        assert!(!config.recent_roms.contains(&PathBuf::from("1.nes")));
+    }
+
+    #[test]
+    fn test_speed_limits() {
+        let mut config = test_config();
+        for _ in 0..20 {
+            config.increase_speed();
+        }
+        assert_eq!(config.speed, MAX_SPEED);
+        for _ in 0..20 {
+            config.decrease_speed();
+        }
+        assert_eq!(config.speed, MIN_SPEED);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::cpu::CPU;
+use crate::emulation::Command;
+use crate::frontend::{state_path, STATE_SLOTS};
+use crate::joypad::button_from_name;
+use crate::labels::Labels;
+use crate::memory::MemoryRegion;
+use crate::statejson::machine_json;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+
+/// Lines of the stdin console that drive the game between frames, so it can be scripted from
+/// a shell pipeline like `printf 'press start 5\nframe\n' | rust_nes game.nes`.
+#[derive(Debug, PartialEq)]
+pub enum ConsoleCommand {
+    Pause(bool),
+    /// Runs one frame and pauses.
+    Frame,
+    /// Writes a byte the way the CPU would.
+    Poke(u16, u8),
+    SaveState(u8),
+    LoadState(u8),
+    /// Holds the buttons for a number of frames, even while paused, then releases them.
+    Press(u8, u64),
+    /// Prints the whole machine state as JSON.
+    Json,
+}
+
+impl ConsoleCommand {
+    /// Parses e.g. `poke 0300 FF`, `loadstate 1` or `press A+B 10`, addresses can be given by
+    /// their label. Returns `None` for lines that are not one of these commands.
+    pub fn parse(line: &str, labels: &Labels) -> Option<Result<Self, String>> {
+        let mut words = line.split_whitespace();
+        let command = words.next()?;
+        let arguments: Vec<&str> = words.collect();
+        let slot = |text: &str| {
+            text.parse()
+                .ok()
+                .filter(|slot| *slot < STATE_SLOTS)
+                .ok_or(format!(
+                    "Invalid slot {:?}, use 0-{}",
+                    text,
+                    STATE_SLOTS - 1
+                ))
+        };
+
+        let parsed = match (command, arguments.as_slice()) {
+            ("pause", []) => Ok(ConsoleCommand::Pause(true)),
+            ("resume", []) => Ok(ConsoleCommand::Pause(false)),
+            ("frame", []) => Ok(ConsoleCommand::Frame),
+            ("json", []) => Ok(ConsoleCommand::Json),
+            ("poke", [address, value]) => match (
+                labels.parse_address(address),
+                u8::from_str_radix(value.trim_start_matches('$'), 16),
+            ) {
+                (Some(address), Ok(value)) => Ok(ConsoleCommand::Poke(address, value)),
+                (None, _) => Err(format!("Invalid address {:?}", address)),
+                (_, Err(_)) => Err(format!("Invalid byte {:?}", value)),
+            },
+            ("savestate", [text]) => slot(text).map(ConsoleCommand::SaveState),
+            ("loadstate", [text]) => slot(text).map(ConsoleCommand::LoadState),
+            ("press", [names, rest @ ..]) if rest.len() <= 1 => {
+                let frames = match rest.first() {
+                    Some(text) => text
+                        .parse()
+                        .map_err(|_| format!("Invalid frame count {:?}", text)),
+                    None => Ok(1),
+                };
+                let buttons = names.split('+').try_fold(0, |buttons, name| {
+                    button_from_name(name)
+                        .map(|button| buttons | button)
+                        .ok_or(format!("Unknown button {:?}", name))
+                });
+                buttons.and_then(|buttons| Ok(ConsoleCommand::Press(buttons, frames?)))
+            }
+            (
+                "pause" | "resume" | "frame" | "json" | "poke" | "savestate" | "loadstate"
+                | "press",
+                _,
+            ) => Err(format!(
+                "Wrong arguments in {:?}, use pause, resume, frame, json, \
+                     poke <address> <byte>, savestate <slot>, loadstate <slot> or \
+                     press <buttons> [frames]",
+                line.trim()
+            )),
+            _ => return None,
+        };
+        Some(parsed)
+    }
+
+    /// Sends the command to the emulation thread, waiting for the ones that report back so
+    /// the next line runs after them. Returns what to print, `None` once the emulation has
+    /// stopped.
+    pub fn run(self, emulation: &Sender<Command>, state_dir: &Path) -> Option<String> {
+        match self {
+            ConsoleCommand::Pause(pause) => {
+                emulation.send(Command::Pause(pause)).ok()?;
+                Some(String::new())
+            }
+            ConsoleCommand::Frame => {
+                emulation.send(Command::FrameAdvance).ok()?;
+                Some(String::new())
+            }
+            ConsoleCommand::Json => inspect(emulation, |cpu| machine_json(cpu)),
+            ConsoleCommand::Poke(address, value) => inspect(emulation, move |cpu| {
+                if MemoryRegion::Cpu.poke(cpu, address, value) {
+                    format!("${:04X} = ${:02X}", address, value)
+                } else {
+                    format!("${:04X} is not writable", address)
+                }
+            }),
+            ConsoleCommand::SaveState(slot) => {
+                let dir = state_dir.to_path_buf();
+                inspect(emulation, move |cpu| {
+                    let path = state_path(&dir, cpu.bus.rom_crc(), slot);
+                    match fs::create_dir_all(&dir).and_then(|_| cpu.save_state(&path)) {
+                        Ok(()) => format!("Saved state {}", slot),
+                        Err(error) => format!("Saving state {} failed: {}", slot, error),
+                    }
+                })
+            }
+            ConsoleCommand::LoadState(slot) => {
+                let dir = state_dir.to_path_buf();
+                inspect(emulation, move |cpu| {
+                    match cpu.load_state(&state_path(&dir, cpu.bus.rom_crc(), slot)) {
+                        Ok(()) => format!("Loaded state {}", slot),
+                        Err(error) => format!("Loading state {} failed: {}", slot, error),
+                    }
+                })
+            }
+            ConsoleCommand::Press(buttons, frames) => {
+                let start = inspect(emulation, move |cpu| {
+                    cpu.bus.joypad_mut().set_buttons(buttons);
+                    cpu.bus.frames()
+                })?;
+                let (sender, released) = mpsc::channel();
+                let release = Box::new(move |cpu: &mut CPU| {
+                    let joypad = cpu.bus.joypad_mut();
+                    joypad.set_buttons(joypad.buttons() & !buttons);
+                    let _ = sender.send(format!("Released at frame {}", cpu.bus.frames()));
+                });
+                emulation
+                    .send(Command::RunUntil(start + frames, release))
+                    .ok()?;
+                released.recv().ok()
+            }
+        }
+    }
+}
+
+/// Runs `f` on the emulation thread and waits for its result.
+fn inspect<T: Send + 'static>(
+    emulation: &Sender<Command>,
+    f: impl FnOnce(&mut CPU) -> T + Send + 'static,
+) -> Option<T> {
+    let (sender, result) = mpsc::channel();
+    emulation
+        .send(Command::Inspect(Box::new(move |cpu| {
+            let _ = sender.send(f(cpu));
+        })))
+        .ok()?;
+    result.recv().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::joypad::{JOYPAD_A, JOYPAD_START};
+
+    #[test]
+    fn test_parse() {
+        let mut labels = Labels::new();
+        labels.insert(0x0300, "Lives");
+        let parse = |line| ConsoleCommand::parse(line, &labels);
+        assert_eq!(parse("pause"), Some(Ok(ConsoleCommand::Pause(true))));
+        assert_eq!(parse(" frame "), Some(Ok(ConsoleCommand::Frame)));
+        assert_eq!(parse("json"), Some(Ok(ConsoleCommand::Json)));
+        assert_eq!(
+            parse("poke 0300 FF"),
+            Some(Ok(ConsoleCommand::Poke(0x0300, 0xff)))
+        );
+        assert_eq!(
+            parse("poke Lives $09"),
+            Some(Ok(ConsoleCommand::Poke(0x0300, 0x09)))
+        );
+        assert_eq!(parse("loadstate 1"), Some(Ok(ConsoleCommand::LoadState(1))));
+        assert_eq!(
+            parse("press A 10"),
+            Some(Ok(ConsoleCommand::Press(JOYPAD_A, 10)))
+        );
+        assert_eq!(
+            parse("press start+a"),
+            Some(Ok(ConsoleCommand::Press(JOYPAD_START | JOYPAD_A, 1)))
+        );
+
+        assert!(parse("poke 0300 100").unwrap().is_err());
+        assert!(parse("loadstate 10").unwrap().is_err());
+        assert!(parse("press X 10").unwrap().is_err());
+        assert!(parse("frame 2").unwrap().is_err());
+        assert_eq!(parse("step"), None);
+        assert_eq!(parse(""), None);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::cdl::{CDL_CODE, CDL_DATA};
+use std::fmt::Write as _;
+
+/// PRG ROM is reported in 16K banks, the same banks as FCEUX's .nl files.
+pub const COVERAGE_BANK_SIZE: usize = 0x4000;
+/// Bytes per row of the coverage map.
+pub const COVERAGE_MAP_WIDTH: usize = 256;
+
+/// How much of a PRG bank ran as code or was read as data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BankCoverage {
+    pub bank: usize,
+    pub size: usize,
+    pub code: usize,
+    pub data: usize,
+}
+
+impl BankCoverage {
+    pub fn code_percent(&self) -> f64 {
+        percent(self.code, self.size)
+    }
+
+    pub fn data_percent(&self) -> f64 {
+        percent(self.data, self.size)
+    }
+}
+
+fn percent(part: usize, size: usize) -> f64 {
+    if size == 0 {
+        0.0
+    } else {
+        100.0 * part as f64 / size as f64
+    }
+}
+
+/// Counts the code and data bytes of every bank from the PRG flags of a code/data log.
+pub fn bank_coverage(prg_flags: &[u8]) -> Vec<BankCoverage> {
+    prg_flags
+        .chunks(COVERAGE_BANK_SIZE)
+        .enumerate()
+        .map(|(bank, flags)| BankCoverage {
+            bank,
+            size: flags.len(),
+            code: flags.iter().filter(|&&flag| flag & CDL_CODE != 0).count(),
+            data: flags.iter().filter(|&&flag| flag & CDL_DATA != 0).count(),
+        })
+        .collect()
+}
+
+/// Text report of the coverage per bank and of the whole ROM, with the ranges that never ran.
+pub fn report(prg_flags: &[u8]) -> String {
+    let banks = bank_coverage(prg_flags);
+    let mut report = String::new();
+    for bank in &banks {
+        let _ = writeln!(
+            report,
+            "Bank {} (PRG ${:05X}-${:05X}): {:.1}% code, {:.1}% data",
+            bank.bank,
+            bank.bank * COVERAGE_BANK_SIZE,
+            bank.bank * COVERAGE_BANK_SIZE + bank.size - 1,
+            bank.code_percent(),
+            bank.data_percent()
+        );
+    }
+    let code = banks.iter().map(|bank| bank.code).sum();
+    let data = banks.iter().map(|bank| bank.data).sum();
+    let _ = writeln!(
+        report,
+        "Total: {:.1}% code, {:.1}% data",
+        percent(code, prg_flags.len()),
+        percent(data, prg_flags.len())
+    );
+
+    let _ = writeln!(report, "\nNever executed or read:");
+    for (start, end) in unused_ranges(prg_flags) {
+        let _ = writeln!(report, "  PRG ${:05X}-${:05X}", start, end);
+    }
+    report
+}
+
+/// PRG ranges, inclusive, whose bytes were neither code nor data.
+pub fn unused_ranges(prg_flags: &[u8]) -> Vec<(usize, usize)> {
+    let mut ranges = vec![];
+    let mut start = None;
+    for (offset, &flag) in prg_flags.iter().enumerate() {
+        match (flag & (CDL_CODE | CDL_DATA) == 0, start) {
+            (true, None) => start = Some(offset),
+            (false, Some(first)) => {
+                ranges.push((first, offset - 1));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(first) = start {
+        ranges.push((first, prg_flags.len() - 1));
+    }
+    ranges
+}
+
+/// RGB image with a pixel per PRG byte, 256 wide: green ran as code, blue was read as data
+/// and dark grey was never used.
+pub fn coverage_map(prg_flags: &[u8]) -> Vec<u8> {
+    prg_flags
+        .iter()
+        .flat_map(|&flag| {
+            if flag & CDL_CODE != 0 {
+                [0x30, 0xc0, 0x30]
+            } else if flag & CDL_DATA != 0 {
+                [0x30, 0x60, 0xe0]
+            } else {
+                [0x20, 0x20, 0x20]
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_coverage() {
+        let mut flags = vec![0; 0x8000];
+        flags[..0x1000].fill(CDL_CODE);
+        flags[0x1000..0x2000].fill(CDL_DATA);
+        flags[0x7fff] = CDL_CODE;
+
+        let banks = bank_coverage(&flags);
+        assert_eq!(banks.len(), 2);
+        assert_eq!(banks[0].code_percent(), 25.0);
+        assert_eq!(banks[0].data_percent(), 25.0);
+        assert_eq!(banks[1].code, 1);
+        assert_eq!(unused_ranges(&flags), vec![(0x2000, 0x7ffe)]);
+        assert!(report(&flags).contains("Bank 0 (PRG $00000-$03FFF): 25.0% code, 25.0% data"));
+        assert_eq!(coverage_map(&flags).len(), 0x8000 * 3);
+    }
+}
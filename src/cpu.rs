@@ -1,10 +1,21 @@
 use crate::bus::Bus;
+use crate::callstack::{CallFrame, CallKind, CallStack};
+use crate::cartridge::Rom;
 use crate::cpu::AddressingMode::{
     Absolute, AbsoluteX, AbsoluteY, Immediate, Implied, Indirect, IndirectX, IndirectY, ZeroPage,
     ZeroPageX, ZeroPageY,
 };
+use crate::events::EventKind;
+use crate::hooks::{CpuHook, HookId, Hooks};
+use crate::interrupts::{Interrupt, InterruptKind, InterruptLog};
+use crate::logging;
 use crate::opcodes;
+use crate::state::{self, StateChunks, StateWriter};
+use crate::trace::Tracer;
 use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
 
 // status register bits, useful for dealing with flags
 const FLG_C: u8 = 0b0000_0001;
@@ -25,6 +36,14 @@ pub struct CPU<'a> {
     pub s: u8,
     pub pc: u16,
     pub bus: Bus<'a>,
+    /// Calls and interrupts that have not returned yet, for the debugger and crash dumps.
+    pub call_stack: CallStack,
+    /// NMIs taken since power on, lets the debugger notice a new one.
+    pub nmi_count: u64,
+    pub interrupts: InterruptLog,
+    /// Traces every instruction while set, `None` costs nothing.
+    pub tracer: Option<Tracer>,
+    hooks: Hooks,
 }
 
 #[derive(Debug)]
@@ -52,7 +71,7 @@ pub trait Mem {
     fn read_address(&mut self, adr: u16) -> u16 {
         let lo = self.read(adr) as u16;
         let hi = self.read(adr + 1) as u16;
-        (hi << 8) | (lo as u16)
+        (hi << 8) | lo
     }
 
     fn write_address(&mut self, adr: u16, val: u16) {
@@ -83,9 +102,33 @@ impl CPU<'_> {
             s: 0,
             pc: 0,
             bus,
+            call_stack: CallStack::new(),
+            nmi_count: 0,
+            interrupts: InterruptLog::new(),
+            tracer: None,
+            hooks: Hooks::default(),
         }
     }
 
+    /// Calls the hook around every instruction and interrupt from now on.
+    pub fn add_hook(&mut self, hook: Box<dyn CpuHook>) -> HookId {
+        self.hooks.add(hook)
+    }
+
+    pub fn remove_hook(&mut self, id: HookId) -> Option<Box<dyn CpuHook>> {
+        self.hooks.remove(id)
+    }
+
+    /// Runs the hooks with a view of the CPU, they are taken out meanwhile.
+    fn call_hooks(&mut self, mut call: impl FnMut(&mut dyn CpuHook, &CPU)) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let mut hooks = std::mem::take(&mut self.hooks);
+        hooks.for_each(|hook| call(hook, self));
+        self.hooks = hooks;
+    }
+
     pub fn get_effective_address(&mut self, mode: &AddressingMode, adr: u16) -> (u16, bool) {
         match mode {
             Immediate => (adr, false),
@@ -126,7 +169,7 @@ impl CPU<'_> {
             IndirectX => {
                 let base = self.read(adr);
 
-                let ptr: u8 = (base as u8).wrapping_add(self.x);
+                let ptr: u8 = base.wrapping_add(self.x);
                 let lo = self.read(ptr as u16);
                 let hi = self.read(ptr.wrapping_add(1) as u16);
                 ((hi as u16) << 8 | (lo as u16), false)
@@ -135,7 +178,7 @@ impl CPU<'_> {
                 let base = self.read(adr);
 
                 let lo = self.read(base as u16);
-                let hi = self.read((base as u8).wrapping_add(1) as u16);
+                let hi = self.read(base.wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | (lo as u16);
                 let deref = deref_base.wrapping_add(self.y as u16);
                 (deref, deref_base & 0xff00 != deref & 0xff00)
@@ -189,16 +232,119 @@ impl CPU<'_> {
         }
     }
 
+    /// Soft reset as done by the reset button, registers and RAM keep their contents.
     pub fn reset(&mut self) {
+        self.bus.reset();
+
+        // The reset sequence pushes three values on the stack with writes suppressed
+        self.s = self.s.wrapping_sub(3);
+        self.update_flag(FLG_I, true);
+        self.call_stack.clear();
+        self.interrupts.clear();
+
+        self.pc = self.read_address(0xfffc);
+    }
+
+    /// Swaps the cartridge and starts it from power-on.
+    pub fn load_rom(&mut self, rom: Rom) {
+        self.bus.load_rom(rom);
+        self.power_cycle();
+    }
+
+    /// Swaps the cartridge for a rebuilt version of the running game and carries on where it
+    /// was. Starts from power-on instead when the region or mirroring changed, as the state
+    /// would not fit the new cartridge. Returns whether the state was kept.
+    pub fn reload_rom(&mut self, rom: Rom) -> bool {
+        let fits =
+            rom.region == self.bus.ppu.region && rom.screen_mirroring == self.bus.ppu.mirroring;
+        let state = self.state_to_bytes();
+        self.load_rom(rom);
+        fits && StateChunks::new(&state)
+            .and_then(|chunks| self.state_from_chunks(&chunks))
+            .is_ok()
+    }
+
+    /// Reinitializes the whole system as if the console was switched off and on again.
+    pub fn power_cycle(&mut self) {
+        self.bus.power_cycle();
+
         self.a = 0;
         self.x = 0;
         self.y = 0;
         self.p = 0x24;
         self.s = 0xfd;
+        self.call_stack.clear();
+        self.interrupts.clear();
 
         self.pc = self.read_address(0xfffc);
     }
 
+    /// Serializes the whole machine without touching disk, cheap enough to call every frame.
+    /// The cartridge ROM is only identified by its CRC.
+    pub fn state_to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_chunk(*b"ROM ", |writer| writer.write_u32(self.bus.rom_crc()));
+        writer.write_chunk(*b"CPU ", |writer| {
+            writer.write_u8(self.a);
+            writer.write_u8(self.x);
+            writer.write_u8(self.y);
+            writer.write_u8(self.p);
+            writer.write_u8(self.s);
+            writer.write_u16(self.pc);
+        });
+        self.bus.save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    pub fn save_state(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.state_to_bytes())
+    }
+
+    /// Restores a state written by `save_state`.
+    pub fn load_state(&mut self, path: &Path) -> io::Result<()> {
+        self.state_from_bytes(&fs::read(path)?)?;
+        self.bus.log_event(EventKind::StateLoad);
+        Ok(())
+    }
+
+    /// Restores a state from `state_to_bytes`, an invalid state leaves the machine untouched.
+    pub fn state_from_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let bytes = state::migrate(bytes)?;
+        let chunks = StateChunks::new(&bytes)?;
+        if chunks.reader(*b"ROM ")?.read_u32()? != self.bus.rom_crc() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "save state is from a different game",
+            ));
+        }
+        self.state_from_chunks(&chunks)
+    }
+
+    fn state_from_chunks(&mut self, chunks: &StateChunks) -> io::Result<()> {
+        // A chunk can still turn out to be short halfway through, so go back to the
+        // current state rather than leaving the machine half restored
+        let current = self.state_to_bytes();
+        let result = self.apply_state(chunks);
+        if result.is_err() {
+            self.apply_state(&StateChunks::new(&current)?)?;
+        }
+        result
+    }
+
+    fn apply_state(&mut self, chunks: &StateChunks) -> io::Result<()> {
+        // Which calls are on the stack is not saved
+        self.call_stack.clear();
+        self.interrupts.clear();
+        let mut reader = chunks.reader(*b"CPU ")?;
+        self.a = reader.read_u8()?;
+        self.x = reader.read_u8()?;
+        self.y = reader.read_u8()?;
+        self.p = reader.read_u8()?;
+        self.s = reader.read_u8()?;
+        self.pc = reader.read_u16()?;
+        self.bus.load_state(chunks)
+    }
+
     pub fn run(&mut self, timeout: bool, max_time: u64) {
         self.run_with_callback(|_| {}, timeout, max_time);
     }
@@ -207,116 +353,183 @@ impl CPU<'_> {
     where
         F: FnMut(&mut CPU),
     {
-        let opcodes: &HashMap<u8, &'static opcodes::OpCode> = &(*opcodes::OPCODES_MAP);
-
         let mut run_time = max_time;
 
         while !timeout || run_time > 0 {
-            // Check for NMI
-            if self.bus.get_nmi() {
-                self.nmi();
-            }
+            // Decrement allowed run-time
+            let length = self.step_with_callback(&mut callback);
+            run_time = run_time.wrapping_sub(length as u64);
+        }
+    }
 
-            // Call provided callback, useful for printing process trace for example
-            callback(self);
+    /// Runs until `callback`, called after every instruction, returns false.
+    pub fn run_while<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&mut CPU) -> bool,
+    {
+        let mut running = true;
+        while running {
+            self.step_with_callback(&mut |cpu| running = callback(cpu));
+        }
+    }
+
+    /// Runs a single instruction, after an NMI if one is pending.
+    pub fn step(&mut self) {
+        self.step_with_callback(&mut |_| {});
+    }
 
-            // Fetch opcode and increment program counter
-            let code = self.read(self.pc);
-            self.pc += 1;
-            let pc_before_instruction = self.pc;
+    /// Runs a single instruction and returns its length, the callback runs right before it.
+    fn step_with_callback<F>(&mut self, callback: &mut F) -> u8
+    where
+        F: FnMut(&mut CPU),
+    {
+        let opcodes: &HashMap<u8, &'static opcodes::OpCode> = &opcodes::OPCODES_MAP;
 
-            let opcode = opcodes
-                .get(&code)
-                .unwrap_or_else(|| panic!("OpCode {:x} is not recognized", code));
+        // Check for NMI
+        if self.bus.get_nmi() {
+            self.nmi();
+        }
 
-            // Decrement allowed run-time
-            run_time = run_time.wrapping_sub(opcode.len as u64);
-
-            // Execute instruction
-            match code {
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
-                0x0a | 0x06 | 0x16 | 0x0e | 0x1e => self.asl(&opcode.mode),
-                0x90 => self.bcc(),
-                0xb0 => self.bcs(),
-                0xf0 => self.beq(),
-                0x24 | 0x2c => self.bit(&opcode.mode),
-                0x30 => self.bmi(),
-                0xd0 => self.bne(),
-                0x10 => self.bpl(),
-                0x00 => self.brk(),
-                0x50 => self.bvc(),
-                0x70 => self.bvs(),
-                0x18 => self.clc(),
-                0xd8 => self.cld(),
-                0x58 => self.cli(),
-                0xb8 => self.clv(),
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => self.cmp(&opcode.mode),
-                0xe0 | 0xe4 | 0xec => self.cpx(&opcode.mode),
-                0xc0 | 0xc4 | 0xcc => self.cpy(&opcode.mode),
-                0xc6 | 0xd6 | 0xce | 0xde => self.dec(&opcode.mode),
-                0xca => self.dex(),
-                0x88 => self.dey(),
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
-                0xe6 | 0xf6 | 0xee | 0xfe => self.inc(&opcode.mode),
-                0xe8 => self.inx(),
-                0xc8 => self.iny(),
-                0x4c | 0x6c => self.jmp(&opcode.mode),
-                0x20 => self.jsr(&opcode.mode),
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.lda(&opcode.mode),
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => self.ldx(&opcode.mode),
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => self.ldy(&opcode.mode),
-                0x4a | 0x46 | 0x56 | 0x4e | 0x5e => self.lsr(&opcode.mode),
-                0xea => self.nop(&opcode.mode),
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
-                0x48 => self.pha(),
-                0x08 => self.php(),
-                0x68 => self.pla(),
-                0x28 => self.plp(),
-                0x2a | 0x26 | 0x36 | 0x2e | 0x3e => self.rol(&opcode.mode),
-                0x6a | 0x66 | 0x76 | 0x6e | 0x7e => self.ror(&opcode.mode),
-                0x40 => self.rti(),
-                0x60 => self.rts(),
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.sbc(&opcode.mode),
-                0x38 => self.sec(),
-                0xf8 => self.sed(),
-                0x78 => self.sei(),
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
-                0x86 | 0x96 | 0x8e => self.stx(&opcode.mode),
-                0x84 | 0x94 | 0x8c => self.sty(&opcode.mode),
-                0xaa => self.tax(),
-                0xa8 => self.tay(),
-                0xba => self.tsx(),
-                0x8a => self.txa(),
-                0x9a => self.txs(),
-                0x98 => self.tya(),
-                // illegal opcodes
-                0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa | 0x80 | 0x82 | 0x89 | 0xc2 | 0xe2
-                | 0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c
-                | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => self.nop(&opcode.mode),
-                0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => self.lax(&opcode.mode),
-                0x87 | 0x97 | 0x8f | 0x83 => self.sax(&opcode.mode),
-                0xeb => self.sbc(&opcode.mode),
-                0xc7 | 0xd7 | 0xcf | 0xdf | 0xdb | 0xd3 | 0xc3 => self.dcp(&opcode.mode),
-                0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => self.isb(&opcode.mode),
-                0x07 | 0x17 | 0x0f | 0x1f | 0x1b | 0x03 | 0x13 => self.slo(&opcode.mode),
-                0x27 | 0x37 | 0x2f | 0x3f | 0x3b | 0x33 | 0x23 => self.rla(&opcode.mode),
-                0x47 | 0x57 | 0x4f | 0x5f | 0x5b | 0x43 | 0x53 => self.sre(&opcode.mode),
-                0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => self.rra(&opcode.mode),
-                _ => todo!("OpCode was parsed, but has not been implemented yet."),
+        // Call provided callback, useful for printing process trace for example
+        self.bus.set_in_callback(true);
+        if let Some(mut tracer) = self.tracer.take() {
+            match tracer.trace(self) {
+                Ok(()) => self.tracer = Some(tracer),
+                Err(error) => log::error!(target: logging::CPU, "Tracing stopped: {}", error),
             }
+        }
+        callback(self);
+        self.call_hooks(|hook, cpu| hook.before_instruction(cpu));
+        self.bus.set_in_callback(false);
+        self.bus.log_code(self.pc);
+
+        // Fetch opcode and increment program counter
+        let code = self.read(self.pc);
+        self.pc += 1;
+        let pc_before_instruction = self.pc;
+
+        let opcode = opcodes
+            .get(&code)
+            .unwrap_or_else(|| panic!("OpCode {:x} is not recognized", code));
+
+        // Execute instruction
+        match code {
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
+            0x0a | 0x06 | 0x16 | 0x0e | 0x1e => self.asl(&opcode.mode),
+            0x90 => self.bcc(),
+            0xb0 => self.bcs(),
+            0xf0 => self.beq(),
+            0x24 | 0x2c => self.bit(&opcode.mode),
+            0x30 => self.bmi(),
+            0xd0 => self.bne(),
+            0x10 => self.bpl(),
+            0x00 => self.brk(),
+            0x50 => self.bvc(),
+            0x70 => self.bvs(),
+            0x18 => self.clc(),
+            0xd8 => self.cld(),
+            0x58 => self.cli(),
+            0xb8 => self.clv(),
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => self.cmp(&opcode.mode),
+            0xe0 | 0xe4 | 0xec => self.cpx(&opcode.mode),
+            0xc0 | 0xc4 | 0xcc => self.cpy(&opcode.mode),
+            0xc6 | 0xd6 | 0xce | 0xde => self.dec(&opcode.mode),
+            0xca => self.dex(),
+            0x88 => self.dey(),
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.eor(&opcode.mode),
+            0xe6 | 0xf6 | 0xee | 0xfe => self.inc(&opcode.mode),
+            0xe8 => self.inx(),
+            0xc8 => self.iny(),
+            0x4c | 0x6c => self.jmp(&opcode.mode),
+            0x20 => self.jsr(&opcode.mode),
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.lda(&opcode.mode),
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => self.ldx(&opcode.mode),
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => self.ldy(&opcode.mode),
+            0x4a | 0x46 | 0x56 | 0x4e | 0x5e => self.lsr(&opcode.mode),
+            0xea => self.nop(&opcode.mode),
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
+            0x48 => self.pha(),
+            0x08 => self.php(),
+            0x68 => self.pla(),
+            0x28 => self.plp(),
+            0x2a | 0x26 | 0x36 | 0x2e | 0x3e => self.rol(&opcode.mode),
+            0x6a | 0x66 | 0x76 | 0x6e | 0x7e => self.ror(&opcode.mode),
+            0x40 => self.rti(),
+            0x60 => self.rts(),
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.sbc(&opcode.mode),
+            0x38 => self.sec(),
+            0xf8 => self.sed(),
+            0x78 => self.sei(),
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
+            0x86 | 0x96 | 0x8e => self.stx(&opcode.mode),
+            0x84 | 0x94 | 0x8c => self.sty(&opcode.mode),
+            0xaa => self.tax(),
+            0xa8 => self.tay(),
+            0xba => self.tsx(),
+            0x8a => self.txa(),
+            0x9a => self.txs(),
+            0x98 => self.tya(),
+            // illegal opcodes
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa | 0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 | 0x04
+            | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 | 0x0c | 0x1c | 0x3c | 0x5c
+            | 0x7c | 0xdc | 0xfc => self.nop(&opcode.mode),
+            0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => self.lax(&opcode.mode),
+            0x87 | 0x97 | 0x8f | 0x83 => self.sax(&opcode.mode),
+            0xeb => self.sbc(&opcode.mode),
+            0xc7 | 0xd7 | 0xcf | 0xdf | 0xdb | 0xd3 | 0xc3 => self.dcp(&opcode.mode),
+            0xe7 | 0xf7 | 0xef | 0xff | 0xfb | 0xe3 | 0xf3 => self.isb(&opcode.mode),
+            0x07 | 0x17 | 0x0f | 0x1f | 0x1b | 0x03 | 0x13 => self.slo(&opcode.mode),
+            0x27 | 0x37 | 0x2f | 0x3f | 0x3b | 0x33 | 0x23 => self.rla(&opcode.mode),
+            0x47 | 0x57 | 0x4f | 0x5f | 0x5b | 0x43 | 0x53 => self.sre(&opcode.mode),
+            0x67 | 0x77 | 0x6f | 0x7f | 0x7b | 0x63 | 0x73 => self.rra(&opcode.mode),
+            _ => todo!("OpCode was parsed, but has not been implemented yet."),
+        }
 
-            // Hand over control to bus
-            self.bus.tick(opcode.cycles);
+        // Hand over control to bus
+        self.bus.tick(opcode.cycles);
 
-            // Increment program counter unless altered by instruction
-            if pc_before_instruction == self.pc {
-                self.pc += (opcode.len - 1) as u16;
-            }
+        // Increment program counter unless altered by instruction
+        if pc_before_instruction == self.pc {
+            self.pc += (opcode.len - 1) as u16;
+        }
+
+        self.bus.profile(
+            pc_before_instruction.wrapping_sub(1),
+            self.call_stack.frames(),
+        );
+        self.bus.set_in_callback(true);
+        let pc = pc_before_instruction.wrapping_sub(1);
+        self.call_hooks(|hook, cpu| hook.after_instruction(cpu, pc));
+        self.bus.set_in_callback(false);
+
+        opcode.len
+    }
+
+    /// An interrupt taken now from `pc`, the handler is filled in once it has been fetched.
+    fn interrupt(&self, kind: InterruptKind, vector: u16, pc: u16) -> Interrupt {
+        Interrupt {
+            kind,
+            frame: self.bus.frames(),
+            scanline: self.bus.ppu.scanline,
+            dot: self.bus.ppu.cycles,
+            cycle: self.bus.cycles(),
+            vector,
+            handler: 0,
+            pc,
         }
     }
 
+    fn log_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupts.push(interrupt);
+        self.call_hooks(|hook, cpu| hook.on_interrupt(cpu, &interrupt));
+    }
+
     fn nmi(&mut self) {
+        let from = self.pc;
+        self.nmi_count += 1;
+        let mut interrupt = self.interrupt(InterruptKind::Nmi, 0xfffa, from);
+
         // Push program counter and status register on stack
         self.stack_push((self.pc >> 8) as u8);
         self.stack_push((self.pc & 0x00ff) as u8);
@@ -329,6 +542,15 @@ impl CPU<'_> {
 
         // Load nmi address into program counter
         self.pc = self.read_address(0xfffa);
+        interrupt.handler = self.pc;
+        self.log_interrupt(interrupt);
+        self.call_stack.call(CallFrame {
+            kind: CallKind::Nmi,
+            from,
+            target: self.pc,
+            return_address: from,
+            s: self.s,
+        });
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
@@ -414,6 +636,9 @@ impl CPU<'_> {
     }
 
     fn brk(&mut self) {
+        let return_address = self.pc;
+        let mut interrupt =
+            self.interrupt(InterruptKind::Brk, 0xfffe, return_address.wrapping_sub(1));
         self.stack_push((self.pc >> 8) as u8);
         self.stack_push((self.pc & 0xff) as u8);
         self.stack_push(self.p | FLG_U | FLG_B);
@@ -421,6 +646,15 @@ impl CPU<'_> {
         self.update_flag(FLG_I, true);
 
         self.pc = self.read_address(0xfffe);
+        interrupt.handler = self.pc;
+        self.log_interrupt(interrupt);
+        self.call_stack.call(CallFrame {
+            kind: CallKind::Brk,
+            from: return_address.wrapping_sub(1),
+            target: self.pc,
+            return_address,
+            s: self.s,
+        });
     }
 
     fn bvc(&mut self) {
@@ -538,6 +772,13 @@ impl CPU<'_> {
 
         self.stack_push(((self.pc + 1) >> 8) as u8);
         self.stack_push(((self.pc + 1) & 0x00ff) as u8);
+        self.call_stack.call(CallFrame {
+            kind: CallKind::Subroutine,
+            from: self.pc - 1,
+            target: adr,
+            return_address: self.pc + 2,
+            s: self.s,
+        });
 
         self.pc = adr;
     }
@@ -686,11 +927,13 @@ impl CPU<'_> {
     }
 
     fn rti(&mut self) {
+        self.call_stack.ret(self.s);
         self.p = self.stack_pop() & !FLG_B | FLG_U;
         self.pc = self.stack_pop() as u16 | (self.stack_pop() as u16) << 8;
     }
 
     fn rts(&mut self) {
+        self.call_stack.ret(self.s);
         self.pc = (self.stack_pop() as u16 | (self.stack_pop() as u16) << 8) + 1;
     }
 
@@ -884,10 +1127,22 @@ impl CPU<'_> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::cartridge::test::test_rom;
+    use crate::bus::test::TestBus;
+    use crate::cartridge::test::{looping_rom, test_rom};
+    use crate::cartridge::Mirroring;
 
     /// Takes a vector of program memory and tests it starting from 0x8000.
     fn test_cpu(program: Vec<u8>) -> CPU<'static> {
+        let mut bus = TestBus::new();
+        bus.load(0x8000, &program);
+        let mut cpu = bus.into_cpu();
+        cpu.run(true, program.len() as u64);
+        cpu
+    }
+
+    /// Like `test_cpu`, but with the program in a cartridge on the real bus, for tests that
+    /// need RAM or the rest of the machine state.
+    fn rom_cpu(program: Vec<u8>) -> CPU<'static> {
         let program_size = program.len();
         let mut padded_program = program;
         padded_program.extend(vec![0; 2 * 0x4000 - program_size - 4]);
@@ -895,7 +1150,7 @@ mod test {
 
         let bus = Bus::new(test_rom(padded_program), |_, _| {});
         let mut cpu = CPU::new(bus);
-        cpu.reset();
+        cpu.power_cycle();
         cpu.run(true, program_size as u64);
 
         cpu
@@ -1078,6 +1333,72 @@ mod test {
         assert_eq!(cpu.pc, 0xbbaa);
     }
 
+    #[test]
+    fn test_call_stack() {
+        // JSR $8005, then a nested JSR $8009 that returns straight away
+        let mut bus = TestBus::new();
+        bus.load(
+            0x8000,
+            &[0x20, 0x05, 0x80, 0xea, 0xea, 0x20, 0x09, 0x80, 0xea, 0x60],
+        );
+        let mut cpu = bus.into_cpu();
+        cpu.run(true, 8);
+
+        assert_eq!(cpu.pc, 0x8009);
+        assert_eq!(
+            cpu.call_stack.frames(),
+            [CallFrame {
+                kind: CallKind::Subroutine,
+                from: 0x8000,
+                target: 0x8005,
+                return_address: 0x8003,
+                s: 0xfb,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_interrupt_log() {
+        // NOP, then BRK with the IRQ/BRK vector pointing at $8010
+        let mut bus = TestBus::new();
+        bus.load(0x8000, &[0xea, 0x00]);
+        bus.set_vectors(0x0000, 0x8000, 0x8010);
+        let mut cpu = bus.into_cpu();
+        cpu.run(true, 2);
+
+        assert_eq!(cpu.pc, 0x8010);
+        let interrupt = cpu.interrupts.interrupts().next().unwrap();
+        assert_eq!(interrupt.kind, InterruptKind::Brk);
+        assert_eq!((interrupt.vector, interrupt.handler), (0xfffe, 0x8010));
+        assert_eq!(interrupt.pc, 0x8001);
+        assert_eq!(interrupt.frame, 0);
+    }
+
+    #[test]
+    fn test_interrupt_reads_vector_once() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        cpu.power_cycle();
+        cpu.bus.start_heatmap();
+        // The program is all BRK
+        cpu.step();
+        let heatmap = cpu.bus.heatmap().unwrap();
+        assert_eq!((heatmap.reads(0xfffe), heatmap.reads(0xffff)), (1, 1));
+    }
+
+    #[test]
+    fn test_code_in_ram() {
+        // Code at $0200 that patches the operand of its own LDA before running it
+        let mut bus = TestBus::new();
+        bus.load(0x0200, &[0xa9, 0x42, 0x8d, 0x06, 0x02, 0xa9, 0x00]);
+        bus.set_vectors(0x0000, 0x0200, 0x0000);
+        let mut cpu = bus.into_cpu();
+        assert_eq!(cpu.pc, 0x0200);
+        cpu.run(true, 7);
+
+        assert_eq!(cpu.a, 0x42);
+        assert_eq!(cpu.pc, 0x0207);
+    }
+
     #[test]
     fn test_lda() {
         let cpu = test_cpu(vec![0xa9, 0xee]);
@@ -1243,6 +1564,97 @@ mod test {
         assert_eq!(cpu.x, 0)
     }
 
+    #[test]
+    fn test_reset_preserves_ram_and_registers() {
+        let mut cpu = rom_cpu(vec![0xa9, 0x55, 0x85, 0x10]);
+        cpu.reset();
+        assert_eq!(cpu.a, 0x55);
+        assert_eq!(cpu.read(0x10), 0x55);
+        assert_eq!(cpu.s, 0xfa);
+        assert_ne!(cpu.p & FLG_I, 0);
+        assert_eq!(cpu.pc, 0x8000);
+    }
+
+    #[test]
+    fn test_power_cycle_clears_state() {
+        let mut cpu = rom_cpu(vec![0xa9, 0x55, 0x85, 0x10]);
+        cpu.power_cycle();
+        assert_eq!(cpu.a, 0);
+        assert_eq!(cpu.read(0x10), 0);
+        assert_eq!(cpu.s, 0xfd);
+        assert_eq!(cpu.p, 0x24);
+        assert_eq!(cpu.pc, 0x8000);
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let path = std::env::temp_dir().join("nes_rust_test_state.state");
+        let mut cpu = rom_cpu(vec![0xa9, 0x55, 0x85, 0x10]);
+        cpu.bus.ppu.vram[0x123] = 0x45;
+        cpu.save_state(&path).unwrap();
+
+        cpu.power_cycle();
+        cpu.load_state(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(cpu.a, 0x55);
+        assert_eq!(cpu.read(0x10), 0x55);
+        assert_eq!(cpu.bus.ppu.vram[0x123], 0x45);
+    }
+
+    #[test]
+    fn test_state_bytes_round_trip() {
+        let mut cpu = rom_cpu(vec![0xa9, 0x55, 0x85, 0x10]);
+        let bytes = cpu.state_to_bytes();
+
+        cpu.power_cycle();
+        cpu.state_from_bytes(&bytes).unwrap();
+        assert_eq!(cpu.a, 0x55);
+        assert_eq!(cpu.read(0x10), 0x55);
+        assert_eq!(cpu.state_to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_invalid_state_is_not_applied() {
+        let path = std::env::temp_dir().join("nes_rust_test_truncated.state");
+        let mut cpu = rom_cpu(vec![0xa9, 0x55]);
+        cpu.save_state(&path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+
+        cpu.power_cycle();
+        assert!(cpu.load_state(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(cpu.a, 0);
+    }
+
+    #[test]
+    fn test_state_of_other_game_is_rejected() {
+        let path = std::env::temp_dir().join("nes_rust_test_other_game.state");
+        let cpu = rom_cpu(vec![0xa9, 0x55]);
+        cpu.save_state(&path).unwrap();
+
+        let mut other = rom_cpu(vec![0xa9, 0x66]);
+        assert!(other.load_state(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(other.a, 0x66);
+    }
+
+    #[test]
+    fn test_reload_rom_keeps_state() {
+        let mut cpu = rom_cpu(vec![0xa9, 0x55, 0x85, 0x10]);
+        let pc = cpu.pc;
+
+        let mut rebuilt = looping_rom();
+        assert!(cpu.reload_rom(looping_rom()));
+        assert_eq!((cpu.a, cpu.pc), (0x55, pc));
+        assert_eq!(cpu.read(0x10), 0x55);
+        assert_eq!(cpu.bus.rom_crc(), rebuilt.crc);
+
+        rebuilt.screen_mirroring = Mirroring::Horizontal;
+        assert!(!cpu.reload_rom(rebuilt));
+        assert_eq!((cpu.a, cpu.pc), (0, 0x8000));
+    }
+
     // todo add SAX test
 
     // todo add DCP test
@@ -0,0 +1,131 @@
+use crate::cpu::{Mem, CPU};
+use crate::disasm::decode;
+use crate::frontend::timestamp;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Number of instructions kept for the crash log.
+pub const CRASH_TRACE_LENGTH: usize = 4096;
+
+/// Registers before an instruction, formatting is left until the history is dumped to keep
+/// recording cheap.
+struct TraceEntry {
+    pc: u16,
+    /// Opcode and operands, only read where reading has no side effects.
+    bytes: Option<[u8; 3]>,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    s: u8,
+    cycles: u64,
+}
+
+/// The most recently executed instructions, recorded whether or not tracing is on.
+/// Written out when emulation crashes and dumped by the debugger on request.
+pub struct CrashTrace {
+    entries: VecDeque<TraceEntry>,
+}
+
+impl CrashTrace {
+    pub fn new() -> Self {
+        CrashTrace {
+            entries: VecDeque::with_capacity(CRASH_TRACE_LENGTH),
+        }
+    }
+
+    /// Records the instruction the CPU is about to execute.
+    pub fn record(&mut self, cpu: &mut CPU) {
+        let pc = cpu.pc;
+        let bytes = match pc {
+            0x0000..=0x1ffd | 0x8000..=0xfffd => {
+                Some([cpu.read(pc), cpu.read(pc + 1), cpu.read(pc + 2)])
+            }
+            _ => None,
+        };
+        if self.entries.len() == CRASH_TRACE_LENGTH {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(TraceEntry {
+            pc,
+            bytes,
+            a: cpu.a,
+            x: cpu.x,
+            y: cpu.y,
+            p: cpu.p,
+            s: cpu.s,
+            cycles: cpu.bus.cycles(),
+        });
+    }
+
+    /// One instruction per line, oldest first.
+    pub fn lines(&self) -> String {
+        let mut text = String::new();
+        for entry in &self.entries {
+            let instruction = match entry.bytes {
+                Some(bytes) => decode(&bytes, entry.pc).to_string(),
+                None => format!("{:04X}  ??", entry.pc),
+            };
+            writeln!(
+                text,
+                "{:32}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+                instruction, entry.a, entry.x, entry.y, entry.p, entry.s, entry.cycles
+            )
+            .unwrap();
+        }
+        text
+    }
+}
+
+impl Default for CrashTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes a save state and the trace of the crashed machine, returns the paths written.
+/// The log starts with the calls that were active, innermost first.
+pub fn save_crash_dump(cpu: &CPU, trace: &CrashTrace, dir: &Path) -> io::Result<[PathBuf; 2]> {
+    let name = format!("{:08X}.crash-{}", cpu.bus.rom_crc(), timestamp());
+    let state_path = dir.join(format!("{}.state", name));
+    let log_path = dir.join(format!("{}.log", name));
+
+    fs::create_dir_all(dir)?;
+    cpu.save_state(&state_path)?;
+    let log = format!(
+        "Call stack:\n{}\nLast instructions:\n{}",
+        cpu.call_stack.lines(),
+        trace.lines()
+    );
+    fs::write(&log_path, log)?;
+    Ok([state_path, log_path])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_keeps_latest_instructions() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        let mut trace = CrashTrace::new();
+        for i in 0..CRASH_TRACE_LENGTH + 10 {
+            cpu.pc = 0x8000 + i as u16;
+            trace.record(&mut cpu);
+        }
+        cpu.pc = 0x2002;
+        trace.record(&mut cpu);
+
+        let lines = trace.lines();
+        assert_eq!(lines.lines().count(), CRASH_TRACE_LENGTH);
+        assert!(lines.starts_with("800B  00        BRK"));
+        assert!(
+            lines.ends_with("2002  ??                          A:00 X:00 Y:00 P:00 SP:00 CYC:7\n")
+        );
+    }
+}
@@ -0,0 +1,538 @@
+use crate::callstack::CallFrame;
+use crate::condition::Condition;
+use crate::console::ConsoleCommand;
+use crate::cpu::{Mem, CPU};
+use crate::disasm::{disassemble_memory, Instruction};
+use crate::dump::{dump_ppu, DUMP_DIR};
+use crate::emulation::Command;
+use crate::labels::Labels;
+use crate::ramsearch::{read_ram, RamSearch, SearchFilter};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+/// Commands for the debugger on the emulation thread.
+pub enum DebugCommand {
+    /// Reports the registers to the sender whenever execution stops, next to the senders
+    /// attached before.
+    Attach(Sender<Registers>),
+    /// Stops at the address, only when the condition holds if there is one.
+    SetBreakpoint(u16, Option<Condition>),
+    ClearBreakpoint(u16),
+    /// Stops after an instruction writes the PPU register, one of $2000-$2007.
+    BreakOnPpuWrite(u16, bool),
+    /// Stops at the first instruction of the NMI handler.
+    BreakOnNmi(bool),
+    /// Stops before the next instruction.
+    Break,
+    Continue,
+    /// Runs one instruction and stops again, entering subroutines.
+    Step,
+    /// Like `Step`, but runs a whole subroutine when the instruction is a JSR.
+    StepOver,
+    /// Runs until the current subroutine or interrupt handler returns.
+    StepOut,
+}
+
+const JSR: u8 = 0x20;
+const RTS: u8 = 0x60;
+const RTI: u8 = 0x40;
+
+/// RAM search results printed by the console.
+const SEARCH_LINES: usize = 16;
+
+/// CPU registers at the moment execution stopped, with the instruction that runs next.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub s: u8,
+    pub instruction: Instruction,
+    /// Calls that have not returned yet, outermost first.
+    pub call_stack: Vec<CallFrame>,
+}
+
+impl Registers {
+    pub fn of(cpu: &mut CPU) -> Self {
+        let pc = cpu.pc;
+        Registers {
+            pc,
+            a: cpu.a,
+            x: cpu.x,
+            y: cpu.y,
+            p: cpu.p,
+            s: cpu.s,
+            instruction: disassemble_memory(cpu, pc, 1).remove(0),
+            call_stack: cpu.call_stack.frames().to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}  {}",
+            self.pc, self.a, self.x, self.y, self.p, self.s, self.instruction.text
+        )
+    }
+}
+
+/// Breakpoints on PC addresses, checked by the emulation thread before every instruction.
+///
+/// Subroutine nesting is followed through the stack pointer, every JSR and interrupt pushes
+/// the return address and every RTS and RTI pops it, however deep the calls go.
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeMap<u16, Option<Condition>>,
+    break_on_nmi: bool,
+    /// NMIs the CPU had taken at the previous check.
+    nmi_count: u64,
+    stepping: bool,
+    /// Return address and stack pointer of the JSR being stepped over.
+    step_over: Option<(u16, u8)>,
+    /// Stack pointer when stepping out started, the return pops above it.
+    step_out: Option<u8>,
+    /// Whether the previous instruction was an RTS or RTI while stepping out.
+    returning: bool,
+    listeners: Vec<Sender<Registers>>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether execution has to stop before the next instruction.
+    pub fn should_break(&mut self, cpu: &mut CPU) -> bool {
+        let mut stop = match self.breakpoints.get(&cpu.pc) {
+            Some(Some(condition)) => condition.holds(cpu),
+            Some(None) => true,
+            None => false,
+        };
+        stop |= self.stepping;
+        stop |= cpu.bus.take_ppu_write().is_some();
+        stop |= self.break_on_nmi && cpu.nmi_count != self.nmi_count;
+        self.nmi_count = cpu.nmi_count;
+        if let Some((pc, s)) = self.step_over {
+            stop |= cpu.pc == pc && cpu.s >= s;
+        }
+        if let Some(s) = self.step_out {
+            stop |= self.returning && cpu.s > s;
+            self.returning = matches!(cpu.read(cpu.pc), RTS | RTI);
+        }
+
+        if stop {
+            self.stepping = false;
+            self.step_over = None;
+            self.step_out = None;
+            self.returning = false;
+        }
+        stop
+    }
+
+    /// Tells the attached frontends where execution stopped, dropping the ones that left.
+    pub fn report(&mut self, cpu: &mut CPU) {
+        if self.listeners.is_empty() {
+            return;
+        }
+        let registers = Registers::of(cpu);
+        self.listeners
+            .retain(|listener| listener.send(registers.clone()).is_ok());
+    }
+
+    /// Returns true when a stopped CPU should run again.
+    pub fn handle(&mut self, command: DebugCommand, cpu: &mut CPU) -> bool {
+        match command {
+            DebugCommand::Attach(listener) => self.listeners.push(listener),
+            DebugCommand::SetBreakpoint(address, condition) => {
+                self.breakpoints.insert(address, condition);
+            }
+            DebugCommand::ClearBreakpoint(address) => {
+                self.breakpoints.remove(&address);
+            }
+            DebugCommand::BreakOnPpuWrite(register, enabled) => {
+                cpu.bus.watch_ppu_write(register, enabled)
+            }
+            DebugCommand::BreakOnNmi(enabled) => {
+                self.break_on_nmi = enabled;
+                self.nmi_count = cpu.nmi_count;
+            }
+            DebugCommand::Break => self.stepping = true,
+            DebugCommand::Continue => return true,
+            DebugCommand::Step => {
+                self.stepping = true;
+                return true;
+            }
+            DebugCommand::StepOver => {
+                if cpu.read(cpu.pc) == JSR {
+                    self.step_over = Some((cpu.pc.wrapping_add(3), cpu.s));
+                } else {
+                    self.stepping = true;
+                }
+                return true;
+            }
+            DebugCommand::StepOut => {
+                self.step_out = Some(cpu.s);
+                self.returning = false;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Parses a hex address, with or without a leading `$`.
+pub fn parse_address(text: &str) -> Option<u16> {
+    u16::from_str_radix(text.trim().trim_start_matches('$'), 16).ok()
+}
+
+/// Parses a line of the debug console, e.g. `break 8000 if A == 0`, `clear Reset` or `step`.
+/// Addresses can be given by their label. `break nmi` and `break write 2001` stop on NMIs and
+/// PPU register writes, `clear` takes the same arguments.
+pub fn parse_command(line: &str, labels: &Labels) -> Result<DebugCommand, String> {
+    let mut words = line.split_whitespace();
+    let set = match words.next() {
+        Some("break" | "b") => Some(true),
+        Some("clear" | "d") => Some(false),
+        _ => None,
+    };
+    if let Some(set) = set {
+        match (words.next(), words.next(), words.next()) {
+            (Some("nmi"), None, None) => return Ok(DebugCommand::BreakOnNmi(set)),
+            (Some("write"), Some(register), None) => {
+                return match parse_address(register) {
+                    Some(register @ 0x2000..=0x2007) => {
+                        Ok(DebugCommand::BreakOnPpuWrite(register, set))
+                    }
+                    _ => Err(format!("Not a PPU register {:?}, use 2000-2007", register)),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    let (line, condition) = match line.split_once(" if ") {
+        Some((line, condition)) => (line, Some(Condition::parse(condition)?)),
+        None => (line, None),
+    };
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let address = words.next().and_then(|word| labels.parse_address(word));
+    if words.next().is_some() {
+        return Err(format!("Too many arguments in {:?}", line.trim()));
+    }
+
+    match (command, address) {
+        ("break" | "b", Some(address)) => Ok(DebugCommand::SetBreakpoint(address, condition)),
+        _ if condition.is_some() => Err("Only breakpoints take a condition".to_string()),
+        ("clear" | "d", Some(address)) => Ok(DebugCommand::ClearBreakpoint(address)),
+        ("pause" | "p", None) => Ok(DebugCommand::Break),
+        ("continue" | "c", None) => Ok(DebugCommand::Continue),
+        ("step" | "s", None) => Ok(DebugCommand::Step),
+        ("next" | "n", None) => Ok(DebugCommand::StepOver),
+        ("finish" | "f", None) => Ok(DebugCommand::StepOut),
+        _ => Err(format!(
+            "Unknown debugger command {:?}, use break <address> [if <condition>], \
+             break nmi, break write <register>, clear, pause, continue, step, next, finish, \
+             history, interrupts or dump",
+            line.trim()
+        )),
+    }
+}
+
+/// Reads debugger commands from stdin and prints where execution stops, with addresses
+/// replaced by their labels. `search new` starts a RAM search and e.g. `search decreased`
+/// narrows it down, `interrupts` lists the last NMIs and BRKs and `dump` writes the pattern
+/// tables, nametables and OAM to `DUMP_DIR`. The `ConsoleCommand`s run the game between
+/// frames, their states go to `state_dir` like the ones of the number keys. `pause` is one of
+/// them here, `p` still stops at the next instruction.
+pub fn spawn_console(emulation: Sender<Command>, labels: Labels, state_dir: PathBuf) {
+    let (listener, stops) = mpsc::channel();
+    if emulation
+        .send(Command::Debug(DebugCommand::Attach(listener)))
+        .is_err()
+    {
+        return;
+    }
+
+    let stop_labels = labels.clone();
+    thread::spawn(move || {
+        for registers in stops {
+            println!(
+                "Break at {}",
+                stop_labels.substitute(&registers.to_string())
+            );
+            for frame in registers.call_stack.iter().rev() {
+                println!("  in {}", stop_labels.substitute(&frame.to_string()));
+            }
+        }
+    });
+    thread::spawn(move || {
+        let mut ram_search = None;
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if matches!(line.trim(), "history" | "h") {
+                let (sender, history) = mpsc::channel();
+                if emulation.send(Command::History(sender)).is_err() {
+                    break;
+                }
+                if let Ok(history) = history.recv() {
+                    print!("{}", labels.substitute(&history));
+                }
+                continue;
+            }
+            if matches!(line.trim(), "interrupts" | "i") {
+                let (sender, log) = mpsc::channel();
+                let inspect = Command::Inspect(Box::new(move |cpu| {
+                    let _ = sender.send(cpu.interrupts.lines());
+                }));
+                if emulation.send(inspect).is_err() {
+                    break;
+                }
+                if let Ok(log) = log.recv() {
+                    print!("{}", labels.substitute(&log));
+                }
+                continue;
+            }
+            if line.trim() == "dump" {
+                let (sender, result) = mpsc::channel();
+                let inspect = Command::Inspect(Box::new(move |cpu| {
+                    let dumped = dump_ppu(&cpu.bus.ppu, cpu.bus.frames(), Path::new(DUMP_DIR));
+                    let _ = sender.send(dumped);
+                }));
+                if emulation.send(inspect).is_err() {
+                    break;
+                }
+                match result.recv() {
+                    Ok(Ok(paths)) => {
+                        for path in paths {
+                            println!("Dumped {}", path.display());
+                        }
+                    }
+                    Ok(Err(error)) => println!("Dumping failed: {}", error),
+                    Err(_) => break,
+                }
+                continue;
+            }
+            match ConsoleCommand::parse(&line, &labels) {
+                Some(Ok(command)) => {
+                    match command.run(&emulation, &state_dir) {
+                        Some(message) if message.is_empty() => {}
+                        Some(message) => println!("{}", message),
+                        None => break,
+                    }
+                    continue;
+                }
+                Some(Err(error)) => {
+                    println!("{}", error);
+                    continue;
+                }
+                None => {}
+            }
+            if let Some(search) = line.trim().strip_prefix("search") {
+                let Some(ram) = inspect_ram(&emulation) else {
+                    break;
+                };
+                match search.trim() {
+                    "" | "new" => ram_search = Some(RamSearch::new(&ram)),
+                    filter => match (SearchFilter::parse(filter), &mut ram_search) {
+                        (Ok(filter), Some(search)) => search.filter(filter, &ram),
+                        (Ok(_), None) => {
+                            println!("No RAM search yet, start one with search new");
+                            continue;
+                        }
+                        (Err(error), _) => {
+                            println!(
+                                "{}, use search new, search = <value>, != <value>, \
+                                 < <value>, > <value>, increased, decreased, changed or \
+                                 unchanged",
+                                error
+                            );
+                            continue;
+                        }
+                    },
+                }
+                if let Some(search) = &ram_search {
+                    print_candidates(search, &labels);
+                }
+                continue;
+            }
+            match parse_command(&line, &labels) {
+                Ok(command) => {
+                    if emulation.send(Command::Debug(command)).is_err() {
+                        break;
+                    }
+                }
+                Err(error) => println!("{}", error),
+            }
+        }
+    });
+}
+
+/// Copies RAM on the emulation thread, `None` once it has stopped.
+fn inspect_ram(emulation: &Sender<Command>) -> Option<Vec<u8>> {
+    let (sender, ram) = mpsc::channel();
+    emulation
+        .send(Command::Inspect(Box::new(move |cpu| {
+            let _ = sender.send(read_ram(cpu));
+        })))
+        .ok()?;
+    ram.recv().ok()
+}
+
+/// Prints how many addresses are left and the first of them with their values.
+fn print_candidates(search: &RamSearch, labels: &Labels) {
+    let candidates = search.candidates();
+    println!("{} addresses left", candidates.len());
+    for &address in candidates.iter().take(SEARCH_LINES) {
+        let name = labels.get(address).unwrap_or_default();
+        println!("  ${:04X} = {:3} {}", address, search.value(address), name);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+
+    fn should_break(debugger: &mut Debugger, cpu: &mut CPU, pc: u16) -> bool {
+        cpu.pc = pc;
+        debugger.should_break(cpu)
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        let mut debugger = Debugger::new();
+        assert!(!debugger.handle(DebugCommand::SetBreakpoint(0x8005, None), &mut cpu));
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8000));
+        assert!(should_break(&mut debugger, &mut cpu, 0x8005));
+
+        assert!(debugger.handle(DebugCommand::Step, &mut cpu));
+        assert!(should_break(&mut debugger, &mut cpu, 0x8008));
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8009));
+
+        debugger.handle(DebugCommand::ClearBreakpoint(0x8005), &mut cpu);
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8005));
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        let mut debugger = Debugger::new();
+        let condition = Condition::parse("X > 2").unwrap();
+        debugger.handle(
+            DebugCommand::SetBreakpoint(0x8005, Some(condition)),
+            &mut cpu,
+        );
+
+        cpu.x = 2;
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8005));
+        cpu.x = 3;
+        assert!(should_break(&mut debugger, &mut cpu, 0x8005));
+    }
+
+    #[test]
+    fn test_break_on_ppu_write_and_nmi() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        let mut debugger = Debugger::new();
+        debugger.handle(DebugCommand::BreakOnPpuWrite(0x2001, true), &mut cpu);
+
+        cpu.write(0x2000, 0x80);
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8000));
+        // $2009 mirrors $2001
+        cpu.write(0x2009, 0x1e);
+        assert!(should_break(&mut debugger, &mut cpu, 0x8003));
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8006));
+
+        debugger.handle(DebugCommand::BreakOnPpuWrite(0x2001, false), &mut cpu);
+        cpu.write(0x2001, 0x1e);
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8009));
+
+        cpu.nmi_count += 1;
+        debugger.handle(DebugCommand::BreakOnNmi(true), &mut cpu);
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8000));
+        cpu.nmi_count += 1;
+        assert!(should_break(&mut debugger, &mut cpu, 0x9000));
+        assert!(!should_break(&mut debugger, &mut cpu, 0x9001));
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        // JSR $8010 at $8000, a nested JSR $8020 at $8010 and RTS at $8013 and $8020
+        let mut program = vec![0; 0x8000];
+        program[..3].copy_from_slice(&[JSR, 0x10, 0x80]);
+        program[0x10..0x14].copy_from_slice(&[JSR, 0x20, 0x80, RTS]);
+        program[0x20] = RTS;
+        let mut cpu = CPU::new(Bus::new(test_rom(program), |_, _| {}));
+        let mut debugger = Debugger::new();
+
+        cpu.pc = 0x8000;
+        cpu.s = 0xfd;
+        assert!(debugger.handle(DebugCommand::StepOver, &mut cpu));
+        cpu.s = 0xfb;
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8010));
+        cpu.s = 0xfd;
+        assert!(should_break(&mut debugger, &mut cpu, 0x8003));
+
+        // Returning from the nested call does not count
+        cpu.s = 0xfb;
+        debugger.handle(DebugCommand::StepOut, &mut cpu);
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8010));
+        cpu.s = 0xf9;
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8020));
+        cpu.s = 0xfb;
+        assert!(!should_break(&mut debugger, &mut cpu, 0x8013));
+        cpu.s = 0xfd;
+        assert!(should_break(&mut debugger, &mut cpu, 0x8003));
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let mut labels = Labels::new();
+        labels.insert(0xc000, "Reset");
+        assert!(matches!(
+            parse_command("break $c000", &labels),
+            Ok(DebugCommand::SetBreakpoint(0xc000, None))
+        ));
+        assert!(matches!(
+            parse_command("b 8000 if [$00FE] != 0", &labels),
+            Ok(DebugCommand::SetBreakpoint(0x8000, Some(_)))
+        ));
+        assert!(parse_command("b 8000 if A ==", &labels).is_err());
+        assert!(parse_command("step if A == 0", &labels).is_err());
+        assert!(matches!(
+            parse_command("d 8000", &labels),
+            Ok(DebugCommand::ClearBreakpoint(0x8000))
+        ));
+        assert!(matches!(
+            parse_command(" c ", &labels),
+            Ok(DebugCommand::Continue)
+        ));
+        assert!(matches!(
+            parse_command("clear Reset", &labels),
+            Ok(DebugCommand::ClearBreakpoint(0xc000))
+        ));
+        assert!(matches!(
+            parse_command("break nmi", &labels),
+            Ok(DebugCommand::BreakOnNmi(true))
+        ));
+        assert!(matches!(
+            parse_command("clear write $2001", &labels),
+            Ok(DebugCommand::BreakOnPpuWrite(0x2001, false))
+        ));
+        assert!(parse_command("break write 4014", &labels).is_err());
+        assert!(parse_command("break", &labels).is_err());
+        assert!(parse_command("step 8000", &labels).is_err());
+    }
+}
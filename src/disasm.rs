@@ -0,0 +1,223 @@
+use crate::cpu::{AddressingMode, CPU};
+use crate::labels::Labels;
+use crate::memory::MemoryRegion;
+use crate::opcodes::{self, OpCode};
+use std::fmt;
+
+/// A decoded instruction, unknown opcodes decode as a single data byte.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    /// `None` for bytes that can't be read without side effects, like I/O registers.
+    pub bytes: Vec<Option<u8>>,
+    pub text: String,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex = self
+            .bytes
+            .iter()
+            .map(|&byte| hex(byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(f, "{:04X}  {:8}  {}", self.address, hex, self.text)
+    }
+}
+
+/// A byte in hex, `??` when unknown.
+fn hex(byte: Option<u8>) -> String {
+    byte.map_or("??".to_string(), |byte| format!("{:02X}", byte))
+}
+
+/// Decodes the instruction at the start of `bytes`, which is located at `address`.
+/// Operands cut off by the end of `bytes` are read as zero.
+pub fn decode(bytes: &[u8], address: u16) -> Instruction {
+    let bytes: Vec<Option<u8>> = bytes.iter().copied().map(Some).collect();
+    decode_partial(&bytes, address)
+}
+
+/// `decode` for bytes that may be unknown, their part of the operand shows as `??`.
+fn decode_partial(bytes: &[Option<u8>], address: u16) -> Instruction {
+    let code = match bytes.first() {
+        Some(Some(code)) => *code,
+        Some(None) => {
+            return Instruction {
+                address,
+                bytes: vec![None],
+                text: "??".to_string(),
+            }
+        }
+        None => 0,
+    };
+    let opcode = match opcodes::OPCODES_MAP.get(&code) {
+        Some(opcode) => opcode,
+        None => {
+            return Instruction {
+                address,
+                bytes: vec![Some(code)],
+                text: format!(".db ${:02X}", code),
+            }
+        }
+    };
+
+    let mut instruction_bytes: Vec<Option<u8>> =
+        bytes.iter().copied().take(opcode.len as usize).collect();
+    instruction_bytes.resize(opcode.len as usize, Some(0));
+    let operand = operand_text(opcode, &instruction_bytes, address);
+    Instruction {
+        address,
+        text: format!("{} {}", opcode.mnemonic, operand)
+            .trim_end()
+            .to_string(),
+        bytes: instruction_bytes,
+    }
+}
+
+fn operand_text(opcode: &OpCode, bytes: &[Option<u8>], address: u16) -> String {
+    let operand = |index: usize| bytes.get(index).copied().unwrap_or(Some(0));
+    let byte = hex(operand(1));
+    let word = format!("{}{}", hex(operand(2)), byte);
+
+    match (&opcode.mode, opcode.len) {
+        // Shifts and rotates on the accumulator
+        (AddressingMode::Implied, 1) if matches!(opcode.code, 0x0a | 0x4a | 0x2a | 0x6a) => {
+            "A".to_string()
+        }
+        (AddressingMode::Implied, 1) => String::new(),
+        // Branches, the operand is an offset from the next instruction
+        (AddressingMode::Implied, _) => match operand(1) {
+            Some(offset) => format!(
+                "${:04X}",
+                address.wrapping_add(2).wrapping_add(offset as i8 as u16)
+            ),
+            None => "$????".to_string(),
+        },
+        (AddressingMode::Immediate, _) => format!("#${}", byte),
+        (AddressingMode::ZeroPage, _) => format!("${}", byte),
+        (AddressingMode::ZeroPageX, _) => format!("${},X", byte),
+        (AddressingMode::ZeroPageY, _) => format!("${},Y", byte),
+        (AddressingMode::Absolute, _) => format!("${}", word),
+        (AddressingMode::AbsoluteX, _) => format!("${},X", word),
+        (AddressingMode::AbsoluteY, _) => format!("${},Y", word),
+        (AddressingMode::Indirect, _) => format!("(${})", word),
+        (AddressingMode::IndirectX, _) => format!("(${},X)", byte),
+        (AddressingMode::IndirectY, _) => format!("(${}),Y", byte),
+    }
+}
+
+/// Decodes consecutive instructions from a block of code located at `start`.
+pub fn disassemble(bytes: &[u8], start: u16) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let instruction = decode(&bytes[offset..], start.wrapping_add(offset as u16));
+        offset += instruction.bytes.len();
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+/// Decodes `count` instructions from the address space, e.g. around the PC in the debugger.
+/// Bytes are peeked, so I/O registers show as `??` instead of being read.
+pub fn disassemble_memory(cpu: &mut CPU, start: u16, count: usize) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut address = start;
+    for _ in 0..count {
+        let bytes: Vec<Option<u8>> = (0..3)
+            .map(|offset| MemoryRegion::Cpu.peek(cpu, address.wrapping_add(offset)))
+            .collect();
+        let instruction = decode_partial(&bytes, address);
+        address = address.wrapping_add(instruction.bytes.len() as u16);
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+/// Lists the whole PRG ROM, mapped to the end of the address space like NROM does.
+/// Labeled addresses get a `name:` line and operands are shown by name.
+pub fn disassemble_prg(prg_rom: &[u8], labels: &Labels) -> String {
+    let start = if prg_rom.len() >= 0x8000 {
+        0x8000
+    } else {
+        0xc000
+    };
+    let mut listing = String::new();
+    for instruction in disassemble(prg_rom, start) {
+        if let Some(name) = labels.get(instruction.address) {
+            listing.push_str(&format!("{}:\n", name));
+        }
+        listing.push_str(&labels.substitute(&instruction.to_string()));
+        listing.push('\n');
+    }
+    listing
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+
+    fn texts(bytes: &[u8], start: u16) -> Vec<String> {
+        disassemble(bytes, start)
+            .into_iter()
+            .map(|instruction| instruction.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        let bytes = [
+            0xa9, 0x3f, // LDA #$3F
+            0x0a, // ASL A
+            0xb5, 0x10, // LDA $10,X
+            0x6c, 0x34, 0x12, // JMP ($1234)
+            0xb1, 0x20, // LDA ($20),Y
+            0xd0, 0xfe, // BNE to itself
+            0xea, // NOP
+            0x02, // not an opcode
+        ];
+        assert_eq!(
+            texts(&bytes, 0x8000),
+            [
+                "LDA #$3F",
+                "ASL A",
+                "LDA $10,X",
+                "JMP ($1234)",
+                "LDA ($20),Y",
+                "BNE $800A",
+                "NOP",
+                ".db $02"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_memory_peeks() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        cpu.write(0x1fff, 0x4c);
+        cpu.bus.ppu.register_status.set_vertical_blank(true);
+        let instructions = disassemble_memory(&mut cpu, 0x1fff, 2);
+        assert_eq!(instructions[0].to_string(), "1FFF  4C ?? ??  JMP $????");
+        assert_eq!(instructions[1].to_string(), "2002  ??        ??");
+        assert!(cpu.bus.ppu.register_status.get_vertical_blank());
+    }
+
+    #[test]
+    fn test_listing() {
+        let instruction = decode(&[0x20, 0x00, 0xc0], 0xc123);
+        assert_eq!(instruction.to_string(), "C123  20 00 C0  JSR $C000");
+        assert_eq!(
+            decode(&[0x4c], 0x8000).bytes,
+            [Some(0x4c), Some(0x00), Some(0x00)]
+        );
+
+        let mut labels = Labels::new();
+        labels.insert(0xc000, "Reset");
+        let mut prg = vec![0xea; 0x4000];
+        prg[..3].copy_from_slice(&[0x4c, 0x00, 0xc0]);
+        assert!(disassemble_prg(&prg, &labels).starts_with("Reset:\nC000  4C 00 C0  JMP Reset\n"));
+    }
+}
@@ -0,0 +1,118 @@
+use crate::ppu::PPU;
+use crate::render::{self, encode_png};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where the debugger console writes dumps, next to the config.
+pub const DUMP_DIR: &str = "debug";
+
+/// The four nametables as hex, tile indices as 30 rows of 32 and then the attribute bytes as 8
+/// rows of 8.
+pub fn nametables_text(ppu: &PPU) -> String {
+    let mut text = String::new();
+    for table in 0..4 {
+        let nametable = render::nametable(ppu, table);
+        writeln!(text, "Nametable ${:04X}", 0x2000 + table * 0x400).unwrap();
+        for (row, tiles) in nametable[..0x3c0].chunks_exact(32).enumerate() {
+            let tiles: Vec<String> = tiles.iter().map(|tile| format!("{:02X}", tile)).collect();
+            writeln!(text, "{:2}: {}", row, tiles.join(" ")).unwrap();
+        }
+        writeln!(text, "Attributes ${:04X}", 0x23c0 + table * 0x400).unwrap();
+        for (row, bytes) in nametable[0x3c0..].chunks_exact(8).enumerate() {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            writeln!(text, "{:2}: {}", row, bytes.join(" ")).unwrap();
+        }
+        writeln!(text).unwrap();
+    }
+    text
+}
+
+/// The 64 sprites with their fields decoded, one per line.
+pub fn oam_text(ppu: &PPU) -> String {
+    let mut text = String::from("Sprite   X   Y  Tile  Palette  Priority  Flip\n");
+    for (index, sprite) in ppu.oam_data.chunks_exact(4).enumerate() {
+        let attributes = sprite[2];
+        let flag = |bit: u8, letter| if attributes & bit != 0 { letter } else { '-' };
+        writeln!(
+            text,
+            "{:6} {:3} {:3}   ${:02X}  {:7}  {:8}  {}{}",
+            index,
+            sprite[3],
+            sprite[0],
+            sprite[1],
+            4 + (attributes & 0b11),
+            if attributes & 0b0010_0000 != 0 {
+                "back"
+            } else {
+                "front"
+            },
+            flag(0b0100_0000, 'H'),
+            flag(0b1000_0000, 'V'),
+        )
+        .unwrap();
+    }
+    text
+}
+
+/// Writes the pattern tables and nametables as PNGs and the nametables and OAM as text to
+/// `dir`, the file names start with the frame so dumps don't overwrite each other.
+pub fn dump_ppu(ppu: &PPU, frame: u64, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let files = [
+        (
+            "pattern_tables.png",
+            encode_png(256, 128, &render::render_pattern_tables(ppu)),
+        ),
+        (
+            "nametables.png",
+            encode_png(512, 480, &render::render_nametables(ppu)),
+        ),
+        ("nametables.txt", nametables_text(ppu).into_bytes()),
+        ("oam.txt", oam_text(ppu).into_bytes()),
+    ];
+
+    let mut paths = vec![];
+    for (name, bytes) in files {
+        let path = dir.join(format!("frame_{}_{}", frame, name));
+        fs::write(&path, bytes)?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+
+    #[test]
+    fn test_dump_ppu() {
+        let mut ppu = PPU::new(vec![0; 0x2000], Mirroring::Vertical);
+        ppu.vram[0x21] = 0xab;
+        ppu.vram[0x3c0] = 0x1b;
+        ppu.oam_data[4..8].copy_from_slice(&[0x10, 0x42, 0b1110_0001, 0x80]);
+
+        let nametables = nametables_text(&ppu);
+        assert!(nametables.starts_with("Nametable $2000\n 0: 00 "));
+        assert!(nametables.contains("\n 1: 00 AB 00"));
+        assert!(nametables.contains("Attributes $23C0\n 0: 1B 00"));
+        // With vertical mirroring $2800 is $2000 again
+        assert_eq!(nametables.matches(" 1: 00 AB 00").count(), 2);
+
+        let oam = oam_text(&ppu);
+        assert_eq!(oam.lines().count(), 65);
+        assert_eq!(
+            oam.lines().nth(2),
+            Some("     1 128  16   $42        5  back      HV")
+        );
+
+        let dir = std::env::temp_dir().join("nes_rust_test_dump");
+        let paths = dump_ppu(&ppu, 12, &dir).unwrap();
+        assert_eq!(paths[3], dir.join("frame_12_oam.txt"));
+        assert_eq!(fs::read_to_string(&paths[3]).unwrap(), oam);
+        assert!(fs::read(&paths[0]).unwrap().starts_with(b"\x89PNG"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,658 @@
+use crate::achievements::AchievementSet;
+use crate::bus::{Bus, RamInit};
+use crate::cartridge::{Region, Rom};
+use crate::cpu::CPU;
+use crate::crash::{save_crash_dump, CrashTrace};
+use crate::debugger::{DebugCommand, Debugger};
+use crate::events::EventKind;
+use crate::joypad::Joypad;
+use crate::netplay::NetplaySession;
+use crate::pacer::FramePacer;
+use crate::ppu::PPU;
+use crate::render::{self, FrameSender};
+use crate::rewind::RewindBuffer;
+#[cfg(feature = "rhai")]
+use crate::rhai_script::RhaiScript;
+use crate::statehash::hash_state;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Frames between the states captured for rewinding, rewinding steps back one state per frame.
+pub const REWIND_INTERVAL: u32 = 2;
+
+/// Code run on the emulation thread with the machine.
+pub type Inspection = Box<dyn FnOnce(&mut CPU) + Send>;
+
+/// Message from the frontend, handled by the emulation thread right before each frame.
+pub enum Command {
+    Reset,
+    PowerCycle,
+    LoadRom(Rom, RamInit),
+    /// Swaps in a rebuilt version of the running game, keeping its state when it fits, see
+    /// `CPU::reload_rom`.
+    ReloadRom(Rom, RamInit),
+    /// Extra scanlines per frame after vertical blank, see `Bus::set_overclock`.
+    Overclock(u16),
+    Button(u8, bool),
+    /// Stops running frames, commands are still handled while paused.
+    Pause(bool),
+    /// Runs exactly one frame and pauses again.
+    FrameAdvance,
+    /// Target frame rate, `None` runs as fast as possible.
+    FrameRate(Option<f64>),
+    /// Frames that run without drawing between the frames that are sent, to fast-forward
+    /// faster. Skipped frames still run the whole PPU timing, only the picture is left out.
+    FrameSkip(u32),
+    /// Steps back through the recent states while held.
+    Rewind(bool),
+    /// Sends the hash of the machine state after every frame, `None` stops.
+    HashStates(Option<Sender<u32>>),
+    /// Runs on the emulation thread, e.g. to save a state or copy state for a debug view.
+    Inspect(Inspection),
+    /// Runs until the frame count reaches this, even while paused, then runs the closure like
+    /// `Inspect`.
+    RunUntil(u64, Inspection),
+    /// Sends the most recently executed instructions, oldest first.
+    History(Sender<String>),
+    Debug(DebugCommand),
+    /// Tests the achievements after every frame, sending the ones that unlock, `None` stops.
+    /// Loading a game stops them too.
+    Achievements(Option<(AchievementSet, Sender<String>)>),
+    /// Power cycles and plays online from then on, the buttons go to the player of this side.
+    /// Rewinding is off meanwhile, resets and loading states make the players drift apart.
+    /// `None` goes back to playing alone.
+    Netplay(Option<NetplaySession>),
+    /// Runs a Lua script from the end of this frame on, replacing any running script, `None`
+    /// stops it.
+    #[cfg(feature = "lua")]
+    LuaScript(Option<PathBuf>),
+    /// Runs a Rhai script from the end of this frame on, replacing any running script, `None`
+    /// stops it.
+    #[cfg(feature = "rhai")]
+    RhaiScript(Option<PathBuf>),
+}
+
+/// Runs the CPU and PPU on their own thread, sending every finished frame to the frontend.
+///
+/// When emulation panics, a save state and a trace of the last instructions are written to
+/// `crash_dir` before the panic continues.
+pub fn spawn(
+    rom: Rom,
+    ram_init: RamInit,
+    frame_rate: Option<f64>,
+    rewind_seconds: u32,
+    crash_dir: PathBuf,
+    commands: Receiver<Command>,
+    frames: FrameSender,
+) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("emulation".to_string())
+        .spawn(move || {
+            run(
+                rom,
+                ram_init,
+                frame_rate,
+                rewind_seconds,
+                crash_dir,
+                commands,
+                frames,
+            )
+        })
+        .unwrap()
+}
+
+fn run(
+    rom: Rom,
+    ram_init: RamInit,
+    mut frame_rate: Option<f64>,
+    rewind_seconds: u32,
+    crash_dir: PathBuf,
+    commands: Receiver<Command>,
+    frames: FrameSender,
+) {
+    // Sent once the frame has been handled, so scripts can draw over it
+    let finished = Rc::new(Cell::new(None));
+    let bus_finished = Rc::clone(&finished);
+    let frames = Rc::new(frames);
+    let bus_frames = Rc::clone(&frames);
+    let skipping = Rc::new(Cell::new(false));
+    let bus_skipping = Rc::clone(&skipping);
+
+    let mut bus = Bus::new(rom, move |ppu: &PPU, _joypad: &mut Joypad| {
+        let mut frame = bus_frames.take();
+        if !bus_skipping.get() {
+            render::render(ppu, &mut frame);
+        }
+        bus_finished.set(Some(frame));
+    });
+
+    bus.set_ram_init(ram_init);
+    let mut cpu = CPU::new(bus);
+
+    let mut paused = false;
+    let mut rewinding = false;
+    let mut rewind = rewind_buffer(rewind_seconds, cpu.bus.ppu.region);
+    let mut frames_since_capture = 0;
+    let mut state_hashes: Option<Sender<u32>> = None;
+    let mut frame_skip = 0;
+    // Frames skipped since the last one that was drawn
+    let mut skipped = 0;
+    let mut pacer = FramePacer::new(cpu.bus.ppu.region.frame_rate());
+    // When the current frame started running, after waiting for it to be due
+    let mut frame_start = Instant::now();
+    let mut debugger = Debugger::new();
+    // Commands that arrived while stopped by the debugger, handled after the frame
+    let mut pending = VecDeque::new();
+    // The frame counts `RunUntil` commands wait for, with what to run then
+    let mut frame_waits: Vec<(u64, Inspection)> = Vec::new();
+    let mut achievements: Option<(AchievementSet, Sender<String>)> = None;
+    let mut netplay: Option<NetplaySession> = None;
+    #[cfg(feature = "lua")]
+    let mut lua_script: Option<crate::lua::LuaScript> = None;
+    #[cfg(feature = "rhai")]
+    let mut rhai_script: Option<RhaiScript> = None;
+
+    let mut crash_trace = CrashTrace::new();
+    let trace = &mut crash_trace;
+
+    cpu.power_cycle();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        // Runs until the frontend drops its end of the commands
+        cpu.run_while(move |cpu| {
+            trace.record(cpu);
+            #[cfg(feature = "rhai")]
+            run_rhai_hook(&mut rhai_script, cpu, RhaiScript::after_instruction);
+            if debugger.should_break(cpu) {
+                debugger.report(cpu);
+                loop {
+                    let Ok(command) = commands.recv() else {
+                        return false;
+                    };
+                    match command {
+                        Command::Debug(command) => {
+                            if debugger.handle(command, cpu) {
+                                break;
+                            }
+                        }
+                        Command::Inspect(inspect) => inspect(cpu),
+                        Command::History(sender) => {
+                            let _ = sender.send(trace.lines());
+                        }
+                        command => pending.push_back(command),
+                    }
+                }
+                // Time stopped in the debugger does not count towards the frame
+                frame_start = Instant::now();
+            }
+
+            #[cfg_attr(not(any(feature = "lua", feature = "rhai")), allow(unused_mut))]
+            let Some(mut frame) = finished.take() else {
+                return true;
+            };
+
+            let frame_time = frame_start.elapsed();
+            cpu.bus.log_event(EventKind::Frame(frame_time));
+            if let Some(rate) = frame_rate {
+                let target = Duration::from_secs_f64(1.0 / rate);
+                if frame_time > target {
+                    cpu.bus.log_event(EventKind::DroppedFrame(target));
+                }
+            }
+
+            #[cfg(feature = "lua")]
+            if let Some(running) = &mut lua_script {
+                if let Err(error) = running.run_frame(cpu, &mut frame) {
+                    println!("Lua script stopped: {}", error);
+                    lua_script = None;
+                }
+            }
+            #[cfg(feature = "rhai")]
+            run_rhai_hook(&mut rhai_script, cpu, |script, cpu| {
+                script.frame_end(cpu, &mut frame)
+            });
+            if let Some((set, unlocked)) = &mut achievements {
+                for achievement in set.do_frame(cpu) {
+                    let _ = unlocked.send(format!("Achievement unlocked: {}", achievement));
+                }
+            }
+            // The frontend only goes away when the process exits
+            if skipping.get() {
+                frames.recycle(frame);
+                skipped += 1;
+            } else {
+                let _ = frames.send(frame);
+                skipped = 0;
+            }
+
+            let mut i = 0;
+            while i < frame_waits.len() {
+                if frame_waits[i].0 <= cpu.bus.frames() {
+                    (frame_waits.swap_remove(i).1)(cpu);
+                } else {
+                    i += 1;
+                }
+            }
+
+            // Wait for the next frame before handling the commands, so buttons pressed
+            // meanwhile still make it into that frame
+            if let Some(rate) = frame_rate {
+                pacer.set_rate(rate);
+                pacer.wait();
+            }
+
+            // Handle the commands that arrived since the last frame, wait for more while paused
+            loop {
+                let command = if let Some(command) = pending.pop_front() {
+                    command
+                } else if paused && frame_waits.is_empty() {
+                    match commands.recv() {
+                        Ok(command) => command,
+                        Err(_) => return false,
+                    }
+                } else {
+                    match commands.try_recv() {
+                        Ok(command) => command,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return false,
+                    }
+                };
+
+                match command {
+                    Command::Reset => cpu.reset(),
+                    Command::PowerCycle => cpu.power_cycle(),
+                    Command::LoadRom(rom, ram_init) => {
+                        cpu.bus.set_ram_init(ram_init);
+                        cpu.load_rom(rom);
+                        rewind = rewind_buffer(rewind_seconds, cpu.bus.ppu.region);
+                        achievements = None;
+                    }
+                    Command::ReloadRom(rom, ram_init) => {
+                        cpu.bus.set_ram_init(ram_init);
+                        if !cpu.reload_rom(rom) {
+                            println!(
+                                "The state does not fit the rebuilt ROM, starting from power-on"
+                            );
+                        }
+                        rewind = rewind_buffer(rewind_seconds, cpu.bus.ppu.region);
+                        achievements = None;
+                    }
+                    Command::Overclock(scanlines) => cpu.bus.set_overclock(scanlines),
+                    Command::Button(button, pressed) => match &mut netplay {
+                        Some(session) => session.set_button_pressed_status(button, pressed),
+                        None => cpu
+                            .bus
+                            .joypad_mut()
+                            .set_button_pressed_status(button, pressed),
+                    },
+                    Command::Pause(pause) => paused = pause,
+                    Command::FrameAdvance => {
+                        paused = true;
+                        break;
+                    }
+                    Command::FrameRate(rate) => frame_rate = rate,
+                    Command::FrameSkip(frames) => frame_skip = frames,
+                    Command::Rewind(rewind) => rewinding = rewind && netplay.is_none(),
+                    Command::HashStates(sender) => state_hashes = sender,
+                    Command::Inspect(inspect) => inspect(cpu),
+                    Command::RunUntil(frame, inspect) if frame <= cpu.bus.frames() => inspect(cpu),
+                    Command::RunUntil(frame, inspect) => frame_waits.push((frame, inspect)),
+                    Command::History(sender) => {
+                        let _ = sender.send(trace.lines());
+                    }
+                    Command::Debug(command) => {
+                        debugger.handle(command, cpu);
+                    }
+                    Command::Achievements(set) => achievements = set,
+                    Command::Netplay(session) => {
+                        if let Some(session) = &session {
+                            session.start(cpu);
+                            rewind.clear();
+                            rewinding = false;
+                        }
+                        netplay = session;
+                    }
+                    #[cfg(feature = "lua")]
+                    Command::LuaScript(path) => {
+                        lua_script =
+                            path.and_then(|path| match crate::lua::LuaScript::load(&path) {
+                                Ok(loaded) => {
+                                    println!("Running {}", path.display());
+                                    Some(loaded)
+                                }
+                                Err(error) => {
+                                    println!("Loading {} failed: {}", path.display(), error);
+                                    None
+                                }
+                            });
+                    }
+                    #[cfg(feature = "rhai")]
+                    Command::RhaiScript(path) => {
+                        cpu.bus.clear_write_watches();
+                        rhai_script = path.and_then(|path| match RhaiScript::load(&path) {
+                            Ok(loaded) => {
+                                println!("Running {}", path.display());
+                                Some(loaded)
+                            }
+                            Err(error) => {
+                                println!("Loading {} failed: {}", path.display(), error);
+                                None
+                            }
+                        });
+                    }
+                }
+            }
+
+            skipping.set(skipped < frame_skip);
+
+            // Restoring a state at the end of the frame shows the frame that followed it next
+            if rewinding {
+                rewind.pop();
+                if let Some(state) = rewind.latest() {
+                    // The states were captured from this machine, so they always fit
+                    let _ = cpu.state_from_bytes(state);
+                }
+            } else {
+                frames_since_capture += 1;
+                if frames_since_capture >= REWIND_INTERVAL {
+                    frames_since_capture = 0;
+                    rewind.push(cpu.state_to_bytes());
+                }
+            }
+
+            #[cfg(feature = "rhai")]
+            run_rhai_hook(&mut rhai_script, cpu, RhaiScript::frame_start);
+            if let Some(session) = &mut netplay {
+                if let Err(error) = session.before_frame(cpu) {
+                    println!("Netplay stopped: {}", error);
+                    netplay = None;
+                }
+                // Frames that ran again after a wrong guess are not shown
+                if let Some(frame) = finished.take() {
+                    frames.recycle(frame);
+                }
+            }
+
+            if let Some(sender) = &state_hashes {
+                let _ = sender.send(hash_state(&cpu.state_to_bytes()));
+            }
+
+            frame_start = Instant::now();
+            true
+        })
+    }));
+
+    // The panic message was already printed, add where the dump went and keep panicking
+    if let Err(payload) = result {
+        match save_crash_dump(&cpu, &crash_trace, &crash_dir) {
+            Ok(paths) => println!(
+                "Saved crash dump {} and {}",
+                paths[0].display(),
+                paths[1].display()
+            ),
+            Err(error) => println!("Saving crash dump failed: {}", error),
+        }
+        panic::resume_unwind(payload);
+    }
+}
+
+/// Room for `seconds` of states at the frame rate of `region`.
+fn rewind_buffer(seconds: u32, region: Region) -> RewindBuffer {
+    RewindBuffer::new((seconds as f64 * region.frame_rate()) as usize / REWIND_INTERVAL as usize)
+}
+
+/// Runs a hook of the Rhai script, stopping the script when the hook fails.
+#[cfg(feature = "rhai")]
+fn run_rhai_hook(
+    script: &mut Option<RhaiScript>,
+    cpu: &mut CPU,
+    hook: impl FnOnce(&mut RhaiScript, &mut CPU) -> Result<(), String>,
+) {
+    if let Some(running) = script {
+        if let Err(error) = hook(running, cpu) {
+            println!("Rhai script stopped: {}", error);
+            *script = None;
+            cpu.bus.clear_write_watches();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::{looping_rom, test_rom};
+    use std::fs;
+    use std::sync::mpsc;
+
+    fn spawn_looping(commands: Receiver<Command>, frames: FrameSender) -> JoinHandle<()> {
+        let temp_dir = std::env::temp_dir();
+        spawn(
+            looping_rom(),
+            RamInit::Zero,
+            None,
+            0,
+            temp_dir,
+            commands,
+            frames,
+        )
+    }
+
+    #[test]
+    fn test_frame_advance() {
+        let (emulation, commands) = mpsc::channel();
+        let (frame_sender, frames) = render::frame_channel();
+        spawn_looping(commands, frame_sender);
+        assert!(frames.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        // Wait until the frames that were running when pausing have arrived
+        emulation.send(Command::Pause(true)).unwrap();
+        while frames.recv_timeout(Duration::from_millis(200)).is_ok() {}
+
+        emulation.send(Command::FrameAdvance).unwrap();
+        assert!(frames.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(frames.recv_timeout(Duration::from_millis(200)).is_err());
+
+        // Commands are still handled while paused
+        let (sender, pc) = mpsc::channel();
+        emulation
+            .send(Command::Inspect(Box::new(move |cpu| {
+                sender.send(cpu.pc).unwrap();
+            })))
+            .unwrap();
+        let pc = pc.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!((0x8000..=0x8002).contains(&pc));
+    }
+
+    #[test]
+    fn test_run_until() {
+        let (emulation, commands) = mpsc::channel();
+        let (frame_sender, frames) = render::frame_channel();
+        emulation.send(Command::Pause(true)).unwrap();
+        spawn_looping(commands, frame_sender);
+
+        let (sender, counts) = mpsc::channel();
+        let first = sender.clone();
+        emulation
+            .send(Command::RunUntil(
+                0,
+                Box::new(move |cpu| first.send(cpu.bus.frames()).unwrap()),
+            ))
+            .unwrap();
+        let start = counts.recv_timeout(Duration::from_secs(5)).unwrap();
+        emulation
+            .send(Command::RunUntil(
+                start + 3,
+                Box::new(move |cpu| sender.send(cpu.bus.frames()).unwrap()),
+            ))
+            .unwrap();
+        assert_eq!(counts.recv_timeout(Duration::from_secs(5)), Ok(start + 3));
+
+        // Still paused afterwards
+        while frames.recv_timeout(Duration::from_millis(200)).is_ok() {}
+        assert!(frames.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn test_stops_without_frontend() {
+        // Once while running at full speed and once while paused
+        for paused in [false, true] {
+            let (emulation, commands) = mpsc::channel();
+            let (frame_sender, _frames) = render::frame_channel();
+            emulation.send(Command::Pause(paused)).unwrap();
+            let thread = spawn_looping(commands, frame_sender);
+            drop(emulation);
+            let start = Instant::now();
+            while !thread.is_finished() {
+                assert!(start.elapsed() < Duration::from_secs(5));
+                thread::sleep(Duration::from_millis(10));
+            }
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_frame_skip() {
+        let (emulation, commands) = mpsc::channel();
+        let (frame_sender, frames) = render::frame_channel();
+        emulation.send(Command::Pause(true)).unwrap();
+        emulation.send(Command::FrameSkip(2)).unwrap();
+        spawn_looping(commands, frame_sender);
+        let (sender, done) = mpsc::channel();
+        emulation
+            .send(Command::RunUntil(
+                10,
+                Box::new(move |_| sender.send(()).unwrap()),
+            ))
+            .unwrap();
+        done.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The first frame ran before the command, then every third frame is drawn
+        assert_eq!(frames.try_iter().count(), 4);
+    }
+
+    #[cfg(feature = "lua")]
+    #[test]
+    fn test_lua_script() {
+        let path = std::env::temp_dir().join("nes_rust_test.lua");
+        let script = "
+            while true do
+                memory.writebyte(0x10, emu.framecount())
+                gui.pixel(0, 0, 'red')
+                emu.frameadvance()
+            end";
+        fs::write(&path, script).unwrap();
+
+        let (emulation, commands) = mpsc::channel();
+        let (frame_sender, frames) = render::frame_channel();
+        emulation.send(Command::Pause(true)).unwrap();
+        emulation.send(Command::LuaScript(Some(path))).unwrap();
+        spawn_looping(commands, frame_sender);
+        let (sender, ram) = mpsc::channel();
+        emulation
+            .send(Command::RunUntil(
+                3,
+                Box::new(move |cpu| sender.send(cpu.bus.ram_mut()[0x10]).unwrap()),
+            ))
+            .unwrap();
+        // Written at the end of frame 3, before the command ran
+        assert_eq!(ram.recv_timeout(Duration::from_secs(5)), Ok(3));
+        for _ in 0..2 {
+            frames.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        let frame = frames.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(frame.get_pixel(0, 0), (0xff, 0x00, 0x00));
+    }
+
+    #[test]
+    fn test_crash_dump() {
+        // An opcode that is not implemented, right where the reset vector points
+        let mut program = vec![0x02];
+        program.resize(2 * 0x4000 - 4, 0);
+        program.extend([0x00, 0x80, 0x00, 0x00]);
+
+        let dir = std::env::temp_dir().join("nes_rust_test_crash");
+        let _ = fs::remove_dir_all(&dir);
+        let (_emulation, commands) = mpsc::channel();
+        let (frame_sender, _frames) = render::frame_channel();
+        let emulation = spawn(
+            test_rom(program),
+            RamInit::Zero,
+            None,
+            0,
+            dir.clone(),
+            commands,
+            frame_sender,
+        );
+        assert!(emulation.join().is_err());
+
+        let names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(names.iter().any(|name| name.ends_with(".state")));
+        assert!(names.iter().any(|name| name.ends_with(".log")));
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        // LDX #$00, then INX and JMP $8002 forever, with the reset vector pointing at it
+        let mut program = vec![0xa2, 0x00, 0xe8, 0x4c, 0x02, 0x80];
+        program.resize(2 * 0x4000 - 4, 0);
+        program.extend([0x00, 0x80, 0x00, 0x00]);
+
+        let (emulation, commands) = mpsc::channel();
+        let (frame_sender, _frames) = render::frame_channel();
+        spawn(
+            test_rom(program),
+            RamInit::Zero,
+            None,
+            0,
+            std::env::temp_dir(),
+            commands,
+            frame_sender,
+        );
+
+        let (listener, stops) = mpsc::channel();
+        emulation
+            .send(Command::Debug(DebugCommand::Attach(listener)))
+            .unwrap();
+        emulation
+            .send(Command::Debug(DebugCommand::SetBreakpoint(0x8003, None)))
+            .unwrap();
+        let stop = stops.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(stop.pc, 0x8003);
+        assert_eq!(stop.instruction.text, "JMP $8002");
+
+        emulation
+            .send(Command::Debug(DebugCommand::ClearBreakpoint(0x8003)))
+            .unwrap();
+        emulation.send(Command::Debug(DebugCommand::Step)).unwrap();
+        let step = stops.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(step.pc, 0x8002);
+        assert_eq!(step.x, stop.x);
+
+        emulation.send(Command::Debug(DebugCommand::Step)).unwrap();
+        let step = stops.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(step.pc, 0x8003);
+        assert_eq!(step.x, stop.x.wrapping_add(1));
+    }
+
+    #[test]
+    fn test_rewind_lasts_as_long_in_both_regions() {
+        let capacity = |region| {
+            let mut rewind = rewind_buffer(10, region);
+            for frame in 0..1000u32 {
+                rewind.push(frame.to_le_bytes().to_vec());
+            }
+            rewind.len()
+        };
+        assert_eq!(capacity(Region::Ntsc), 300);
+        assert_eq!(capacity(Region::Pal), 250);
+    }
+}
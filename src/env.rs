@@ -0,0 +1,237 @@
+use crate::bus::{Bus, RamInit};
+use crate::cartridge::Rom;
+use crate::condition::Condition;
+use crate::cpu::CPU;
+use crate::memory::MemoryRegion;
+use crate::render::{self, PixelFormat};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Frames an action is held for unless configured otherwise, as is common for Atari agents.
+pub const DEFAULT_FRAME_SKIP: u32 = 4;
+
+/// What an observation holds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObservationKind {
+    /// The 2K of CPU RAM.
+    Ram,
+    /// Bytes at chosen addresses of the CPU address space, e.g. the player position and lives.
+    Addresses(Vec<u16>),
+    /// The last frame as 256x240 pixels in `EnvConfig::screen_format`, which costs rendering
+    /// every frame.
+    Screen,
+}
+
+#[derive(Debug, Clone)]
+pub struct EnvConfig {
+    pub frame_skip: u32,
+    pub observation: ObservationKind,
+    pub screen_format: PixelFormat,
+    /// Ends the episode when it holds after a step, e.g. `[$0075] == 0` once the lives run out.
+    pub done: Option<Condition>,
+    /// Ends the episode after this many frames.
+    pub max_frames: Option<u64>,
+    /// Up to this many frames without input after a reset, a random number of them, so an
+    /// agent does not just learn one sequence of inputs.
+    pub max_start_noops: u32,
+    /// Seeds the RAM contents at power on and the start no-ops, the same seed replays the same
+    /// episodes.
+    pub seed: u64,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        EnvConfig {
+            frame_skip: DEFAULT_FRAME_SKIP,
+            observation: ObservationKind::Ram,
+            screen_format: PixelFormat::Rgb24,
+            done: None,
+            max_frames: None,
+            max_start_noops: 0,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Observation {
+    pub data: Vec<u8>,
+    /// Frames since the episode started.
+    pub frame: u64,
+}
+
+/// Runs a game as a reinforcement learning environment, an action is the controller bits
+/// held during a step.
+pub struct Env {
+    cpu: CPU<'static>,
+    screen: Rc<RefCell<Vec<u8>>>,
+    config: EnvConfig,
+    rng: StdRng,
+    episode_start: u64,
+}
+
+impl Env {
+    pub fn new(rom: Rom, config: EnvConfig) -> Self {
+        let format = config.screen_format;
+        let screen = Rc::new(RefCell::new(vec![0; format.frame_size()]));
+        let bus_screen = Rc::clone(&screen);
+        let observe_screen = config.observation == ObservationKind::Screen;
+        let bus = Bus::new(rom, move |ppu, _| {
+            if observe_screen {
+                render::render_into(ppu, format, &mut bus_screen.borrow_mut());
+            }
+        });
+        Env {
+            cpu: CPU::new(bus),
+            screen,
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            episode_start: 0,
+        }
+    }
+
+    /// Restarts the episode sequence as if the environment was created with `seed`.
+    pub fn seed(&mut self, seed: u64) {
+        self.config.seed = seed;
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Power cycles into a new episode.
+    pub fn reset(&mut self) -> Observation {
+        self.cpu.bus.set_ram_init(RamInit::Random(self.rng.gen()));
+        self.cpu.power_cycle();
+        let noops = match self.config.max_start_noops {
+            0 => 0,
+            max => self.rng.gen_range(0, max + 1),
+        };
+        for _ in 0..noops {
+            self.run_frame();
+        }
+        self.episode_start = self.cpu.bus.frames();
+        self.observe()
+    }
+
+    /// Holds `action` for `frame_skip` frames, returns what happened and whether the episode
+    /// is over.
+    pub fn step(&mut self, action: u8) -> (Observation, bool) {
+        self.cpu.bus.joypad_mut().set_buttons(action);
+        for _ in 0..self.config.frame_skip.max(1) {
+            self.run_frame();
+        }
+        let observation = self.observe();
+        let done = self
+            .config
+            .done
+            .as_ref()
+            .is_some_and(|done| done.holds(&mut self.cpu))
+            || self
+                .config
+                .max_frames
+                .is_some_and(|max_frames| observation.frame >= max_frames);
+        (observation, done)
+    }
+
+    /// Reads a byte without side effects, e.g. the score to compute a reward from.
+    pub fn read(&mut self, address: u16) -> u8 {
+        MemoryRegion::Cpu.peek(&mut self.cpu, address).unwrap_or(0)
+    }
+
+    /// The machine, for anything the environment does not cover.
+    pub fn cpu(&mut self) -> &mut CPU<'static> {
+        &mut self.cpu
+    }
+
+    fn run_frame(&mut self) {
+        let end = self.cpu.bus.frames() + 1;
+        while self.cpu.bus.frames() < end {
+            self.cpu.step();
+        }
+    }
+
+    fn observe(&mut self) -> Observation {
+        let data = match &self.config.observation {
+            ObservationKind::Ram => self.cpu.bus.ram_mut().to_vec(),
+            ObservationKind::Addresses(addresses) => addresses
+                .iter()
+                .map(|&address| MemoryRegion::Cpu.peek(&mut self.cpu, address).unwrap_or(0))
+                .collect(),
+            ObservationKind::Screen => self.screen.borrow().clone(),
+        };
+        Observation {
+            data,
+            frame: self.cpu.bus.frames() - self.episode_start,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::joypad::{JOYPAD_A, JOYPAD_START};
+
+    /// Copies the controller bits to $10 forever.
+    fn joypad_rom() -> Rom {
+        let mut program = vec![
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, // strobe
+            0xa2, 0x08, // LDX #8
+            0xad, 0x16, 0x40, 0x4a, 0x26, 0x11, // LDA $4016, LSR A, ROL $11
+            0xca, 0xd0, 0xf7, // DEX, BNE
+            0xa5, 0x11, 0x85, 0x10, 0x4c, 0x00, 0x80, // LDA $11, STA $10, JMP $8000
+        ];
+        program.resize(2 * 0x4000 - 4, 0);
+        program.extend([0x00, 0x80, 0x00, 0x00]);
+        test_rom(program)
+    }
+
+    #[test]
+    fn test_step() {
+        let mut env = Env::new(
+            joypad_rom(),
+            EnvConfig {
+                observation: ObservationKind::Addresses(vec![0x10]),
+                done: Some(Condition::parse("[$10] == $10").unwrap()),
+                max_frames: Some(12),
+                ..EnvConfig::default()
+            },
+        );
+        assert_eq!(env.reset().frame, 0);
+
+        // ROL puts the first button read, A, in the top bit
+        let (observation, done) = env.step(JOYPAD_A);
+        assert_eq!(observation.data, [0x80]);
+        assert_eq!(observation.frame, 4);
+        assert!(!done);
+        assert_eq!(env.read(0x10), 0x80);
+
+        let (observation, done) = env.step(JOYPAD_START);
+        assert_eq!(observation.data, [0x10]);
+        assert!(done);
+
+        // Out of frames
+        assert!(env.step(0).1);
+    }
+
+    #[test]
+    fn test_seeding() {
+        let config = EnvConfig {
+            max_start_noops: 30,
+            seed: 7,
+            ..EnvConfig::default()
+        };
+        let mut env = Env::new(joypad_rom(), config.clone());
+        let mut same = Env::new(joypad_rom(), config.clone());
+        let first = env.reset();
+        assert_eq!(first, same.reset());
+        assert_eq!(first.data.len(), 0x800);
+        // The next episode starts from other RAM
+        assert_ne!(env.reset(), first);
+
+        env.seed(7);
+        assert_eq!(env.reset(), first);
+        let mut other = Env::new(joypad_rom(), EnvConfig { seed: 8, ..config });
+        assert_ne!(other.reset(), first);
+    }
+}
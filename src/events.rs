@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+/// Events kept, the oldest are dropped first. About ten minutes of frames.
+pub const EVENT_LOG_CAPACITY: usize = 40_000;
+
+/// Something that happened to the emulator, as opposed to inside the game.
+///
+/// There is no APU or IRQ yet, so audio underruns and IRQ storms are not recorded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    /// Time it took to emulate a frame, without waiting for the next one to be due.
+    Frame(Duration),
+    /// Emulating the frame took longer than a frame lasts at the target rate, so it showed
+    /// late. Holds the target frame time.
+    DroppedFrame(Duration),
+    StateLoad,
+    Reset,
+    PowerCycle,
+    RomLoad,
+}
+
+impl EventKind {
+    /// Name used in queries and in the CSV export.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Frame(_) => "frame",
+            EventKind::DroppedFrame(_) => "dropped_frame",
+            EventKind::StateLoad => "state_load",
+            EventKind::Reset => "reset",
+            EventKind::PowerCycle => "power_cycle",
+            EventKind::RomLoad => "rom_load",
+        }
+    }
+
+    /// Duration that comes with the event, if any.
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            EventKind::Frame(duration) | EventKind::DroppedFrame(duration) => Some(*duration),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+    /// Time since the log started.
+    pub time: Duration,
+    /// Frame count of the machine, which restarts at 0 on a power cycle.
+    pub frame: u64,
+    pub kind: EventKind,
+}
+
+/// Frame timings over the events in the log.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EventSummary {
+    pub frames: usize,
+    pub dropped_frames: usize,
+    pub average_frame_time: Duration,
+    pub worst_frame_time: Duration,
+    pub state_loads: usize,
+}
+
+impl fmt::Display for EventSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} frames, {} dropped, {:.2} ms average, {:.2} ms worst, {} state loads",
+            self.frames,
+            self.dropped_frames,
+            self.average_frame_time.as_secs_f64() * 1000.0,
+            self.worst_frame_time.as_secs_f64() * 1000.0,
+            self.state_loads
+        )
+    }
+}
+
+/// Recent emulator events in memory, to find out why a game runs slowly on someone's machine.
+#[derive(Debug, Clone)]
+pub struct EventLog {
+    events: VecDeque<Event>,
+    capacity: usize,
+    start: Instant,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self::new(EVENT_LOG_CAPACITY)
+    }
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        EventLog {
+            events: VecDeque::with_capacity(capacity.min(EVENT_LOG_CAPACITY)),
+            capacity,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, frame: u64, kind: EventKind) {
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(Event {
+            time: self.start.elapsed(),
+            frame,
+            kind,
+        });
+    }
+
+    /// Every event kept, oldest first.
+    pub fn events(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter()
+    }
+
+    /// Events with the given name, e.g. `dropped_frame`.
+    pub fn named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Event> {
+        self.events
+            .iter()
+            .filter(move |event| event.kind.name() == name)
+    }
+
+    /// Events that happened within the last `duration`.
+    pub fn last(&self, duration: Duration) -> impl Iterator<Item = &Event> {
+        let since = self.start.elapsed().saturating_sub(duration);
+        self.events.iter().filter(move |event| event.time >= since)
+    }
+
+    pub fn summary(&self) -> EventSummary {
+        let mut summary = EventSummary::default();
+        let mut total = Duration::ZERO;
+        for event in &self.events {
+            match event.kind {
+                EventKind::Frame(duration) => {
+                    summary.frames += 1;
+                    total += duration;
+                    summary.worst_frame_time = summary.worst_frame_time.max(duration);
+                }
+                EventKind::DroppedFrame(_) => summary.dropped_frames += 1,
+                EventKind::StateLoad => summary.state_loads += 1,
+                _ => {}
+            }
+        }
+        if summary.frames > 0 {
+            summary.average_frame_time = total / summary.frames as u32;
+        }
+        summary
+    }
+
+    /// One line per event with the time in seconds and the duration in milliseconds.
+    pub fn to_csv(&self) -> String {
+        let mut csv = "time,frame,event,duration_ms\n".to_string();
+        for event in &self.events {
+            let duration = event
+                .kind
+                .duration()
+                .map(|duration| format!("{:.3}", duration.as_secs_f64() * 1000.0))
+                .unwrap_or_default();
+            let _ = writeln!(
+                csv,
+                "{:.6},{},{},{}",
+                event.time.as_secs_f64(),
+                event.frame,
+                event.kind.name(),
+                duration
+            );
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event_log() {
+        let mut log = EventLog::new(3);
+        log.record(1, EventKind::Frame(Duration::from_millis(4)));
+        log.record(2, EventKind::Frame(Duration::from_millis(20)));
+        log.record(2, EventKind::DroppedFrame(Duration::from_millis(16)));
+        log.record(0, EventKind::StateLoad);
+
+        // The first frame was dropped to make room
+        assert_eq!(log.events().count(), 3);
+        assert_eq!(log.named("frame").count(), 1);
+        assert_eq!(log.last(Duration::from_secs(60)).count(), 3);
+        assert_eq!(
+            log.summary(),
+            EventSummary {
+                frames: 1,
+                dropped_frames: 1,
+                average_frame_time: Duration::from_millis(20),
+                worst_frame_time: Duration::from_millis(20),
+                state_loads: 1,
+            }
+        );
+
+        let csv = log.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "time,frame,event,duration_ms");
+        assert!(lines[2].ends_with(",2,dropped_frame,16.000"));
+        assert!(lines[3].ends_with(",0,state_load,"));
+    }
+}
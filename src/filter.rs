@@ -0,0 +1,454 @@
+use crate::render::Frame;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+/// Filtered frames are twice the size of a frame in both directions.
+pub const FILTERED_WIDTH: usize = 512;
+pub const FILTERED_HEIGHT: usize = 480;
+
+/// Frames handed to the worker that haven't come back yet, more are dropped so a slow filter
+/// falls behind by frames rather than by a growing queue.
+const MAX_IN_FLIGHT: usize = 2;
+
+/// Brightness of the dark lines of the scanline filter.
+const SCANLINE_BRIGHTNESS: f32 = 0.6;
+
+/// How far a pixel lights up towards brighter neighbours with phosphor glow.
+const GLOW_STRENGTH: f32 = 0.35;
+
+/// How strongly the picture bulges with curvature, the corners move in by about this much.
+const CURVATURE: f32 = 0.04;
+
+/// Post-processing of the frames before they are shown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoFilter {
+    None,
+    /// Doubles the size and smooths diagonal edges, see `scale2x`.
+    Scale2x,
+    /// Doubles the size and blends along edges of any color, see `xbr`.
+    Xbr,
+    /// Doubles the size and darkens every second line like a CRT.
+    Scanlines,
+    /// Doubles the size and lets bright pixels bleed into darker neighbours.
+    Glow,
+    /// Doubles the size and bends the picture like the curved glass of a CRT.
+    Curvature,
+    /// Scanlines, glow and curvature together.
+    Crt,
+}
+
+/// The effects of the CRT filters, see `crt`.
+#[derive(Debug, Clone, Copy, Default)]
+struct CrtEffects {
+    scanlines: bool,
+    glow: bool,
+    curvature: bool,
+}
+
+impl VideoFilter {
+    pub fn name(self) -> &'static str {
+        match self {
+            VideoFilter::None => "none",
+            VideoFilter::Scale2x => "scale2x",
+            VideoFilter::Xbr => "2xbr",
+            VideoFilter::Scanlines => "scanlines",
+            VideoFilter::Glow => "glow",
+            VideoFilter::Curvature => "curvature",
+            VideoFilter::Crt => "crt",
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<Self> {
+        [
+            VideoFilter::None,
+            VideoFilter::Scale2x,
+            VideoFilter::Xbr,
+            VideoFilter::Scanlines,
+            VideoFilter::Glow,
+            VideoFilter::Curvature,
+            VideoFilter::Crt,
+        ]
+        .into_iter()
+        .find(|filter| filter.name() == text)
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            VideoFilter::None => VideoFilter::Scale2x,
+            VideoFilter::Scale2x => VideoFilter::Xbr,
+            VideoFilter::Xbr => VideoFilter::Scanlines,
+            VideoFilter::Scanlines => VideoFilter::Glow,
+            VideoFilter::Glow => VideoFilter::Curvature,
+            VideoFilter::Curvature => VideoFilter::Crt,
+            VideoFilter::Crt => VideoFilter::None,
+        }
+    }
+
+    /// Filters the frame into `output`, `FILTERED_WIDTH` by `FILTERED_HEIGHT` RGB.
+    pub fn apply(self, frame: &Frame, output: &mut [u8]) {
+        let effects = match self {
+            VideoFilter::None => CrtEffects::default(),
+            VideoFilter::Scale2x => return scale2x(frame, output),
+            VideoFilter::Xbr => return xbr(frame, output),
+            VideoFilter::Scanlines => CrtEffects {
+                scanlines: true,
+                ..CrtEffects::default()
+            },
+            VideoFilter::Glow => CrtEffects {
+                glow: true,
+                ..CrtEffects::default()
+            },
+            VideoFilter::Curvature => CrtEffects {
+                curvature: true,
+                ..CrtEffects::default()
+            },
+            VideoFilter::Crt => CrtEffects {
+                scanlines: true,
+                glow: true,
+                curvature: true,
+            },
+        };
+        crt(frame, output, effects)
+    }
+}
+
+fn set_pixel(output: &mut [u8], x: usize, y: usize, rgb: (u8, u8, u8)) {
+    let index = (y * FILTERED_WIDTH + x) * 3;
+    output[index..index + 3].copy_from_slice(&[rgb.0, rgb.1, rgb.2]);
+}
+
+/// The pixel lit up towards its brighter neighbours, per channel.
+fn glow(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+    let (width, height) = (FILTERED_WIDTH / 2, FILTERED_HEIGHT / 2);
+    let neighbours = [
+        frame.get_pixel(x.saturating_sub(1), y),
+        frame.get_pixel((x + 1).min(width - 1), y),
+        frame.get_pixel(x, y.saturating_sub(1)),
+        frame.get_pixel(x, (y + 1).min(height - 1)),
+    ];
+    let channel = |value: u8, channel: fn(&(u8, u8, u8)) -> u8| {
+        let average = neighbours
+            .iter()
+            .map(|rgb| channel(rgb) as f32)
+            .sum::<f32>()
+            / 4.0;
+        let value = value as f32;
+        (value + (average - value).max(0.0) * GLOW_STRENGTH) as u8
+    };
+    let (r, g, b) = frame.get_pixel(x, y);
+    (
+        channel(r, |rgb| rgb.0),
+        channel(g, |rgb| rgb.1),
+        channel(b, |rgb| rgb.2),
+    )
+}
+
+/// Where a filtered pixel comes from when the picture bulges outwards, `None` past the edges.
+fn curve(x: usize, y: usize) -> Option<(usize, usize)> {
+    let (width, height) = ((FILTERED_WIDTH - 1) as f32, (FILTERED_HEIGHT - 1) as f32);
+    let (cx, cy) = (x as f32 / width * 2.0 - 1.0, y as f32 / height * 2.0 - 1.0);
+    let (sx, sy) = (
+        cx * (1.0 + CURVATURE * cy * cy),
+        cy * (1.0 + CURVATURE * cx * cx),
+    );
+    if sx.abs() > 1.0 || sy.abs() > 1.0 {
+        return None;
+    }
+    Some((
+        ((sx + 1.0) / 2.0 * width).round() as usize,
+        ((sy + 1.0) / 2.0 * height).round() as usize,
+    ))
+}
+
+/// Every pixel as 2x2 pixels with the chosen effects, without any it's a plain doubling.
+fn crt(frame: &Frame, output: &mut [u8], effects: CrtEffects) {
+    let dim = |value: u8| (value as f32 * SCANLINE_BRIGHTNESS) as u8;
+    for y in 0..FILTERED_HEIGHT {
+        for x in 0..FILTERED_WIDTH {
+            let source = if effects.curvature {
+                curve(x, y)
+            } else {
+                Some((x, y))
+            };
+            let rgb = match source {
+                Some((x, y)) => {
+                    let (r, g, b) = if effects.glow {
+                        glow(frame, x / 2, y / 2)
+                    } else {
+                        frame.get_pixel(x / 2, y / 2)
+                    };
+                    if effects.scanlines && y % 2 == 1 {
+                        (dim(r), dim(g), dim(b))
+                    } else {
+                        (r, g, b)
+                    }
+                }
+                None => (0, 0, 0),
+            };
+            set_pixel(output, x, y, rgb);
+        }
+    }
+}
+
+/// The Scale2x pixel art scaler: a corner takes the color of its two neighbours when they agree
+/// and the other two neighbours differ.
+fn scale2x(frame: &Frame, output: &mut [u8]) {
+    let (width, height) = (FILTERED_WIDTH / 2, FILTERED_HEIGHT / 2);
+    for y in 0..height {
+        for x in 0..width {
+            let center = frame.get_pixel(x, y);
+            let up = frame.get_pixel(x, y.saturating_sub(1));
+            let down = frame.get_pixel(x, (y + 1).min(height - 1));
+            let left = frame.get_pixel(x.saturating_sub(1), y);
+            let right = frame.get_pixel((x + 1).min(width - 1), y);
+
+            let corners = if up != down && left != right {
+                [
+                    if left == up { left } else { center },
+                    if up == right { right } else { center },
+                    if down == left { left } else { center },
+                    if right == down { right } else { center },
+                ]
+            } else {
+                [center; 4]
+            };
+            set_pixel(output, x * 2, y * 2, corners[0]);
+            set_pixel(output, x * 2 + 1, y * 2, corners[1]);
+            set_pixel(output, x * 2, y * 2 + 1, corners[2]);
+            set_pixel(output, x * 2 + 1, y * 2 + 1, corners[3]);
+        }
+    }
+}
+
+/// How different two colors look, weighing brightness over hue like xBR does.
+fn color_distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> f32 {
+    let (r, g, b) = (
+        a.0 as f32 - b.0 as f32,
+        a.1 as f32 - b.1 as f32,
+        a.2 as f32 - b.2 as f32,
+    );
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let u = -0.169 * r - 0.331 * g + 0.5 * b;
+    let v = 0.5 * r - 0.419 * g - 0.081 * b;
+    48.0 * y.abs() + 7.0 * u.abs() + 6.0 * v.abs()
+}
+
+fn blend(a: (u8, u8, u8), b: (u8, u8, u8)) -> (u8, u8, u8) {
+    let mix = |a: u8, b: u8| ((a as u16 + b as u16) / 2) as u8;
+    (mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2))
+}
+
+/// The 2xBR scaler, level 1 of xBR. Each corner of a pixel looks at the 5x5 pixels around it
+/// and blends towards its neighbours when an edge runs diagonally past the corner.
+fn xbr(frame: &Frame, output: &mut [u8]) {
+    let (width, height) = (FILTERED_WIDTH / 2, FILTERED_HEIGHT / 2);
+    for y in 0..height {
+        for x in 0..width {
+            let center = frame.get_pixel(x, y);
+            // The bottom right corner with offsets rotated by a quarter turn for each corner
+            for (rotate_x, rotate_y) in [(1, 0), (0, 1), (-1, 0), (0, -1)] {
+                let pixel = |dx: isize, dy: isize| {
+                    let (dx, dy) = (dx * rotate_x - dy * rotate_y, dx * rotate_y + dy * rotate_x);
+                    let x = (x as isize + dx).clamp(0, width as isize - 1);
+                    let y = (y as isize + dy).clamp(0, height as isize - 1);
+                    frame.get_pixel(x as usize, y as usize)
+                };
+                let (b, c, d, f, g, h, i) = (
+                    pixel(0, -1),
+                    pixel(1, -1),
+                    pixel(-1, 0),
+                    pixel(1, 0),
+                    pixel(-1, 1),
+                    pixel(0, 1),
+                    pixel(1, 1),
+                );
+                let (f4, i4, h5, i5) = (pixel(2, 0), pixel(2, 1), pixel(0, 2), pixel(1, 2));
+
+                let across = color_distance(center, c)
+                    + color_distance(center, g)
+                    + color_distance(i, f4)
+                    + color_distance(i, h5)
+                    + 4.0 * color_distance(h, f);
+                let along = color_distance(h, d)
+                    + color_distance(h, i5)
+                    + color_distance(f, i4)
+                    + color_distance(f, b)
+                    + 4.0 * color_distance(center, i);
+                // Keeps the corners of lone pixels and thin lines
+                let edge = (f != b && h != d)
+                    || (center == i && f != i4 && h != i5)
+                    || center == g
+                    || center == c;
+                let rgb = if across < along && edge {
+                    let closer = if color_distance(center, f) <= color_distance(center, h) {
+                        f
+                    } else {
+                        h
+                    };
+                    blend(center, closer)
+                } else {
+                    center
+                };
+
+                // Where the rotated bottom right corner ends up
+                let corner_x = (1 + rotate_x - rotate_y) as usize / 2;
+                let corner_y = (1 + rotate_y + rotate_x) as usize / 2;
+                set_pixel(output, x * 2 + corner_x, y * 2 + corner_y, rgb);
+            }
+        }
+    }
+}
+
+type Job = (VideoFilter, Box<Frame>, Vec<u8>);
+
+/// Runs a filter on its own thread, one frame behind the frames handed to it, so an expensive
+/// filter doesn't slow down presenting.
+pub struct FilterWorker {
+    jobs: Sender<Job>,
+    done: Receiver<(Box<Frame>, Vec<u8>)>,
+    spare_frames: Vec<Box<Frame>>,
+    spare_outputs: Vec<Vec<u8>>,
+    in_flight: usize,
+    latest: Option<Vec<u8>>,
+}
+
+impl Default for FilterWorker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FilterWorker {
+    pub fn new() -> Self {
+        let (jobs, received) = mpsc::channel::<Job>();
+        let (finished, done) = mpsc::channel();
+        thread::Builder::new()
+            .name("video filter".to_string())
+            .spawn(move || {
+                for (filter, frame, mut output) in received {
+                    filter.apply(&frame, &mut output);
+                    if finished.send((frame, output)).is_err() {
+                        break;
+                    }
+                }
+            })
+            .unwrap();
+        FilterWorker {
+            jobs,
+            done,
+            spare_frames: vec![],
+            spare_outputs: vec![],
+            in_flight: 0,
+            latest: None,
+        }
+    }
+
+    /// Queues a copy of the frame, dropped when the worker is still busy with earlier frames.
+    pub fn submit(&mut self, filter: VideoFilter, frame: &Frame) {
+        self.collect();
+        if self.in_flight >= MAX_IN_FLIGHT {
+            return;
+        }
+        let mut copy = self
+            .spare_frames
+            .pop()
+            .unwrap_or_else(|| Box::new(Frame::new()));
+        copy.data = frame.data;
+        let output = self
+            .spare_outputs
+            .pop()
+            .unwrap_or_else(|| vec![0; FILTERED_WIDTH * FILTERED_HEIGHT * 3]);
+        if self.jobs.send((filter, copy, output)).is_ok() {
+            self.in_flight += 1;
+        }
+    }
+
+    /// The most recently filtered frame, `None` until the first one is done.
+    pub fn latest(&mut self) -> Option<&[u8]> {
+        self.collect();
+        self.latest.as_deref()
+    }
+
+    fn collect(&mut self) {
+        for (frame, output) in self.done.try_iter() {
+            self.in_flight -= 1;
+            self.spare_frames.push(frame);
+            if let Some(older) = self.latest.replace(output) {
+                self.spare_outputs.push(older);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn filtered_pixel(output: &[u8], x: usize, y: usize) -> (u8, u8, u8) {
+        let index = (y * FILTERED_WIDTH + x) * 3;
+        (output[index], output[index + 1], output[index + 2])
+    }
+
+    #[test]
+    fn test_filters() {
+        let white = (0xff, 0xff, 0xff);
+        let mut frame = Frame::new();
+        // A diagonal step, the corner between the two white pixels gets filled in
+        frame.set_pixel(10, 10, white);
+        frame.set_pixel(11, 11, white);
+        frame.set_pixel(10, 11, (0, 0, 0));
+        let mut output = vec![0; FILTERED_WIDTH * FILTERED_HEIGHT * 3];
+
+        VideoFilter::Scale2x.apply(&frame, &mut output);
+        assert_eq!(filtered_pixel(&output, 20, 20), white);
+        assert_eq!(filtered_pixel(&output, 21, 22), white);
+        assert_eq!(filtered_pixel(&output, 20, 22), (0, 0, 0));
+
+        // Half way between the black pixel and the edge it touches
+        VideoFilter::Xbr.apply(&frame, &mut output);
+        assert_eq!(filtered_pixel(&output, 20, 20), white);
+        assert_eq!(filtered_pixel(&output, 21, 22), (0x7f, 0x7f, 0x7f));
+        assert_eq!(filtered_pixel(&output, 20, 23), (0, 0, 0));
+        assert_eq!(filtered_pixel(&output, 40, 40), (0, 0, 0));
+
+        VideoFilter::Scanlines.apply(&frame, &mut output);
+        assert_eq!(filtered_pixel(&output, 21, 20), white);
+        assert_eq!(filtered_pixel(&output, 21, 21), (0x99, 0x99, 0x99));
+        assert_eq!(filtered_pixel(&output, 20, 22), (0, 0, 0));
+
+        // Dark neighbours of a bright pixel light up, the bright pixel stays as it is
+        VideoFilter::Glow.apply(&frame, &mut output);
+        assert_eq!(filtered_pixel(&output, 20, 20), white);
+        assert_eq!(filtered_pixel(&output, 22, 20), (0x2c, 0x2c, 0x2c));
+        assert_eq!(filtered_pixel(&output, 40, 40), (0, 0, 0));
+
+        // The corners fall outside the bulging picture, the middle stays in place
+        frame.set_pixel(128, 120, white);
+        VideoFilter::Curvature.apply(&frame, &mut output);
+        assert_eq!(filtered_pixel(&output, 0, 0), (0, 0, 0));
+        assert_eq!(filtered_pixel(&output, 256, 240), white);
+
+        assert_eq!(VideoFilter::parse("scale2x"), Some(VideoFilter::Scale2x));
+        assert_eq!(VideoFilter::parse("crt"), Some(VideoFilter::Crt));
+        assert_eq!(VideoFilter::parse("hq4x"), None);
+    }
+
+    #[test]
+    fn test_worker() {
+        let mut worker = FilterWorker::new();
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, (1, 2, 3));
+        assert!(worker.latest().is_none());
+        worker.submit(VideoFilter::None, &frame);
+
+        let start = Instant::now();
+        while worker.latest().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+        let output = worker.latest().unwrap();
+        assert_eq!(filtered_pixel(output, 1, 1), (1, 2, 3));
+        assert_eq!(filtered_pixel(output, 2, 0), (0, 0, 0));
+    }
+}
@@ -0,0 +1,638 @@
+use crate::achievements::AchievementSet;
+use crate::cartridge::{Region, Rom};
+use crate::cheats::CheatList;
+use crate::clip::ClipBuffer;
+use crate::config::Config;
+use crate::coverage;
+use crate::emulation::Command;
+use crate::labels::Labels;
+use crate::netplay::{NetplaySession, DEFAULT_INPUT_DELAY};
+use crate::osd::Osd;
+use crate::recorder::Recorder;
+use crate::region::{self, RomDatabase, DATABASE_PATH};
+use crate::render::Frame;
+use crate::statehash::HashLog;
+use crate::trace::{TraceFormat, Tracer};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the unix epoch, used to give output files unique names.
+pub fn timestamp() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or(0)
+}
+
+/// Reports a message both on stdout and on screen.
+pub fn notify(osd: &mut Osd, message: String) {
+    println!("{}", message);
+    osd.push(message);
+}
+
+pub fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+pub fn save_screenshot(frame: &Frame, dir: &Path, osd: &mut Osd) {
+    let path = dir.join(format!("screenshot-{}.png", timestamp()));
+    match fs::create_dir_all(dir).and_then(|_| frame.save_png(&path)) {
+        Ok(()) => notify(osd, format!("Saved {}", file_name(&path))),
+        Err(error) => notify(osd, format!("Screenshot failed: {}", error)),
+    }
+}
+
+/// Starts a new recording, or finishes the running one.
+pub fn toggle_recording(recorder: &mut Option<Recorder>, config: &Config, osd: &mut Osd) {
+    match recorder.take() {
+        Some(running) => {
+            let path = running.path().to_path_buf();
+            match running.finish() {
+                Ok(frames) => notify(
+                    osd,
+                    format!(
+                        "Recording stopped, {} frames in {}",
+                        frames,
+                        file_name(&path)
+                    ),
+                ),
+                Err(error) => notify(osd, format!("Recording failed: {}", error)),
+            }
+        }
+        None => {
+            let path = config.recording_dir.join(format!(
+                "recording-{}.{}",
+                timestamp(),
+                config.recording_format.extension()
+            ));
+            let started = fs::create_dir_all(&config.recording_dir).and_then(|_| {
+                Recorder::start(config.recording_format, &path, region::NTSC_FRAME_RATE)
+            });
+            match started {
+                Ok(started) => {
+                    notify(osd, "Recording started".to_string());
+                    *recorder = Some(started);
+                }
+                Err(error) => notify(osd, format!("Recording failed: {}", error)),
+            }
+        }
+    }
+}
+
+pub fn save_clip(clip: &ClipBuffer, dir: &Path, osd: &mut Osd) {
+    if clip.is_empty() {
+        notify(osd, "No frames to save as GIF".to_string());
+        return;
+    }
+
+    let path = dir.join(format!("clip-{}.gif", timestamp()));
+    match fs::create_dir_all(dir).and_then(|_| clip.save_gif(&path)) {
+        Ok(()) => notify(osd, format!("Saved {}", file_name(&path))),
+        Err(error) => notify(osd, format!("GIF failed: {}", error)),
+    }
+}
+
+/// Reads a ROM and picks its region, `--region ntsc|pal` on the command line overrides the
+/// detection.
+pub fn open_rom(path: &Path) -> Result<Rom, String> {
+    let bytes = fs::read(path).map_err(|error| error.to_string())?;
+    let mut rom = Rom::new(&bytes)?;
+    let args: Vec<String> = std::env::args().collect();
+    let region_flag = args
+        .iter()
+        .position(|arg| arg == "--region")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|region| Region::parse(region));
+    let (region, source) = match region_flag {
+        Some(region) => (region, "command line"),
+        None => {
+            let database = RomDatabase::load(Path::new(DATABASE_PATH)).unwrap_or_else(|error| {
+                println!("Reading {} failed: {}", DATABASE_PATH, error);
+                RomDatabase::default()
+            });
+            region::detect(&bytes, rom.crc, path, &database)
+        }
+    };
+    log::info!("{} timing from the {}", region.name(), source);
+    rom.region = region;
+    Ok(rom)
+}
+
+pub fn window_title(path: &Path, rom: &Rom) -> String {
+    format!(
+        "{} - Mapper {} - {}",
+        game_name(path),
+        rom.mapper_id,
+        rom.region.name()
+    )
+}
+
+/// Name shown in the window title, taken from the ROM file name.
+pub fn game_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "Unknown".to_string())
+}
+
+/// Number of save state slots, selected with the number keys.
+pub const STATE_SLOTS: u8 = 10;
+
+/// States are named after the ROM CRC, so renaming a ROM keeps its states.
+pub fn state_path(dir: &Path, crc: u32, slot: u8) -> PathBuf {
+    dir.join(format!("{:08X}.{}.state", crc, slot))
+}
+
+/// Saves the state on the emulation thread, the outcome is sent to `notices` for the OSD.
+pub fn save_state_slot(
+    emulation: &Sender<Command>,
+    notices: &Sender<String>,
+    dir: &Path,
+    crc: u32,
+    slot: u8,
+) {
+    let dir = dir.to_path_buf();
+    let notices = notices.clone();
+    emulation
+        .send(Command::Inspect(Box::new(move |cpu| {
+            let path = state_path(&dir, crc, slot);
+            let message = match fs::create_dir_all(&dir).and_then(|_| cpu.save_state(&path)) {
+                Ok(()) => format!("Saved state {}", slot),
+                Err(error) => format!("Saving state {} failed: {}", slot, error),
+            };
+            let _ = notices.send(message);
+        })))
+        .unwrap();
+}
+
+/// Loads the state on the emulation thread, states of other games are refused.
+pub fn load_state_slot(
+    emulation: &Sender<Command>,
+    notices: &Sender<String>,
+    dir: &Path,
+    crc: u32,
+    slot: u8,
+) {
+    let path = state_path(dir, crc, slot);
+    load_state_file(emulation, notices, path, format!("state {}", slot));
+}
+
+/// The state saved on quit, so a game can be resumed where it was left.
+pub fn autosave_path(dir: &Path, crc: u32) -> PathBuf {
+    dir.join(format!("{:08X}.auto.state", crc))
+}
+
+/// Saves the autosave and waits for it to be written, used right before quitting.
+pub fn save_autosave(emulation: &Sender<Command>, dir: &Path, crc: u32) -> io::Result<()> {
+    let path = autosave_path(dir, crc);
+    let dir = dir.to_path_buf();
+    let (sender, result) = mpsc::channel();
+    emulation
+        .send(Command::Inspect(Box::new(move |cpu| {
+            let _ = sender.send(fs::create_dir_all(&dir).and_then(|_| cpu.save_state(&path)));
+        })))
+        .map_err(|_| io::Error::other("emulation stopped"))?;
+    result
+        .recv_timeout(Duration::from_secs(2))
+        .map_err(|_| io::Error::other("emulation did not respond"))?
+}
+
+/// Tells the user about an autosave of the game that was just loaded.
+pub fn offer_resume(config: &Config, crc: u32, osd: &mut Osd) {
+    if config.autosave && autosave_path(&config.state_dir, crc).exists() {
+        osd.push("Press F4 to resume where you left off");
+    }
+}
+
+pub fn resume_autosave(
+    emulation: &Sender<Command>,
+    notices: &Sender<String>,
+    dir: &Path,
+    crc: u32,
+) {
+    let path = autosave_path(dir, crc);
+    load_state_file(emulation, notices, path, "autosave".to_string());
+}
+
+fn load_state_file(
+    emulation: &Sender<Command>,
+    notices: &Sender<String>,
+    path: PathBuf,
+    name: String,
+) {
+    let notices = notices.clone();
+    emulation
+        .send(Command::Inspect(Box::new(move |cpu| {
+            let message = match cpu.load_state(&path) {
+                Ok(()) => format!("Loaded {}", name),
+                Err(error) => format!("Loading {} failed: {}", name, error),
+            };
+            let _ = notices.send(message);
+        })))
+        .unwrap();
+}
+
+/// State hashes of the running log, sent by the emulation thread after every frame.
+pub struct HashRecording {
+    hashes: Receiver<u32>,
+    crc: u32,
+}
+
+/// Starts logging state hashes, or saves the running log and compares it with the previous
+/// log of the same game.
+pub fn toggle_hash_log(
+    hash_log: &mut Option<HashRecording>,
+    emulation: &Sender<Command>,
+    dir: &Path,
+    crc: u32,
+    osd: &mut Osd,
+) {
+    let running = match hash_log.take() {
+        Some(running) => running,
+        None => {
+            let (sender, hashes) = mpsc::channel();
+            emulation.send(Command::HashStates(Some(sender))).unwrap();
+            *hash_log = Some(HashRecording { hashes, crc });
+            notify(osd, "Logging state hashes".to_string());
+            return;
+        }
+    };
+
+    // The emulation thread drops its sender when it stops, so this gets every frame
+    emulation.send(Command::HashStates(None)).unwrap();
+    let mut log = HashLog::new();
+    for hash in running.hashes.iter() {
+        log.push(hash);
+    }
+
+    let previous = previous_hash_log(dir, running.crc);
+    let path = dir.join(format!("{:08X}-{}.hashes", running.crc, timestamp()));
+    if let Err(error) = fs::create_dir_all(dir).and_then(|_| log.save(&path)) {
+        notify(osd, format!("Saving state hashes failed: {}", error));
+        return;
+    }
+
+    let message = match previous.map(|previous| HashLog::load(&previous)) {
+        None => format!("Saved {} state hashes", log.len()),
+        Some(Err(error)) => format!("Reading the previous state hashes failed: {}", error),
+        Some(Ok(previous)) => match log.first_mismatch(&previous) {
+            Some(frame) => format!("State differs from the previous run at frame {}", frame),
+            None => format!(
+                "State matches the previous run for {} frames",
+                log.len().min(previous.len())
+            ),
+        },
+    };
+    notify(osd, message);
+}
+
+/// Reads the label files next to the ROM, a broken file only costs the labels.
+pub fn load_labels(rom_path: &Path, rom: &Rom) -> Labels {
+    match Labels::load(rom_path, rom.prg_rom.len()) {
+        Ok(labels) => {
+            if !labels.is_empty() {
+                println!("Loaded {} labels", labels.len());
+            }
+            labels
+        }
+        Err(error) => {
+            println!("Reading labels failed: {}", error);
+            Labels::new()
+        }
+    }
+}
+
+/// Starts testing the achievements of `game.achievements.txt` next to the ROM, when there is
+/// one. Unlocked achievements arrive as notices.
+pub fn load_achievements(emulation: &Sender<Command>, notices: &Sender<String>, rom_path: &Path) {
+    let path = rom_path.with_extension("achievements.txt");
+    if !path.exists() {
+        return;
+    }
+    match AchievementSet::load(&path) {
+        Ok(set) => {
+            let _ = notices.send(format!("Loaded {} achievements", set.achievements().len()));
+            emulation
+                .send(Command::Achievements(Some((set, notices.clone()))))
+                .unwrap();
+        }
+        Err(error) => {
+            let _ = notices.send(format!("Reading {} failed: {}", file_name(&path), error));
+        }
+    }
+}
+
+/// Where the cheats of a game are kept, an FCEUX `.cht` file named after its CRC.
+pub fn cheats_path(dir: &Path, rom_crc: u32) -> PathBuf {
+    dir.join(format!("{:08X}.cht", rom_crc))
+}
+
+/// Loads the cheats of the game when it has any and turns on the enabled ones. Without saved
+/// cheats, an FCEUX `game.cht` next to the ROM is imported into the cheat directory.
+pub fn load_cheats(
+    emulation: &Sender<Command>,
+    notices: &Sender<String>,
+    dir: &Path,
+    rom_crc: u32,
+    rom_path: &Path,
+) -> CheatList {
+    let mut path = cheats_path(dir, rom_crc);
+    let import = !path.exists();
+    if import {
+        path = rom_path.with_extension("cht");
+        if !path.exists() {
+            return CheatList::default();
+        }
+    }
+    match CheatList::load(&path) {
+        Ok(list) => {
+            if import {
+                let _ = notices.send(format!(
+                    "Imported {} cheats from {}",
+                    list.cheats.len(),
+                    file_name(&path)
+                ));
+                if let Err(error) = save_cheats(&list, dir, rom_crc) {
+                    let _ = notices.send(format!("Saving cheats failed: {}", error));
+                }
+            } else {
+                let _ = notices.send(format!("Loaded {} cheats", list.cheats.len()));
+            }
+            apply_cheats(emulation, &list);
+            list
+        }
+        Err(error) => {
+            let _ = notices.send(format!("Reading {} failed: {}", file_name(&path), error));
+            CheatList::default()
+        }
+    }
+}
+
+/// Saves the cheats of the game into the cheat directory.
+pub fn save_cheats(list: &CheatList, dir: &Path, rom_crc: u32) -> io::Result<()> {
+    fs::create_dir_all(dir).and_then(|_| list.save(&cheats_path(dir, rom_crc)))
+}
+
+/// Freezes the bytes of the enabled cheats and releases the others.
+pub fn apply_cheats(emulation: &Sender<Command>, list: &CheatList) {
+    let list = list.clone();
+    emulation
+        .send(Command::Inspect(Box::new(move |cpu| {
+            list.apply(&mut cpu.bus)
+        })))
+        .unwrap();
+}
+
+/// Connects to the other player of `--netplay-host <address>` or `--netplay-join <address>`
+/// on the command line in the background, `--input-delay <frames>` sets the delay of the host.
+pub fn start_netplay(emulation: &Sender<Command>, notices: &Sender<String>, rom_crc: u32) {
+    let args: Vec<String> = std::env::args().collect();
+    let flag = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+    };
+    let input_delay = flag("--input-delay")
+        .and_then(|delay| delay.parse().ok())
+        .unwrap_or(DEFAULT_INPUT_DELAY);
+    let connect: Box<dyn FnOnce() -> io::Result<NetplaySession> + Send> =
+        match (flag("--netplay-host"), flag("--netplay-join")) {
+            (Some(address), _) => {
+                let _ = notices.send(format!("Waiting for player 2 on {}", address));
+                Box::new(move || NetplaySession::host(&address, rom_crc, input_delay))
+            }
+            (None, Some(address)) => Box::new(move || NetplaySession::join(&address, rom_crc)),
+            (None, None) => return,
+        };
+
+    let emulation = emulation.clone();
+    let notices = notices.clone();
+    thread::spawn(move || match connect() {
+        Ok(session) => {
+            let _ = notices.send(format!("Netplay started as player {}", session.player()));
+            let _ = emulation.send(Command::Netplay(Some(session)));
+        }
+        Err(error) => {
+            let _ = notices.send(format!("Netplay failed: {}", error));
+        }
+    });
+}
+
+/// Starts the remote control server when the command line has `--remote <address>`.
+#[cfg(feature = "remote")]
+pub fn start_remote_server(emulation: &Sender<Command>) {
+    let args: Vec<String> = std::env::args().collect();
+    let Some(address) = args
+        .iter()
+        .position(|arg| arg == "--remote")
+        .and_then(|i| args.get(i + 1))
+    else {
+        return;
+    };
+    if let Err(error) = crate::remote::spawn_server(address, emulation.clone()) {
+        println!("Remote server failed: {}", error);
+    }
+}
+
+/// Runs the scripts of `--lua <script>` and `--rhai <script>` on the command line.
+#[cfg(any(feature = "lua", feature = "rhai"))]
+pub fn start_scripts(emulation: &Sender<Command>) {
+    let args: Vec<String> = std::env::args().collect();
+    let flag = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .and_then(|i| args.get(i + 1))
+            .map(PathBuf::from)
+    };
+    #[cfg(feature = "lua")]
+    if let Some(path) = flag("--lua") {
+        let _ = emulation.send(Command::LuaScript(Some(path)));
+    }
+    #[cfg(feature = "rhai")]
+    if let Some(path) = flag("--rhai") {
+        let _ = emulation.send(Command::RhaiScript(Some(path)));
+    }
+}
+
+/// Starts logging code and data, or saves the running log next to the ROM as FCEUX does,
+/// along with a `.coverage.txt` report of how much of each PRG bank ran.
+pub fn toggle_code_data_log(
+    logging: &mut bool,
+    emulation: &Sender<Command>,
+    notices: &Sender<String>,
+    rom_path: &Path,
+) {
+    let notices = notices.clone();
+    if !*logging {
+        *logging = true;
+        emulation
+            .send(Command::Inspect(Box::new(move |cpu| {
+                cpu.bus.start_code_data_log();
+                let _ = notices.send("Logging code and data".to_string());
+            })))
+            .unwrap();
+        return;
+    }
+
+    *logging = false;
+    let path = rom_path.with_extension("cdl");
+    let coverage_path = rom_path.with_extension("coverage.txt");
+    emulation
+        .send(Command::Inspect(Box::new(move |cpu| {
+            let message = match cpu.bus.take_code_data_log() {
+                Some(log) => match log
+                    .save(&path)
+                    .and_then(|()| fs::write(&coverage_path, coverage::report(log.prg())))
+                {
+                    Ok(()) => format!(
+                        "Saved {} and {}",
+                        file_name(&path),
+                        file_name(&coverage_path)
+                    ),
+                    Err(error) => format!("Saving code/data log failed: {}", error),
+                },
+                None => "No code/data log is running".to_string(),
+            };
+            let _ = notices.send(message);
+        })))
+        .unwrap();
+}
+
+/// Starts profiling, or stops and saves the report next to the ROM as `.profile.txt` with
+/// routine addresses replaced by their labels.
+pub fn toggle_profiler(
+    profiling: &mut bool,
+    emulation: &Sender<Command>,
+    notices: &Sender<String>,
+    rom_path: &Path,
+    labels: &Labels,
+) {
+    let notices = notices.clone();
+    if !*profiling {
+        *profiling = true;
+        emulation
+            .send(Command::Inspect(Box::new(move |cpu| {
+                cpu.bus.start_profiler();
+                let _ = notices.send("Profiling".to_string());
+            })))
+            .unwrap();
+        return;
+    }
+
+    *profiling = false;
+    let path = rom_path.with_extension("profile.txt");
+    let labels = labels.clone();
+    emulation
+        .send(Command::Inspect(Box::new(move |cpu| {
+            let message = match cpu.bus.take_profiler() {
+                Some(profiler) => match fs::write(&path, labels.substitute(&profiler.report())) {
+                    Ok(()) => format!("Saved {}", file_name(&path)),
+                    Err(error) => format!("Saving profile failed: {}", error),
+                },
+                None => "No profiler is running".to_string(),
+            };
+            let _ = notices.send(message);
+        })))
+        .unwrap();
+}
+
+/// Starts the event log, or stops and saves it next to the ROM as `.events.csv` and shows a
+/// summary of the frame timings.
+pub fn toggle_event_log(
+    logging: &mut bool,
+    emulation: &Sender<Command>,
+    notices: &Sender<String>,
+    rom_path: &Path,
+) {
+    let notices = notices.clone();
+    if !*logging {
+        *logging = true;
+        emulation
+            .send(Command::Inspect(Box::new(move |cpu| {
+                cpu.bus.start_event_log();
+                let _ = notices.send("Logging events".to_string());
+            })))
+            .unwrap();
+        return;
+    }
+
+    *logging = false;
+    let path = rom_path.with_extension("events.csv");
+    emulation
+        .send(Command::Inspect(Box::new(move |cpu| {
+            let message = match cpu.bus.take_event_log() {
+                Some(events) => match fs::write(&path, events.to_csv()) {
+                    Ok(()) => format!("Saved {}: {}", file_name(&path), events.summary()),
+                    Err(error) => format!("Saving event log failed: {}", error),
+                },
+                None => "No event log is running".to_string(),
+            };
+            let _ = notices.send(message);
+        })))
+        .unwrap();
+}
+
+/// Traces every instruction to `<rom>.trace.log`, toggling again pauses the trace and flushes
+/// the file. Resuming appends to the same file, in the format configured at that time.
+pub fn toggle_trace(
+    tracing: &mut bool,
+    emulation: &Sender<Command>,
+    notices: &Sender<String>,
+    rom_path: &Path,
+    format: TraceFormat,
+    labels: &Labels,
+) {
+    *tracing = !*tracing;
+    let enabled = *tracing;
+    let notices = notices.clone();
+    let path = rom_path.with_extension("trace.log");
+    let labels = labels.clone();
+    emulation
+        .send(Command::Inspect(Box::new(move |cpu| {
+            let message = match (&mut cpu.tracer, enabled) {
+                (Some(tracer), true) => {
+                    tracer.set_format(format, labels);
+                    tracer.set_enabled(true);
+                    format!("Tracing to {}", file_name(&path))
+                }
+                (Some(tracer), false) => {
+                    tracer.set_enabled(false);
+                    match tracer.flush() {
+                        Ok(()) => format!("Trace paused, saved {}", file_name(&path)),
+                        Err(error) => format!("Saving trace failed: {}", error),
+                    }
+                }
+                (None, true) => match Tracer::file(&path) {
+                    Ok(mut tracer) => {
+                        tracer.set_format(format, labels);
+                        cpu.tracer = Some(tracer);
+                        format!("Tracing to {}", file_name(&path))
+                    }
+                    Err(error) => format!("Tracing failed: {}", error),
+                },
+                (None, false) => "No trace is running".to_string(),
+            };
+            let _ = notices.send(message);
+        })))
+        .unwrap();
+}
+
+/// The most recent hash log of the game, the timestamps in the names sort by age.
+fn previous_hash_log(dir: &Path, crc: u32) -> Option<PathBuf> {
+    let prefix = format!("{:08X}-", crc);
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = file_name(path);
+            name.starts_with(&prefix) && name.ends_with(".hashes")
+        })
+        .max()
+}
@@ -0,0 +1,243 @@
+use crate::bus::{Bus, RamInit};
+use crate::cartridge::Rom;
+use crate::condition::Condition;
+use crate::cpu::CPU;
+use crate::joypad::button_from_name;
+use crate::statehash::hash_state;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What a script does once a frame is reached.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Press(u8),
+    Release(u8),
+    Assert(Condition),
+    /// Expected hash of the whole machine state, as in the state hash logs.
+    Hash(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptLine {
+    pub line: usize,
+    /// Frames finished before the action runs.
+    pub frame: u64,
+    pub action: Action,
+}
+
+/// A game test, with a line per action like:
+///
+/// ```text
+/// # Start the game and check the number of lives
+/// 30 press start
+/// 32 release start
+/// 120 assert [$0750] == 3
+/// 200 hash 1A2B3C4D
+/// ```
+///
+/// Buttons are held from `press` until `release`. Assertions are breakpoint conditions and
+/// are checked with the CPU state when the frame starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestScript {
+    lines: Vec<ScriptLine>,
+}
+
+/// A check that did not hold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub line: usize,
+    pub frame: u64,
+    pub message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, frame {}: {}",
+            self.line, self.frame, self.message
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestReport {
+    pub frames: u64,
+    pub checks: usize,
+    pub failures: Vec<Failure>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl fmt::Display for TestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for failure in &self.failures {
+            writeln!(f, "FAIL {}", failure)?;
+        }
+        write!(
+            f,
+            "{} after {} frames, {} of {} checks passed",
+            if self.passed() { "PASS" } else { "FAIL" },
+            self.frames,
+            self.checks - self.failures.len(),
+            self.checks
+        )
+    }
+}
+
+impl TestScript {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = vec![];
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let number = index + 1;
+            let error = |message: String| format!("Line {}: {}", number, message);
+
+            let mut words = line.splitn(3, char::is_whitespace);
+            let (Some(frame), Some(command)) = (words.next(), words.next()) else {
+                return Err(error(format!("Expected <frame> <action> in {:?}", line)));
+            };
+            let frame = frame
+                .parse()
+                .map_err(|_| error(format!("Invalid frame {:?}", frame)))?;
+            let argument = words.next().unwrap_or_default().trim();
+            let button = || {
+                button_from_name(argument)
+                    .ok_or_else(|| error(format!("Unknown button {:?}", argument)))
+            };
+            let action = match command {
+                "press" => Action::Press(button()?),
+                "release" => Action::Release(button()?),
+                "assert" => Action::Assert(Condition::parse(argument).map_err(error)?),
+                "hash" => Action::Hash(
+                    u32::from_str_radix(argument, 16)
+                        .map_err(|_| error(format!("Invalid hash {:?}", argument)))?,
+                ),
+                _ => return Err(error(format!("Unknown action {:?}", command))),
+            };
+            lines.push(ScriptLine {
+                line: number,
+                frame,
+                action,
+            });
+        }
+        // Keeps the order of the file within a frame
+        lines.sort_by_key(|line| line.frame);
+        Ok(TestScript { lines })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    pub fn lines(&self) -> &[ScriptLine] {
+        &self.lines
+    }
+
+    /// Runs the script on the ROM without any output, until the last action.
+    pub fn run(&self, rom: Rom, ram_init: RamInit) -> TestReport {
+        let mut bus = Bus::new(rom, |_, _| {});
+        bus.set_ram_init(ram_init);
+        let mut cpu = CPU::new(bus);
+        cpu.power_cycle();
+
+        let mut report = TestReport {
+            frames: 0,
+            checks: 0,
+            failures: vec![],
+        };
+        for line in &self.lines {
+            while cpu.bus.frames() < line.frame {
+                cpu.step();
+            }
+            report.frames = line.frame;
+            let failure = match &line.action {
+                Action::Press(button) => {
+                    cpu.bus
+                        .joypad_mut()
+                        .set_button_pressed_status(*button, true);
+                    continue;
+                }
+                Action::Release(button) => {
+                    cpu.bus
+                        .joypad_mut()
+                        .set_button_pressed_status(*button, false);
+                    continue;
+                }
+                Action::Assert(condition) => {
+                    (!condition.holds(&mut cpu)).then(|| format!("{} does not hold", condition))
+                }
+                Action::Hash(expected) => {
+                    let hash = hash_state(&cpu.state_to_bytes());
+                    (hash != *expected)
+                        .then(|| format!("state hash is {:08X}, expected {:08X}", hash, expected))
+                }
+            };
+            report.checks += 1;
+            if let Some(message) = failure {
+                report.failures.push(Failure {
+                    line: line.line,
+                    frame: line.frame,
+                    message,
+                });
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::joypad::JOYPAD_START;
+
+    #[test]
+    fn test_parse() {
+        let script = TestScript::parse(
+            "# comment\n\n10 assert [$10] == 3\n5 press start\n10 hash 1a2b3c4d\n",
+        )
+        .unwrap();
+        let lines = script.lines();
+        assert_eq!(lines[0].action, Action::Press(JOYPAD_START));
+        assert_eq!(lines[1].line, 3);
+        assert!(matches!(lines[1].action, Action::Assert(_)));
+        assert_eq!(lines[2].action, Action::Hash(0x1a2b3c4d));
+
+        assert_eq!(
+            TestScript::parse("1 press turbo").unwrap_err(),
+            "Line 1: Unknown button \"turbo\""
+        );
+        assert!(TestScript::parse("x assert A == 0").is_err());
+        assert!(TestScript::parse("1 assert A ==").is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        // INC $10, then JMP to it forever
+        let mut program = vec![0xe6, 0x10, 0x4c, 0x00, 0x80];
+        program.resize(2 * 0x4000 - 4, 0);
+        program.extend([0x00, 0x80, 0x00, 0x00]);
+
+        let script =
+            TestScript::parse("0 assert [$10] == 0\n2 assert [$10] == 0\n2 hash 0").unwrap();
+        let report = script.run(test_rom(program), RamInit::Zero);
+        assert_eq!(report.frames, 2);
+        assert_eq!(report.checks, 3);
+        assert_eq!(report.failures.len(), 2);
+        assert_eq!(report.failures[0].line, 2);
+        assert!(report
+            .to_string()
+            .ends_with("FAIL after 2 frames, 1 of 3 checks passed"));
+    }
+}
@@ -0,0 +1,319 @@
+use crate::bus::{Bus, RamInit};
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use crate::movie::{Movie, MovieFrame};
+use crate::render::{self, Frame};
+use crate::statehash::hash_state;
+use crate::statejson::machine_json;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Frames a benchmark runs unless told otherwise, 10 seconds of NTSC.
+pub const BENCH_FRAMES: u64 = 600;
+
+/// What to run for `--headless` or `--bench`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeadlessOptions {
+    pub rom: PathBuf,
+    pub frames: u64,
+    /// Input for the frames, nothing is pressed without one.
+    pub movie: Option<PathBuf>,
+    /// Renders every frame like a frontend would, to measure the whole emulator.
+    pub bench: bool,
+    /// Writes every frame as headerless 256x240 RGB24 to this file or named pipe, `-` for
+    /// stdout.
+    pub stream: Option<PathBuf>,
+    /// Writes the machine state after the last frame as JSON to this file, `-` for stdout.
+    pub json: Option<PathBuf>,
+}
+
+impl HeadlessOptions {
+    /// Reads `<rom> --headless --frames N [--movie file.fm2] [--stream file|-] [--json file|-]` or
+    /// `<rom> --bench [--frames N]`, flags in any order. Returns `None` without `--headless` or `--bench`.
+    pub fn parse(args: &[String]) -> Option<Result<Self, String>> {
+        if !args
+            .iter()
+            .any(|arg| arg == "--headless" || arg == "--bench")
+        {
+            return None;
+        }
+        Some(Self::parse_flags(args))
+    }
+
+    fn parse_flags(args: &[String]) -> Result<Self, String> {
+        let mut rom = None;
+        let mut frames = None;
+        let mut movie = None;
+        let mut bench = false;
+        let mut stream = None;
+        let mut json = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => {}
+                "--bench" => bench = true,
+                "--frames" => {
+                    let count = args.next().ok_or("--frames needs a number")?;
+                    frames = Some(
+                        count
+                            .parse()
+                            .map_err(|_| format!("Invalid frame count {:?}", count))?,
+                    );
+                }
+                "--movie" => {
+                    movie = Some(PathBuf::from(args.next().ok_or("--movie needs a file")?))
+                }
+                "--stream" => {
+                    stream = Some(PathBuf::from(
+                        args.next().ok_or("--stream needs a file or -")?,
+                    ))
+                }
+                "--json" => {
+                    json = Some(PathBuf::from(
+                        args.next().ok_or("--json needs a file or -")?,
+                    ))
+                }
+                // There is no APU yet, so the only sound would be silence
+                "--audio" => return Err("There is no audio to stream yet".to_string()),
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => rom = Some(PathBuf::from(arg)),
+            }
+        }
+        Ok(HeadlessOptions {
+            rom: rom.ok_or("No ROM given")?,
+            frames: match (frames, bench) {
+                (Some(frames), _) => frames,
+                (None, true) => BENCH_FRAMES,
+                (None, false) => return Err("--frames is required".to_string()),
+            },
+            movie,
+            bench,
+            stream,
+            json,
+        })
+    }
+}
+
+/// How a headless run went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeadlessReport {
+    pub frames: u64,
+    pub cycles: u64,
+    pub elapsed: Duration,
+    /// Hash of the machine state after the last frame, as in the state hash logs.
+    pub hash: u32,
+    /// The machine after the last frame, see `machine_json`.
+    pub json: String,
+}
+
+impl HeadlessReport {
+    pub fn frames_per_second(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn cycles_per_second(&self) -> f64 {
+        self.cycles as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for HeadlessReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Ran {} frames ({} CPU cycles) in {:.2}s, {:.1} frames per second, {:.2} MHz, \
+             state hash {:08X}",
+            self.frames,
+            self.cycles,
+            self.elapsed.as_secs_f64(),
+            self.frames_per_second(),
+            self.cycles_per_second() / 1e6,
+            self.hash
+        )
+    }
+}
+
+/// Runs the ROM for a number of frames as fast as possible without any window, rendering to
+/// an offscreen frame when asked. The movie input of each frame is applied before it runs.
+pub fn run(
+    rom: Rom,
+    ram_init: RamInit,
+    frames: u64,
+    movie: Option<&Movie>,
+    render: bool,
+) -> HeadlessReport {
+    let mut frame = Frame::new();
+    let mut bus = Bus::new(rom, move |ppu, _| {
+        if render {
+            render::render(ppu, &mut frame);
+        }
+    });
+    bus.set_ram_init(ram_init);
+    run_frames(CPU::new(bus), frames, movie, || Ok(())).unwrap()
+}
+
+/// Like `run` with rendering, but writes every frame as headerless RGB24 to `output`, e.g.
+/// for `ffmpeg -f rawvideo -pixel_format rgb24 -video_size 256x240 -i -`. Stops at the first
+/// write that fails, like when the reading end of a pipe is closed.
+pub fn stream(
+    rom: Rom,
+    ram_init: RamInit,
+    frames: u64,
+    movie: Option<&Movie>,
+    output: &mut dyn Write,
+) -> io::Result<HeadlessReport> {
+    let frame = Rc::new(RefCell::new(Frame::new()));
+    let bus_frame = Rc::clone(&frame);
+    let mut bus = Bus::new(rom, move |ppu, _| {
+        render::render(ppu, &mut bus_frame.borrow_mut());
+    });
+    bus.set_ram_init(ram_init);
+    let report = run_frames(CPU::new(bus), frames, movie, || {
+        output.write_all(&frame.borrow().data)
+    })?;
+    output.flush()?;
+    Ok(report)
+}
+
+fn run_frames(
+    mut cpu: CPU,
+    frames: u64,
+    movie: Option<&Movie>,
+    mut frame_done: impl FnMut() -> io::Result<()>,
+) -> io::Result<HeadlessReport> {
+    cpu.power_cycle();
+
+    let start = Instant::now();
+    // Counted here, since power cycles from the movie restart the bus count
+    for frame in 0..frames {
+        run_frame(&mut cpu, movie.map(|movie| movie.frame(frame)));
+        frame_done()?;
+    }
+
+    Ok(HeadlessReport {
+        frames,
+        cycles: cpu.bus.cycles(),
+        elapsed: start.elapsed(),
+        hash: hash_state(&cpu.state_to_bytes()),
+        json: machine_json(&cpu),
+    })
+}
+
+/// Applies the input, if any, and runs until the next frame is done.
+pub(crate) fn run_frame(cpu: &mut CPU, input: Option<MovieFrame>) {
+    if let Some(input) = input {
+        if input.power_cycle {
+            cpu.power_cycle();
+        } else if input.reset {
+            cpu.reset();
+        }
+        cpu.bus.joypad_mut().set_buttons(input.buttons);
+    }
+    let end = cpu.bus.frames() + 1;
+    while cpu.bus.frames() < end {
+        cpu.step();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(HeadlessOptions::parse(&args("game.nes")), None);
+        assert_eq!(
+            HeadlessOptions::parse(&args("game.nes --headless --frames 60 --movie run.fm2")),
+            Some(Ok(HeadlessOptions {
+                rom: PathBuf::from("game.nes"),
+                frames: 60,
+                movie: Some(PathBuf::from("run.fm2")),
+                bench: false,
+                stream: None,
+                json: None,
+            }))
+        );
+        assert_eq!(
+            HeadlessOptions::parse(&args("--bench game.nes")),
+            Some(Ok(HeadlessOptions {
+                rom: PathBuf::from("game.nes"),
+                frames: BENCH_FRAMES,
+                movie: None,
+                bench: true,
+                stream: None,
+                json: None,
+            }))
+        );
+        assert_eq!(
+            HeadlessOptions::parse(&args("game.nes --headless --frames 5 --json state.json"))
+                .unwrap()
+                .unwrap()
+                .json,
+            Some(PathBuf::from("state.json"))
+        );
+        assert_eq!(
+            HeadlessOptions::parse(&args("game.nes --headless --frames 5 --stream -"))
+                .unwrap()
+                .unwrap()
+                .stream,
+            Some(PathBuf::from("-"))
+        );
+        assert!(
+            HeadlessOptions::parse(&args("game.nes --headless --frames 5 --audio 3"))
+                .unwrap()
+                .is_err()
+        );
+        assert_eq!(
+            HeadlessOptions::parse(&args("--headless game.nes")),
+            Some(Err("--frames is required".to_string()))
+        );
+        assert!(
+            HeadlessOptions::parse(&args("--headless --frames x game.nes"))
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        // Copy the controller bits to $10 forever
+        let mut program = vec![
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, // strobe
+            0xad, 0x16, 0x40, 0x85, 0x10, 0x4c, 0x00, 0x80, // LDA $4016, STA $10, JMP $8000
+        ];
+        program.resize(2 * 0x4000 - 4, 0);
+        program.extend([0x00, 0x80, 0x00, 0x00]);
+
+        let movie = Movie::parse_fm2("|0|........|||\n|0|.......A|||").unwrap();
+        let with_movie = run(
+            test_rom(program.clone()),
+            RamInit::Zero,
+            2,
+            Some(&movie),
+            false,
+        );
+        let without = run(test_rom(program.clone()), RamInit::Zero, 2, None, false);
+        assert_eq!(with_movie.frames, 2);
+        assert!(with_movie.json.contains("\"frames\": 2,"));
+        assert!(with_movie.cycles > 2 * 29780);
+        assert_ne!(with_movie.hash, without.hash);
+
+        // Rendering does not change what the game does
+        let rendered = run(test_rom(program.clone()), RamInit::Zero, 2, None, true);
+        assert_eq!(rendered.hash, without.hash);
+
+        // Streaming writes one frame after another
+        let mut output = Vec::new();
+        let streamed = stream(test_rom(program), RamInit::Zero, 2, None, &mut output).unwrap();
+        assert_eq!(streamed.hash, without.hash);
+        assert_eq!(output.len(), 2 * Frame::new().data.len());
+    }
+}
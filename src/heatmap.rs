@@ -0,0 +1,95 @@
+/// Width and height of the heatmap, a pixel per CPU address with a page per row.
+pub const HEATMAP_SIZE: usize = 256;
+
+/// How often every CPU address was read and written, shown as a 256x256 image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessHeatmap {
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+}
+
+impl Default for AccessHeatmap {
+    fn default() -> Self {
+        AccessHeatmap {
+            reads: vec![0; 0x10000],
+            writes: vec![0; 0x10000],
+        }
+    }
+}
+
+impl AccessHeatmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn log_read(&mut self, adr: u16) {
+        let count = &mut self.reads[adr as usize];
+        *count = count.saturating_add(1);
+    }
+
+    pub fn log_write(&mut self, adr: u16) {
+        let count = &mut self.writes[adr as usize];
+        *count = count.saturating_add(1);
+    }
+
+    pub fn reads(&self, adr: u16) -> u32 {
+        self.reads[adr as usize]
+    }
+
+    pub fn writes(&self, adr: u16) -> u32 {
+        self.writes[adr as usize]
+    }
+
+    pub fn clear(&mut self) {
+        self.reads.fill(0);
+        self.writes.fill(0);
+    }
+
+    /// RGB pixels with reads in green and writes in red, so addresses that are both read and
+    /// written show yellow. Brightness follows the logarithm of the count, relative to the
+    /// busiest address.
+    pub fn image(&self) -> Vec<u8> {
+        let scale = |counts: &[u32]| {
+            let max = counts.iter().copied().max().unwrap_or_default();
+            let max = ((max as f32) + 1.0).ln();
+            move |count: u32| {
+                if count == 0 {
+                    0
+                } else {
+                    (64.0 + 191.0 * ((count as f32) + 1.0).ln() / max) as u8
+                }
+            }
+        };
+        let (read_scale, write_scale) = (scale(&self.reads), scale(&self.writes));
+        self.reads
+            .iter()
+            .zip(&self.writes)
+            .flat_map(|(&reads, &writes)| [write_scale(writes), read_scale(reads), 0])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_image() {
+        let mut heatmap = AccessHeatmap::new();
+        for _ in 0..10 {
+            heatmap.log_read(0x0101);
+        }
+        heatmap.log_read(0x0000);
+        heatmap.log_write(0x0000);
+
+        let image = heatmap.image();
+        assert_eq!(image.len(), HEATMAP_SIZE * HEATMAP_SIZE * 3);
+        assert_eq!(&image[(HEATMAP_SIZE + 1) * 3..][..3], [0, 255, 0]);
+        assert_eq!(image[0], 255);
+        assert!(image[1] > 64 && image[1] < 255);
+        assert_eq!(&image[3..6], [0, 0, 0]);
+
+        heatmap.clear();
+        assert_eq!(heatmap.reads(0x0101), 0);
+    }
+}
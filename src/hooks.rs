@@ -0,0 +1,118 @@
+use crate::cpu::CPU;
+use crate::interrupts::Interrupt;
+
+/// Watches the CPU run without changing it, any number of hooks can be added with
+/// `CPU::add_hook` next to the callback of `run_with_callback`.
+pub trait CpuHook {
+    /// Right before the instruction at `cpu.pc` runs.
+    fn before_instruction(&mut self, _cpu: &CPU) {}
+
+    /// Right after an instruction ran, `pc` is the address it started at.
+    fn after_instruction(&mut self, _cpu: &CPU, _pc: u16) {}
+
+    /// When the CPU takes an interrupt, before jumping to the handler.
+    fn on_interrupt(&mut self, _cpu: &CPU, _interrupt: &Interrupt) {}
+}
+
+/// Identifies an added hook, to remove it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookId(u32);
+
+/// The hooks of a CPU in the order they were added.
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<(HookId, Box<dyn CpuHook>)>,
+    next_id: u32,
+}
+
+impl Hooks {
+    pub fn add(&mut self, hook: Box<dyn CpuHook>) -> HookId {
+        let id = HookId(self.next_id);
+        self.next_id += 1;
+        self.hooks.push((id, hook));
+        id
+    }
+
+    pub fn remove(&mut self, id: HookId) -> Option<Box<dyn CpuHook>> {
+        let index = self.hooks.iter().position(|(hook_id, _)| *hook_id == id)?;
+        Some(self.hooks.remove(index).1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn for_each(&mut self, mut call: impl FnMut(&mut dyn CpuHook)) {
+        for (_, hook) in &mut self.hooks {
+            call(hook.as_mut());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::test::TestBus;
+    use crate::interrupts::InterruptKind;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Recorder(Rc<RefCell<Vec<String>>>, &'static str);
+
+    impl CpuHook for Recorder {
+        fn before_instruction(&mut self, cpu: &CPU) {
+            self.0
+                .borrow_mut()
+                .push(format!("{} before {:04X}", self.1, cpu.pc));
+        }
+
+        fn after_instruction(&mut self, cpu: &CPU, pc: u16) {
+            self.0
+                .borrow_mut()
+                .push(format!("{} after {:04X} a={:02X}", self.1, pc, cpu.a));
+        }
+
+        fn on_interrupt(&mut self, _cpu: &CPU, interrupt: &Interrupt) {
+            assert_eq!(interrupt.kind, InterruptKind::Brk);
+            self.0
+                .borrow_mut()
+                .push(format!("{} interrupt {:04X}", self.1, interrupt.handler));
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        // LDA #$55, BRK into a handler at $9000
+        let mut bus = TestBus::new();
+        bus.load(0x8000, &[0xa9, 0x55, 0x00]);
+        bus.set_vectors(0x0000, 0x8000, 0x9000);
+        let mut cpu = bus.into_cpu();
+
+        let log = Rc::new(RefCell::new(vec![]));
+        let first = cpu.add_hook(Box::new(Recorder(Rc::clone(&log), "first")));
+        cpu.add_hook(Box::new(Recorder(Rc::clone(&log), "second")));
+        cpu.step();
+        cpu.step();
+        assert_eq!(
+            *log.borrow(),
+            [
+                "first before 8000",
+                "second before 8000",
+                "first after 8000 a=55",
+                "second after 8000 a=55",
+                "first before 8002",
+                "second before 8002",
+                "first interrupt 9000",
+                "second interrupt 9000",
+                "first after 8002 a=55",
+                "second after 8002 a=55",
+            ]
+        );
+
+        log.borrow_mut().clear();
+        assert!(cpu.remove_hook(first).is_some());
+        assert!(cpu.remove_hook(first).is_none());
+        cpu.step();
+        assert_eq!(log.borrow()[0], "second before 9000");
+    }
+}
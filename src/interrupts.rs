@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::Write as _;
+
+/// Interrupts kept by the log, the oldest are dropped first.
+pub const INTERRUPT_LOG_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterruptKind {
+    Nmi,
+    Brk,
+}
+
+/// An interrupt the CPU took, with where the PPU was at the time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interrupt {
+    pub kind: InterruptKind,
+    pub frame: u64,
+    pub scanline: u16,
+    pub dot: u16,
+    /// CPU cycles since power on.
+    pub cycle: u64,
+    pub vector: u16,
+    /// Start of the handler the vector pointed to.
+    pub handler: u16,
+    /// The instruction that was interrupted, or the BRK itself.
+    pub pc: u16,
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            InterruptKind::Nmi => "NMI",
+            InterruptKind::Brk => "BRK",
+        };
+        write!(
+            f,
+            "{} frame {} scanline {} dot {} cycle {}  (${:04X}) -> ${:04X} from ${:04X}",
+            kind,
+            self.frame,
+            self.scanline,
+            self.dot,
+            self.cycle,
+            self.vector,
+            self.handler,
+            self.pc
+        )
+    }
+}
+
+/// The most recent interrupts, to find missed or doubled NMIs.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptLog {
+    interrupts: VecDeque<Interrupt>,
+}
+
+impl InterruptLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, interrupt: Interrupt) {
+        if self.interrupts.len() == INTERRUPT_LOG_SIZE {
+            self.interrupts.pop_front();
+        }
+        self.interrupts.push_back(interrupt);
+    }
+
+    /// Oldest first.
+    pub fn interrupts(&self) -> impl Iterator<Item = &Interrupt> {
+        self.interrupts.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.interrupts.clear();
+    }
+
+    /// One interrupt per line, oldest first. Frames without an NMI, or with more than one,
+    /// are pointed out.
+    pub fn lines(&self) -> String {
+        let mut text = String::new();
+        let mut previous_nmi: Option<u64> = None;
+        for interrupt in &self.interrupts {
+            write!(text, "{}", interrupt).unwrap();
+            if interrupt.kind == InterruptKind::Nmi {
+                match previous_nmi.map(|frame| interrupt.frame - frame) {
+                    Some(0) => write!(text, "  (second NMI this frame)").unwrap(),
+                    Some(frames @ 2..) => {
+                        write!(text, "  ({} frames without NMI)", frames - 1).unwrap()
+                    }
+                    _ => {}
+                }
+                previous_nmi = Some(interrupt.frame);
+            }
+            writeln!(text).unwrap();
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nmi(frame: u64) -> Interrupt {
+        Interrupt {
+            kind: InterruptKind::Nmi,
+            frame,
+            scanline: 241,
+            dot: 3,
+            cycle: frame * 29781,
+            vector: 0xfffa,
+            handler: 0xc0a0,
+            pc: 0x8012,
+        }
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut log = InterruptLog::new();
+        for frame in [1, 2, 2, 5] {
+            log.push(nmi(frame));
+        }
+        let text = log.lines();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "NMI frame 1 scanline 241 dot 3 cycle 29781  ($FFFA) -> $C0A0 from $8012"
+        );
+        assert!(lines[2].ends_with("(second NMI this frame)"));
+        assert!(lines[3].ends_with("(2 frames without NMI)"));
+    }
+
+    #[test]
+    fn test_log_size() {
+        let mut log = InterruptLog::new();
+        for frame in 0..INTERRUPT_LOG_SIZE as u64 + 1 {
+            log.push(nmi(frame));
+        }
+        assert_eq!(log.interrupts().count(), INTERRUPT_LOG_SIZE);
+        assert_eq!(log.interrupts().next().unwrap().frame, 1);
+    }
+}
@@ -1,3 +1,6 @@
+use crate::state::{StateReader, StateWriter};
+use std::io;
+
 pub const JOYPAD_A: u8 = 0b0000_0001;
 pub const JOYPAD_B: u8 = 0b0000_0010;
 pub const JOYPAD_SELECT: u8 = 0b0000_0100;
@@ -7,10 +10,34 @@ pub const JOYPAD_DOWN: u8 = 0b0010_0000;
 pub const JOYPAD_LEFT: u8 = 0b0100_0000;
 pub const JOYPAD_RIGHT: u8 = 0b1000_0000;
 
+/// The button with a name like `start` or `left`, as used by scripts and remote clients.
+pub fn button_from_name(name: &str) -> Option<u8> {
+    match name.to_ascii_lowercase().as_str() {
+        "a" => Some(JOYPAD_A),
+        "b" => Some(JOYPAD_B),
+        "select" => Some(JOYPAD_SELECT),
+        "start" => Some(JOYPAD_START),
+        "up" => Some(JOYPAD_UP),
+        "down" => Some(JOYPAD_DOWN),
+        "left" => Some(JOYPAD_LEFT),
+        "right" => Some(JOYPAD_RIGHT),
+        _ => None,
+    }
+}
+
 pub struct Joypad {
     strobe: bool,
     button_index: u8,
     button_flags: u8,
+    /// The buttons the shift register was loaded with, it reloads for as long as the strobe
+    /// is high and keeps what was held when it goes low.
+    latched: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Joypad {
@@ -19,27 +46,60 @@ impl Joypad {
             strobe: false,
             button_index: 0,
             button_flags: 0b0000_0000,
+            latched: 0b0000_0000,
         }
     }
 
+    /// Only bit 0 is the strobe, the other bits of the write don't matter.
     pub fn write(&mut self, data: u8) {
+        let was_strobing = self.strobe;
         self.strobe = data & 0b0000_0001 != 0;
-        if self.strobe {
-            self.button_index = 0
+        if self.strobe || was_strobing {
+            self.reload();
         }
     }
 
+    fn reload(&mut self) {
+        self.latched = self.button_flags;
+        self.button_index = 0;
+    }
+
+    /// With the strobe high every read reloads the shift register, so it keeps returning the
+    /// current state of A.
     pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            self.reload();
+        }
         if self.button_index > 7 {
             return 1;
         }
-        let response = (self.button_flags & (1 << self.button_index)) >> self.button_index;
-        if !self.strobe && self.button_index <= 7 {
+        let response = (self.latched & (1 << self.button_index)) >> self.button_index;
+        if !self.strobe {
             self.button_index += 1;
         }
         response
     }
 
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.strobe);
+        writer.write_u8(self.button_index);
+        writer.write_u8(self.button_flags);
+        writer.write_u8(self.latched);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.strobe = reader.read_bool()?;
+        self.button_index = reader.read_u8()?;
+        self.button_flags = reader.read_u8()?;
+        // States from before the shift register was saved read the buttons as they are
+        self.latched = if reader.remaining() > 0 {
+            reader.read_u8()?
+        } else {
+            self.button_flags
+        };
+        Ok(())
+    }
+
     pub fn set_button_pressed_status(&mut self, button: u8, pressed: bool) {
         if pressed {
             self.button_flags |= button;
@@ -47,6 +107,15 @@ impl Joypad {
             self.button_flags &= !button;
         }
     }
+
+    /// The buttons held, one `JOYPAD_*` bit each.
+    pub fn buttons(&self) -> u8 {
+        self.button_flags
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.button_flags = buttons;
+    }
 }
 
 #[cfg(test)]
@@ -61,17 +130,49 @@ mod test {
         for _x in 0..10 {
             assert_eq!(joypad.read(), 1);
         }
+        // Every read reloads, so it follows A and never gets to B
+        joypad.set_buttons(JOYPAD_B);
+        assert_eq!(joypad.read(), 0);
+        // Only bit 0 is the strobe
+        joypad.write(0b11);
+        joypad.set_buttons(JOYPAD_A);
+        assert_eq!(joypad.read(), 1);
+        joypad.write(0b10);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 0);
+    }
+
+    #[test]
+    fn test_latches_on_strobe_release() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(JOYPAD_A | JOYPAD_START);
+        joypad.write(1);
+        joypad.write(0);
+        // Buttons pressed and released after the strobe don't show until the next one
+        joypad.set_buttons(JOYPAD_B);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 0);
+        assert_eq!(joypad.read(), 0);
+        assert_eq!(joypad.read(), 1);
+        // Writing 0 again doesn't reload
+        joypad.write(0);
+        assert_eq!(joypad.read(), 0);
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(joypad.read(), 0);
+        assert_eq!(joypad.read(), 1);
     }
 
     #[test]
     fn test_strobe_mode_on_off() {
         let mut joypad = Joypad::new();
 
-        joypad.write(0);
         joypad.set_button_pressed_status(JOYPAD_RIGHT, true);
         joypad.set_button_pressed_status(JOYPAD_LEFT, true);
         joypad.set_button_pressed_status(JOYPAD_SELECT, true);
         joypad.set_button_pressed_status(JOYPAD_B, true);
+        joypad.write(1);
+        joypad.write(0);
 
         for _ in 0..=1 {
             assert_eq!(joypad.read(), 0);
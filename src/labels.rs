@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Names for CPU addresses, read from FCEUX .nl or Mesen .mlb label files.
+///
+/// PRG ROM labels are mapped to CPU addresses the way NROM maps the ROM, so a 16K ROM gets
+/// each label at both of its mirrors.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Labels {
+    names: HashMap<u16, String>,
+    addresses: HashMap<String, u16>,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, address: u16, name: &str) {
+        self.names.insert(address, name.to_string());
+        self.addresses.entry(name.to_string()).or_insert(address);
+    }
+
+    pub fn get(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Reads the label files that FCEUX and Mesen keep next to the ROM, e.g. `game.nes.0.nl`,
+    /// `game.nes.ram.nl` and `game.mlb`. Missing files are skipped.
+    pub fn load(rom_path: &Path, prg_size: usize) -> io::Result<Self> {
+        let mut labels = Labels::new();
+        let mut nl_files = vec![nl_path(rom_path, "ram")];
+        nl_files.extend((0..prg_size / 0x4000).map(|bank| nl_path(rom_path, &bank.to_string())));
+        for path in nl_files {
+            if let Some(text) = read_if_exists(&path)? {
+                labels.parse_nl(&text);
+            }
+        }
+        if let Some(text) = read_if_exists(&rom_path.with_extension("mlb"))? {
+            labels.parse_mlb(&text, prg_size);
+        }
+        Ok(labels)
+    }
+
+    /// Parses FCEUX lines like `$C000#Reset#comment`, the addresses are CPU addresses.
+    pub fn parse_nl(&mut self, text: &str) {
+        for line in text.lines() {
+            let mut fields = line.trim().split('#');
+            let address = fields.next().and_then(|address| address.strip_prefix('$'));
+            let address = address.and_then(|address| u16::from_str_radix(address, 16).ok());
+            match (address, fields.next()) {
+                (Some(address), Some(name)) if !name.is_empty() => self.insert(address, name),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Parses Mesen lines like `P:0010:Reset:comment`, where the address is an offset into
+    /// the memory type before it. Both Mesen's one letter types and Mesen 2's names are read.
+    pub fn parse_mlb(&mut self, text: &str, prg_size: usize) {
+        for line in text.lines() {
+            let mut fields = line.trim().splitn(4, ':');
+            let (Some(kind), Some(offset), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            // A label can cover a range like 0010-001F, it names the start
+            let offset = offset.split('-').next().unwrap_or_default();
+            let Ok(offset) = u16::from_str_radix(offset, 16) else {
+                continue;
+            };
+            if name.is_empty() {
+                continue;
+            }
+
+            match kind {
+                "P" | "NesPrgRom" => {
+                    if (offset as usize) >= prg_size {
+                        continue;
+                    }
+                    let mut address = 0x8000 + offset as usize;
+                    while address <= 0xffff {
+                        self.insert(address as u16, name);
+                        address += prg_size;
+                    }
+                }
+                "R" | "NesInternalRam" | "G" | "NesMemory" => self.insert(offset, name),
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => {
+                    self.insert(0x6000u16.wrapping_add(offset), name)
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Replaces `$XXXX` and `$XX` addresses in disassembly with their names, immediate
+    /// operands like `#$3F` are left alone.
+    pub fn substitute(&self, text: &str) -> String {
+        if self.is_empty() {
+            return text.to_string();
+        }
+
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find('$') {
+            let digits = rest[start + 1..]
+                .chars()
+                .take_while(|c| c.is_ascii_hexdigit())
+                .count();
+            let immediate = rest[..start].ends_with('#');
+            let name = match (digits, immediate) {
+                (2 | 4, false) => u16::from_str_radix(&rest[start + 1..start + 1 + digits], 16)
+                    .ok()
+                    .and_then(|address| self.get(address)),
+                _ => None,
+            };
+
+            result.push_str(&rest[..start]);
+            match name {
+                Some(name) => result.push_str(name),
+                None => result.push_str(&rest[start..start + 1 + digits]),
+            }
+            rest = &rest[start + 1 + digits..];
+        }
+        result.push_str(rest);
+        result
+    }
+
+    /// Parses a label name or a hex address, with or without a leading `$`.
+    pub fn parse_address(&self, text: &str) -> Option<u16> {
+        let text = text.trim();
+        self.address_of(text)
+            .or_else(|| u16::from_str_radix(text.trim_start_matches('$'), 16).ok())
+    }
+}
+
+fn nl_path(rom_path: &Path, bank: &str) -> PathBuf {
+    let mut name = rom_path.as_os_str().to_owned();
+    name.push(format!(".{}.nl", bank));
+    PathBuf::from(name)
+}
+
+fn read_if_exists(path: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(Some(text)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_files() {
+        let mut labels = Labels::new();
+        labels.parse_nl("$C000#Reset#Entry point\n$0010#player_x#\nbroken line\n");
+        labels.parse_mlb(
+            "P:0010:Nmi:comment\nR:0020-0021:pointer\nW:0000:save\n",
+            0x4000,
+        );
+
+        assert_eq!(labels.get(0xc000), Some("Reset"));
+        assert_eq!(labels.get(0x0010), Some("player_x"));
+        assert_eq!(labels.get(0x8010), Some("Nmi"));
+        assert_eq!(labels.get(0xc010), Some("Nmi"));
+        assert_eq!(labels.get(0x0020), Some("pointer"));
+        assert_eq!(labels.get(0x6000), Some("save"));
+        assert_eq!(labels.parse_address("Reset"), Some(0xc000));
+        assert_eq!(labels.parse_address("$8000"), Some(0x8000));
+    }
+
+    #[test]
+    fn test_substitute() {
+        let mut labels = Labels::new();
+        labels.insert(0xc000, "Reset");
+        labels.insert(0x0010, "player_x");
+
+        assert_eq!(labels.substitute("JSR $C000"), "JSR Reset");
+        assert_eq!(labels.substitute("LDA $10,X"), "LDA player_x,X");
+        assert_eq!(labels.substitute("LDA #$10"), "LDA #$10");
+        assert_eq!(labels.substitute("JMP ($C001)"), "JMP ($C001)");
+    }
+}
@@ -0,0 +1,68 @@
+#![allow(dead_code)]
+
+pub mod achievements;
+pub mod bus;
+pub mod callstack;
+pub mod cartridge;
+pub mod cdl;
+pub mod cheats;
+pub mod clip;
+pub mod compare;
+pub mod compat;
+pub mod condition;
+pub mod config;
+pub mod console;
+pub mod coverage;
+pub mod cpu;
+pub mod crash;
+pub mod debugger;
+pub mod disasm;
+pub mod dump;
+pub mod emulation;
+pub mod env;
+pub mod events;
+pub mod filter;
+pub mod frontend;
+pub mod gametest;
+pub mod headless;
+pub mod heatmap;
+pub mod hooks;
+pub mod interrupts;
+pub mod joypad;
+pub mod labels;
+pub mod logging;
+#[cfg(feature = "lua")]
+pub mod lua;
+pub mod memory;
+pub mod menu;
+pub mod movie;
+pub mod nestest;
+pub mod netplay;
+pub mod opcodes;
+pub mod osd;
+pub mod pacer;
+pub mod ppu;
+pub mod profiler;
+pub mod ramsearch;
+pub mod ramwatch;
+pub mod recorder;
+pub mod region;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod render;
+pub mod rewind;
+#[cfg(feature = "rhai")]
+pub mod rhai_script;
+pub mod scaling;
+#[cfg(feature = "conformance")]
+pub mod singlestep;
+pub mod sprites;
+pub mod state;
+pub mod statediff;
+pub mod statehash;
+pub mod statejson;
+pub mod trace;
+#[cfg(feature = "tui")]
+pub mod tui;
+mod unchecked;
+pub mod watcher;
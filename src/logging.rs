@@ -0,0 +1,73 @@
+use env_logger::Builder;
+
+/// Log targets of the subsystems, to use as `log::debug!(target: logging::BUS, ...)`.
+pub const CPU: &str = "cpu";
+pub const PPU: &str = "ppu";
+/// Reserved until there is an APU.
+pub const APU: &str = "apu";
+pub const MAPPER: &str = "mapper";
+pub const BUS: &str = "bus";
+
+/// Filters used without `--log` or `RUST_LOG`, warnings and errors of every target.
+pub const DEFAULT_FILTERS: &str = "warn";
+
+/// Takes `--log <filters>` out of the arguments, e.g. `--log bus=debug,ppu=trace`.
+/// Returns the filters, if any, and the remaining arguments.
+pub fn split_filters(args: Vec<String>) -> Result<(Option<String>, Vec<String>), String> {
+    let mut filters = None;
+    let mut rest = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--log" {
+            filters = Some(args.next().ok_or("--log needs filters like bus=debug")?);
+        } else {
+            rest.push(arg);
+        }
+    }
+    Ok((filters, rest))
+}
+
+/// Sets up logging with the filters of `--log`, falling back to `RUST_LOG` and then
+/// `DEFAULT_FILTERS`, and returns the other arguments. Exits on a malformed `--log`.
+pub fn init(args: Vec<String>) -> Vec<String> {
+    let (filters, args) = match split_filters(args) {
+        Ok(split) => split,
+        Err(error) => {
+            println!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    let filters = filters
+        .or_else(|| std::env::var("RUST_LOG").ok())
+        .unwrap_or_else(|| DEFAULT_FILTERS.to_string());
+    Builder::new()
+        .parse_filters(&filters)
+        .format_timestamp(None)
+        .init();
+    args
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_split_filters() {
+        assert_eq!(
+            split_filters(args("rust_nes --log bus=debug,cpu=trace game.nes")),
+            Ok((
+                Some("bus=debug,cpu=trace".to_string()),
+                args("rust_nes game.nes")
+            ))
+        );
+        assert_eq!(
+            split_filters(args("rust_nes game.nes")),
+            Ok((None, args("rust_nes game.nes")))
+        );
+        assert!(split_filters(args("rust_nes game.nes --log")).is_err());
+    }
+}
@@ -0,0 +1,496 @@
+use crate::cpu::CPU;
+use crate::joypad::{
+    JOYPAD_A, JOYPAD_B, JOYPAD_DOWN, JOYPAD_LEFT, JOYPAD_RIGHT, JOYPAD_SELECT, JOYPAD_START,
+    JOYPAD_UP,
+};
+use crate::memory::MemoryRegion;
+use crate::osd::draw_text;
+use crate::render::Frame;
+use mlua::{Function, Lua, RegistryKey, Table, Thread, ThreadStatus, Value};
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+/// Button names as FCEUX scripts use them in joypad tables.
+const BUTTONS: [(&str, u8); 8] = [
+    ("A", JOYPAD_A),
+    ("B", JOYPAD_B),
+    ("select", JOYPAD_SELECT),
+    ("start", JOYPAD_START),
+    ("up", JOYPAD_UP),
+    ("down", JOYPAD_DOWN),
+    ("left", JOYPAD_LEFT),
+    ("right", JOYPAD_RIGHT),
+];
+
+/// Callbacks scripts register, kept in the Lua registry under these names.
+const CALLBACKS: [&str; 3] = ["registerafter", "registerbefore", "guiregister"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Color {
+    rgb: (u8, u8, u8),
+    alpha: f32,
+}
+
+impl Color {
+    const fn opaque(r: u8, g: u8, b: u8) -> Self {
+        Color {
+            rgb: (r, g, b),
+            alpha: 1.0,
+        }
+    }
+
+    const WHITE: Color = Color::opaque(0xff, 0xff, 0xff);
+    const BLACK: Color = Color::opaque(0x00, 0x00, 0x00);
+
+    /// `0xRRGGBBAA` as FCEUX packs colors into numbers.
+    fn from_rgba(rgba: u32) -> Self {
+        let [r, g, b, a] = rgba.to_be_bytes();
+        Color {
+            rgb: (r, g, b),
+            alpha: a as f32 / 255.0,
+        }
+    }
+
+    /// A color name like `red` or `clear`, or `#RRGGBB` with an optional alpha byte.
+    fn parse(text: &str) -> Option<Self> {
+        if let Some(hex) = text.strip_prefix('#') {
+            let value = u32::from_str_radix(hex, 16).ok()?;
+            return match hex.len() {
+                6 => Some(Color::from_rgba(value << 8 | 0xff)),
+                8 => Some(Color::from_rgba(value)),
+                _ => None,
+            };
+        }
+        let color = match text.to_ascii_lowercase().as_str() {
+            "white" => Color::WHITE,
+            "black" => Color::BLACK,
+            "clear" => Color {
+                rgb: (0, 0, 0),
+                alpha: 0.0,
+            },
+            "red" => Color::opaque(0xff, 0x00, 0x00),
+            "green" => Color::opaque(0x00, 0xff, 0x00),
+            "blue" => Color::opaque(0x00, 0x00, 0xff),
+            "yellow" => Color::opaque(0xff, 0xff, 0x00),
+            "orange" => Color::opaque(0xff, 0x80, 0x00),
+            "purple" => Color::opaque(0x80, 0x00, 0x80),
+            "gray" | "grey" => Color::opaque(0x7f, 0x7f, 0x7f),
+            _ => return None,
+        };
+        Some(color)
+    }
+
+    fn from_lua(value: &Value, default: Color) -> mlua::Result<Self> {
+        let color = match value {
+            Value::Nil => Some(default),
+            Value::Integer(rgba) => Some(Color::from_rgba(*rgba as u32)),
+            Value::Number(rgba) => Some(Color::from_rgba(*rgba as u32)),
+            Value::String(text) => Color::parse(text.to_str()?),
+            _ => None,
+        };
+        color.ok_or_else(|| mlua::Error::RuntimeError(format!("Invalid color {:?}", value)))
+    }
+}
+
+/// What the gui functions draw over the frame, cleared after every frame.
+#[derive(Debug, Clone, PartialEq)]
+enum Shape {
+    Pixel(i32, i32, Color),
+    Line(i32, i32, i32, i32, Color),
+    Box(i32, i32, i32, i32, Color, Color),
+    Text(i32, i32, String, Color, Color),
+}
+
+impl Shape {
+    fn draw(&self, frame: &mut Frame) {
+        let mut pixel = |x: i32, y: i32, color: Color| {
+            if x >= 0 && y >= 0 {
+                frame.blend_pixel(x as usize, y as usize, color.rgb, color.alpha);
+            }
+        };
+        match *self {
+            Shape::Pixel(x, y, color) => pixel(x, y, color),
+            Shape::Line(x1, y1, x2, y2, color) => {
+                let steps = (x2 - x1).abs().max((y2 - y1).abs());
+                for step in 0..=steps {
+                    let t = if steps == 0 {
+                        0.0
+                    } else {
+                        step as f32 / steps as f32
+                    };
+                    let x = x1 as f32 + (x2 - x1) as f32 * t;
+                    let y = y1 as f32 + (y2 - y1) as f32 * t;
+                    pixel(x.round() as i32, y.round() as i32, color);
+                }
+            }
+            Shape::Box(x1, y1, x2, y2, fill, outline) => {
+                let (left, right) = (x1.min(x2), x1.max(x2));
+                let (top, bottom) = (y1.min(y2), y1.max(y2));
+                for y in top..=bottom {
+                    for x in left..=right {
+                        let edge = x == left || x == right || y == top || y == bottom;
+                        pixel(x, y, if edge { outline } else { fill });
+                    }
+                }
+            }
+            Shape::Text(x, y, ref text, color, shadow) => {
+                let (x, y) = (x.max(0) as usize, y.max(0) as usize);
+                draw_text(frame, x + 1, y + 1, text, shadow.rgb, shadow.alpha);
+                draw_text(frame, x, y, text, color.rgb, color.alpha);
+            }
+        }
+    }
+}
+
+/// The machine as scripts see it while they run. Reads come from a copy of the CPU address
+/// space taken when the frame ended, everything else is applied once the script yields.
+#[derive(Default)]
+struct Machine {
+    memory: Vec<Option<u8>>,
+    frame: u64,
+    buttons: u8,
+    writes: Vec<(u16, u8)>,
+    /// Buttons forced down and up for the next frame.
+    pressed: u8,
+    released: u8,
+    shapes: Vec<Shape>,
+}
+
+impl Machine {
+    fn read(&self, address: i64) -> u8 {
+        self.memory
+            .get(address as u16 as usize)
+            .copied()
+            .flatten()
+            .unwrap_or(0)
+    }
+}
+
+/// A Lua script using a subset of the FCEUX API: `memory.readbyte`, `memory.writebyte`,
+/// `joypad.read`, `joypad.set`, `emu.frameadvance`, `emu.framecount`, `emu.registerafter`,
+/// `gui.text`, `gui.box`, `gui.line`, `gui.pixel` and some of their aliases.
+///
+/// The main chunk runs as a coroutine that `emu.frameadvance` yields from, so scripts can
+/// loop forever like they do in FCEUX.
+pub struct LuaScript {
+    lua: Lua,
+    main: RegistryKey,
+    machine: Rc<RefCell<Machine>>,
+    /// The joypad bits the last override replaced, put back before the script runs again.
+    overridden: Option<(u8, u8)>,
+}
+
+impl LuaScript {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let source = fs::read_to_string(path).map_err(|error| error.to_string())?;
+        Self::new(&source, &path.display().to_string())
+    }
+
+    /// Compiles a script, it starts running at the end of the next frame.
+    pub fn new(source: &str, name: &str) -> Result<Self, String> {
+        let lua = Lua::new();
+        let machine = Rc::new(RefCell::new(Machine::default()));
+        install(&lua, &machine).map_err(|error| error.to_string())?;
+        let main = lua
+            .load(source)
+            .set_name(name)
+            .into_function()
+            .and_then(|function| lua.create_thread(function))
+            .and_then(|thread| lua.create_registry_value(thread))
+            .map_err(|error| error.to_string())?;
+        Ok(LuaScript {
+            lua,
+            main,
+            machine,
+            overridden: None,
+        })
+    }
+
+    /// Runs the script until it advances the frame, then the registered callbacks, and
+    /// applies what they did to the machine and to the finished frame.
+    pub fn run_frame(&mut self, cpu: &mut CPU, frame: &mut Frame) -> Result<(), String> {
+        if let Some((mask, bits)) = self.overridden.take() {
+            let joypad = cpu.bus.joypad_mut();
+            joypad.set_buttons(joypad.buttons() & !mask | bits);
+        }
+        {
+            let mut machine = self.machine.borrow_mut();
+            machine.memory = MemoryRegion::Cpu.peek_range(cpu, 0, 0x10000);
+            machine.frame = cpu.bus.frames();
+            machine.buttons = cpu.bus.joypad_mut().buttons();
+        }
+
+        self.run().map_err(|error| error.to_string())?;
+
+        let mut machine = self.machine.borrow_mut();
+        for (address, value) in machine.writes.drain(..) {
+            MemoryRegion::Cpu.poke(cpu, address, value);
+        }
+        let mask = machine.pressed | machine.released;
+        if mask != 0 {
+            let joypad = cpu.bus.joypad_mut();
+            self.overridden = Some((mask, joypad.buttons() & mask));
+            joypad.set_buttons((joypad.buttons() | machine.pressed) & !machine.released);
+            machine.pressed = 0;
+            machine.released = 0;
+        }
+        for shape in machine.shapes.drain(..) {
+            shape.draw(frame);
+        }
+        Ok(())
+    }
+
+    fn run(&self) -> mlua::Result<()> {
+        let callback = |name: &str| -> mlua::Result<()> {
+            if let Some(callback) = self.lua.named_registry_value::<Option<Function>>(name)? {
+                callback.call::<_, ()>(())?;
+            }
+            Ok(())
+        };
+        callback("registerafter")?;
+        let main: Thread = self.lua.registry_value(&self.main)?;
+        if main.status() == ThreadStatus::Resumable {
+            main.resume::<_, ()>(())?;
+        }
+        callback("registerbefore")?;
+        callback("guiregister")
+    }
+}
+
+/// Adds the `memory`, `joypad`, `emu` and `gui` tables.
+fn install(lua: &Lua, machine: &Rc<RefCell<Machine>>) -> mlua::Result<()> {
+    let globals = lua.globals();
+
+    let memory = lua.create_table()?;
+    let m = Rc::clone(machine);
+    let readbyte = lua.create_function(move |_, address: i64| Ok(m.borrow().read(address)))?;
+    memory.set("readbyte", readbyte.clone())?;
+    memory.set("readbyteunsigned", readbyte)?;
+    let m = Rc::clone(machine);
+    memory.set(
+        "readbytesigned",
+        lua.create_function(move |_, address: i64| Ok(m.borrow().read(address) as i8))?,
+    )?;
+    let m = Rc::clone(machine);
+    memory.set(
+        "readword",
+        lua.create_function(move |_, (low, high): (i64, Option<i64>)| {
+            let machine = m.borrow();
+            let high = machine.read(high.unwrap_or(low + 1));
+            Ok(u16::from_le_bytes([machine.read(low), high]))
+        })?,
+    )?;
+    let m = Rc::clone(machine);
+    memory.set(
+        "writebyte",
+        lua.create_function(move |_, (address, value): (i64, i64)| {
+            let (address, value) = (address as u16, value as u8);
+            let mut machine = m.borrow_mut();
+            // Later reads of the same script run see the write
+            if let Some(byte @ Some(_)) = machine.memory.get_mut(address as usize) {
+                *byte = Some(value);
+            }
+            machine.writes.push((address, value));
+            Ok(())
+        })?,
+    )?;
+    globals.set("memory", memory)?;
+
+    let joypad = lua.create_table()?;
+    let m = Rc::clone(machine);
+    let read = lua.create_function(move |lua, port: Option<i64>| {
+        let buttons = match port.unwrap_or(1) {
+            1 => m.borrow().buttons,
+            _ => 0,
+        };
+        let table = lua.create_table()?;
+        for (name, button) in BUTTONS {
+            table.set(name, buttons & button != 0)?;
+        }
+        Ok(table)
+    })?;
+    joypad.set("read", read.clone())?;
+    joypad.set("get", read)?;
+    let m = Rc::clone(machine);
+    let set = lua.create_function(move |_, (port, buttons): (i64, Table)| {
+        if port != 1 {
+            return Ok(());
+        }
+        let mut machine = m.borrow_mut();
+        for (name, button) in BUTTONS {
+            match buttons.get::<_, Value>(name)? {
+                Value::Boolean(true) => machine.pressed |= button,
+                Value::Boolean(false) => machine.released |= button,
+                _ => {}
+            }
+        }
+        Ok(())
+    })?;
+    joypad.set("set", set.clone())?;
+    joypad.set("write", set)?;
+    globals.set("joypad", joypad)?;
+
+    let emu = lua.create_table()?;
+    let m = Rc::clone(machine);
+    emu.set(
+        "framecount",
+        lua.create_function(move |_, ()| Ok(m.borrow().frame))?,
+    )?;
+    emu.set(
+        "message",
+        lua.create_function(|_, text: String| {
+            println!("{}", text);
+            Ok(())
+        })?,
+    )?;
+    for name in CALLBACKS {
+        let register = lua.create_function(move |lua, callback: Option<Function>| {
+            lua.set_named_registry_value(name, callback)
+        })?;
+        match name {
+            "guiregister" => {}
+            name => emu.set(name, register)?,
+        }
+    }
+    globals.set("emu", emu)?;
+
+    let gui = lua.create_table()?;
+    let m = Rc::clone(machine);
+    let pixel = lua.create_function(move |_, (x, y, color): (f64, f64, Value)| {
+        let color = Color::from_lua(&color, Color::WHITE)?;
+        m.borrow_mut()
+            .shapes
+            .push(Shape::Pixel(x as i32, y as i32, color));
+        Ok(())
+    })?;
+    gui.set("pixel", pixel.clone())?;
+    gui.set("setpixel", pixel)?;
+    let m = Rc::clone(machine);
+    let line = lua.create_function(
+        move |_, (x1, y1, x2, y2, color): (f64, f64, f64, f64, Value)| {
+            let color = Color::from_lua(&color, Color::WHITE)?;
+            let line = Shape::Line(x1 as i32, y1 as i32, x2 as i32, y2 as i32, color);
+            m.borrow_mut().shapes.push(line);
+            Ok(())
+        },
+    )?;
+    gui.set("line", line.clone())?;
+    gui.set("drawline", line)?;
+    let m = Rc::clone(machine);
+    let rectangle = lua.create_function(
+        move |_, (x1, y1, x2, y2, fill, outline): (f64, f64, f64, f64, Value, Value)| {
+            let default = Color {
+                alpha: 0.25,
+                ..Color::WHITE
+            };
+            let fill = Color::from_lua(&fill, default)?;
+            let outline = Color::from_lua(&outline, Color::WHITE)?;
+            let rectangle = Shape::Box(x1 as i32, y1 as i32, x2 as i32, y2 as i32, fill, outline);
+            m.borrow_mut().shapes.push(rectangle);
+            Ok(())
+        },
+    )?;
+    gui.set("box", rectangle.clone())?;
+    gui.set("drawbox", rectangle.clone())?;
+    gui.set("rect", rectangle)?;
+    let m = Rc::clone(machine);
+    let text = lua.create_function(
+        move |_, (x, y, text, color, shadow): (f64, f64, String, Value, Value)| {
+            let color = Color::from_lua(&color, Color::WHITE)?;
+            let shadow = Color::from_lua(&shadow, Color::BLACK)?;
+            let text = Shape::Text(x as i32, y as i32, text, color, shadow);
+            m.borrow_mut().shapes.push(text);
+            Ok(())
+        },
+    )?;
+    gui.set("text", text.clone())?;
+    gui.set("drawtext", text)?;
+    gui.set(
+        "register",
+        lua.create_function(|lua, callback: Option<Function>| {
+            lua.set_named_registry_value("guiregister", callback)
+        })?,
+    )?;
+    globals.set("gui", gui)?;
+
+    lua.load("emu.frameadvance = coroutine.yield\nemu.print = print")
+        .set_name("fceux")
+        .exec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::looping_rom;
+
+    fn cpu() -> CPU<'static> {
+        let mut cpu = CPU::new(Bus::new(looping_rom(), |_, _| {}));
+        cpu.power_cycle();
+        cpu
+    }
+
+    #[test]
+    fn test_script() {
+        let mut cpu = cpu();
+        let mut frame = Frame::new();
+        let mut script = LuaScript::new(
+            r##"
+            emu.registerafter(function()
+                memory.writebyte(0x12, memory.readbyte(0x12) + 1)
+            end)
+            memory.writebyte(0x10, 5)
+            emu.frameadvance()
+            memory.writebyte(0x11, memory.readbyte(0x10) + memory.readbyte(0xfffc))
+            joypad.set(1, {A = true, right = true})
+            gui.box(0, 0, 2, 2, "clear", "red")
+            while true do
+                gui.text(10, 10, joypad.read(1).right and "RIGHT" or "-", "#00FF00")
+                emu.frameadvance()
+            end
+            "##,
+            "test",
+        )
+        .unwrap();
+
+        script.run_frame(&mut cpu, &mut frame).unwrap();
+        assert_eq!(cpu.bus.ram_mut()[0x10], 5);
+        // Registered callbacks run from the next frame on
+        assert_eq!(cpu.bus.ram_mut()[0x12], 0);
+
+        script.run_frame(&mut cpu, &mut frame).unwrap();
+        assert_eq!(cpu.bus.ram_mut()[0x11], 5);
+        assert_eq!(cpu.bus.ram_mut()[0x12], 1);
+        assert_eq!(cpu.bus.joypad_mut().buttons(), JOYPAD_A | JOYPAD_RIGHT);
+        assert_eq!(frame.get_pixel(0, 0), (0xff, 0x00, 0x00));
+        assert_eq!(frame.get_pixel(1, 1), (0x00, 0x00, 0x00));
+        // The override only lasts for one frame
+        let mut next = Frame::new();
+        script.run_frame(&mut cpu, &mut next).unwrap();
+        assert_eq!(cpu.bus.joypad_mut().buttons(), 0);
+        assert!(next.data.contains(&0xff));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(LuaScript::new("while true", "broken").is_err());
+
+        let mut script = LuaScript::new("emu.frameadvance()\nnope()", "test").unwrap();
+        let mut cpu = cpu();
+        let mut frame = Frame::new();
+        script.run_frame(&mut cpu, &mut frame).unwrap();
+        let error = script.run_frame(&mut cpu, &mut frame).unwrap_err();
+        assert!(error.contains("nope"), "{}", error);
+    }
+
+    #[test]
+    fn test_colors() {
+        assert_eq!(Color::parse("#FF000080").unwrap().rgb, (0xff, 0, 0));
+        assert_eq!(Color::parse("#00ff00"), Some(Color::opaque(0, 0xff, 0)));
+        assert_eq!(Color::parse("Clear").unwrap().alpha, 0.0);
+        assert_eq!(Color::parse("#12345"), None);
+        assert_eq!(Color::from_rgba(0x0000ffff), Color::opaque(0, 0, 0xff));
+    }
+}
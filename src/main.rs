@@ -1,147 +1,847 @@
-#![allow(dead_code)]
-
-mod bus;
-mod cartridge;
-pub mod cpu;
-mod joypad;
-pub mod opcodes;
-mod ppu;
-mod render;
-mod trace;
-
-use crate::bus::Bus;
-use crate::cartridge::Rom;
-use crate::cpu::CPU;
-use crate::joypad::{
-    Joypad, JOYPAD_A, JOYPAD_B, JOYPAD_DOWN, JOYPAD_LEFT, JOYPAD_RIGHT, JOYPAD_SELECT,
-    JOYPAD_START, JOYPAD_UP,
+mod windows;
+
+use rust_nes::bus::RamInit;
+use rust_nes::clip::ClipBuffer;
+use rust_nes::compare::{CompareOptions, DualInstance, COMPARE_WIDTH};
+use rust_nes::config::{Config, CONFIG_PATH};
+use rust_nes::debugger;
+use rust_nes::disasm::disassemble_prg;
+use rust_nes::emulation::{self, Command};
+use rust_nes::filter::{FilterWorker, VideoFilter, FILTERED_HEIGHT, FILTERED_WIDTH};
+use rust_nes::frontend::{
+    file_name, load_achievements, load_cheats, load_labels, load_state_slot, notify, offer_resume,
+    open_rom, resume_autosave, save_autosave, save_clip, save_screenshot, save_state_slot,
+    start_netplay, toggle_code_data_log, toggle_event_log, toggle_hash_log, toggle_profiler,
+    toggle_recording, toggle_trace, window_title,
+};
+use rust_nes::headless::{self, HeadlessOptions};
+use rust_nes::joypad::{
+    JOYPAD_A, JOYPAD_B, JOYPAD_DOWN, JOYPAD_LEFT, JOYPAD_RIGHT, JOYPAD_SELECT, JOYPAD_START,
+    JOYPAD_UP,
 };
-use crate::ppu::PPU;
-use crate::render::{Frame, PALETTE};
-use crate::trace::trace;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::PixelFormatEnum;
+use rust_nes::logging;
+use rust_nes::menu::RomMenu;
+use rust_nes::movie::Movie;
+use rust_nes::osd::Osd;
+use rust_nes::pacer::FramePacer;
+use rust_nes::recorder::Recorder;
+use rust_nes::render::{self, Frame};
+use rust_nes::scaling::{self, ScaleMode, FRAME_HEIGHT, FRAME_WIDTH};
+use rust_nes::statediff::diff_state_files;
+use rust_nes::watcher::FileWatcher;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
 use std::collections::HashMap;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+use windows::{DebugWindows, View};
+
+/// How often the screen is refreshed while no frames arrive, e.g. when paused.
+const IDLE_REFRESH_INTERVAL: Duration = Duration::from_millis(16);
 
-fn show_tile_bank(chr_rom: &[u8], bank: u8) -> Frame {
-    if bank > 1 {
-        panic!("There is no bank {}", bank);
+/// Finishes the recording and writes the autosave before exiting.
+fn quit(
+    recorder: &mut Option<Recorder>,
+    config: &Config,
+    osd: &mut Osd,
+    emulation: &Sender<Command>,
+    rom_crc: u32,
+) -> ! {
+    if recorder.is_some() {
+        toggle_recording(recorder, config, osd);
     }
+    if config.autosave {
+        if let Err(error) = save_autosave(emulation, &config.state_dir, rom_crc) {
+            println!("Autosave failed: {}", error);
+        }
+    }
+    std::process::exit(0)
+}
 
-    let mut frame = Frame::new();
+/// Save state slot of a number key.
+fn state_slot(keycode: Keycode) -> Option<u8> {
+    match keycode {
+        Keycode::Num0 => Some(0),
+        Keycode::Num1 => Some(1),
+        Keycode::Num2 => Some(2),
+        Keycode::Num3 => Some(3),
+        Keycode::Num4 => Some(4),
+        Keycode::Num5 => Some(5),
+        Keycode::Num6 => Some(6),
+        Keycode::Num7 => Some(7),
+        Keycode::Num8 => Some(8),
+        Keycode::Num9 => Some(9),
+        _ => None,
+    }
+}
 
-    let mut offset_x = 0;
-    let mut offset_y = 0;
-    let offset_rom = bank as usize * 0x1000;
+/// Controller button of each key.
+fn key_map() -> HashMap<Keycode, u8> {
+    let mut key_map = HashMap::new();
+    key_map.insert(Keycode::Down, JOYPAD_DOWN);
+    key_map.insert(Keycode::Up, JOYPAD_UP);
+    key_map.insert(Keycode::Right, JOYPAD_RIGHT);
+    key_map.insert(Keycode::Left, JOYPAD_LEFT);
+    key_map.insert(Keycode::Space, JOYPAD_SELECT);
+    key_map.insert(Keycode::Return, JOYPAD_START);
+    key_map.insert(Keycode::A, JOYPAD_A);
+    key_map.insert(Keycode::S, JOYPAD_B);
+    key_map
+}
 
-    for tile_index in 0x00..=0xff {
-        // Increment row every 32 tiles
-        if tile_index != 0 && tile_index % 32 == 0 {
-            offset_y += 8;
-        }
+fn load_movie(path: &Path) -> Result<Movie, String> {
+    Movie::load(path).map_err(|error| format!("Reading {} failed: {}", path.display(), error))
+}
 
-        // Fetch tile bytes
-        let tile = &chr_rom[(offset_rom + tile_index * 16)..=(offset_rom + tile_index * 16 + 15)];
+/// Shows two instances of the game side by side with the keyboard driving both, until the
+/// window is closed. The first frame they differ goes to the title and stdout.
+fn run_compare(options: CompareOptions) -> Result<(), String> {
+    let open = || open_rom(&options.rom).map_err(|error| format!("Open failed: {}", error));
+    let roms = [open()?, open()?];
+    let region = roms[0].region;
+    let title = format!("{} - compare", window_title(&options.rom, &roms[0]));
+    let [first, second] = &options.sides;
+    let movies = [
+        first.movie.as_deref().map(load_movie).transpose()?,
+        second.movie.as_deref().map(load_movie).transpose()?,
+    ];
+    let mut dual = DualInstance::new(roms, [first.ram_init, second.ram_init], movies);
 
-        for y in 0..=7 {
-            let color_hi = tile[y].reverse_bits();
-            let color_lo = tile[y + 8].reverse_bits();
+    let sdl_context = sdl2::init()?;
+    let window = sdl_context
+        .video()?
+        .window(&title, FRAME_WIDTH * 4, FRAME_HEIGHT * 2)
+        .position_centered()
+        .resizable()
+        .build()
+        .map_err(|error| error.to_string())?;
+    let mut canvas = window
+        .into_canvas()
+        .build()
+        .map_err(|error| error.to_string())?;
+    let creator = canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, COMPARE_WIDTH as u32, 240)
+        .map_err(|error| error.to_string())?;
+    let mut event_pump = sdl_context.event_pump()?;
+    let key_map = key_map();
+    let mut buttons = 0;
+    let mut pacer = FramePacer::new(region.frame_rate());
+    let mut screen = vec![0; COMPARE_WIDTH * 240 * 3];
 
-            for x in 0..=7 {
-                let rgb = match ((color_hi >> x) & 1) << 1 | ((color_lo >> x) & 1) {
-                    0 => PALETTE[0x01],
-                    1 => PALETTE[0x23],
-                    2 => PALETTE[0x27],
-                    3 => PALETTE[0x30],
-                    _ => unreachable!(),
-                };
-                frame.set_pixel(offset_x + x, offset_y + y, rgb)
+    loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => return Ok(()),
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => buttons |= key_map.get(&keycode).copied().unwrap_or(0),
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => buttons &= !key_map.get(&keycode).copied().unwrap_or(0),
+                _ => { /* do nothing */ }
             }
         }
 
-        // Increment column every tile
-        offset_x += 8;
+        if dual.run_frame(buttons) {
+            let divergence = dual.divergence().unwrap();
+            println!("The states differ after frame {}:", divergence.frame);
+            for difference in &divergence.differences {
+                println!("  {}", difference);
+            }
+            canvas
+                .window_mut()
+                .set_title(&format!(
+                    "{} - differ after frame {}",
+                    title, divergence.frame
+                ))
+                .map_err(|error| error.to_string())?;
+        }
+        dual.side_by_side(&mut screen);
+        texture
+            .update(None, &screen, COMPARE_WIDTH * 3)
+            .map_err(|error| error.to_string())?;
+        canvas.copy(&texture, None, None)?;
+        canvas.present();
+        pacer.wait();
+    }
+}
+
+fn run_headless(options: HeadlessOptions) -> Result<(), String> {
+    let rom = open_rom(&options.rom).map_err(|error| format!("Open failed: {}", error))?;
+    let movie = options.movie.as_deref().map(load_movie).transpose()?;
+    // RAM starts zeroed whatever the configuration says, so runs can be compared
+    let Some(path) = &options.stream else {
+        let report = headless::run(
+            rom,
+            RamInit::Zero,
+            options.frames,
+            movie.as_ref(),
+            options.bench,
+        );
+        println!("{}", report);
+        return write_json(options.json.as_deref(), &report.json);
+    };
+    let mut output: Box<dyn Write> = if path.as_os_str() == "-" {
+        Box::new(BufWriter::new(io::stdout().lock()))
+    } else {
+        Box::new(BufWriter::new(File::create(path).map_err(|error| {
+            format!("Opening {} failed: {}", path.display(), error)
+        })?))
+    };
+    let report = headless::stream(
+        rom,
+        RamInit::Zero,
+        options.frames,
+        movie.as_ref(),
+        &mut output,
+    )
+    .map_err(|error| format!("Streaming failed: {}", error))?;
+    // Stdout may be carrying the frames
+    eprintln!("{}", report);
+    write_json(options.json.as_deref(), &report.json)
+}
+
+/// Writes the machine state of `--json` to the file, `-` for stdout.
+fn write_json(path: Option<&Path>, json: &str) -> Result<(), String> {
+    match path {
+        None => Ok(()),
+        Some(path) if path.as_os_str() == "-" => {
+            print!("{}", json);
+            Ok(())
+        }
+        Some(path) => fs::write(path, json)
+            .map_err(|error| format!("Writing {} failed: {}", path.display(), error)),
     }
-    frame
 }
 
 fn main() {
+    // `--log bus=debug` enables diagnostics of a subsystem, the rest takes the other arguments
+    let args = logging::init(std::env::args().collect());
+    if args.len() == 3 && args[1] == "--disassemble" {
+        match open_rom(Path::new(&args[2])) {
+            Ok(rom) => {
+                let labels = load_labels(Path::new(&args[2]), &rom);
+                print!("{}", disassemble_prg(&rom.prg_rom, &labels));
+            }
+            Err(error) => println!("Open failed: {}", error),
+        }
+        return;
+    }
+    if args.len() == 4 && args[1] == "--diff-states" {
+        match diff_state_files(Path::new(&args[2]), Path::new(&args[3])) {
+            Ok(differences) if differences.is_empty() => println!("The states are the same"),
+            Ok(differences) => {
+                for difference in differences {
+                    println!("{}", difference);
+                }
+            }
+            Err(error) => println!("Diff failed: {}", error),
+        }
+        return;
+    }
+
+    // Two instances side by side, to find where they stop agreeing
+    if let Some(options) = CompareOptions::parse(&args[1..]) {
+        std::process::exit(match options.and_then(run_compare) {
+            Ok(()) => 0,
+            Err(error) => {
+                println!("{}", error);
+                1
+            }
+        });
+    }
+
+    // Run or benchmark a number of frames as fast as possible without opening a window
+    if let Some(options) = HeadlessOptions::parse(&args[1..]) {
+        std::process::exit(match options.and_then(run_headless) {
+            Ok(()) => 0,
+            Err(error) => {
+                println!("{}", error);
+                1
+            }
+        });
+    }
+
     // init sdl2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
-        .window("Tile viewer", (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
+        .window("nes_rust", FRAME_WIDTH * 3, FRAME_HEIGHT * 3)
         .position_centered()
+        .resizable()
         .build()
         .unwrap();
 
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    let mut config = Config::load(Path::new(CONFIG_PATH));
+
+    let mut canvas = if config.vsync {
+        window.into_canvas().present_vsync().build().unwrap()
+    } else {
+        window.into_canvas().build().unwrap()
+    };
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(3.0, 3.0).unwrap();
+
+    let mut scale_mode = ScaleMode::Integer;
+    let (window_width, window_height) = canvas.output_size().unwrap();
+    let mut viewport = scaling::viewport(scale_mode, window_width, window_height);
 
     let creator = canvas.texture_creator();
     let mut texture = creator
         .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
         .unwrap();
+    let mut filtered_texture = creator
+        .create_texture_target(
+            PixelFormatEnum::RGB24,
+            FILTERED_WIDTH as u32,
+            FILTERED_HEIGHT as u32,
+        )
+        .unwrap();
+    let mut filter_worker = FilterWorker::new();
 
     //load the game
-    let bytes: Vec<u8> = fs::read("pacman.nes").unwrap();
-    let rom = Rom::new(&bytes);
+    let mut rom_path = args
+        .get(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("pacman.nes"));
+    let rom = open_rom(&rom_path).unwrap();
 
-    let mut frame = Frame::new();
+    let mut title = window_title(&rom_path, &rom);
+    canvas.window_mut().set_title(&title).unwrap();
+    let mut fps_frames = 0;
+    let mut fps_start = Instant::now();
 
-    let mut key_map = HashMap::new();
-    key_map.insert(Keycode::Down, JOYPAD_DOWN);
-    key_map.insert(Keycode::Up, JOYPAD_UP);
-    key_map.insert(Keycode::Right, JOYPAD_RIGHT);
-    key_map.insert(Keycode::Left, JOYPAD_LEFT);
-    key_map.insert(Keycode::Space, JOYPAD_SELECT);
-    key_map.insert(Keycode::Return, JOYPAD_START);
-    key_map.insert(Keycode::A, JOYPAD_A);
-    key_map.insert(Keycode::S, JOYPAD_B);
+    let mut frame = Box::new(Frame::new());
+    let mut display = Frame::new();
+    let mut osd = Osd::new();
+
+    let key_map = key_map();
+
+    config.add_recent_rom(&rom_path);
+    config.apply_profile(rom.crc);
+    config.save();
+
+    let (emulation, commands) = mpsc::channel();
+    let (frame_sender, frames) = render::frame_channel();
+    let (notice_sender, notices) = mpsc::channel();
+    let mut rom_crc = rom.crc;
+    let mut region = rom.region;
+    offer_resume(&config, rom_crc, &mut osd);
+    let mut frame_rate = Some(region.frame_rate() * config.speed as f64 / 100.0);
+    let labels = load_labels(&rom_path, &rom);
+    let profile_labels = labels.clone();
+    emulation::spawn(
+        rom,
+        config.ram_init,
+        frame_rate,
+        config.rewind_seconds,
+        config.state_dir.clone(),
+        commands,
+        frame_sender,
+    );
+    emulation
+        .send(Command::Overclock(config.overclock))
+        .unwrap();
+    load_achievements(&emulation, &notice_sender, &rom_path);
+    load_cheats(
+        &emulation,
+        &notice_sender,
+        &config.cheat_dir,
+        rom_crc,
+        &rom_path,
+    );
+    start_netplay(&emulation, &notice_sender, rom_crc);
+    #[cfg(feature = "remote")]
+    rust_nes::frontend::start_remote_server(&emulation);
+    #[cfg(any(feature = "lua", feature = "rhai"))]
+    rust_nes::frontend::start_scripts(&emulation);
+    // The terminal debugger replaces the plain stdin console when asked for
+    #[cfg(feature = "tui")]
+    if args.iter().any(|arg| arg == "--tui") {
+        let emulation = emulation.clone();
+        std::thread::spawn(move || rust_nes::tui::run(emulation, labels));
+    } else {
+        debugger::spawn_console(emulation.clone(), labels, config.state_dir.clone());
+    }
+    #[cfg(not(feature = "tui"))]
+    debugger::spawn_console(emulation.clone(), labels, config.state_dir.clone());
+
+    let mut watcher = FileWatcher::new(&rom_path);
+    let mut debug_windows = DebugWindows::new(video_subsystem.clone());
+    let mut paused = false;
+    let mut emulation_paused = false;
+    let mut menu: Option<RomMenu> = None;
+    let mut fast_forward = false;
+    let mut frame_skip = 0;
+    let mut recorder: Option<Recorder> = None;
+    let mut hash_log = None;
+    let mut code_data_log = false;
+    let mut profiling = false;
+    let mut event_log = false;
+    let mut tracing = false;
+    let mut clip = ClipBuffer::new(config.gif_seconds, region.frame_rate());
+
+    // Present every new frame, but keep refreshing the screen and handling input while paused
+    loop {
+        let mut new_frames = match frames.recv_timeout(IDLE_REFRESH_INTERVAL) {
+            Ok(new_frame) => vec![new_frame],
+            Err(RecvTimeoutError::Timeout) => vec![],
+            // The emulation thread panicked and already reported why
+            Err(RecvTimeoutError::Disconnected) => std::process::exit(1),
+        };
+        new_frames.extend(frames.try_iter());
 
-    // the game cycle
-    let bus = Bus::new(rom, move |ppu: &PPU, joypad: &mut Joypad| {
-        render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
+        for new_frame in new_frames {
+            fps_frames += 1;
+            clip.push(&new_frame);
+            if let Some(running) = recorder.as_mut() {
+                if let Err(error) = running.write_frame(&new_frame) {
+                    notify(&mut osd, format!("Recording stopped: {}", error));
+                    recorder = None;
+                }
+            }
+            frames.recycle(std::mem::replace(&mut frame, new_frame));
+        }
+        for message in notices.try_iter() {
+            notify(&mut osd, message);
+        }
 
-        canvas.copy(&texture, None, None).unwrap();
+        // Update the frame rate in the title once per second
+        let elapsed = fps_start.elapsed().as_secs_f64();
+        if elapsed >= 1.0 {
+            let fps = fps_frames as f64 / elapsed;
+            canvas
+                .window_mut()
+                .set_title(&format!("{} - {:.1} FPS", title, fps))
+                .unwrap();
+            fps_frames = 0;
+            fps_start = Instant::now();
+        }
+
+        // Draw the messages on a copy, so recordings and a paused frame stay clean. Without
+        // any the frame goes to the texture as it is
+        let shown = if menu.is_some() || !osd.is_empty() {
+            display.data = frame.data;
+            if let Some(menu) = &menu {
+                menu.draw(&mut display);
+            }
+            osd.draw(&mut display);
+            &display
+        } else {
+            &frame
+        };
+        // A filter runs on its own thread, so the screen shows the previous filtered frame
+        let filtered = config.video_filter != VideoFilter::None && {
+            filter_worker.submit(config.video_filter, shown);
+            match filter_worker.latest() {
+                Some(data) => {
+                    filtered_texture
+                        .update(None, data, FILTERED_WIDTH * 3)
+                        .unwrap();
+                    true
+                }
+                None => false,
+            }
+        };
+        if !filtered {
+            texture.update(None, &shown.data, 256 * 3).unwrap();
+        }
+        osd.tick();
+
+        canvas.set_draw_color(Color::BLACK);
+        canvas.clear();
+        canvas
+            .copy(
+                if filtered {
+                    &filtered_texture
+                } else {
+                    &texture
+                },
+                None,
+                Rect::new(viewport.x, viewport.y, viewport.width, viewport.height),
+            )
+            .unwrap();
 
         canvas.present();
+        debug_windows.update(&emulation);
+
+        // Reload the ROM when it was rebuilt, checked before the events so the menu wins
+        let mut load_path = None;
+        let mut reload = false;
+        if config.hot_reload && watcher.changed() {
+            load_path = Some(watcher.path().to_path_buf());
+            reload = true;
+        }
+
         for event in event_pump.poll_iter() {
+            let event = match debug_windows.route(event) {
+                Some(event) => event,
+                None => continue,
+            };
+
+            // The open menu takes all keyboard input
+            if let Some(open_menu) = menu.as_mut() {
+                match event {
+                    Event::Quit { .. }
+                    | Event::Window {
+                        win_event: WindowEvent::Close,
+                        ..
+                    } => quit(&mut recorder, &config, &mut osd, &emulation, rom_crc),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Up),
+                        ..
+                    } => open_menu.up(),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Down),
+                        ..
+                    } => open_menu.down(),
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Escape | Keycode::F3),
+                        repeat: false,
+                        ..
+                    } => menu = None,
+                    Event::KeyDown {
+                        keycode: Some(Keycode::Return),
+                        repeat: false,
+                        ..
+                    } => {
+                        load_path = open_menu.selected().map(Path::to_path_buf);
+                        reload = false;
+                        menu = None;
+                    }
+                    _ => { /* do nothing */ }
+                }
+                continue;
+            }
+
             match event {
                 Event::Quit { .. }
+                | Event::Window {
+                    win_event: WindowEvent::Close,
+                    ..
+                }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
+                } => quit(&mut recorder, &config, &mut osd, &emulation, rom_crc),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    repeat: false,
+                    ..
+                } => resume_autosave(&emulation, &notice_sender, &config.state_dir, rom_crc),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    repeat: false,
+                    ..
+                } => {
+                    scale_mode = scale_mode.next();
+                    osd.push(format!("Scaling: {:?}", scale_mode));
+                    let (width, height) = canvas.output_size().unwrap();
+                    viewport = scaling::viewport(scale_mode, width, height);
+                }
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        emulation.send(Command::PowerCycle).unwrap();
+                        osd.push("Power cycle");
+                    } else {
+                        emulation.send(Command::Reset).unwrap();
+                        osd.push("Reset");
+                    }
+                }
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    repeat: false,
+                    ..
+                } => {
+                    let dir = rom_path
+                        .parent()
+                        .filter(|dir| !dir.as_os_str().is_empty())
+                        .unwrap_or(Path::new("."));
+                    menu = Some(RomMenu::new(&config.recent_roms, dir));
+                }
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => debug_windows.toggle(View::Nametables),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    repeat: false,
+                    ..
+                } => debug_windows.toggle(View::PatternTables),
+
+                // Shift+number saves a state, the number alone loads it
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if state_slot(keycode).is_some() => {
+                    let slot = state_slot(keycode).unwrap();
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        save_state_slot(
+                            &emulation,
+                            &notice_sender,
+                            &config.state_dir,
+                            rom_crc,
+                            slot,
+                        );
+                    } else {
+                        load_state_slot(
+                            &emulation,
+                            &notice_sender,
+                            &config.state_dir,
+                            rom_crc,
+                            slot,
+                        );
+                    }
+                }
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    repeat: false,
+                    ..
+                } => save_screenshot(&frame, &config.screenshot_dir, &mut osd),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    repeat: false,
+                    ..
+                } => toggle_hash_log(
+                    &mut hash_log,
+                    &emulation,
+                    &config.state_dir,
+                    rom_crc,
+                    &mut osd,
+                ),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    repeat: false,
+                    ..
+                } => {
+                    toggle_code_data_log(&mut code_data_log, &emulation, &notice_sender, &rom_path)
+                }
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => toggle_profiler(
+                    &mut profiling,
+                    &emulation,
+                    &notice_sender,
+                    &rom_path,
+                    &profile_labels,
+                ),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::E),
+                    repeat: false,
+                    ..
+                } => toggle_event_log(&mut event_log, &emulation, &notice_sender, &rom_path),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::T),
+                    repeat: false,
+                    ..
+                } => toggle_trace(
+                    &mut tracing,
+                    &emulation,
+                    &notice_sender,
+                    &rom_path,
+                    config.trace_format,
+                    &profile_labels,
+                ),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    repeat: false,
+                    ..
+                } => save_clip(&clip, &config.screenshot_dir, &mut osd),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => toggle_recording(&mut recorder, &config, &mut osd),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    repeat: false,
+                    ..
+                } => {
+                    paused = !paused;
+                    osd.push(if paused { "Paused" } else { "Resumed" });
+                }
+
+                // Run exactly one frame, the emulation thread pauses again by itself
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backslash),
+                    ..
+                } => {
+                    paused = true;
+                    emulation_paused = true;
+                    emulation.send(Command::FrameAdvance).unwrap();
+                }
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    repeat: false,
+                    ..
+                } => fast_forward = true,
+                Event::KeyUp {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => fast_forward = false,
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::V),
+                    repeat: false,
+                    ..
+                } => {
+                    config.video_filter = config.video_filter.next();
+                    config.save();
+                    osd.push(format!("Filter: {}", config.video_filter.name()));
+                }
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    repeat: false,
+                    ..
+                } => emulation.send(Command::Rewind(true)).unwrap(),
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => emulation.send(Command::Rewind(false)).unwrap(),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::Equals),
+                    ..
+                } => {
+                    config.increase_speed();
+                    config.save();
+                    osd.push(format!("Speed {}%", config.speed));
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Minus),
+                    ..
+                } => {
+                    config.decrease_speed();
+                    config.save();
+                    osd.push(format!("Speed {}%", config.speed));
+                }
+
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                } => {
+                    let (width, height) = canvas.output_size().unwrap();
+                    viewport = scaling::viewport(scale_mode, width, height);
+                }
 
                 Event::KeyDown { keycode, .. } => {
                     if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, true);
+                        emulation.send(Command::Button(*key, true)).unwrap();
                     }
                 }
                 Event::KeyUp { keycode, .. } => {
                     if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, false);
+                        emulation.send(Command::Button(*key, false)).unwrap();
                     }
                 }
 
                 _ => { /* do nothing */ }
             }
         }
-    });
 
-    let mut cpu = CPU::new(bus);
+        if let Some(path) = load_path {
+            match open_rom(&path) {
+                Ok(rom) => {
+                    title = window_title(&path, &rom);
+                    rom_crc = rom.crc;
+                    region = rom.region;
+                    canvas.window_mut().set_title(&title).unwrap();
+                    config.add_recent_rom(&path);
+                    config.apply_profile(rom.crc);
+                    config.save();
+                    if reload {
+                        osd.push(format!("Reloaded {}", file_name(&path)));
+                    } else {
+                        osd.push(format!("Loaded {}", file_name(&path)));
+                        if config.has_profile() {
+                            osd.push("Game profile applied");
+                        }
+                        offer_resume(&config, rom_crc, &mut osd);
+                    }
+                    let command = if reload {
+                        Command::ReloadRom(rom, config.ram_init)
+                    } else {
+                        Command::LoadRom(rom, config.ram_init)
+                    };
+                    emulation.send(command).unwrap();
+                    emulation
+                        .send(Command::Overclock(config.overclock))
+                        .unwrap();
+                    load_achievements(&emulation, &notice_sender, &path);
+                    load_cheats(
+                        &emulation,
+                        &notice_sender,
+                        &config.cheat_dir,
+                        rom_crc,
+                        &path,
+                    );
+                    watcher = FileWatcher::new(&path);
+                    rom_path = path;
+                }
+                Err(error) => notify(&mut osd, format!("Open failed: {}", error)),
+            }
+        }
 
-    cpu.reset();
-    cpu.run(false, 0);
+        // Only tell the emulation thread about changes
+        let pause = paused || menu.is_some();
+        if pause != emulation_paused {
+            emulation.send(Command::Pause(pause)).unwrap();
+            emulation_paused = pause;
+        }
 
-    // // nestest code
-    // cpu.pc = 0xc000;
-    // cpu.run_with_callback(move |cpu| {
-    //     println!("{}", trace(cpu));
-    // }, false, 0);
+        let speed = config.speed as f64 / 100.0;
+        let multiplier = if fast_forward {
+            config.fast_forward.multiplier()
+        } else {
+            Some(1.0)
+        };
+        let rate = multiplier.map(|multiplier| region.frame_rate() * speed * multiplier);
+        if rate != frame_rate {
+            emulation.send(Command::FrameRate(rate)).unwrap();
+            frame_rate = rate;
+        }
+        let skip = if fast_forward { config.frame_skip } else { 0 };
+        if skip != frame_skip {
+            emulation.send(Command::FrameSkip(skip)).unwrap();
+            frame_skip = skip;
+        }
+    }
 }
@@ -0,0 +1,154 @@
+use crate::cpu::{Mem, CPU};
+
+/// Memory that the hex viewer can show and edit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryRegion {
+    /// The CPU address space, as seen through the bus.
+    Cpu,
+    Vram,
+    Oam,
+    Palettes,
+}
+
+impl MemoryRegion {
+    pub const ALL: [MemoryRegion; 4] = [
+        MemoryRegion::Cpu,
+        MemoryRegion::Vram,
+        MemoryRegion::Oam,
+        MemoryRegion::Palettes,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MemoryRegion::Cpu => "CPU",
+            MemoryRegion::Vram => "VRAM",
+            MemoryRegion::Oam => "OAM",
+            MemoryRegion::Palettes => "Palettes",
+        }
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            MemoryRegion::Cpu => 0x10000,
+            MemoryRegion::Vram => 0x800,
+            MemoryRegion::Oam => 0x100,
+            MemoryRegion::Palettes => 0x20,
+        }
+    }
+
+    /// Reads a byte without side effects, I/O registers and unmapped addresses read as `None`.
+    pub fn peek(self, cpu: &mut CPU, address: u16) -> Option<u8> {
+        let ppu = &cpu.bus.ppu;
+        match self {
+            MemoryRegion::Cpu => cpu.bus.peek(address),
+            MemoryRegion::Vram => ppu.vram.get(address as usize).copied(),
+            MemoryRegion::Oam => ppu.oam_data.get(address as usize).copied(),
+            MemoryRegion::Palettes => ppu.palette_table.get(address as usize).copied(),
+        }
+    }
+
+    /// Reads `length` bytes from `start`, e.g. a page of the hex viewer.
+    pub fn peek_range(self, cpu: &mut CPU, start: u16, length: usize) -> Vec<Option<u8>> {
+        (0..length)
+            .map(|offset| start as usize + offset)
+            .map(|address| {
+                if address < self.size() {
+                    self.peek(cpu, address as u16)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Writes a byte the way the CPU would, so writes to registers take effect, without
+    /// logging the write or tripping watches. Returns false for ROM and read-only registers.
+    pub fn poke(self, cpu: &mut CPU, address: u16, value: u8) -> bool {
+        let ppu = &mut cpu.bus.ppu;
+        let byte = match self {
+            MemoryRegion::Cpu => {
+                let writable = match address {
+                    0x2000..=0x3fff => address & 0x2007 != 0x2002,
+                    0x0000..=0x1fff | 0x4000..=0x4017 => true,
+                    _ => false,
+                };
+                if writable {
+                    cpu.bus.set_in_callback(true);
+                    cpu.write(address, value);
+                    cpu.bus.set_in_callback(false);
+                }
+                return writable;
+            }
+            MemoryRegion::Vram => {
+                ppu.mark_vram_dirty(address as usize);
+                ppu.vram.get_mut(address as usize)
+            }
+            MemoryRegion::Oam => ppu.oam_data.get_mut(address as usize),
+            MemoryRegion::Palettes => {
+                ppu.mark_palette_dirty();
+                ppu.palette_table.get_mut(address as usize)
+            }
+        };
+        match byte {
+            Some(byte) => {
+                *byte = value;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_peek_and_poke() {
+        let mut program = vec![0; 0x8000];
+        program[0] = 0xa9;
+        let mut cpu = CPU::new(Bus::new(test_rom(program), |_, _| {}));
+
+        assert!(MemoryRegion::Cpu.poke(&mut cpu, 0x0810, 0x42));
+        assert_eq!(MemoryRegion::Cpu.peek(&mut cpu, 0x0010), Some(0x42));
+        assert_eq!(MemoryRegion::Cpu.peek(&mut cpu, 0x8000), Some(0xa9));
+        assert_eq!(MemoryRegion::Cpu.peek(&mut cpu, 0x2002), None);
+        assert!(!MemoryRegion::Cpu.poke(&mut cpu, 0x8000, 0));
+        assert!(!MemoryRegion::Cpu.poke(&mut cpu, 0x2002, 0));
+
+        // OAM writes go through the PPU registers
+        assert!(MemoryRegion::Cpu.poke(&mut cpu, 0x2003, 0x10));
+        assert!(MemoryRegion::Cpu.poke(&mut cpu, 0x2004, 0x77));
+        assert_eq!(MemoryRegion::Oam.peek(&mut cpu, 0x10), Some(0x77));
+
+        assert!(MemoryRegion::Palettes.poke(&mut cpu, 0x1f, 0x30));
+        assert!(!MemoryRegion::Palettes.poke(&mut cpu, 0x20, 0x30));
+        assert_eq!(
+            MemoryRegion::Palettes.peek_range(&mut cpu, 0x1e, 3),
+            [Some(0), Some(0x30), None]
+        );
+    }
+
+    #[test]
+    fn test_peek_and_poke_are_not_logged() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        cpu.bus.start_code_data_log();
+        cpu.bus.start_heatmap();
+        cpu.bus.watch_write(0x0010, true);
+        cpu.bus.watch_ppu_write(0x2001, true);
+
+        MemoryRegion::Cpu.peek(&mut cpu, 0x0010);
+        MemoryRegion::Cpu.peek(&mut cpu, 0x8100);
+        assert!(MemoryRegion::Cpu.poke(&mut cpu, 0x0010, 0x42));
+        assert!(MemoryRegion::Cpu.poke(&mut cpu, 0x2001, 0x1e));
+
+        assert_eq!(cpu.bus.code_data_log().unwrap().prg()[0x100], 0);
+        let heatmap = cpu.bus.heatmap().unwrap();
+        assert_eq!((heatmap.reads(0x0010), heatmap.writes(0x0010)), (0, 0));
+        assert!(cpu.bus.take_watched_writes().is_empty());
+        assert_eq!(cpu.bus.take_ppu_write(), None);
+        assert_eq!(MemoryRegion::Cpu.peek(&mut cpu, 0x0010), Some(0x42));
+    }
+}
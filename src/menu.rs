@@ -0,0 +1,149 @@
+use crate::osd::draw_text;
+use crate::render::Frame;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const VISIBLE_ENTRIES: usize = 18;
+const LINE_HEIGHT: usize = 11;
+const MAX_NAME_LENGTH: usize = 38;
+
+const TEXT_COLOR: (u8, u8, u8) = (0xff, 0xff, 0xff);
+const SELECTED_COLOR: (u8, u8, u8) = (0xff, 0xd2, 0x30);
+const BACKGROUND_COLOR: (u8, u8, u8) = (0x00, 0x00, 0x00);
+
+/// Minimal in-emulator ROM picker listing recent ROMs followed by the ROMs in a directory.
+pub struct RomMenu {
+    entries: Vec<PathBuf>,
+    selected: usize,
+}
+
+impl RomMenu {
+    pub fn new(recent_roms: &[PathBuf], dir: &Path) -> Self {
+        let mut entries = recent_roms.to_vec();
+        for rom in list_roms(dir) {
+            if !entries.contains(&rom) {
+                entries.push(rom);
+            }
+        }
+
+        RomMenu {
+            entries,
+            selected: 0,
+        }
+    }
+
+    pub fn up(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn down(&mut self) {
+        if self.selected + 1 < self.entries.len() {
+            self.selected += 1;
+        }
+    }
+
+    pub fn selected(&self) -> Option<&Path> {
+        self.entries.get(self.selected).map(PathBuf::as_path)
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        for y in 0..240 {
+            for x in 0..256 {
+                frame.blend_pixel(x, y, BACKGROUND_COLOR, 0.8);
+            }
+        }
+
+        draw_text(frame, 8, 8, "Open ROM", TEXT_COLOR, 1.0);
+        if self.entries.is_empty() {
+            draw_text(
+                frame,
+                8,
+                8 + 2 * LINE_HEIGHT,
+                "No ROMs found",
+                TEXT_COLOR,
+                1.0,
+            );
+            return;
+        }
+
+        // Scroll so the selected entry stays visible
+        let first = self.selected.saturating_sub(VISIBLE_ENTRIES - 1);
+        for (row, (i, path)) in self
+            .entries
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(VISIBLE_ENTRIES)
+            .enumerate()
+        {
+            let color = if i == self.selected {
+                SELECTED_COLOR
+            } else {
+                TEXT_COLOR
+            };
+            let name: String = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default()
+                .chars()
+                .take(MAX_NAME_LENGTH)
+                .collect();
+            draw_text(frame, 14, 8 + (row + 2) * LINE_HEIGHT, &name, color, 1.0);
+            if i == self.selected {
+                draw_text(frame, 6, 8 + (row + 2) * LINE_HEIGHT, ">", color, 1.0);
+            }
+        }
+    }
+}
+
+/// Lists the iNES files in a directory, sorted by name.
+pub fn list_roms(dir: &Path) -> Vec<PathBuf> {
+    let mut roms: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    path.extension()
+                        .map(|extension| extension.eq_ignore_ascii_case("nes"))
+                        .unwrap_or(false)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    roms.sort();
+    roms
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recent_roms_first_without_duplicates() {
+        let dir = std::env::temp_dir().join("nes_rust_test_rom_menu");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.nes"), []).unwrap();
+        fs::write(dir.join("a.NES"), []).unwrap();
+        fs::write(dir.join("notes.txt"), []).unwrap();
+
+        let recent = vec![dir.join("b.nes"), PathBuf::from("elsewhere.nes")];
+        let mut menu = RomMenu::new(&recent, &dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            menu.entries,
+            vec![
+                dir.join("b.nes"),
+                PathBuf::from("elsewhere.nes"),
+                dir.join("a.NES")
+            ]
+        );
+
+        menu.up();
+        assert_eq!(menu.selected(), Some(dir.join("b.nes").as_path()));
+        for _ in 0..5 {
+            menu.down();
+        }
+        assert_eq!(menu.selected(), Some(dir.join("a.NES").as_path()));
+    }
+}
@@ -0,0 +1,113 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Bits of the command column of an .fm2 movie.
+const FM2_SOFT_RESET: u8 = 0b01;
+const FM2_HARD_RESET: u8 = 0b10;
+
+/// Input for a single frame, applied before the frame runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MovieFrame {
+    /// Buttons of the first controller, one `JOYPAD_*` bit each.
+    pub buttons: u8,
+    pub reset: bool,
+    pub power_cycle: bool,
+}
+
+/// Input recorded for every frame since power-on, read from FCEUX .fm2 movies.
+/// Only the first controller is used.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Movie {
+    frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    /// Parses the input lines like `|0|R..U...A|||`, header lines are skipped.
+    pub fn parse_fm2(text: &str) -> Result<Self, String> {
+        let mut frames = vec![];
+        for (index, line) in text.lines().enumerate() {
+            let Some(line) = line.trim_end().strip_prefix('|') else {
+                continue;
+            };
+            let error = |message: String| format!("Line {}: {}", index + 1, message);
+
+            let mut columns = line.split('|');
+            let commands = columns.next().unwrap_or_default();
+            let commands: u8 = commands
+                .parse()
+                .map_err(|_| error(format!("Invalid command {:?}", commands)))?;
+            let controller = columns.next().unwrap_or_default();
+            let buttons = match controller.len() {
+                // No controller plugged in
+                0 => 0,
+                // The columns are RLDUTSBA, the order of the bits from the highest
+                8 => controller
+                    .bytes()
+                    .enumerate()
+                    .filter(|(_, held)| !matches!(held, b'.' | b' '))
+                    .fold(0, |buttons, (column, _)| buttons | 1 << (7 - column)),
+                _ => return Err(error(format!("Invalid controller {:?}", controller))),
+            };
+            frames.push(MovieFrame {
+                buttons,
+                reset: commands & FM2_SOFT_RESET != 0,
+                power_cycle: commands & FM2_HARD_RESET != 0,
+            });
+        }
+        Ok(Movie { frames })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Self::parse_fm2(&text).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    /// Number of frames with input.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Input of a frame, counted from 0 at power-on. Nothing is held after the movie ends.
+    pub fn frame(&self, frame: u64) -> MovieFrame {
+        self.frames.get(frame as usize).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::joypad::{JOYPAD_A, JOYPAD_RIGHT, JOYPAD_START, JOYPAD_UP};
+
+    #[test]
+    fn test_parse_fm2() {
+        let movie =
+            Movie::parse_fm2("version 3\nromFilename game\n|2|........|||\n|0|R..UT..A|||\n|1||||")
+                .unwrap();
+        assert_eq!(movie.len(), 3);
+        assert_eq!(
+            movie.frame(0),
+            MovieFrame {
+                buttons: 0,
+                reset: false,
+                power_cycle: true
+            }
+        );
+        assert_eq!(
+            movie.frame(1).buttons,
+            JOYPAD_RIGHT | JOYPAD_UP | JOYPAD_START | JOYPAD_A
+        );
+        assert!(movie.frame(2).reset);
+        assert_eq!(movie.frame(3), MovieFrame::default());
+
+        assert_eq!(
+            Movie::parse_fm2("|0|RL|||").unwrap_err(),
+            "Line 1: Invalid controller \"RL\""
+        );
+        assert!(Movie::parse_fm2("|x|........|||").is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use crate::trace::trace;
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Start of the automated mode of nestest, which runs every test without needing a PPU.
+pub const NESTEST_START: u16 = 0xc000;
+/// Matching lines shown before the first difference.
+pub const DIVERGENCE_CONTEXT: usize = 5;
+
+/// The first trace line that differs from the reference log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Line number in the reference log, starting at 1.
+    pub line: usize,
+    /// The lines right before, which matched.
+    pub context: Vec<String>,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Line {} differs from the reference log:", self.line)?;
+        for line in &self.context {
+            writeln!(f, "  {}", line)?;
+        }
+        writeln!(f, "- {}", self.expected)?;
+        write!(f, "+ {}", self.actual)
+    }
+}
+
+/// Runs nestest from its automated entry point and compares the trace with a reference log in
+/// the nestest.log format, returning the number of lines that matched.
+///
+/// There is no APU, so the values read from its registers are not compared. The registers and
+/// cycle counts on those lines still are.
+pub fn compare(rom: Rom, reference: &str) -> Result<usize, Divergence> {
+    let mut cpu = CPU::new(Bus::new(rom, |_, _| {}));
+    cpu.power_cycle();
+    cpu.pc = NESTEST_START;
+
+    let mut context = VecDeque::with_capacity(DIVERGENCE_CONTEXT);
+    let mut matched = 0;
+    for (index, expected) in reference.lines().enumerate() {
+        let actual = trace(&mut cpu);
+        if comparable(&actual) != comparable(expected) {
+            return Err(Divergence {
+                line: index + 1,
+                context: context.into(),
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+        if context.len() == DIVERGENCE_CONTEXT {
+            context.pop_front();
+        }
+        context.push_back(actual);
+        matched += 1;
+        cpu.step();
+    }
+    Ok(matched)
+}
+
+/// Leaves out the disassembly of instructions that access APU registers, whose values would
+/// show up there.
+fn comparable(line: &str) -> String {
+    match (line.get(..16), line.get(16..48), line.get(48..)) {
+        (Some(bytes), Some(disassembly), Some(registers)) if disassembly.contains(" $40") => {
+            format!("{}{}", bytes, registers)
+        }
+        _ => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_nestest_log() {
+        let rom = Rom::new(&fs::read("nestest.nes").unwrap()).unwrap();
+        let reference = fs::read_to_string("nestest.log").unwrap();
+        if let Err(divergence) = compare(rom, &reference) {
+            panic!("{}", divergence);
+        }
+    }
+
+    #[test]
+    fn test_divergence() {
+        let rom = Rom::new(&fs::read("nestest.nes").unwrap()).unwrap();
+        let reference = fs::read_to_string("nestest.log").unwrap();
+        let mut lines: Vec<&str> = reference.lines().take(10).collect();
+        let changed = lines[7].replace("A:00", "A:01");
+        lines[7] = &changed;
+
+        let divergence = compare(rom, &lines.join("\n")).unwrap_err();
+        assert_eq!(divergence.line, 8);
+        assert_eq!(divergence.context.len(), DIVERGENCE_CONTEXT);
+        assert_eq!(divergence.context[4], lines[6]);
+        assert_eq!(divergence.expected, changed);
+        assert!(divergence
+            .to_string()
+            .starts_with("Line 8 differs from the reference log:\n"));
+    }
+}
@@ -0,0 +1,545 @@
+use crate::bus::RamInit;
+use crate::cpu::CPU;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Frames the session may run ahead of the inputs of the other player, so at most this many
+/// frames run again after a wrong guess.
+pub const MAX_ROLLBACK: u64 = 8;
+
+/// Frames local input is held back by, which hides that much latency without any rollback.
+pub const DEFAULT_INPUT_DELAY: u8 = 2;
+
+/// How long the other player may be silent before the session ends.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often inputs are sent again while waiting for the other player.
+const RESEND_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Local inputs sent in one packet at most, older ones are sent again later.
+const MAX_INPUTS_PER_PACKET: usize = 64;
+
+const HELLO: u8 = b'H';
+const INPUTS: u8 = b'I';
+
+/// Rollback for two players, without any networking: remote inputs that have not arrived are
+/// guessed to be the last ones that did, and when a guess turns out wrong the frames since
+/// are run again with the actual inputs.
+///
+/// Both machines have to start from the same state, and call `before_frame` right before each
+/// frame runs.
+pub struct Rollback {
+    /// 0 for the first controller, 1 for the second.
+    local_player: usize,
+    /// The next frame to run, counted from the start of the session.
+    frame: u64,
+    input_delay: u64,
+    /// Inputs of both players by frame, the local ones are known ahead by the input delay.
+    inputs: [BTreeMap<u64, u8>; 2],
+    /// The first frame without remote input, all earlier frames are final.
+    confirmed: u64,
+    /// Remote inputs guessed for frames that already ran.
+    predictions: BTreeMap<u64, u8>,
+    /// The first frame that ran with a wrong guess.
+    mispredicted: Option<u64>,
+    /// States from right before the frames that may have to run again, oldest first.
+    states: VecDeque<(u64, Vec<u8>)>,
+    /// Frames that ran again, for statistics.
+    pub resimulated_frames: u64,
+}
+
+impl Rollback {
+    pub fn new(local_player: usize, input_delay: u8) -> Self {
+        let input_delay = input_delay as u64;
+        // Nobody presses anything in the frames before the first delayed input
+        let nothing: BTreeMap<u64, u8> = (0..input_delay).map(|frame| (frame, 0)).collect();
+        Rollback {
+            local_player,
+            frame: 0,
+            input_delay,
+            inputs: [nothing.clone(), nothing],
+            confirmed: input_delay,
+            predictions: BTreeMap::new(),
+            mispredicted: None,
+            states: VecDeque::new(),
+            resimulated_frames: 0,
+        }
+    }
+
+    /// The next frame to run.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Sets the local buttons of the frame `input_delay` frames from now, returns that frame.
+    pub fn add_local_input(&mut self, buttons: u8) -> u64 {
+        let frame = self.frame + self.input_delay;
+        self.inputs[self.local_player].insert(frame, buttons);
+        frame
+    }
+
+    /// Local inputs from `frame` on, as far as they are known.
+    pub fn local_inputs(&self, frame: u64) -> impl Iterator<Item = (u64, u8)> + '_ {
+        self.inputs[self.local_player]
+            .range(frame..)
+            .map(|(&frame, &buttons)| (frame, buttons))
+    }
+
+    /// Takes an input of the other player, inputs of frames that are already known are
+    /// ignored so they can be sent more than once.
+    pub fn add_remote_input(&mut self, frame: u64, buttons: u8) {
+        let remote = &mut self.inputs[1 - self.local_player];
+        if frame < self.confirmed || remote.contains_key(&frame) {
+            return;
+        }
+        remote.insert(frame, buttons);
+        while remote.contains_key(&self.confirmed) {
+            self.confirmed += 1;
+        }
+        if self
+            .predictions
+            .remove(&frame)
+            .is_some_and(|guess| guess != buttons)
+        {
+            self.mispredicted = Some(self.mispredicted.map_or(frame, |first| first.min(frame)));
+        }
+    }
+
+    /// The first frame without input of the other player.
+    pub fn confirmed(&self) -> u64 {
+        self.confirmed
+    }
+
+    /// Whether the next frame is close enough to the remote inputs to run.
+    pub fn can_advance(&self) -> bool {
+        self.frame < self.confirmed + MAX_ROLLBACK
+    }
+
+    /// Runs the frames with wrong guesses again, then sets the controllers for the next frame.
+    pub fn before_frame(&mut self, cpu: &mut CPU) {
+        if let Some(first) = self.mispredicted.take() {
+            // There is always a state, the confirmed frames only move past a guess by fixing it
+            let position = self
+                .states
+                .iter()
+                .position(|&(frame, _)| frame == first)
+                .unwrap();
+            // Loading a state of this machine cannot fail
+            let _ = cpu.state_from_bytes(&self.states[position].1);
+            self.states.truncate(position);
+            for frame in first..self.frame {
+                self.prepare(cpu, frame);
+                run_frame(cpu);
+                self.resimulated_frames += 1;
+            }
+        }
+        self.prepare(cpu, self.frame);
+        self.frame += 1;
+    }
+
+    /// Saves the state before `frame` and sets the inputs it runs with.
+    fn prepare(&mut self, cpu: &mut CPU, frame: u64) {
+        // Frames before the confirmed one never run again
+        while self
+            .states
+            .front()
+            .is_some_and(|&(state_frame, _)| state_frame < self.confirmed)
+        {
+            self.states.pop_front();
+        }
+        self.states.push_back((frame, cpu.state_to_bytes()));
+
+        let local = self.inputs[self.local_player]
+            .get(&frame)
+            .copied()
+            .unwrap_or(0);
+        let remote_inputs = &self.inputs[1 - self.local_player];
+        let remote = match remote_inputs.get(&frame) {
+            Some(&buttons) => buttons,
+            None => {
+                let guess = remote_inputs
+                    .range(..frame)
+                    .next_back()
+                    .map_or(0, |(_, &buttons)| buttons);
+                self.predictions.insert(frame, guess);
+                guess
+            }
+        };
+        let (first, second) = if self.local_player == 0 {
+            (local, remote)
+        } else {
+            (remote, local)
+        };
+        cpu.bus.joypad_mut().set_buttons(first);
+        cpu.bus.joypad_2_mut().set_buttons(second);
+    }
+}
+
+fn run_frame(cpu: &mut CPU) {
+    let end = cpu.bus.frames() + 1;
+    while cpu.bus.frames() < end {
+        cpu.step();
+    }
+}
+
+/// A message between the two players.
+#[derive(Debug, Clone, PartialEq)]
+enum Packet {
+    /// Sent until the other player answers, both have to run the same game.
+    Hello { rom_crc: u32, input_delay: u8 },
+    /// Inputs from frame `first` on, and the first frame the sender has no input for yet.
+    Inputs {
+        confirmed: u64,
+        first: u64,
+        buttons: Vec<u8>,
+    },
+}
+
+impl Packet {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            Packet::Hello {
+                rom_crc,
+                input_delay,
+            } => {
+                bytes.push(HELLO);
+                bytes.extend(rom_crc.to_le_bytes());
+                bytes.push(*input_delay);
+            }
+            Packet::Inputs {
+                confirmed,
+                first,
+                buttons,
+            } => {
+                bytes.push(INPUTS);
+                bytes.extend(confirmed.to_le_bytes());
+                bytes.extend(first.to_le_bytes());
+                bytes.extend(buttons);
+            }
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let u64_at = |at: usize| Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?));
+        match *bytes.first()? {
+            HELLO if bytes.len() == 6 => Some(Packet::Hello {
+                rom_crc: u32::from_le_bytes(bytes[1..5].try_into().ok()?),
+                input_delay: bytes[5],
+            }),
+            INPUTS => Some(Packet::Inputs {
+                confirmed: u64_at(1)?,
+                first: u64_at(9)?,
+                buttons: bytes.get(17..)?.to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Online play for two over UDP with rollback. The host is the first player.
+pub struct NetplaySession {
+    socket: UdpSocket,
+    rollback: Rollback,
+    /// What the host answered, sent again when the answer got lost.
+    hello: Option<Vec<u8>>,
+    /// Buttons the local player holds, whatever the controllers are set to.
+    buttons: u8,
+    /// The first frame the other player has no local input for.
+    peer_confirmed: u64,
+    last_received: Instant,
+}
+
+impl NetplaySession {
+    /// Waits for the second player on `address`, like `0.0.0.0:7845`.
+    pub fn host(address: &str, rom_crc: u32, input_delay: u8) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        let mut buffer = [0; 1500];
+        loop {
+            let (length, peer) = socket.recv_from(&mut buffer)?;
+            if let Some(Packet::Hello { rom_crc: crc, .. }) = Packet::from_bytes(&buffer[..length])
+            {
+                let hello = Packet::Hello {
+                    rom_crc,
+                    input_delay,
+                };
+                socket.send_to(&hello.to_bytes(), peer)?;
+                if crc != rom_crc {
+                    return Err(invalid("the other player runs a different game"));
+                }
+                socket.connect(peer)?;
+                let mut session = Self::new(socket, 0, input_delay)?;
+                session.hello = Some(hello.to_bytes());
+                return Ok(session);
+            }
+        }
+    }
+
+    /// Connects to a host as the second player, with the input delay the host chose.
+    pub fn join(address: &str, rom_crc: u32) -> io::Result<Self> {
+        let peer = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| invalid("no address"))?;
+        let socket = UdpSocket::bind(if peer.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })?;
+        socket.connect(peer)?;
+        socket.set_read_timeout(Some(Duration::from_millis(250)))?;
+        let hello = Packet::Hello {
+            rom_crc,
+            input_delay: 0,
+        };
+        let start = Instant::now();
+        let mut buffer = [0; 1500];
+        while start.elapsed() < DISCONNECT_TIMEOUT {
+            socket.send(&hello.to_bytes())?;
+            let length = match socket.recv(&mut buffer) {
+                Ok(length) => length,
+                Err(error) if is_timeout(&error) => continue,
+                Err(error) => return Err(error),
+            };
+            if let Some(Packet::Hello {
+                rom_crc: crc,
+                input_delay,
+            }) = Packet::from_bytes(&buffer[..length])
+            {
+                if crc != rom_crc {
+                    return Err(invalid("the other player runs a different game"));
+                }
+                return Self::new(socket, 1, input_delay);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the host did not answer",
+        ))
+    }
+
+    fn new(socket: UdpSocket, local_player: usize, input_delay: u8) -> io::Result<Self> {
+        socket.set_read_timeout(Some(RESEND_INTERVAL))?;
+        Ok(NetplaySession {
+            socket,
+            rollback: Rollback::new(local_player, input_delay),
+            hello: None,
+            buttons: 0,
+            peer_confirmed: 0,
+            last_received: Instant::now(),
+        })
+    }
+
+    /// 1 or 2.
+    pub fn player(&self) -> usize {
+        self.rollback.local_player + 1
+    }
+
+    pub fn rollback(&self) -> &Rollback {
+        &self.rollback
+    }
+
+    pub fn set_button_pressed_status(&mut self, button: u8, pressed: bool) {
+        if pressed {
+            self.buttons |= button;
+        } else {
+            self.buttons &= !button;
+        }
+    }
+
+    /// Starts both machines from the same state, when the session starts.
+    pub fn start(&self, cpu: &mut CPU) {
+        cpu.bus.set_ram_init(RamInit::Zero);
+        cpu.power_cycle();
+    }
+
+    /// Exchanges inputs, waiting while too far ahead of the other player, then rolls back
+    /// when needed and sets the controllers for the next frame.
+    pub fn before_frame(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        self.rollback.add_local_input(self.buttons);
+        self.send_inputs()?;
+        self.receive(false)?;
+        while !self.rollback.can_advance() {
+            if self.last_received.elapsed() > DISCONNECT_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the other player stopped responding",
+                ));
+            }
+            self.receive(true)?;
+            self.send_inputs()?;
+        }
+        self.rollback.before_frame(cpu);
+        Ok(())
+    }
+
+    /// Sends the local inputs the other player does not have yet.
+    fn send_inputs(&mut self) -> io::Result<()> {
+        let buttons: Vec<u8> = self
+            .rollback
+            .local_inputs(self.peer_confirmed)
+            .take(MAX_INPUTS_PER_PACKET)
+            .map(|(_, buttons)| buttons)
+            .collect();
+        let packet = Packet::Inputs {
+            confirmed: self.rollback.confirmed(),
+            first: self.peer_confirmed,
+            buttons,
+        };
+        match self.socket.send(&packet.to_bytes()) {
+            // Nobody listening yet is the same as a lost packet
+            Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Takes the packets that arrived, waiting for one when asked.
+    fn receive(&mut self, wait: bool) -> io::Result<()> {
+        self.socket.set_nonblocking(!wait)?;
+        let mut buffer = [0; 1500];
+        loop {
+            let length = match self.socket.recv(&mut buffer) {
+                Ok(length) => length,
+                Err(error)
+                    if is_timeout(&error) || error.kind() == io::ErrorKind::ConnectionRefused =>
+                {
+                    return Ok(())
+                }
+                Err(error) => return Err(error),
+            };
+            self.last_received = Instant::now();
+            match Packet::from_bytes(&buffer[..length]) {
+                Some(Packet::Inputs {
+                    confirmed,
+                    first,
+                    buttons,
+                }) => {
+                    self.peer_confirmed = self.peer_confirmed.max(confirmed);
+                    for (frame, buttons) in (first..).zip(buttons) {
+                        self.rollback.add_remote_input(frame, buttons);
+                    }
+                }
+                Some(Packet::Hello { .. }) => {
+                    if let Some(hello) = &self.hello {
+                        self.socket.send(hello)?;
+                    }
+                }
+                None => {}
+            }
+            // Take whatever else arrived without waiting
+            self.socket.set_nonblocking(true)?;
+        }
+    }
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+
+    /// Adds both controllers to $10 forever, so any input changes the state from then on.
+    fn cpu() -> CPU<'static> {
+        let mut program = vec![
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40, // strobe
+            0xad, 0x16, 0x40, 0x6d, 0x17, 0x40, // LDA $4016, ADC $4017
+            0x65, 0x10, 0x85, 0x10, 0x4c, 0x00, 0x80, // ADC $10, STA $10, JMP $8000
+        ];
+        program.resize(2 * 0x4000 - 4, 0);
+        program.extend([0x00, 0x80, 0x00, 0x00]);
+        let mut cpu = CPU::new(Bus::new(test_rom(program), |_, _| {}));
+        cpu.power_cycle();
+        cpu
+    }
+
+    fn input(player: usize, frame: u64) -> u8 {
+        // The A button of each player, on and off at different times
+        ((frame / (3 + player as u64)) % 2) as u8
+    }
+
+    #[test]
+    fn test_rollback() {
+        const FRAMES: u64 = 40;
+        const LATENCY: u64 = 5;
+
+        // Both players with the inputs on time
+        let mut reference = cpu();
+        for frame in 0..FRAMES {
+            reference.bus.joypad_mut().set_buttons(input(0, frame));
+            reference.bus.joypad_2_mut().set_buttons(input(1, frame));
+            run_frame(&mut reference);
+        }
+
+        // Each gets the inputs of the other a few frames late
+        let mut players = [(cpu(), Rollback::new(0, 0)), (cpu(), Rollback::new(1, 0))];
+        let mut sent: Vec<(u64, usize, u64, u8)> = vec![];
+        for frame in 0..FRAMES {
+            for (player, (cpu, rollback)) in players.iter_mut().enumerate() {
+                for &(_, _, input_frame, buttons) in sent
+                    .iter()
+                    .filter(|&&(arrival, from, ..)| arrival == frame && from != player)
+                {
+                    rollback.add_remote_input(input_frame, buttons);
+                }
+                assert!(rollback.can_advance());
+                let input_frame = rollback.add_local_input(input(player, frame));
+                sent.push((frame + LATENCY, player, input_frame, input(player, frame)));
+                rollback.before_frame(cpu);
+                run_frame(cpu);
+            }
+        }
+        // The last inputs arrive, nothing runs after them but the rollback
+        for (player, (cpu, rollback)) in players.iter_mut().enumerate() {
+            for &(_, _, input_frame, buttons) in
+                sent.iter().filter(|&&(_, from, ..)| from != player)
+            {
+                rollback.add_remote_input(input_frame, buttons);
+            }
+            rollback.before_frame(cpu);
+            assert_eq!(rollback.confirmed(), FRAMES);
+            assert!(rollback.resimulated_frames > 0);
+            assert_eq!(cpu.bus.ram_mut(), reference.bus.ram_mut());
+        }
+
+        // Too far ahead of the other player
+        let (mut cpu, mut rollback) = (cpu(), Rollback::new(0, 0));
+        for _ in 0..MAX_ROLLBACK {
+            rollback.before_frame(&mut cpu);
+            run_frame(&mut cpu);
+        }
+        assert!(!rollback.can_advance());
+    }
+
+    #[test]
+    fn test_packets() {
+        for packet in [
+            Packet::Hello {
+                rom_crc: 0x1234_5678,
+                input_delay: 2,
+            },
+            Packet::Inputs {
+                confirmed: 7,
+                first: 3,
+                buttons: vec![1, 2, 3],
+            },
+        ] {
+            assert_eq!(Packet::from_bytes(&packet.to_bytes()), Some(packet));
+        }
+        assert_eq!(Packet::from_bytes(b"H12"), None);
+        assert_eq!(Packet::from_bytes(b"X"), None);
+    }
+}
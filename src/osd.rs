@@ -0,0 +1,201 @@
+use crate::render::Frame;
+use std::collections::VecDeque;
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 3;
+const MARGIN: usize = 4;
+
+/// Number of frames a message is shown, including the fade-out.
+const MESSAGE_FRAMES: u32 = 180;
+const FADE_FRAMES: u32 = 60;
+const MAX_MESSAGES: usize = 4;
+
+const TEXT_COLOR: (u8, u8, u8) = (0xff, 0xff, 0xff);
+const SHADOW_COLOR: (u8, u8, u8) = (0x00, 0x00, 0x00);
+
+struct Message {
+    text: String,
+    frames_left: u32,
+}
+
+/// On-screen display of short-lived messages, drawn on top of the frame.
+pub struct Osd {
+    messages: VecDeque<Message>,
+}
+
+impl Default for Osd {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Osd {
+            messages: VecDeque::with_capacity(MAX_MESSAGES),
+        }
+    }
+
+    /// Queues a message, the oldest message is dropped if too many are shown.
+    pub fn push(&mut self, text: impl Into<String>) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(Message {
+            text: text.into(),
+            frames_left: MESSAGE_FRAMES,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Ages all messages by one displayed frame and removes the expired ones.
+    pub fn tick(&mut self) {
+        for message in self.messages.iter_mut() {
+            message.frames_left -= 1;
+        }
+        self.messages.retain(|message| message.frames_left > 0);
+    }
+
+    /// Draws the messages in the bottom left corner, newest at the bottom.
+    pub fn draw(&self, frame: &mut Frame) {
+        let bottom = 240 - MARGIN - GLYPH_HEIGHT;
+        for (i, message) in self.messages.iter().rev().enumerate() {
+            let alpha = (message.frames_left.min(FADE_FRAMES) as f32) / FADE_FRAMES as f32;
+            let y = match bottom.checked_sub(i * LINE_HEIGHT) {
+                Some(y) => y,
+                None => break,
+            };
+            draw_text(frame, MARGIN + 1, y + 1, &message.text, SHADOW_COLOR, alpha);
+            draw_text(frame, MARGIN, y, &message.text, TEXT_COLOR, alpha);
+        }
+    }
+}
+
+/// Draws text with the built-in font, characters without a glyph are drawn as a box.
+pub fn draw_text(frame: &mut Frame, x: usize, y: usize, text: &str, rgb: (u8, u8, u8), alpha: f32) {
+    for (i, character) in text.chars().enumerate() {
+        let glyph = glyph(character);
+        let offset_x = x + i * (GLYPH_WIDTH + 1);
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (0b10000 >> column) != 0 {
+                    frame.blend_pixel(offset_x + column, y + row, rgb, alpha);
+                }
+            }
+        }
+    }
+}
+
+#[rustfmt::skip]
+fn glyph(character: char) -> [u8; GLYPH_HEIGHT] {
+    match character.to_ascii_uppercase() {
+        ' ' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000],
+        '0' => [0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110],
+        '1' => [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        '2' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111],
+        '3' => [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110],
+        '4' => [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010],
+        '5' => [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110],
+        '6' => [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110],
+        '7' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000],
+        '8' => [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110],
+        '9' => [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100],
+        'A' => [0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'B' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110],
+        'C' => [0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110],
+        'D' => [0b11100, 0b10010, 0b10001, 0b10001, 0b10001, 0b10010, 0b11100],
+        'E' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111],
+        'F' => [0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000],
+        'G' => [0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111],
+        'H' => [0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001],
+        'I' => [0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110],
+        'J' => [0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100],
+        'K' => [0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001],
+        'L' => [0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111],
+        'M' => [0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001],
+        'N' => [0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001],
+        'O' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'P' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000],
+        'Q' => [0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101],
+        'R' => [0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001],
+        'S' => [0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110],
+        'T' => [0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100],
+        'U' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110],
+        'V' => [0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100],
+        'W' => [0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010],
+        'X' => [0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001],
+        'Y' => [0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100],
+        'Z' => [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111],
+        '.' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b01100],
+        ',' => [0b00000, 0b00000, 0b00000, 0b00000, 0b01100, 0b00100, 0b01000],
+        ':' => [0b00000, 0b01100, 0b01100, 0b00000, 0b01100, 0b01100, 0b00000],
+        '-' => [0b00000, 0b00000, 0b00000, 0b11111, 0b00000, 0b00000, 0b00000],
+        '+' => [0b00000, 0b00100, 0b00100, 0b11111, 0b00100, 0b00100, 0b00000],
+        '%' => [0b11000, 0b11001, 0b00010, 0b00100, 0b01000, 0b10011, 0b00011],
+        '/' => [0b00000, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b00000],
+        '(' => [0b00010, 0b00100, 0b01000, 0b01000, 0b01000, 0b00100, 0b00010],
+        ')' => [0b01000, 0b00100, 0b00010, 0b00010, 0b00010, 0b00100, 0b01000],
+        '!' => [0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00000, 0b00100],
+        '?' => [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100],
+        '$' => [0b00100, 0b01111, 0b10100, 0b01110, 0b00101, 0b11110, 0b00100],
+        '#' => [0b01010, 0b01010, 0b11111, 0b01010, 0b11111, 0b01010, 0b01010],
+        '=' => [0b00000, 0b00000, 0b11111, 0b00000, 0b11111, 0b00000, 0b00000],
+        '_' => [0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b00000, 0b11111],
+        '\'' => [0b01100, 0b00100, 0b01000, 0b00000, 0b00000, 0b00000, 0b00000],
+        '"' => [0b01010, 0b01010, 0b01010, 0b00000, 0b00000, 0b00000, 0b00000],
+        _ => [0b11111, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b11111],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_messages_expire() {
+        let mut osd = Osd::new();
+        osd.push("State 1 saved");
+        for _ in 0..MESSAGE_FRAMES - 1 {
+            osd.tick();
+        }
+        assert_eq!(osd.messages.len(), 1);
+        osd.tick();
+        assert!(osd.messages.is_empty());
+    }
+
+    #[test]
+    fn test_queue_drops_oldest() {
+        let mut osd = Osd::new();
+        for i in 0..MAX_MESSAGES + 2 {
+            osd.push(format!("{}", i));
+        }
+        assert_eq!(osd.messages.len(), MAX_MESSAGES);
+        assert_eq!(osd.messages[0].text, "2");
+    }
+
+    #[test]
+    fn test_draw_text() {
+        let mut frame = Frame::new();
+        draw_text(&mut frame, 0, 0, "1", (0xff, 0xff, 0xff), 1.0);
+        assert_eq!(frame.get_pixel(2, 0), (0xff, 0xff, 0xff));
+        assert_eq!(frame.get_pixel(0, 0), (0x00, 0x00, 0x00));
+    }
+
+    #[test]
+    fn test_fade_out() {
+        let mut osd = Osd::new();
+        osd.push("1");
+        for _ in 0..MESSAGE_FRAMES - FADE_FRAMES / 2 {
+            osd.tick();
+        }
+
+        let mut frame = Frame::new();
+        osd.draw(&mut frame);
+        let (r, _, _) = frame.get_pixel(MARGIN + 2, 240 - MARGIN - GLYPH_HEIGHT);
+        assert!(r > 0x70 && r < 0x90);
+    }
+}
@@ -0,0 +1,68 @@
+use spin_sleep::SpinSleeper;
+use std::time::{Duration, Instant};
+
+/// Paces frames to a target rate, sleeping most of the wait and spinning the rest for precision.
+pub struct FramePacer {
+    sleeper: SpinSleeper,
+    frame_time: Duration,
+    deadline: Instant,
+}
+
+impl FramePacer {
+    pub fn new(rate: f64) -> Self {
+        let frame_time = Duration::from_secs_f64(1.0 / rate);
+        FramePacer {
+            sleeper: SpinSleeper::default(),
+            frame_time,
+            deadline: Instant::now() + frame_time,
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: f64) {
+        self.frame_time = Duration::from_secs_f64(1.0 / rate);
+    }
+
+    /// Waits until the next frame is due.
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        if now < self.deadline {
+            self.sleeper.sleep(self.deadline - now);
+        }
+
+        // Deadlines advance by whole frames so rounding does not drift, unless a stall
+        // like a pause put us more than a frame behind, then start over instead of rushing
+        if now.saturating_duration_since(self.deadline) > self.frame_time {
+            self.deadline = now + self.frame_time;
+        } else {
+            self.deadline += self.frame_time;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_paces_frames() {
+        let mut pacer = FramePacer::new(200.0);
+        let start = Instant::now();
+        for _ in 0..20 {
+            pacer.wait();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(95));
+        assert!(elapsed < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_does_not_catch_up_after_stall() {
+        let mut pacer = FramePacer::new(200.0);
+        std::thread::sleep(Duration::from_millis(50));
+        pacer.wait();
+
+        let start = Instant::now();
+        pacer.wait();
+        assert!(start.elapsed() >= Duration::from_millis(4));
+    }
+}
@@ -1,5 +1,12 @@
 use crate::cartridge::Mirroring;
 use crate::cartridge::Mirroring::{Horizontal, Vertical};
+use crate::cartridge::Region;
+use crate::render;
+use crate::sprites::{self, SpriteLine};
+use crate::state::{StateReader, StateWriter};
+use crate::unchecked;
+use std::cell::{Cell, RefCell, RefMut};
+use std::io;
 
 #[allow(clippy::upper_case_acronyms)]
 pub struct PPU {
@@ -22,11 +29,58 @@ pub struct PPU {
     pub scanline: u16,
     pub cycles: u16,
     pub nmi: bool,
+    /// Idle scanlines added at the end of vertical blank, giving the CPU more time per frame
+    /// without moving NMI. Not part of save states.
+    pub extra_scanlines: u16,
+    /// Sets the scanlines per frame, comes from the ROM like the mirroring.
+    pub region: Region,
+    /// RGB of the palette entries as last resolved, see `rgb_palette`.
+    rgb_palette: Cell<[(u8, u8, u8); 32]>,
+    palette_dirty: Cell<bool>,
+    /// Background palette indices of both nametables in VRAM as last drawn, one 256x256
+    /// image after the other, see `background_cache`.
+    background: RefCell<Vec<u8>>,
+    dirty_tiles: Cell<DirtyTiles>,
+    /// The scroll of every visible scanline of the frame, see `LineScroll`.
+    pub line_scroll: [LineScroll; 240],
+    /// Horizontal scroll copied at dot 257, for the scanline after it.
+    next_line_x: u16,
+    /// Where the next scanline is, copied from the scroll registers as the frame starts.
+    next_line_y: u16,
+    /// The sprites of every visible scanline, evaluated on the line above as on hardware, so
+    /// line 0 has none.
+    pub sprite_lines: [SpriteLine; 240],
+}
+
+/// Tiles of the two nametables in VRAM that changed, a bit per column for each of the 32 rows.
+/// Rows 30 and 31 are the attribute table read as tiles, which a Y scroll of 240-255 shows.
+pub type DirtyTiles = [[u32; 32]; 2];
+
+/// The `LineScroll::y` of the line below: after the last row of tiles it's the nametable
+/// below, after the attribute rows the top of the same nametable.
+fn next_row(y: u16) -> u16 {
+    let (table, row) = (y / 256, y % 256);
+    match row {
+        239 => (table ^ 1) * 256,
+        255 => table * 256,
+        _ => y + 1,
+    }
+}
+
+/// Where a visible scanline of the background starts, latched as the frame runs so scroll
+/// writes in the middle of it split the screen the way they do on hardware.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LineScroll {
+    /// In the 512 pixels of two nametables next to each other.
+    pub x: u16,
+    /// In the 512 rows of two nametables above each other, 256 each so the attribute rows
+    /// 240-255 can be shown.
+    pub y: u16,
 }
 
 impl PPU {
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
-        PPU {
+        let mut ppu = PPU {
             chr_rom,
             vram: [0; 2048],
             oam_data: [0; 256],
@@ -46,11 +100,103 @@ impl PPU {
             scanline: 0,
             cycles: 21,
             nmi: false,
-        }
+            extra_scanlines: 0,
+            region: Region::Ntsc,
+            rgb_palette: Cell::new([(0, 0, 0); 32]),
+            palette_dirty: Cell::new(true),
+            background: RefCell::new(vec![0; 2 * 256 * 256]),
+            dirty_tiles: Cell::new([[u32::MAX; 32]; 2]),
+            line_scroll: [LineScroll::default(); 240],
+            next_line_x: 0,
+            next_line_y: 0,
+            sprite_lines: [SpriteLine::EMPTY; 240],
+        };
+        ppu.latch_lines();
+        ppu
+    }
+
+    /// Clears the registers that are affected by the reset line, memory is kept.
+    pub fn reset(&mut self) {
+        self.buffer = 0x00;
+        self.register_control = PpuControl::new();
+        self.register_mask = PpuMask::new();
+        self.register_scroll = PpuScroll::new();
+        self.register_address.reset_latch();
+        self.nmi = false;
+        self.mark_palette_dirty();
+        self.mark_background_dirty();
+        self.latch_lines();
+    }
+
+    /// Returns all memory and registers to their power-on state, keeping the cartridge.
+    pub fn power_cycle(&mut self) {
+        self.vram = [0; 2048];
+        self.oam_data = [0; 256];
+        self.palette_table = [0; 32];
+        self.buffer = 0x00;
+        self.register_control = PpuControl::new();
+        self.register_mask = PpuMask::new();
+        self.register_status = PpuStatus::new();
+        self.oam_address = 0x00;
+        self.register_scroll = PpuScroll::new();
+        self.register_address = PpuAddress::new();
+        self.scanline = 0;
+        self.cycles = 21;
+        self.nmi = false;
+        self.mark_palette_dirty();
+        self.mark_background_dirty();
+        self.latch_lines();
+    }
+
+    /// Writes memory and registers, the cartridge and mirroring come from the ROM.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.palette_table);
+        writer.write_bytes(&self.vram);
+        writer.write_bytes(&self.oam_data);
+        writer.write_u8(self.buffer);
+        writer.write_u8(self.register_control.flags);
+        writer.write_u8(self.register_mask.flags);
+        writer.write_u8(self.register_status.flags);
+        writer.write_u8(self.oam_address);
+        writer.write_u8(self.register_scroll.x);
+        writer.write_u8(self.register_scroll.y);
+        writer.write_bool(self.register_scroll.x_next);
+        writer.write_u16(self.register_address.address);
+        writer.write_bool(self.register_address.hi_next);
+        writer.write_u16(self.scanline);
+        writer.write_u16(self.cycles);
+        writer.write_bool(self.nmi);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        reader.read_bytes(&mut self.palette_table)?;
+        reader.read_bytes(&mut self.vram)?;
+        reader.read_bytes(&mut self.oam_data)?;
+        self.buffer = reader.read_u8()?;
+        self.register_control.flags = reader.read_u8()?;
+        self.register_mask.flags = reader.read_u8()?;
+        self.register_status.flags = reader.read_u8()?;
+        self.oam_address = reader.read_u8()?;
+        self.register_scroll.x = reader.read_u8()?;
+        self.register_scroll.y = reader.read_u8()?;
+        self.register_scroll.x_next = reader.read_bool()?;
+        self.register_address.address = reader.read_u16()?;
+        self.register_address.hi_next = reader.read_bool()?;
+        self.scanline = reader.read_u16()?;
+        self.cycles = reader.read_u16()?;
+        self.nmi = reader.read_bool()?;
+        self.mark_palette_dirty();
+        self.mark_background_dirty();
+        self.latch_lines();
+        Ok(())
     }
 
     pub fn tick(&mut self, cycles: u8) -> bool {
+        let dot = self.cycles;
         self.cycles += cycles as u16;
+        if dot < 257 && self.cycles >= 257 {
+            self.next_line_x = self.scroll_x();
+        }
 
         // Enter next scanline
         if self.cycles >= 341 {
@@ -67,17 +213,145 @@ impl PPU {
             }
 
             // Enter next frame and reset vertical blank
-            if self.scanline >= 262 {
+            if self.scanline >= self.region.scanlines() + self.extra_scanlines {
                 self.scanline = 0;
                 self.nmi = false;
                 self.register_status.set_sprite_zero_hit(false);
+                self.register_status.set_sprite_overflow(false);
                 self.register_status.set_vertical_blank(false);
+                self.next_line_y = self.scroll_y();
+                self.sprite_lines[0] = SpriteLine::EMPTY;
+                self.start_line();
+                self.evaluate_sprites();
                 return true;
             }
+            self.start_line();
+            self.evaluate_sprites();
         }
         false
     }
 
+    /// The horizontal scroll from PPUCTRL and PPUSCROLL, see `LineScroll`.
+    fn scroll_x(&self) -> u16 {
+        (self.register_control.bits() & 1) as u16 * 256 + self.register_scroll.x as u16
+    }
+
+    /// The vertical scroll from PPUCTRL and PPUSCROLL, see `LineScroll`.
+    fn scroll_y(&self) -> u16 {
+        (self.register_control.bits() >> 1 & 1) as u16 * 256 + self.register_scroll.y as u16
+    }
+
+    /// Uses the current scroll and sprites for every line, for when the PPU didn't run a frame
+    /// with them, e.g. after loading a state.
+    pub fn latch_lines(&mut self) {
+        self.next_line_x = self.scroll_x();
+        let mut y = self.scroll_y();
+        for line in 0..self.line_scroll.len() {
+            self.line_scroll[line] = LineScroll {
+                x: self.next_line_x,
+                y,
+            };
+            y = next_row(y);
+            if line == self.scanline as usize {
+                self.next_line_y = y;
+            }
+        }
+        self.sprite_lines[0] = SpriteLine::EMPTY;
+        for line in 1..self.sprite_lines.len() {
+            self.sprite_lines[line] = self.sprites_below(line - 1);
+        }
+    }
+
+    /// Latches the scroll of a visible scanline as it starts.
+    fn start_line(&mut self) {
+        let Some(line) = self.line_scroll.get_mut(self.scanline as usize) else {
+            return;
+        };
+        *line = LineScroll {
+            x: self.next_line_x,
+            y: self.next_line_y,
+        };
+        self.next_line_y = next_row(self.next_line_y);
+    }
+
+    /// Evaluates the sprites for the line below a visible scanline as it starts, setting the
+    /// overflow flag.
+    fn evaluate_sprites(&mut self) {
+        let line = self.scanline as usize;
+        if line >= 240 {
+            return;
+        }
+        let sprites = self.sprites_below(line);
+        if sprites.overflow {
+            self.register_status.set_sprite_overflow(true);
+        }
+        if let Some(below) = self.sprite_lines.get_mut(line + 1) {
+            *below = sprites;
+        }
+    }
+
+    /// Secondary OAM as evaluated on `line`, nothing while rendering is off.
+    fn sprites_below(&self, line: usize) -> SpriteLine {
+        let rendering = self.register_mask.flags & 0b0001_1000 != 0;
+        if rendering {
+            sprites::evaluate(&self.oam_data, line, self.register_control.sprite_height())
+        } else {
+            SpriteLine::EMPTY
+        }
+    }
+
+    /// RGB of the 32 palette entries with grayscale and emphasis applied. Resolved again only
+    /// after palette RAM, PPUMASK or the region changed.
+    pub fn rgb_palette(&self) -> [(u8, u8, u8); 32] {
+        if self.palette_dirty.replace(false) {
+            self.rgb_palette.set(render::resolve_palette(
+                &self.palette_table,
+                self.register_mask.flags,
+                self.region,
+            ));
+        }
+        self.rgb_palette.get()
+    }
+
+    /// Call after changing `palette_table` or `region` directly.
+    pub fn mark_palette_dirty(&self) {
+        self.palette_dirty.set(true);
+    }
+
+    /// The background as last drawn, with the tiles that changed since the last call. The
+    /// caller draws those tiles again, the rest of the screen is still up to date.
+    pub fn background_cache(&self) -> (RefMut<'_, Vec<u8>>, DirtyTiles) {
+        (
+            self.background.borrow_mut(),
+            self.dirty_tiles.replace([[0; 32]; 2]),
+        )
+    }
+
+    /// Call after changing `chr_rom` or the nametables directly.
+    pub fn mark_background_dirty(&self) {
+        self.dirty_tiles.set([[u32::MAX; 32]; 2]);
+    }
+
+    /// Call after writing `vram` at `index` directly, an attribute byte covers 4x4 tiles and
+    /// is a tile of the attribute rows itself.
+    pub fn mark_vram_dirty(&self, index: usize) {
+        let mut dirty = self.dirty_tiles.get();
+        let Some(table) = dirty.get_mut(index / 0x400) else {
+            return;
+        };
+        match index % 0x400 {
+            index @ 0x000..=0x3bf => table[index / 32] |= 1 << (index % 32),
+            index => {
+                table[index / 32] |= 1 << (index % 32);
+                let (row, column) = ((index - 0x3c0) / 8 * 4, (index - 0x3c0) % 8 * 4);
+                for tiles in &mut table[row..row + 4] {
+                    *tiles |= 0b1111 << column;
+                }
+            }
+        }
+        self.dirty_tiles.set(dirty);
+    }
+
     pub fn get_nmi(&mut self) -> bool {
         let nmi = self.nmi;
         self.nmi = false;
@@ -91,7 +365,11 @@ impl PPU {
     /// Triggers NMI if the PPU is currently in vertical blank and the NMI flag changes from 0 to 1
     pub fn write_control(&mut self, data: u8) {
         let nmi_flag_before = self.register_control.get_vertical_blank_nmi();
+        let bank_before = self.register_control.background_pattern_address();
         self.register_control.update(data);
+        if self.register_control.background_pattern_address() != bank_before {
+            self.mark_background_dirty();
+        }
         if !nmi_flag_before
             && self.register_control.get_vertical_blank_nmi()
             && self.register_status.get_vertical_blank()
@@ -131,7 +409,8 @@ impl PPU {
             }
             0x2000..=0x2fff => {
                 let res = self.buffer;
-                self.buffer = self.vram[self.mirror_vram_address(address) as usize];
+                let index = self.mirror_vram_address(address) as usize;
+                self.buffer = unchecked::read(&self.vram, index);
                 res
             }
             0x3000..=0x3eff => panic!(
@@ -155,16 +434,22 @@ impl PPU {
 
         match adr {
             0x0000..=0x1fff => panic!("Attempted to write to chr rom at {:#x}", adr),
-            0x2000..=0x2fff => self.vram[self.mirror_vram_address(adr) as usize] = data,
+            0x2000..=0x2fff => {
+                let index = self.mirror_vram_address(adr) as usize;
+                unchecked::write(&mut self.vram, index, data);
+                self.mark_vram_dirty(index);
+            }
             0x3000..=0x3eff => panic!(
                 "addresses in 0x3000..=0x3eff are not expected, requested: {:#x}",
                 adr
             ),
             0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
                 self.palette_table[(adr - 0x10 - 0x3f00) as usize] = data;
+                self.mark_palette_dirty();
             }
             0x3f00..=0x3fff => {
                 self.palette_table[(adr - 0x3f00) as usize] = data;
+                self.mark_palette_dirty();
             }
             _ => panic!(
                 "addresses in 0x4000..=0xffff are not expected, requested: {:#x}",
@@ -202,6 +487,9 @@ impl PPU {
     }
 
     pub fn write_mask(&mut self, data: u8) {
+        if data != self.register_mask.flags {
+            self.mark_palette_dirty();
+        }
         self.register_mask.update(data);
     }
 
@@ -215,6 +503,12 @@ pub struct PpuAddress {
     hi_next: bool,
 }
 
+impl Default for PpuAddress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PpuAddress {
     pub fn new() -> Self {
         PpuAddress {
@@ -223,6 +517,15 @@ impl PpuAddress {
         }
     }
 
+    pub fn get(&self) -> u16 {
+        self.address
+    }
+
+    /// Whether the next write goes to the high byte, shared with PPUSCROLL on hardware.
+    pub fn hi_next(&self) -> bool {
+        self.hi_next
+    }
+
     fn set(&mut self, address: u16) {
         self.address = address & 0x3fff;
     }
@@ -253,11 +556,21 @@ pub struct PpuControl {
     flags: u8,
 }
 
+impl Default for PpuControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PpuControl {
     pub fn new() -> Self {
         PpuControl { flags: 0x00 }
     }
 
+    pub fn bits(&self) -> u8 {
+        self.flags
+    }
+
     pub fn get_address_increment(&self) -> u8 {
         if self.flags & 0b0000_0100 == 0 {
             1
@@ -278,6 +591,15 @@ impl PpuControl {
         }
     }
 
+    /// 8 or 16 pixels, 8x16 sprites pick their pattern table by tile number.
+    pub fn sprite_height(&self) -> usize {
+        if self.flags & 0b0010_0000 == 0 {
+            8
+        } else {
+            16
+        }
+    }
+
     pub fn sprite_pattern_address(&self) -> u16 {
         if self.flags & 0b0000_1000 == 0 {
             0x0000
@@ -295,11 +617,21 @@ pub struct PpuStatus {
     flags: u8,
 }
 
+impl Default for PpuStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PpuStatus {
     pub fn new() -> Self {
         PpuStatus { flags: 0x00 }
     }
 
+    pub fn bits(&self) -> u8 {
+        self.flags
+    }
+
     pub fn set_vertical_blank(&mut self, condition: bool) {
         if condition {
             self.flags |= 0b1000_0000;
@@ -312,6 +644,14 @@ impl PpuStatus {
         self.flags & 0b1000_0000 != 0
     }
 
+    pub fn set_sprite_overflow(&mut self, condition: bool) {
+        if condition {
+            self.flags |= 0b0010_0000;
+        } else {
+            self.flags &= !0b0010_0000;
+        }
+    }
+
     pub fn set_sprite_zero_hit(&mut self, condition: bool) {
         if condition {
             self.flags |= 0b0100_0000;
@@ -325,11 +665,21 @@ pub struct PpuMask {
     flags: u8,
 }
 
+impl Default for PpuMask {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PpuMask {
     pub fn new() -> Self {
         PpuMask { flags: 0x00 }
     }
 
+    pub fn bits(&self) -> u8 {
+        self.flags
+    }
+
     pub fn update(&mut self, data: u8) {
         self.flags = data;
     }
@@ -341,6 +691,12 @@ pub struct PpuScroll {
     pub x_next: bool,
 }
 
+impl Default for PpuScroll {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PpuScroll {
     pub fn new() -> Self {
         PpuScroll {
@@ -540,6 +896,86 @@ pub mod test {
         assert_eq!(ppu.read_oam_data(), 0x77);
     }
 
+    #[test]
+    fn test_reset_keeps_memory() {
+        let mut ppu = test_ppu();
+        ppu.vram[0x0305] = 0x66;
+        ppu.write_control(0b1000_0100);
+        ppu.write_address(0x23);
+
+        ppu.reset();
+        assert_eq!(ppu.vram[0x0305], 0x66);
+        assert!(!ppu.register_control.get_vertical_blank_nmi());
+
+        ppu.power_cycle();
+        assert_eq!(ppu.vram[0x0305], 0x00);
+    }
+
+    #[test]
+    fn test_rgb_palette_follows_writes() {
+        let mut ppu = test_ppu();
+        ppu.write_address(0x3f);
+        ppu.write_address(0x01);
+        ppu.write_data(0x16);
+        assert_eq!(ppu.rgb_palette()[1], render::PALETTE[0x16]);
+
+        ppu.write_mask(0b0000_0001);
+        assert_eq!(ppu.rgb_palette()[1], render::PALETTE[0x10]);
+
+        ppu.palette_table[1] = 0x2a;
+        assert_eq!(ppu.rgb_palette()[1], render::PALETTE[0x10]);
+        ppu.mark_palette_dirty();
+        assert_eq!(ppu.rgb_palette()[1], render::PALETTE[0x20]);
+    }
+
+    #[test]
+    fn test_dirty_tiles_follow_writes() {
+        let mut ppu = test_ppu();
+        assert_eq!(ppu.background_cache().1, [[u32::MAX; 32]; 2]);
+        assert_eq!(ppu.background_cache().1, [[0; 32]; 2]);
+
+        // Tile 3 of row 2, then the attribute byte of the bottom right 4x2 tiles
+        ppu.write_address(0x20);
+        ppu.write_address(0x43);
+        ppu.write_data(0x01);
+        ppu.write_address(0x23);
+        ppu.write_address(0xff);
+        ppu.write_data(0x01);
+        let mut expected = [[0; 32]; 2];
+        expected[0][2] = 1 << 3;
+        // Down to the attribute rows, where the byte is also the last tile
+        expected[0][28..].fill(0xf000_0000);
+        assert_eq!(ppu.background_cache().1, expected);
+
+        // $2800 is the second nametable in VRAM with horizontal mirroring
+        ppu.write_address(0x28);
+        ppu.write_address(0x00);
+        ppu.write_data(0x01);
+        let mut expected = [[0; 32]; 2];
+        expected[1][0] = 1;
+        assert_eq!(ppu.background_cache().1, expected);
+
+        ppu.write_control(0b0001_0000);
+        assert_eq!(ppu.background_cache().1, [[u32::MAX; 32]; 2]);
+    }
+
+    #[test]
+    fn test_extra_scanlines() {
+        let mut ppu = test_ppu();
+        ppu.write_control(0b1000_0000);
+        ppu.extra_scanlines = 10;
+        let mut dots = 0;
+        let mut nmi_dot = None;
+        while !ppu.tick(1) {
+            dots += 1;
+            if nmi_dot.is_none() && ppu.get_nmi() {
+                nmi_dot = Some(dots);
+            }
+        }
+        assert_eq!(nmi_dot, Some(241 * 341 - 21));
+        assert_eq!(dots + 1, (262 + 10) * 341 - 21);
+    }
+
     #[test]
     fn test_oam_dma() {
         let mut ppu = test_ppu();
@@ -560,4 +996,33 @@ pub mod test {
         ppu.write_oam_address(0x11);
         assert_eq!(ppu.read_oam_data(), 0x66);
     }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut ppu = test_ppu();
+        ppu.oam_data.fill(0xf0);
+        for sprite in ppu.oam_data[..9 * 4].chunks_exact_mut(4) {
+            sprite[0] = 100;
+        }
+        let run_to_line = |ppu: &mut PPU, line: u16| {
+            while ppu.scanline != line {
+                ppu.tick(1);
+            }
+        };
+
+        // Nothing is evaluated while rendering is off
+        run_to_line(&mut ppu, 104);
+        assert_eq!(ppu.register_status.bits() & 0b0010_0000, 0);
+
+        ppu.write_mask(0b0001_0000);
+        run_to_line(&mut ppu, 99);
+        assert_eq!(ppu.register_status.bits() & 0b0010_0000, 0);
+        run_to_line(&mut ppu, 100);
+        assert_ne!(ppu.register_status.bits() & 0b0010_0000, 0);
+        // Stays set until the next frame starts
+        run_to_line(&mut ppu, 250);
+        assert_ne!(ppu.register_status.bits() & 0b0010_0000, 0);
+        run_to_line(&mut ppu, 0);
+        assert_eq!(ppu.register_status.bits() & 0b0010_0000, 0);
+    }
 }
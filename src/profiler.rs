@@ -0,0 +1,289 @@
+use crate::callstack::{CallFrame, CallKind};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+/// CPU cycles of the NTSC vertical blank, 20 scanlines of 341 dots at 3 dots per cycle.
+pub const VBLANK_CYCLES: u64 = 20 * 341 / 3;
+/// Routines and addresses listed in a report.
+const REPORT_LINES: usize = 10;
+
+/// Cycles spent in a routine, `inclusive` counts the routines it calls as well.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RoutineCycles {
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+/// Where the cycles of one frame went, hottest routine first.
+///
+/// A routine is named by its start address, `None` is the code outside any call, e.g. the
+/// main loop after reset.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FrameProfile {
+    pub frame: u64,
+    /// Cycles spent inside the NMI handler.
+    pub nmi_cycles: u64,
+    pub routines: Vec<(Option<u16>, RoutineCycles)>,
+}
+
+/// Counts the cycles of every instruction by address and by the subroutines on the call
+/// stack, frame by frame.
+///
+/// Instructions are counted after they ran, so a JSR counts towards the routine it calls
+/// and an RTS towards the routine it returns to.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    /// Bus cycles when the previous instruction finished.
+    last_cycles: Option<u64>,
+    /// Cycles per instruction address since profiling started.
+    address_cycles: Vec<u64>,
+    totals: HashMap<Option<u16>, RoutineCycles>,
+    current: HashMap<Option<u16>, RoutineCycles>,
+    nmi_cycles: u64,
+    frames: u64,
+    nmi_overruns: u64,
+    last_frame: FrameProfile,
+    /// The frame with the longest NMI handler.
+    worst_frame: FrameProfile,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            last_cycles: None,
+            address_cycles: vec![0; 0x10000],
+            totals: HashMap::new(),
+            current: HashMap::new(),
+            nmi_cycles: 0,
+            frames: 0,
+            nmi_overruns: 0,
+            last_frame: FrameProfile::default(),
+            worst_frame: FrameProfile::default(),
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the instruction at `pc` that just finished, `cycles` are the bus cycles since
+    /// power on and `call_stack` the calls after the instruction ran.
+    pub fn record(&mut self, pc: u16, cycles: u64, call_stack: &[CallFrame]) {
+        let elapsed = cycles - self.last_cycles.unwrap_or(cycles);
+        self.last_cycles = Some(cycles);
+        self.address_cycles[pc as usize] += elapsed;
+
+        let innermost = call_stack.last().map(|frame| frame.target);
+        self.current.entry(innermost).or_default().exclusive += elapsed;
+        self.current.entry(None).or_default().inclusive += elapsed;
+        for (i, frame) in call_stack.iter().enumerate() {
+            // A recursive routine is only counted once
+            if call_stack[..i]
+                .iter()
+                .all(|outer| outer.target != frame.target)
+            {
+                self.current
+                    .entry(Some(frame.target))
+                    .or_default()
+                    .inclusive += elapsed;
+            }
+        }
+        if call_stack.iter().any(|frame| frame.kind == CallKind::Nmi) {
+            self.nmi_cycles += elapsed;
+        }
+    }
+
+    /// Forgets when the previous instruction finished, for when the bus cycles start over
+    /// after a power cycle.
+    pub fn restart_timing(&mut self) {
+        self.last_cycles = None;
+    }
+
+    /// Closes the frame that just finished.
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        for (&routine, cycles) in &self.current {
+            let total = self.totals.entry(routine).or_default();
+            total.inclusive += cycles.inclusive;
+            total.exclusive += cycles.exclusive;
+        }
+        if self.nmi_cycles > VBLANK_CYCLES {
+            self.nmi_overruns += 1;
+        }
+
+        self.last_frame = FrameProfile {
+            frame: self.frames,
+            nmi_cycles: self.nmi_cycles,
+            routines: sorted(self.current.drain()),
+        };
+        if self.last_frame.nmi_cycles > self.worst_frame.nmi_cycles {
+            self.worst_frame = self.last_frame.clone();
+        }
+        self.nmi_cycles = 0;
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn last_frame(&self) -> &FrameProfile {
+        &self.last_frame
+    }
+
+    pub fn worst_frame(&self) -> &FrameProfile {
+        &self.worst_frame
+    }
+
+    /// Cycles per routine over all finished frames, hottest first.
+    pub fn totals(&self) -> Vec<(Option<u16>, RoutineCycles)> {
+        sorted(
+            self.totals
+                .iter()
+                .map(|(&routine, &cycles)| (routine, cycles)),
+        )
+    }
+
+    /// Instruction addresses with the most cycles, hottest first.
+    pub fn hottest_addresses(&self, count: usize) -> Vec<(u16, u64)> {
+        let mut addresses: Vec<(u16, u64)> = self
+            .address_cycles
+            .iter()
+            .enumerate()
+            .filter(|(_, &cycles)| cycles > 0)
+            .map(|(address, &cycles)| (address as u16, cycles))
+            .collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        addresses.truncate(count);
+        addresses
+    }
+
+    /// Text report of the last frame, the frame with the longest NMI handler and the totals.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let _ = writeln!(
+            report,
+            "{} frames, the NMI handler ran past vblank ({} cycles) in {}",
+            self.frames, VBLANK_CYCLES, self.nmi_overruns
+        );
+        for (title, frame) in [
+            ("Last frame", &self.last_frame),
+            ("Longest NMI handler", &self.worst_frame),
+        ] {
+            let _ = writeln!(
+                report,
+                "\n{}: frame {}, NMI handler {} cycles",
+                title, frame.frame, frame.nmi_cycles
+            );
+            write_routines(&mut report, &frame.routines, 1);
+        }
+
+        let _ = writeln!(report, "\nAverage per frame:");
+        write_routines(&mut report, &self.totals(), self.frames.max(1));
+
+        let _ = writeln!(report, "\nHottest instructions:");
+        for (address, cycles) in self.hottest_addresses(REPORT_LINES) {
+            let _ = writeln!(report, "  ${:04X}  {:>10}", address, cycles);
+        }
+        report
+    }
+}
+
+fn sorted(
+    routines: impl Iterator<Item = (Option<u16>, RoutineCycles)>,
+) -> Vec<(Option<u16>, RoutineCycles)> {
+    let mut routines: Vec<_> = routines.collect();
+    routines.sort_by(|a, b| {
+        (b.1.inclusive, b.1.exclusive)
+            .cmp(&(a.1.inclusive, a.1.exclusive))
+            .then(a.0.cmp(&b.0))
+    });
+    routines
+}
+
+fn write_routines(report: &mut String, routines: &[(Option<u16>, RoutineCycles)], frames: u64) {
+    let _ = writeln!(
+        report,
+        "  {:<12} {:>10} {:>10}",
+        "Routine", "Inclusive", "Self"
+    );
+    for (routine, cycles) in routines.iter().take(REPORT_LINES) {
+        let name = match routine {
+            Some(address) => format!("${:04X}", address),
+            None => "(top level)".to_string(),
+        };
+        let _ = writeln!(
+            report,
+            "  {:<12} {:>10} {:>10}",
+            name,
+            cycles.inclusive / frames,
+            cycles.exclusive / frames
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(kind: CallKind, target: u16) -> CallFrame {
+        CallFrame {
+            kind,
+            from: 0x8000,
+            target,
+            return_address: 0x8003,
+            s: 0xfb,
+        }
+    }
+
+    #[test]
+    fn test_routine_cycles() {
+        let nmi = frame(CallKind::Nmi, 0xc000);
+        let sub = frame(CallKind::Subroutine, 0xc100);
+        let mut profiler = Profiler::new();
+        profiler.record(0x8000, 100, &[]);
+        profiler.record(0x8001, 102, &[]);
+        profiler.record(0xc000, 109, &[nmi]);
+        profiler.record(0xc100, 115, &[nmi, sub]);
+        profiler.record(0xc101, 2500, &[nmi, sub]);
+        profiler.end_frame();
+
+        let last = profiler.last_frame();
+        assert_eq!(last.nmi_cycles, 2398);
+        assert_eq!(
+            last.routines,
+            vec![
+                (
+                    None,
+                    RoutineCycles {
+                        inclusive: 2400,
+                        exclusive: 2
+                    }
+                ),
+                (
+                    Some(0xc000),
+                    RoutineCycles {
+                        inclusive: 2398,
+                        exclusive: 7
+                    }
+                ),
+                (
+                    Some(0xc100),
+                    RoutineCycles {
+                        inclusive: 2391,
+                        exclusive: 2391
+                    }
+                ),
+            ]
+        );
+        assert_eq!(profiler.hottest_addresses(1), vec![(0xc101, 2385)]);
+        assert!(profiler.report().contains("vblank (2273 cycles) in 1"));
+
+        profiler.record(0x8000, 2510, &[]);
+        profiler.end_frame();
+        assert_eq!(profiler.frames(), 2);
+        assert_eq!(profiler.worst_frame().frame, 1);
+        assert_eq!(profiler.totals()[0].1.inclusive, 2410);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::cpu::{Mem, CPU};
+use std::fmt;
+
+/// Size of the CPU's internal RAM, the rest of $0000-$1FFF mirrors it.
+pub const RAM_SIZE: usize = 0x800;
+
+/// How a byte has to compare to narrow down a RAM search.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchFilter {
+    Equal(u8),
+    NotEqual(u8),
+    Less(u8),
+    Greater(u8),
+    /// Compared to the value at the previous search.
+    Increased,
+    Decreased,
+    Changed,
+    Unchanged,
+}
+
+impl SearchFilter {
+    /// Filters for the search window, with 0 for the ones that take a value.
+    pub const ALL: [SearchFilter; 8] = [
+        SearchFilter::Equal(0),
+        SearchFilter::NotEqual(0),
+        SearchFilter::Less(0),
+        SearchFilter::Greater(0),
+        SearchFilter::Increased,
+        SearchFilter::Decreased,
+        SearchFilter::Changed,
+        SearchFilter::Unchanged,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SearchFilter::Equal(_) => "Equal to",
+            SearchFilter::NotEqual(_) => "Not equal to",
+            SearchFilter::Less(_) => "Less than",
+            SearchFilter::Greater(_) => "Greater than",
+            SearchFilter::Increased => "Increased",
+            SearchFilter::Decreased => "Decreased",
+            SearchFilter::Changed => "Changed",
+            SearchFilter::Unchanged => "Unchanged",
+        }
+    }
+
+    pub fn takes_value(self) -> bool {
+        matches!(
+            self,
+            SearchFilter::Equal(_)
+                | SearchFilter::NotEqual(_)
+                | SearchFilter::Less(_)
+                | SearchFilter::Greater(_)
+        )
+    }
+
+    /// The same filter comparing to `value`, filters without a value stay as they are.
+    pub fn with_value(self, value: u8) -> Self {
+        match self {
+            SearchFilter::Equal(_) => SearchFilter::Equal(value),
+            SearchFilter::NotEqual(_) => SearchFilter::NotEqual(value),
+            SearchFilter::Less(_) => SearchFilter::Less(value),
+            SearchFilter::Greater(_) => SearchFilter::Greater(value),
+            filter => filter,
+        }
+    }
+
+    /// Parses filters like `= 3`, `< $10`, `decreased` or `changed`, values are decimal
+    /// unless they start with `$`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut words = text.split_whitespace();
+        let (Some(name), value, None) = (words.next(), words.next(), words.next()) else {
+            return Err(format!("Invalid search {:?}", text.trim()));
+        };
+        let filter = match name {
+            "=" | "==" => SearchFilter::Equal(0),
+            "!=" => SearchFilter::NotEqual(0),
+            "<" => SearchFilter::Less(0),
+            ">" => SearchFilter::Greater(0),
+            "increased" | "inc" => SearchFilter::Increased,
+            "decreased" | "dec" => SearchFilter::Decreased,
+            "changed" => SearchFilter::Changed,
+            "unchanged" => SearchFilter::Unchanged,
+            _ => return Err(format!("Unknown search {:?}", name)),
+        };
+        match (filter.takes_value(), value) {
+            (true, Some(value)) => Ok(filter.with_value(parse_value(value)?)),
+            (false, None) => Ok(filter),
+            (true, None) => Err(format!("{} needs a value", filter.name())),
+            (false, Some(_)) => Err(format!("{} takes no value", filter.name())),
+        }
+    }
+
+    fn matches(self, previous: u8, value: u8) -> bool {
+        match self {
+            SearchFilter::Equal(other) => value == other,
+            SearchFilter::NotEqual(other) => value != other,
+            SearchFilter::Less(other) => value < other,
+            SearchFilter::Greater(other) => value > other,
+            SearchFilter::Increased => value > previous,
+            SearchFilter::Decreased => value < previous,
+            SearchFilter::Changed => value != previous,
+            SearchFilter::Unchanged => value == previous,
+        }
+    }
+}
+
+impl fmt::Display for SearchFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SearchFilter::Equal(value)
+            | SearchFilter::NotEqual(value)
+            | SearchFilter::Less(value)
+            | SearchFilter::Greater(value) => write!(f, "{} {}", self.name(), value),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// Parses a byte, decimal or hex with a leading `$`.
+pub fn parse_value(text: &str) -> Result<u8, String> {
+    let parsed = match text.strip_prefix('$') {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid value {:?}", text))
+}
+
+/// Narrows down the RAM addresses that could hold a variable, like the number of lives,
+/// by comparing RAM between searches made at different moments of the game.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RamSearch {
+    /// RAM at the previous search.
+    previous: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl RamSearch {
+    /// Starts a search with every address as a candidate.
+    pub fn new(ram: &[u8]) -> Self {
+        RamSearch {
+            previous: ram.to_vec(),
+            candidates: (0..ram.len() as u16).collect(),
+        }
+    }
+
+    /// Keeps the candidates whose value in `ram` passes the filter.
+    pub fn filter(&mut self, filter: SearchFilter, ram: &[u8]) {
+        let previous = &self.previous;
+        self.candidates
+            .retain(|&address| filter.matches(previous[address as usize], ram[address as usize]));
+        self.previous = ram.to_vec();
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    /// The value of an address at the previous search.
+    pub fn value(&self, address: u16) -> u8 {
+        self.previous[address as usize]
+    }
+}
+
+/// Copies the CPU's internal RAM.
+pub fn read_ram(cpu: &mut CPU) -> Vec<u8> {
+    (0..RAM_SIZE as u16)
+        .map(|address| cpu.read(address))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let mut ram = vec![0; RAM_SIZE];
+        ram[0x30] = 3;
+        ram[0x31] = 3;
+        ram[0x40] = 3;
+        let mut search = RamSearch::new(&ram);
+
+        search.filter(SearchFilter::Equal(3), &ram);
+        assert_eq!(search.candidates(), [0x30, 0x31, 0x40]);
+
+        ram[0x30] = 2;
+        ram[0x40] = 4;
+        search.filter(SearchFilter::Decreased, &ram);
+        assert_eq!(search.candidates(), [0x30]);
+        assert_eq!(search.value(0x30), 2);
+
+        search.filter(SearchFilter::Changed, &ram);
+        assert!(search.candidates().is_empty());
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(SearchFilter::parse("= 3"), Ok(SearchFilter::Equal(3)));
+        assert_eq!(SearchFilter::parse("< $10"), Ok(SearchFilter::Less(0x10)));
+        assert_eq!(SearchFilter::parse(" dec "), Ok(SearchFilter::Decreased));
+        assert!(SearchFilter::parse("=").is_err());
+        assert!(SearchFilter::parse("changed 3").is_err());
+        assert!(SearchFilter::parse("> 256").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::cpu::CPU;
+use crate::memory::MemoryRegion;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchSize {
+    Byte,
+    /// Two bytes, low byte first.
+    Word,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchFormat {
+    Hex,
+    Unsigned,
+    Signed,
+}
+
+impl WatchSize {
+    pub const ALL: [WatchSize; 2] = [WatchSize::Byte, WatchSize::Word];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WatchSize::Byte => "Byte",
+            WatchSize::Word => "Word",
+        }
+    }
+
+    pub fn bytes(self) -> u16 {
+        match self {
+            WatchSize::Byte => 1,
+            WatchSize::Word => 2,
+        }
+    }
+}
+
+impl WatchFormat {
+    pub const ALL: [WatchFormat; 3] =
+        [WatchFormat::Hex, WatchFormat::Unsigned, WatchFormat::Signed];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WatchFormat::Hex => "Hex",
+            WatchFormat::Unsigned => "Unsigned",
+            WatchFormat::Signed => "Signed",
+        }
+    }
+}
+
+/// A named RAM address whose value is shown every frame, and can be frozen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Watch {
+    pub name: String,
+    pub address: u16,
+    pub size: WatchSize,
+    pub format: WatchFormat,
+    /// Value written back every frame.
+    pub frozen: Option<u16>,
+}
+
+impl Watch {
+    pub fn new(name: &str, address: u16, size: WatchSize, format: WatchFormat) -> Self {
+        Watch {
+            name: name.to_string(),
+            address,
+            size,
+            format,
+            frozen: None,
+        }
+    }
+
+    /// The current value, read without side effects. `None` when the watch covers I/O
+    /// registers or unmapped addresses.
+    pub fn read(&self, cpu: &mut CPU) -> Option<u16> {
+        let low = MemoryRegion::Cpu.peek(cpu, self.address)? as u16;
+        match self.size {
+            WatchSize::Byte => Some(low),
+            WatchSize::Word => {
+                let high = MemoryRegion::Cpu.peek(cpu, self.address.wrapping_add(1))? as u16;
+                Some(high << 8 | low)
+            }
+        }
+    }
+
+    /// The bytes to write to freeze the watch at `value`, lowest address first.
+    pub fn bytes(&self, value: u16) -> Vec<(u16, u8)> {
+        (0..self.size.bytes())
+            .map(|i| (self.address.wrapping_add(i), (value >> (8 * i)) as u8))
+            .collect()
+    }
+
+    pub fn format_value(&self, value: u16) -> String {
+        match (self.format, self.size) {
+            (WatchFormat::Hex, WatchSize::Byte) => format!("${:02X}", value),
+            (WatchFormat::Hex, WatchSize::Word) => format!("${:04X}", value),
+            (WatchFormat::Unsigned, _) => value.to_string(),
+            (WatchFormat::Signed, WatchSize::Byte) => (value as u8 as i8).to_string(),
+            (WatchFormat::Signed, WatchSize::Word) => (value as i16).to_string(),
+        }
+    }
+
+    /// Parses a value in the watch's format, hex may start with `$` and signed values may be
+    /// negative.
+    pub fn parse_value(&self, text: &str) -> Result<u16, String> {
+        let text = text.trim();
+        let max: i64 = match self.size {
+            WatchSize::Byte => 0xff,
+            WatchSize::Word => 0xffff,
+        };
+        let (value, range) = match self.format {
+            WatchFormat::Hex => (
+                i64::from_str_radix(text.trim_start_matches('$'), 16),
+                0..=max,
+            ),
+            WatchFormat::Unsigned => (text.parse(), 0..=max),
+            WatchFormat::Signed => (text.parse(), -(max + 1) / 2..=max / 2),
+        };
+        match value {
+            Ok(value) if range.contains(&value) => Ok(value as u16 & max as u16),
+            _ => Err(format!("Invalid value {:?}", text)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_format_and_parse() {
+        let mut lives = Watch::new("lives", 0x30, WatchSize::Byte, WatchFormat::Signed);
+        assert_eq!(lives.format_value(0xff), "-1");
+        assert_eq!(lives.parse_value("-1"), Ok(0xff));
+        assert!(lives.parse_value("-129").is_err());
+        assert!(lives.parse_value("200").is_err());
+        lives.format = WatchFormat::Hex;
+        assert_eq!(lives.format_value(0x0a), "$0A");
+        assert_eq!(lives.parse_value("$0a"), Ok(0x0a));
+        assert!(lives.parse_value("100").is_err());
+
+        let score = Watch::new("score", 0x40, WatchSize::Word, WatchFormat::Unsigned);
+        assert_eq!(score.format_value(1000), "1000");
+        assert_eq!(score.bytes(0x1234), vec![(0x40, 0x34), (0x41, 0x12)]);
+    }
+
+    #[test]
+    fn test_read_without_side_effects() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        cpu.power_cycle();
+        cpu.write(0x40, 0x34);
+        cpu.write(0x41, 0x12);
+        let score = Watch::new("score", 0x40, WatchSize::Word, WatchFormat::Unsigned);
+        assert_eq!(score.read(&mut cpu), Some(0x1234));
+
+        // Write-only registers don't panic and PPUSTATUS keeps its vblank flag
+        for address in [0x2000, 0x2002, 0x2007, 0x4016] {
+            let watch = Watch::new("io", address, WatchSize::Byte, WatchFormat::Hex);
+            cpu.bus.ppu.register_status.set_vertical_blank(true);
+            assert_eq!(watch.read(&mut cpu), None);
+            assert!(cpu.bus.ppu.register_status.get_vertical_blank());
+        }
+        let edge = Watch::new("edge", 0x1fff, WatchSize::Word, WatchFormat::Hex);
+        assert_eq!(edge.read(&mut cpu), None);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::render::Frame;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordingFormat {
+    /// Headerless RGB24 frames, one after another.
+    Raw,
+    /// Frames are piped to an external ffmpeg process that encodes an mp4.
+    Ffmpeg,
+}
+
+impl RecordingFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            RecordingFormat::Raw => "rgb",
+            RecordingFormat::Ffmpeg => "mp4",
+        }
+    }
+}
+
+/// Captures rendered frames to a file or an ffmpeg process until it is finished.
+pub struct Recorder {
+    path: PathBuf,
+    output: Box<dyn Write>,
+    ffmpeg: Option<Child>,
+    frames: u64,
+}
+
+impl Recorder {
+    pub fn start(format: RecordingFormat, path: &Path, frame_rate: f64) -> io::Result<Self> {
+        match format {
+            RecordingFormat::Raw => Recorder::raw(path),
+            RecordingFormat::Ffmpeg => Recorder::ffmpeg(path, frame_rate),
+        }
+    }
+
+    pub fn raw(path: &Path) -> io::Result<Self> {
+        Ok(Recorder {
+            path: path.to_path_buf(),
+            output: Box::new(BufWriter::new(File::create(path)?)),
+            ffmpeg: None,
+            frames: 0,
+        })
+    }
+
+    pub fn ffmpeg(path: &Path, frame_rate: f64) -> io::Result<Self> {
+        let mut child = Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-y"])
+            .args(["-f", "rawvideo", "-pixel_format", "rgb24"])
+            .args(["-video_size", "256x240"])
+            .args(["-framerate", &frame_rate.to_string()])
+            .args(["-i", "-"])
+            .args(["-c:v", "libx264", "-pix_fmt", "yuv420p"])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+
+        Ok(Recorder {
+            path: path.to_path_buf(),
+            output: Box::new(BufWriter::new(stdin)),
+            ffmpeg: Some(child),
+            frames: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) -> io::Result<()> {
+        self.output.write_all(&frame.data)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Flushes all frames and waits for ffmpeg to finish encoding, returns the number of frames.
+    pub fn finish(mut self) -> io::Result<u64> {
+        self.output.flush()?;
+
+        // Dropping the pipe signals end of input to ffmpeg
+        drop(self.output);
+        if let Some(mut child) = self.ffmpeg {
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("ffmpeg exited with {}", status)));
+            }
+        }
+        Ok(self.frames)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_raw_recording() {
+        let path = std::env::temp_dir().join("nes_rust_test_raw_recording.rgb");
+        let mut frame = Frame::new();
+        let mut recorder = Recorder::raw(&path).unwrap();
+
+        recorder.write_frame(&frame).unwrap();
+        frame.set_pixel(0, 0, (1, 2, 3));
+        recorder.write_frame(&frame).unwrap();
+        assert_eq!(recorder.finish().unwrap(), 2);
+
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), 2 * frame.data.len());
+        assert_eq!(&bytes[frame.data.len()..frame.data.len() + 3], &[1, 2, 3]);
+    }
+}
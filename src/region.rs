@@ -0,0 +1,169 @@
+use crate::cartridge::{header_region, Region};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Frame rate of the NTSC PPU, 262 scanlines of 341 dots at 5.369318 MHz.
+pub const NTSC_FRAME_RATE: f64 = 60.0988;
+
+/// Frame rate of the PAL PPU, 312 scanlines of 341 dots at 5.320342 MHz.
+pub const PAL_FRAME_RATE: f64 = 50.007;
+
+/// The ROM database next to the config, see `RomDatabase::parse`.
+pub const DATABASE_PATH: &str = "romdb.txt";
+
+/// Regions of known games by the CRC32 of their PRG and CHR data.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RomDatabase {
+    regions: HashMap<u32, Region>,
+}
+
+impl RomDatabase {
+    /// Parses lines like `1B3BAF4C PAL Elite (Europe)`, the name is optional. Blank lines and
+    /// lines starting with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut database = RomDatabase::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let crc = fields
+                .next()
+                .and_then(|crc| u32::from_str_radix(crc, 16).ok());
+            let region = fields.next().and_then(Region::parse);
+            match crc.zip(region) {
+                Some((crc, region)) => database.regions.insert(crc, region),
+                None => return Err(format!("Line {}: Expected CRC and region", index + 1)),
+            };
+        }
+        Ok(database)
+    }
+
+    /// Reads the database, a missing file is an empty database.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    pub fn get(&self, crc: u32) -> Option<Region> {
+        self.regions.get(&crc).copied()
+    }
+}
+
+/// Region from the GoodNES and No-Intro tags of the file name, like `(E)` or `(Europe)`. Games
+/// tagged for both regions are taken as NTSC.
+pub fn file_name_region(path: &Path) -> Option<Region> {
+    let name = path.file_stem()?.to_string_lossy();
+    let tags: Vec<&str> = name
+        .split('(')
+        .skip(1)
+        .filter_map(|group| group.split_once(')'))
+        .flat_map(|(group, _)| group.split(','))
+        .map(str::trim)
+        .collect();
+    let tagged = |names: &[&str]| tags.iter().any(|tag| names.contains(tag));
+    if tagged(&["U", "J", "JU", "USA", "Japan"]) {
+        Some(Region::Ntsc)
+    } else if tagged(&[
+        "E",
+        "Europe",
+        "PAL",
+        "A",
+        "Australia",
+        "G",
+        "Germany",
+        "F",
+        "France",
+        "S",
+        "Spain",
+        "I",
+        "Italy",
+        "Sw",
+        "Sweden",
+    ]) {
+        Some(Region::Pal)
+    } else {
+        None
+    }
+}
+
+/// The region to run a ROM in, trusting the header first, then the database and then the file
+/// name. Returns where the region came from too.
+pub fn detect(
+    bytes: &[u8],
+    crc: u32,
+    path: &Path,
+    database: &RomDatabase,
+) -> (Region, &'static str) {
+    if let Some(region) = header_region(bytes) {
+        (region, "header")
+    } else if let Some(region) = database.get(crc) {
+        (region, "ROM database")
+    } else if let Some(region) = file_name_region(path) {
+        (region, "file name")
+    } else {
+        (Region::Ntsc, "default")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_database() {
+        let database =
+            RomDatabase::parse("# Known games\n1B3BAF4C PAL Elite (Europe)\n\n0000ABCD ntsc\n")
+                .unwrap();
+        assert_eq!(database.get(0x1b3baf4c), Some(Region::Pal));
+        assert_eq!(database.get(0xabcd), Some(Region::Ntsc));
+        assert_eq!(database.get(0x1234), None);
+        assert_eq!(
+            RomDatabase::parse("1B3BAF4C SECAM"),
+            Err("Line 1: Expected CRC and region".to_string())
+        );
+    }
+
+    #[test]
+    fn test_detect() {
+        let header = [
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let database = RomDatabase::parse("00000001 PAL").unwrap();
+        let path = Path::new("roms/Game (Europe).nes");
+
+        assert_eq!(file_name_region(path), Some(Region::Pal));
+        assert_eq!(
+            file_name_region(Path::new("Game (E) [!].nes")),
+            Some(Region::Pal)
+        );
+        assert_eq!(
+            file_name_region(Path::new("Game (USA, Europe).nes")),
+            Some(Region::Ntsc)
+        );
+        assert_eq!(file_name_region(Path::new("Europe.nes")), None);
+
+        assert_eq!(
+            detect(&header, 1, path, &database),
+            (Region::Pal, "ROM database")
+        );
+        assert_eq!(
+            detect(&header, 2, path, &database),
+            (Region::Pal, "file name")
+        );
+        let path = Path::new("Game.nes");
+        assert_eq!(
+            detect(&header, 2, path, &database),
+            (Region::Ntsc, "default")
+        );
+        let mut pal = header;
+        pal[9] = 0x01;
+        assert_eq!(detect(&pal, 2, path, &database), (Region::Pal, "header"));
+    }
+}
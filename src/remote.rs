@@ -0,0 +1,607 @@
+use crate::condition::Condition;
+use crate::cpu::CPU;
+use crate::debugger::{DebugCommand, Registers};
+use crate::emulation::{Command, Inspection};
+use crate::joypad::button_from_name;
+use crate::memory::MemoryRegion;
+use crate::render::{self, Frame};
+use serde_json::{json, Value};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+use tungstenite::{Message, WebSocket};
+
+/// How long a client connection waits for a request before forwarding stops.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A request of the remote protocol, sent as a JSON text message like
+/// `{"id": 1, "command": "read", "address": 0, "length": 16}`.
+///
+/// Every request is answered with a message carrying the same `id`, either with the result
+/// fields or with an `error`. An answer with a `length` is followed by that many bytes of
+/// binary data: the 256x240 RGB pixels for `frame`, a PNG for `screenshot` without a `path`.
+/// Whenever execution stops, `{"event": "break", "registers": ...}` is sent without being
+/// asked for.
+///
+/// Clients that do not speak WebSocket can send the same requests over plain TCP, one JSON
+/// object per line, and get the answers the same way with the binary data in between.
+enum Request {
+    Debug(DebugCommand),
+    Registers,
+    Read {
+        region: MemoryRegion,
+        address: u16,
+        length: usize,
+    },
+    Write {
+        region: MemoryRegion,
+        address: u16,
+        value: u8,
+    },
+    Frame,
+    Reset,
+    PowerCycle,
+    Button(u8, bool),
+    /// Holds exactly these buttons for a number of frames, even while paused, then releases
+    /// them and answers with the frame count.
+    Press {
+        buttons: u8,
+        frames: u64,
+    },
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+    /// The current frame as PNG, saved on the server when there is a path.
+    Screenshot(Option<PathBuf>),
+}
+
+/// What a client sent, if anything.
+enum Incoming {
+    Text(String),
+    Idle,
+    Closed,
+}
+
+/// A client, told apart by whether it starts with the `GET` of a WebSocket handshake.
+enum Connection {
+    WebSocket(Box<WebSocket<TcpStream>>),
+    Lines { stream: TcpStream, buffer: Vec<u8> },
+}
+
+impl Connection {
+    fn accept(stream: TcpStream) -> Result<Self, String> {
+        let mut start = [0; 4];
+        let length = stream.peek(&mut start).map_err(|error| error.to_string())?;
+        let connection = if start[..length] == *b"GET " {
+            let socket = tungstenite::accept(stream).map_err(|error| error.to_string())?;
+            Connection::WebSocket(Box::new(socket))
+        } else {
+            Connection::Lines {
+                stream,
+                buffer: Vec::new(),
+            }
+        };
+        let stream = match &connection {
+            Connection::WebSocket(socket) => socket.get_ref(),
+            Connection::Lines { stream, .. } => stream,
+        };
+        stream
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(|error| error.to_string())?;
+        Ok(connection)
+    }
+
+    fn receive(&mut self) -> Result<Incoming, String> {
+        match self {
+            Connection::WebSocket(socket) => match socket.read() {
+                Ok(Message::Text(text)) => Ok(Incoming::Text(text)),
+                Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => {
+                    Ok(Incoming::Closed)
+                }
+                Ok(_) => Ok(Incoming::Idle),
+                Err(tungstenite::Error::Io(error)) if timed_out(&error) => Ok(Incoming::Idle),
+                Err(error) => Err(error.to_string()),
+            },
+            Connection::Lines { stream, buffer } => loop {
+                if let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=end).collect();
+                    let line = String::from_utf8_lossy(&line).trim().to_string();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    return Ok(Incoming::Text(line));
+                }
+                let mut chunk = [0; 4096];
+                match stream.read(&mut chunk) {
+                    Ok(0) => return Ok(Incoming::Closed),
+                    Ok(length) => buffer.extend_from_slice(&chunk[..length]),
+                    Err(error) if timed_out(&error) => return Ok(Incoming::Idle),
+                    Err(error) => return Err(error.to_string()),
+                }
+            },
+        }
+    }
+
+    fn send_text(&mut self, text: String) -> Result<(), String> {
+        match self {
+            Connection::WebSocket(socket) => socket
+                .send(Message::Text(text))
+                .map_err(|error| error.to_string()),
+            Connection::Lines { stream, .. } => {
+                writeln!(stream, "{}", text).map_err(|error| error.to_string())
+            }
+        }
+    }
+
+    fn send_binary(&mut self, data: Vec<u8>) -> Result<(), String> {
+        match self {
+            Connection::WebSocket(socket) => socket
+                .send(Message::Binary(data))
+                .map_err(|error| error.to_string()),
+            Connection::Lines { stream, .. } => {
+                stream.write_all(&data).map_err(|error| error.to_string())
+            }
+        }
+    }
+}
+
+fn timed_out(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Listens for clients on `address`, e.g. `127.0.0.1:6502`, each is served on its own
+/// thread.
+pub fn spawn_server(address: &str, emulation: Sender<Command>) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!(
+        "Remote control on {}, WebSocket or JSON lines",
+        listener.local_addr()?
+    );
+    thread::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            let emulation = emulation.clone();
+            thread::spawn(move || {
+                if let Err(error) = serve(stream, emulation) {
+                    println!("Remote client disconnected: {}", error);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: TcpStream, emulation: Sender<Command>) -> Result<(), String> {
+    let mut connection = Connection::accept(stream)?;
+
+    let (listener, stops) = mpsc::channel();
+    emulation
+        .send(Command::Debug(DebugCommand::Attach(listener)))
+        .map_err(|_| "emulation has stopped".to_string())?;
+
+    loop {
+        for registers in stops.try_iter() {
+            let event = json!({"event": "break", "registers": registers_json(&registers)});
+            connection.send_text(event.to_string())?;
+        }
+
+        let text = match connection.receive()? {
+            Incoming::Text(text) => text,
+            Incoming::Idle => continue,
+            Incoming::Closed => return Ok(()),
+        };
+
+        let (id, request) = match parse_request(&text) {
+            Ok(parsed) => parsed,
+            Err((id, error)) => {
+                connection.send_text(json!({"id": id, "error": error}).to_string())?;
+                continue;
+            }
+        };
+        let (mut reply, data) = match execute(request, &emulation) {
+            Ok(result) => result,
+            Err(error) => (json!({"error": error}), None),
+        };
+        reply["id"] = id;
+        if let Some(data) = &data {
+            reply["length"] = json!(data.len());
+        }
+        connection.send_text(reply.to_string())?;
+        if let Some(data) = data {
+            connection.send_binary(data)?;
+        }
+    }
+}
+
+/// Parses a request, errors carry the id so they can still be answered.
+fn parse_request(text: &str) -> Result<(Value, Request), (Value, String)> {
+    let message: Value =
+        serde_json::from_str(text).map_err(|error| (Value::Null, error.to_string()))?;
+    let id = message.get("id").cloned().unwrap_or(Value::Null);
+    let request = parse_fields(&message).map_err(|error| (id.clone(), error))?;
+    Ok((id, request))
+}
+
+fn parse_fields(message: &Value) -> Result<Request, String> {
+    let number = |name: &str| {
+        message
+            .get(name)
+            .and_then(Value::as_u64)
+            .ok_or_else(|| format!("Missing number {:?}", name))
+    };
+    let address = || {
+        number("address").and_then(|address| {
+            u16::try_from(address).map_err(|_| format!("Address {} is too large", address))
+        })
+    };
+    let enabled = || {
+        message
+            .get("enabled")
+            .and_then(Value::as_bool)
+            .ok_or("Missing bool \"enabled\"")
+    };
+    let path = || {
+        message
+            .get("path")
+            .and_then(Value::as_str)
+            .map(PathBuf::from)
+            .ok_or("Missing string \"path\"")
+    };
+    let region = || match message.get("region").and_then(Value::as_str) {
+        None => Ok(MemoryRegion::Cpu),
+        Some(name) => MemoryRegion::ALL
+            .into_iter()
+            .find(|region| region.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("Unknown region {:?}", name)),
+    };
+
+    let command = message
+        .get("command")
+        .and_then(Value::as_str)
+        .ok_or("Missing \"command\"")?;
+    let request = match command {
+        "pause" => Request::Debug(DebugCommand::Break),
+        "continue" => Request::Debug(DebugCommand::Continue),
+        "step" => Request::Debug(DebugCommand::Step),
+        "step_over" => Request::Debug(DebugCommand::StepOver),
+        "step_out" => Request::Debug(DebugCommand::StepOut),
+        "set_breakpoint" => {
+            let condition = match message.get("condition").and_then(Value::as_str) {
+                Some(condition) => Some(Condition::parse(condition)?),
+                None => None,
+            };
+            Request::Debug(DebugCommand::SetBreakpoint(address()?, condition))
+        }
+        "clear_breakpoint" => Request::Debug(DebugCommand::ClearBreakpoint(address()?)),
+        "break_on_nmi" => Request::Debug(DebugCommand::BreakOnNmi(enabled()?)),
+        "break_on_ppu_write" => match address()? {
+            register @ 0x2000..=0x2007 => {
+                Request::Debug(DebugCommand::BreakOnPpuWrite(register, enabled()?))
+            }
+            _ => return Err("Address is not a PPU register".to_string()),
+        },
+        "registers" => Request::Registers,
+        "read" => Request::Read {
+            region: region()?,
+            address: address()?,
+            length: number("length")?.min(0x10000) as usize,
+        },
+        "write" => Request::Write {
+            region: region()?,
+            address: address()?,
+            value: u8::try_from(number("value")?).map_err(|_| "Value is not a byte")?,
+        },
+        "frame" => Request::Frame,
+        "reset" => Request::Reset,
+        "power_cycle" => Request::PowerCycle,
+        "button" => {
+            let button = message
+                .get("button")
+                .and_then(Value::as_str)
+                .and_then(button_from_name)
+                .ok_or("Unknown button")?;
+            let pressed = message
+                .get("pressed")
+                .and_then(Value::as_bool)
+                .ok_or("Missing bool \"pressed\"")?;
+            Request::Button(button, pressed)
+        }
+        "press" => {
+            let names = match message.get("buttons") {
+                None => Vec::new(),
+                Some(names) => names.as_array().ok_or("\"buttons\" is not a list")?.clone(),
+            };
+            let mut buttons = 0;
+            for name in names {
+                buttons |= name
+                    .as_str()
+                    .and_then(button_from_name)
+                    .ok_or_else(|| format!("Unknown button {}", name))?;
+            }
+            Request::Press {
+                buttons,
+                frames: number("frames")?,
+            }
+        }
+        "save_state" => Request::SaveState(path()?),
+        "load_state" => Request::LoadState(path()?),
+        "screenshot" => Request::Screenshot(path().ok()),
+        _ => return Err(format!("Unknown command {:?}", command)),
+    };
+    Ok(request)
+}
+
+/// Runs a request, returns the reply fields and any binary data that follows them.
+fn execute(
+    request: Request,
+    emulation: &Sender<Command>,
+) -> Result<(Value, Option<Vec<u8>>), String> {
+    let command = match request {
+        Request::Debug(command) => Command::Debug(command),
+        Request::Reset => Command::Reset,
+        Request::PowerCycle => Command::PowerCycle,
+        Request::Button(button, pressed) => Command::Button(button, pressed),
+        Request::Registers => {
+            let registers = inspect(emulation, Registers::of)?;
+            return Ok((json!({"registers": registers_json(&registers)}), None));
+        }
+        Request::Read {
+            region,
+            address,
+            length,
+        } => {
+            let bytes = inspect(emulation, move |cpu| {
+                region.peek_range(cpu, address, length)
+            })?;
+            return Ok((json!({ "bytes": bytes }), None));
+        }
+        Request::Write {
+            region,
+            address,
+            value,
+        } => {
+            let written = inspect(emulation, move |cpu| region.poke(cpu, address, value))?;
+            return Ok((json!({ "written": written }), None));
+        }
+        Request::Frame => {
+            let frame = inspect(emulation, render_frame)?;
+            let reply = json!({"width": 256, "height": 240, "format": "rgb24"});
+            return Ok((reply, Some(frame.data.to_vec())));
+        }
+        Request::Press { buttons, frames } => {
+            let start = inspect(emulation, move |cpu| {
+                cpu.bus.joypad_mut().set_buttons(buttons);
+                cpu.bus.frames()
+            })?;
+            let frame = run_until(emulation, start + frames, move |cpu| {
+                let joypad = cpu.bus.joypad_mut();
+                joypad.set_buttons(joypad.buttons() & !buttons);
+                cpu.bus.frames()
+            })?;
+            return Ok((json!({ "frame": frame }), None));
+        }
+        Request::SaveState(path) => {
+            inspect(emulation, move |cpu| cpu.save_state(&path))?
+                .map_err(|error| error.to_string())?;
+            return Ok((json!({}), None));
+        }
+        Request::LoadState(path) => {
+            inspect(emulation, move |cpu| cpu.load_state(&path))?
+                .map_err(|error| error.to_string())?;
+            return Ok((json!({}), None));
+        }
+        Request::Screenshot(path) => {
+            let frame = inspect(emulation, render_frame)?;
+            let Some(path) = path else {
+                return Ok((json!({"format": "png"}), Some(frame.to_png())));
+            };
+            frame.save_png(&path).map_err(|error| error.to_string())?;
+            return Ok((json!({ "path": path }), None));
+        }
+    };
+    emulation
+        .send(command)
+        .map_err(|_| "emulation has stopped".to_string())?;
+    Ok((json!({}), None))
+}
+
+fn render_frame(cpu: &mut CPU) -> Box<Frame> {
+    let mut frame = Box::new(Frame::new());
+    render::render(&cpu.bus.ppu, &mut frame);
+    frame
+}
+
+/// Runs `f` on the emulation thread and waits for its result.
+fn inspect<T: Send + 'static>(
+    emulation: &Sender<Command>,
+    f: impl FnOnce(&mut CPU) -> T + Send + 'static,
+) -> Result<T, String> {
+    call(emulation, Command::Inspect, f)
+}
+
+/// Runs `f` on the emulation thread once the frame count reaches `frame`.
+fn run_until<T: Send + 'static>(
+    emulation: &Sender<Command>,
+    frame: u64,
+    f: impl FnOnce(&mut CPU) -> T + Send + 'static,
+) -> Result<T, String> {
+    call(emulation, |inspect| Command::RunUntil(frame, inspect), f)
+}
+
+fn call<T: Send + 'static>(
+    emulation: &Sender<Command>,
+    command: impl FnOnce(Inspection) -> Command,
+    f: impl FnOnce(&mut CPU) -> T + Send + 'static,
+) -> Result<T, String> {
+    let (sender, result) = mpsc::channel();
+    emulation
+        .send(command(Box::new(move |cpu| {
+            let _ = sender.send(f(cpu));
+        })))
+        .map_err(|_| "emulation has stopped".to_string())?;
+    result
+        .recv()
+        .map_err(|_| "emulation has stopped".to_string())
+}
+
+fn registers_json(registers: &Registers) -> Value {
+    let call_stack: Vec<Value> = registers
+        .call_stack
+        .iter()
+        .map(|frame| {
+            json!({
+                "from": frame.from,
+                "target": frame.target,
+                "return_address": frame.return_address,
+            })
+        })
+        .collect();
+    json!({
+        "pc": registers.pc,
+        "a": registers.a,
+        "x": registers.x,
+        "y": registers.y,
+        "p": registers.p,
+        "s": registers.s,
+        "instruction": registers.instruction.text,
+        "call_stack": call_stack,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::RamInit;
+    use crate::cartridge::test::looping_rom;
+    use crate::emulation;
+    use crate::joypad::{JOYPAD_A, JOYPAD_RIGHT, JOYPAD_START};
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn test_parse_request() {
+        let (id, request) = parse_request(
+            r#"{"id": 7, "command": "read", "region": "oam", "address": 16, "length": 4}"#,
+        )
+        .unwrap();
+        assert_eq!(id, json!(7));
+        assert!(matches!(
+            request,
+            Request::Read {
+                region: MemoryRegion::Oam,
+                address: 16,
+                length: 4
+            }
+        ));
+
+        assert!(matches!(
+            parse_request(
+                r#"{"command": "set_breakpoint", "address": 49152, "condition": "A == 0"}"#
+            ),
+            Ok((
+                Value::Null,
+                Request::Debug(DebugCommand::SetBreakpoint(0xc000, Some(_)))
+            ))
+        ));
+        assert!(matches!(
+            parse_request(r#"{"command": "button", "button": "start", "pressed": true}"#),
+            Ok((_, Request::Button(JOYPAD_START, true)))
+        ));
+        assert!(matches!(
+            parse_request(r#"{"command": "break_on_ppu_write", "address": 8193, "enabled": true}"#),
+            Ok((
+                _,
+                Request::Debug(DebugCommand::BreakOnPpuWrite(0x2001, true))
+            ))
+        ));
+        assert!(matches!(
+            parse_request(r#"{"command": "press", "buttons": ["a", "right"], "frames": 30}"#),
+            Ok((
+                _,
+                Request::Press {
+                    buttons,
+                    frames: 30
+                }
+            )) if buttons == JOYPAD_A | JOYPAD_RIGHT
+        ));
+        assert!(matches!(
+            parse_request(r#"{"command": "load_state", "path": "level2.state"}"#),
+            Ok((_, Request::LoadState(path))) if path.as_os_str() == "level2.state"
+        ));
+        assert!(matches!(
+            parse_request(r#"{"command": "screenshot"}"#),
+            Ok((_, Request::Screenshot(None)))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        assert_eq!(
+            parse_request(r#"{"id": "x", "command": "jump"}"#).err(),
+            Some((json!("x"), "Unknown command \"jump\"".to_string()))
+        );
+        assert!(parse_request(r#"{"command": "write", "address": 70000, "value": 1}"#).is_err());
+        assert!(parse_request("not json").is_err());
+        assert!(parse_request(
+            r#"{"command": "break_on_ppu_write", "address": 16405, "enabled": true}"#
+        )
+        .is_err());
+        assert!(
+            parse_request(r#"{"command": "press", "buttons": ["jump"], "frames": 1}"#).is_err()
+        );
+        assert!(parse_request(r#"{"command": "save_state"}"#).is_err());
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let (emulation, commands) = mpsc::channel();
+        let (frames, _) = render::frame_channel();
+        emulation.send(Command::Pause(true)).unwrap();
+        emulation::spawn(
+            looping_rom(),
+            RamInit::Zero,
+            None,
+            0,
+            std::env::temp_dir(),
+            commands,
+            frames,
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        thread::spawn(move || serve(stream, emulation));
+
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut request = |text: &str| {
+            writeln!(&client, "{}", text).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let reply: Value = serde_json::from_str(&line).unwrap();
+            let mut data = vec![0; reply["length"].as_u64().unwrap_or(0) as usize];
+            reader.read_exact(&mut data).unwrap();
+            (reply, data)
+        };
+
+        let (reply, _) = request(r#"{"id": 1, "command": "press", "frames": 0}"#);
+        let start = reply["frame"].as_u64().unwrap();
+        // Paused, but the frames of a press still run
+        let (reply, _) = request(r#"{"id": 2, "command": "press", "buttons": ["a"], "frames": 2}"#);
+        assert_eq!(reply, json!({"id": 2, "frame": start + 2}));
+
+        let (reply, _) = request(r#"{"id": 3, "command": "write", "address": 16, "value": 7}"#);
+        assert_eq!(reply["written"], json!(true));
+        let (reply, _) = request(r#"{"id": 4, "command": "read", "address": 16, "length": 1}"#);
+        assert_eq!(reply["bytes"], json!([7]));
+
+        let (reply, png) = request(r#"{"id": 5, "command": "screenshot"}"#);
+        assert_eq!(reply["format"], json!("png"));
+        assert_eq!(&png[1..4], b"PNG");
+
+        let (reply, _) = request(r#"{"id": 6, "command": "load_state", "path": "/nonexistent"}"#);
+        assert!(reply["error"].is_string());
+    }
+}
@@ -1,7 +1,16 @@
-use crate::ppu::PPU;
+use crate::cartridge::{Mirroring, Region};
+use crate::ppu::{DirtyTiles, LineScroll, PPU};
+use crate::sprites::fetch_row;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender, TryIter};
+use std::time::Duration;
 
 const WIDTH: usize = 256;
 const HEIGHT: usize = 240;
+/// Rows of a nametable in the background cache, the last 16 are its attribute table.
+const TABLE_HEIGHT: usize = 256;
 
 pub const PALETTE: [(u8, u8, u8); 0x40] = [
     // 0x0_
@@ -74,119 +83,349 @@ pub const PALETTE: [(u8, u8, u8); 0x40] = [
     (0x11, 0x11, 0x11),
 ];
 
-fn background_palette(ppu: &PPU, tile_column: usize, tile_row: usize) -> [u8; 4] {
+/// Every byte with each bit moved to the low bit of its own byte, leftmost pixel in the lowest
+/// byte, so a tile row decodes with one lookup per bit plane.
+const PLANE_PIXELS: [u64; 256] = spread_bits();
+
+const fn spread_bits() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut x = 0;
+        while x < 8 {
+            if (byte >> (7 - x)) & 1 != 0 {
+                table[byte] |= 1 << (x * 8);
+            }
+            x += 1;
+        }
+        byte += 1;
+    }
+    table
+}
+
+/// Color indices 0-3 of the 8 pixels of a tile row, leftmost first.
+pub fn decode_tile_row(plane_0: u8, plane_1: u8) -> [u8; 8] {
+    (PLANE_PIXELS[plane_0 as usize] | PLANE_PIXELS[plane_1 as usize] << 1).to_le_bytes()
+}
+
+/// How much each emphasis bit of PPUMASK dims the other two color channels.
+const EMPHASIS_DIMMING: f32 = 0.816328;
+
+/// RGB of the palette entries as PPUMASK shows them: grayscale keeps only the brightness column
+/// and the emphasis bits dim the other channels. PAL consoles swap the red and green bits.
+pub fn resolve_palette(palette_table: &[u8; 32], mask: u8, region: Region) -> [(u8, u8, u8); 32] {
+    let grayscale = mask & 0b0000_0001 != 0;
+    let emphasis = mask >> 5;
+    let (red, green, blue) = match region {
+        Region::Ntsc => (0b001, 0b010, 0b100),
+        Region::Pal => (0b010, 0b001, 0b100),
+    };
+    let dim = |value: u8, channel: u8| {
+        let dimmed_by = (emphasis & !channel).count_ones() as i32;
+        (value as f32 * EMPHASIS_DIMMING.powi(dimmed_by)) as u8
+    };
+    palette_table.map(|entry| {
+        let entry = if grayscale {
+            entry & 0x30
+        } else {
+            entry & 0x3f
+        };
+        let (r, g, b) = PALETTE[entry as usize];
+        (dim(r, red), dim(g, green), dim(b, blue))
+    })
+}
+
+/// Which of the four background palettes the attribute table picks for a tile.
+fn attribute_palette(nametable: &[u8], tile_column: usize, tile_row: usize) -> u8 {
     let attr_table_idx = tile_row / 4 * 8 + tile_column / 4;
-    let attr_byte = ppu.vram[0x3c0 + attr_table_idx]; // note: still using hardcoded first nametable
+    let attr_byte = nametable[0x3c0 + attr_table_idx];
 
-    let pallet_idx = match (tile_column % 4 / 2, tile_row % 4 / 2) {
+    match (tile_column % 4 / 2, tile_row % 4 / 2) {
         (0, 0) => attr_byte & 0b11,
         (1, 0) => (attr_byte >> 2) & 0b11,
         (0, 1) => (attr_byte >> 4) & 0b11,
         (1, 1) => (attr_byte >> 6) & 0b11,
         _ => unreachable!(),
-    };
+    }
+}
 
-    let palette_start: usize = 1 + (pallet_idx as usize) * 4;
+fn background_palette(
+    palette: &[(u8, u8, u8); 32],
+    nametable: &[u8],
+    tile_column: usize,
+    tile_row: usize,
+) -> [(u8, u8, u8); 4] {
+    let palette_start = 1 + attribute_palette(nametable, tile_column, tile_row) as usize * 4;
     [
-        ppu.palette_table[0],
-        ppu.palette_table[palette_start],
-        ppu.palette_table[palette_start + 1],
-        ppu.palette_table[palette_start + 2],
+        palette[0],
+        palette[palette_start],
+        palette[palette_start + 1],
+        palette[palette_start + 2],
     ]
 }
 
-fn sprite_palette(ppu: &PPU, palette_index: u8) -> [u8; 4] {
-    let start = 0x11 + (palette_index * 4) as usize;
-    [
-        0,
-        ppu.palette_table[start],
-        ppu.palette_table[start + 1],
-        ppu.palette_table[start + 2],
-    ]
+/// Puts the opaque sprite pixels over the background, 8 pixels per step in a `u64`. Both lines
+/// hold palette RAM indices, sprite pixels are never 0 unless transparent.
+pub fn composite_line(background: &[u8], sprites: &[u8], line: &mut [u8]) {
+    const LOW_BITS: u64 = 0x7f7f_7f7f_7f7f_7f7f;
+    const HIGH_BITS: u64 = !LOW_BITS;
+
+    let chunks = line.len() / 8 * 8;
+    let lanes = background[..chunks]
+        .chunks_exact(8)
+        .zip(sprites[..chunks].chunks_exact(8))
+        .zip(line[..chunks].chunks_exact_mut(8));
+    for ((background, sprites), line) in lanes {
+        let background = u64::from_le_bytes(background.try_into().unwrap());
+        let sprites = u64::from_le_bytes(sprites.try_into().unwrap());
+        // The high bit of every byte that isn't zero, spread over the whole byte
+        let opaque = (((sprites & LOW_BITS) + LOW_BITS) | sprites) & HIGH_BITS;
+        let mask = (opaque >> 7) * 0xff;
+        line.copy_from_slice(&((background & !mask) | (sprites & mask)).to_le_bytes());
+    }
+    composite_scalar(
+        &background[chunks..],
+        &sprites[chunks..],
+        &mut line[chunks..],
+    );
+}
+
+/// One pixel at a time, for what doesn't fill a `u64`.
+fn composite_scalar(background: &[u8], sprites: &[u8], line: &mut [u8]) {
+    for ((pixel, &background), &sprite) in line.iter_mut().zip(background).zip(sprites) {
+        *pixel = if sprite != 0 { sprite } else { background };
+    }
+}
+
+/// Draws the tiles that changed since the last frame into the cached background palette
+/// indices. Every tile takes its palette from the attribute table of its own nametable.
+fn update_background(ppu: &PPU, background: &mut [u8], dirty_tiles: DirtyTiles) {
+    let background_bank = ppu.register_control.background_pattern_address() as usize;
+    let tables = background.chunks_exact_mut(WIDTH * TABLE_HEIGHT);
+    for ((nametable, background), dirty) in
+        ppu.vram.chunks_exact(0x400).zip(tables).zip(dirty_tiles)
+    {
+        update_nametable(ppu, background_bank, nametable, background, dirty);
+    }
+}
+
+fn update_nametable(
+    ppu: &PPU,
+    background_bank: usize,
+    nametable: &[u8],
+    background: &mut [u8],
+    dirty_tiles: [u32; 32],
+) {
+    for (tile_row, mut columns) in dirty_tiles.into_iter().enumerate() {
+        while columns != 0 {
+            let tile_column = columns.trailing_zeros() as usize;
+            columns &= columns - 1;
+
+            let tile_start = background_bank + nametable[tile_row * 32 + tile_column] as usize * 16;
+            let palette_start = attribute_palette(nametable, tile_column, tile_row) * 4;
+            for y in 0..8 {
+                let plane_0 = ppu.chr_rom[tile_start + y];
+                let plane_1 = ppu.chr_rom[tile_start + y + 8];
+                let start = (tile_row * 8 + y) * WIDTH + tile_column * 8;
+                let pixels = &mut background[start..start + 8];
+                for (pixel, color) in pixels.iter_mut().zip(decode_tile_row(plane_0, plane_1)) {
+                    // Color 0 of every background palette is the shared backdrop
+                    *pixel = if color == 0 { 0 } else { palette_start + color };
+                }
+            }
+        }
+    }
+}
+
+/// Byte layout of rendered pixels, rows of 256 pixels follow each other without padding so the
+/// stride is 256 times `bytes_per_pixel`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PixelFormat {
+    /// Red, green and blue bytes, the layout of `Frame`.
+    #[default]
+    Rgb24,
+    /// Red, green, blue and an opaque alpha byte.
+    Rgba8888,
+    /// 5 bits of red, 6 of green and 5 of blue in a little-endian `u16`.
+    Rgb565,
+    /// `0x00RRGGBB` in a little-endian `u32`, what libretro calls XRGB8888.
+    Xrgb8888,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Rgba8888 | PixelFormat::Xrgb8888 => 4,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+
+    /// Bytes from one row of pixels to the next.
+    pub fn stride(self) -> usize {
+        WIDTH * self.bytes_per_pixel()
+    }
+
+    /// Bytes of a whole 256x240 frame.
+    pub fn frame_size(self) -> usize {
+        self.stride() * HEIGHT
+    }
+
+    /// The pixel in this format, in the first `bytes_per_pixel` bytes.
+    pub fn encode(self, (r, g, b): (u8, u8, u8)) -> [u8; 4] {
+        match self {
+            PixelFormat::Rgb24 => [r, g, b, 0],
+            PixelFormat::Rgba8888 => [r, g, b, 0xff],
+            PixelFormat::Rgb565 => {
+                let pixel = (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3;
+                let [low, high] = pixel.to_le_bytes();
+                [low, high, 0, 0]
+            }
+            PixelFormat::Xrgb8888 => [b, g, r, 0],
+        }
+    }
 }
 
 pub fn render(ppu: &PPU, frame: &mut Frame) {
-    let offset_rom = ppu.register_control.background_pattern_address();
+    render_into(ppu, PixelFormat::Rgb24, &mut frame.data);
+}
 
-    // Draw background
-    for i in 0x0000..=0x03bf {
-        let tile_index = ppu.vram[i] as u16;
-        let offset_x = i % 32;
-        let offset_y = i / 32;
-        let tile = &ppu.chr_rom[(offset_rom + tile_index * 16) as usize
-            ..=(offset_rom + tile_index * 16 + 15) as usize];
-        let palette = background_palette(ppu, offset_x, offset_y);
+/// Renders straight into `buffer` in the given format, which must hold `format.frame_size()`
+/// bytes. Saves converting frames for outputs that don't take RGB.
+pub fn render_into(ppu: &PPU, format: PixelFormat, buffer: &mut [u8]) {
+    assert_eq!(buffer.len(), format.frame_size());
+    let bytes_per_pixel = format.bytes_per_pixel();
+    let colors = ppu.rgb_palette().map(|rgb| format.encode(rgb));
+    let sprite_height = ppu.register_control.sprite_height();
+    let (mut background, dirty_tiles) = ppu.background_cache();
+    update_background(ppu, &mut background, dirty_tiles);
 
-        for y in 0..=7 {
-            let color_lo = tile[y].reverse_bits();
-            let color_hi = tile[y + 8].reverse_bits();
-
-            for x in 0..=7 {
-                let rgb = match ((color_hi >> x) & 1) << 1 | ((color_lo >> x) & 1) {
-                    0 => PALETTE[palette[0] as usize],
-                    1 => PALETTE[palette[1] as usize],
-                    2 => PALETTE[palette[2] as usize],
-                    3 => PALETTE[palette[3] as usize],
-                    _ => unreachable!(),
-                };
-                frame.set_pixel(offset_x * 8 + x, offset_y * 8 + y, rgb)
+    let mut scrolled = [0; WIDTH];
+    let mut sprites = [0; WIDTH];
+    let mut line = [0; WIDTH];
+    for (y, row) in buffer.chunks_exact_mut(format.stride()).enumerate() {
+        let LineScroll { x, y: line_y } = ppu.line_scroll[y];
+        scrolled_line(ppu, &background, x as usize, line_y as usize, &mut scrolled);
+        // The sprites the PPU evaluated on the line above, lower OAM entries last so they end
+        // up on top
+        sprites.fill(0);
+        for sprite in ppu.sprite_lines[y].sprites().iter().rev() {
+            let palette_start = 0x10 + (sprite[2] & 0b11) * 4;
+            let pixels = fetch_row(ppu, sprite, y - 1, sprite_height);
+            for (pixel, color) in sprites[sprite[3] as usize..].iter_mut().zip(pixels) {
+                // Color 0 is transparent
+                if color != 0 {
+                    *pixel = palette_start + color;
+                }
             }
         }
+
+        composite_line(&scrolled, &sprites, &mut line);
+        for (pixel, &index) in row.chunks_exact_mut(bytes_per_pixel).zip(&line) {
+            pixel.copy_from_slice(&colors[index as usize][..bytes_per_pixel]);
+        }
     }
+}
 
-    // Draw sprites
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
-        let tile_idx = ppu.oam_data[i + 1] as u16;
-        let tile_x = ppu.oam_data[i + 3] as usize;
-        let tile_y = ppu.oam_data[i] as usize;
+/// A row of the cached background as the scroll shows it, `x` and `y` are where it starts in
+/// the four nametables, see `LineScroll`. The row can run across two of them.
+fn scrolled_line(ppu: &PPU, background: &[u8], x: usize, y: usize, line: &mut [u8]) {
+    let row = y % TABLE_HEIGHT * WIDTH;
+    let mut filled = 0;
+    while filled < WIDTH {
+        let column = (x + filled) % (2 * WIDTH);
+        let table = physical_nametable(ppu, y / TABLE_HEIGHT * 2 + column / WIDTH);
+        let start = table * WIDTH * TABLE_HEIGHT + row + column % WIDTH;
+        let length = (WIDTH - column % WIDTH).min(WIDTH - filled);
+        line[filled..filled + length].copy_from_slice(&background[start..start + length]);
+        filled += length;
+    }
+}
 
-        let flip_vertical = if ppu.oam_data[i + 2] >> 7 & 1 == 1 {
-            true
-        } else {
-            false
-        };
-        let flip_horizontal = if ppu.oam_data[i + 2] >> 6 & 1 == 1 {
-            true
-        } else {
-            false
-        };
-        let palette_index = ppu.oam_data[i + 2] & 0b11;
-        let sprite_palette = sprite_palette(ppu, palette_index);
+/// Which of the two nametables in VRAM one of the four of PPU address space mirrors.
+fn physical_nametable(ppu: &PPU, table: usize) -> usize {
+    match ppu.mirroring {
+        Mirroring::Horizontal => table / 2,
+        Mirroring::Vertical | Mirroring::FourScreen => table % 2,
+    }
+}
+
+/// One of the four nametables of PPU address space, from $2000 on, after mirroring.
+pub fn nametable(ppu: &PPU, table: usize) -> &[u8] {
+    let physical = physical_nametable(ppu, table);
+    &ppu.vram[physical * 0x400..(physical + 1) * 0x400]
+}
 
-        let bank: u16 = ppu.register_control.sprite_pattern_address();
+/// Draws all four nametables as laid out in PPU address space, 512x480 RGB.
+pub fn render_nametables(ppu: &PPU) -> Vec<u8> {
+    const IMAGE_WIDTH: usize = 512;
+    let mut image = vec![0; IMAGE_WIDTH * 480 * 3];
+    let offset_rom = ppu.register_control.background_pattern_address() as usize;
+    let rgb_palette = ppu.rgb_palette();
 
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
+    for table in 0..4 {
+        let nametable = nametable(ppu, table);
 
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-            'ololo: for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => continue 'ololo, // skip coloring the pixel
-                    1 => PALETTE[sprite_palette[1] as usize],
-                    2 => PALETTE[sprite_palette[2] as usize],
-                    3 => PALETTE[sprite_palette[3] as usize],
-                    _ => unreachable!(),
-                };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
+        for i in 0..0x3c0 {
+            let column = i % 32;
+            let row = i / 32;
+            let tile_start = offset_rom + nametable[i] as usize * 16;
+            let tile = &ppu.chr_rom[tile_start..tile_start + 16];
+            let palette = background_palette(&rgb_palette, nametable, column, row);
+
+            for y in 0..8 {
+                let pixels = decode_tile_row(tile[y], tile[y + 8]);
+                for (x, color) in pixels.into_iter().enumerate() {
+                    let (r, g, b) = palette[color as usize];
+                    let image_x = (table % 2) * 256 + column * 8 + x;
+                    let image_y = (table / 2) * 240 + row * 8 + y;
+                    let index = (image_y * IMAGE_WIDTH + image_x) * 3;
+                    image[index..index + 3].copy_from_slice(&[r, g, b]);
                 }
             }
         }
     }
+    image
+}
+
+/// Draws both pattern tables side by side as 16x16 tiles with the first background palette,
+/// 256x128 RGB.
+pub fn render_pattern_tables(ppu: &PPU) -> Vec<u8> {
+    const IMAGE_WIDTH: usize = 256;
+    let mut image = vec![0; IMAGE_WIDTH * 128 * 3];
+    if ppu.chr_rom.len() < 0x2000 {
+        return image;
+    }
+    let rgb_palette = ppu.rgb_palette();
+
+    for tile_index in 0..512 {
+        let tile = &ppu.chr_rom[tile_index * 16..tile_index * 16 + 16];
+        let bank = tile_index / 256;
+        let column = bank * 16 + tile_index % 16;
+        let row = tile_index % 256 / 16;
+
+        for y in 0..8 {
+            let pixels = decode_tile_row(tile[y], tile[y + 8]);
+            for (x, color) in pixels.into_iter().enumerate() {
+                let (r, g, b) = rgb_palette[color as usize];
+                let index = ((row * 8 + y) * IMAGE_WIDTH + column * 8 + x) * 3;
+                image[index..index + 3].copy_from_slice(&[r, g, b]);
+            }
+        }
+    }
+    image
 }
 
 pub struct Frame {
     pub data: [u8; WIDTH * HEIGHT * 3],
 }
 
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Frame {
     pub fn new() -> Self {
         Frame {
@@ -204,4 +443,534 @@ impl Frame {
             self.data[pixel_index + 2] = rgb.2;
         }
     }
+
+    /// The RGB bytes of scanline `y`, for writing a whole line at once.
+    pub fn scanline_mut(&mut self, y: usize) -> &mut [u8] {
+        &mut self.data[y * WIDTH * 3..(y + 1) * WIDTH * 3]
+    }
+
+    /// Sets the 8 pixels of a tile row starting at `x`, pixels past the right edge are dropped
+    /// and rows below the bottom are ignored.
+    pub fn set_tile_row(&mut self, x: usize, y: usize, rgb: &[(u8, u8, u8); 8]) {
+        if x >= WIDTH || y >= HEIGHT {
+            return;
+        }
+        let line = &mut self.scanline_mut(y)[x * 3..];
+        for (pixel, &(r, g, b)) in line.chunks_exact_mut(3).zip(rgb) {
+            pixel.copy_from_slice(&[r, g, b]);
+        }
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let pixel_index = (y * WIDTH + x) * 3;
+        (
+            self.data[pixel_index],
+            self.data[pixel_index + 1],
+            self.data[pixel_index + 2],
+        )
+    }
+
+    /// Mixes the color with the current pixel, an alpha of 1.0 replaces the pixel.
+    pub fn blend_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8), alpha: f32) {
+        if x >= WIDTH || y >= HEIGHT {
+            return;
+        }
+        let mix = |new: u8, old: u8| (new as f32 * alpha + old as f32 * (1.0 - alpha)) as u8;
+        let old = self.get_pixel(x, y);
+        self.set_pixel(
+            x,
+            y,
+            (mix(rgb.0, old.0), mix(rgb.1, old.1), mix(rgb.2, old.2)),
+        );
+    }
+
+    /// Encodes the frame as an RGB PNG image.
+    pub fn to_png(&self) -> Vec<u8> {
+        encode_png(WIDTH, HEIGHT, &self.data)
+    }
+
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_png())
+    }
+}
+
+/// Encodes RGB pixels as a PNG, `data` has to hold `width` by `height` pixels.
+pub fn encode_png(width: usize, height: usize, data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    // Writing to a vector only fails when the data doesn't match the dimensions
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(data).unwrap();
+    writer.finish().unwrap();
+
+    bytes
+}
+
+/// Creates the two ends of the frames going to the frontend. Frames the frontend is done with
+/// come back to be rendered into again, so the emulation never allocates a frame per frame or
+/// draws into the one on screen.
+pub fn frame_channel() -> (FrameSender, FrameReceiver) {
+    let (frames, received) = mpsc::channel();
+    let (recycled, pool) = mpsc::channel();
+    (
+        FrameSender {
+            frames,
+            pool,
+            recycled: recycled.clone(),
+        },
+        FrameReceiver {
+            frames: received,
+            recycled,
+        },
+    )
+}
+
+/// The emulation end of `frame_channel`.
+pub struct FrameSender {
+    frames: Sender<Box<Frame>>,
+    pool: Receiver<Box<Frame>>,
+    recycled: Sender<Box<Frame>>,
+}
+
+impl FrameSender {
+    /// A frame to render into, a recycled one unless the frontend still holds all of them.
+    pub fn take(&self) -> Box<Frame> {
+        self.pool
+            .try_recv()
+            .unwrap_or_else(|_| Box::new(Frame::new()))
+    }
+
+    pub fn send(&self, frame: Box<Frame>) -> Result<(), SendError<Box<Frame>>> {
+        self.frames.send(frame)
+    }
+
+    /// Returns a frame that won't be shown to the pool.
+    pub fn recycle(&self, frame: Box<Frame>) {
+        let _ = self.recycled.send(frame);
+    }
+}
+
+/// The frontend end of `frame_channel`.
+pub struct FrameReceiver {
+    frames: Receiver<Box<Frame>>,
+    recycled: Sender<Box<Frame>>,
+}
+
+impl FrameReceiver {
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Box<Frame>, RecvTimeoutError> {
+        self.frames.recv_timeout(timeout)
+    }
+
+    pub fn try_iter(&self) -> TryIter<'_, Box<Frame>> {
+        self.frames.try_iter()
+    }
+
+    /// Hands a frame that is no longer shown back to the emulation.
+    pub fn recycle(&self, frame: Box<Frame>) {
+        let _ = self.recycled.send(frame);
+    }
+}
+
+/// Draws the 256 tiles of a CHR bank with a fixed grey palette, 32 tiles per row.
+pub fn show_tile_bank(chr_rom: &[u8], bank: u8) -> Frame {
+    if bank > 1 {
+        panic!("There is no bank {}", bank);
+    }
+
+    let mut frame = Frame::new();
+    let offset_rom = bank as usize * 0x1000;
+    let colors = [PALETTE[0x01], PALETTE[0x23], PALETTE[0x27], PALETTE[0x30]];
+
+    for tile_index in 0x00..=0xff {
+        let offset_x = tile_index % 32 * 8;
+        let offset_y = tile_index / 32 * 8;
+        let tile = &chr_rom[(offset_rom + tile_index * 16)..=(offset_rom + tile_index * 16 + 15)];
+
+        for y in 0..=7 {
+            let rgb = decode_tile_row(tile[y], tile[y + 8]).map(|color| colors[color as usize]);
+            frame.set_tile_row(offset_x, offset_y + y, &rgb);
+        }
+    }
+    frame
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nametables_follow_mirroring() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[16..24].fill(0xff);
+        let mut ppu = PPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.palette_table[1] = 0x16;
+        ppu.vram[0x400] = 1;
+
+        let image = render_nametables(&ppu);
+        let pixel = |x: usize, y: usize| {
+            let index = (y * 512 + x) * 3;
+            (image[index], image[index + 1], image[index + 2])
+        };
+        assert_eq!(pixel(0, 0), PALETTE[0]);
+        assert_eq!(pixel(0, 240), PALETTE[0x16]);
+        assert_eq!(pixel(256, 240), PALETTE[0x16]);
+    }
+
+    #[test]
+    fn test_render_redraws_changed_tiles() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[16..24].fill(0xff);
+        let mut ppu = PPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.palette_table[1] = 0x16;
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(8, 0), PALETTE[0]);
+
+        // Tile 1 at column 1, the rest of the screen comes from the cache
+        ppu.write_address(0x20);
+        ppu.write_address(0x01);
+        ppu.write_data(1);
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(8, 0), PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(0, 0), PALETTE[0]);
+
+        let mut uncached = PPU::new(ppu.chr_rom.clone(), Mirroring::Horizontal);
+        uncached.palette_table = ppu.palette_table;
+        uncached.vram = ppu.vram;
+        let mut fresh = Frame::new();
+        render(&uncached, &mut fresh);
+        assert!(fresh.data == frame.data);
+    }
+
+    #[test]
+    fn test_render_scrolls_across_nametables() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[16..24].fill(0xff);
+        let mut ppu = PPU::new(chr_rom, Mirroring::Vertical);
+        ppu.palette_table[1] = 0x16;
+        ppu.palette_table[5] = 0x2a;
+        // The first and last tile of the first row at $2000 with palette 0, the first at $2400
+        // with palette 1 from its own attribute table
+        ppu.write_address(0x20);
+        ppu.write_address(0x00);
+        ppu.write_data(1);
+        ppu.write_address(0x20);
+        ppu.write_address(0x1f);
+        ppu.write_data(1);
+        ppu.write_address(0x24);
+        ppu.write_address(0x00);
+        ppu.write_data(1);
+        ppu.write_address(0x27);
+        ppu.write_address(0xc0);
+        ppu.write_data(0b01);
+
+        ppu.write_scroll(8);
+        ppu.write_scroll(0);
+        let mut frame = Frame::new();
+        ppu.latch_lines();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(240, 0), PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(247, 7), PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(248, 0), PALETTE[0x2a]);
+        assert_eq!(frame.get_pixel(255, 7), PALETTE[0x2a]);
+        assert_eq!(frame.get_pixel(248, 8), PALETTE[0]);
+
+        // Starting in the second nametable, the right edge wraps around to the first
+        ppu.write_control(0b01);
+        ppu.write_scroll(0);
+        ppu.write_scroll(0);
+        ppu.latch_lines();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(0, 0), PALETTE[0x2a]);
+        assert_eq!(frame.get_pixel(8, 0), PALETTE[0]);
+        ppu.write_scroll(8);
+        ppu.write_scroll(0);
+        ppu.latch_lines();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(0, 0), PALETTE[0]);
+        assert_eq!(frame.get_pixel(248, 0), PALETTE[0x16]);
+    }
+
+    #[test]
+    fn test_render_scroll_split() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[16..24].fill(0xff);
+        let mut ppu = PPU::new(chr_rom, Mirroring::Vertical);
+        ppu.palette_table[1] = 0x16;
+        // A column of tiles in the middle of the first nametable
+        for row in 0..30 {
+            ppu.vram[row * 32 + 16] = 1;
+        }
+        let run_to_line = |ppu: &mut PPU, line: u16| {
+            while ppu.scanline != line {
+                ppu.tick(1);
+            }
+        };
+
+        // Scrolled halfway from line 100 on, the Y scroll only takes effect next frame
+        run_to_line(&mut ppu, 100);
+        ppu.write_scroll(128);
+        ppu.write_scroll(50);
+        run_to_line(&mut ppu, 241);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        for y in [0, 99, 100] {
+            assert_eq!(frame.get_pixel(128, y), PALETTE[0x16]);
+            assert_eq!(frame.get_pixel(0, y), PALETTE[0]);
+        }
+        for y in [101, 239] {
+            assert_eq!(frame.get_pixel(0, y), PALETTE[0x16]);
+            assert_eq!(frame.get_pixel(128, y), PALETTE[0]);
+        }
+        assert_eq!(ppu.line_scroll[239], LineScroll { x: 128, y: 239 });
+
+        run_to_line(&mut ppu, 0);
+        assert_eq!(ppu.line_scroll[0], LineScroll { x: 128, y: 50 });
+    }
+
+    #[test]
+    fn test_render_attribute_rows() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[16..24].fill(0xff);
+        let mut ppu = PPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.palette_table[1] = 0x16;
+        ppu.palette_table[5] = 0x2a;
+        // The first attribute byte is tile 1 in the attribute rows and gives the top left
+        // tiles palette 1, then tiles at the top of the first nametable and the one below it
+        ppu.write_address(0x23);
+        ppu.write_address(0xc0);
+        ppu.write_data(1);
+        ppu.write_address(0x20);
+        ppu.write_address(0x01);
+        ppu.write_data(1);
+        ppu.write_address(0x28);
+        ppu.write_address(0x02);
+        ppu.write_data(1);
+
+        ppu.write_scroll(0);
+        ppu.write_scroll(240);
+        ppu.latch_lines();
+        let run_to_line = |ppu: &mut PPU, line: u16| {
+            while ppu.scanline != line {
+                ppu.tick(1);
+            }
+        };
+        run_to_line(&mut ppu, 241);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(0, 0), PALETTE[0x16]);
+        assert_eq!(frame.get_pixel(8, 0), PALETTE[0]);
+        // After the attribute rows it goes back to the top of the same nametable
+        assert_eq!(frame.get_pixel(8, 16), PALETTE[0x2a]);
+        assert_eq!(frame.get_pixel(16, 16), PALETTE[0]);
+    }
+
+    #[test]
+    fn test_render_sprites() {
+        let mut chr_rom = vec![0; 0x2000];
+        // Only the top left pixel is set, in tile 1 and the top half of the 8x16 tiles $02/$03
+        chr_rom[0x10] = 0b1000_0000;
+        chr_rom[0x1020] = 0b1000_0000;
+        let mut ppu = PPU::new(chr_rom, Mirroring::Vertical);
+        ppu.palette_table[0x19] = 0x21;
+        ppu.palette_table[0x1d] = 0x27;
+
+        ppu.write_mask(0b0001_0000);
+
+        // Flipped horizontally with palette 2, a line below its Y
+        ppu.oam_data[..4].copy_from_slice(&[20, 1, 0b0100_0010, 40]);
+        ppu.latch_lines();
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(40, 21), PALETTE[0]);
+        assert_eq!(frame.get_pixel(47, 20), PALETTE[0]);
+        assert_eq!(frame.get_pixel(47, 21), PALETTE[0x21]);
+
+        // A tall sprite flipped vertically draws its top half at the bottom, with palette 3
+        ppu.write_control(0b0010_0000);
+        ppu.oam_data[..4].copy_from_slice(&[50, 0x03, 0b1000_0011, 100]);
+        ppu.latch_lines();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(100, 51), PALETTE[0]);
+        assert_eq!(frame.get_pixel(100, 66), PALETTE[0x27]);
+        ppu.oam_data[2] = 0b1100_0011;
+        ppu.latch_lines();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.get_pixel(100, 66), PALETTE[0]);
+        assert_eq!(frame.get_pixel(107, 66), PALETTE[0x27]);
+    }
+
+    #[test]
+    fn test_render_evaluated_sprites() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0x10..0x18].fill(0xff);
+        let mut ppu = PPU::new(chr_rom, Mirroring::Vertical);
+        ppu.palette_table[0x11] = 0x21;
+        ppu.write_mask(0b0001_0000);
+        // Nine sprites at Y 0, only the first eight fit on the line
+        ppu.oam_data.fill(0xf0);
+        for (n, sprite) in ppu.oam_data[..9 * 4].chunks_exact_mut(4).enumerate() {
+            sprite.copy_from_slice(&[0, 1, 0, n as u8 * 16]);
+        }
+        while !ppu.tick(1) {}
+        while ppu.scanline < 240 {
+            ppu.tick(1);
+        }
+        assert_ne!(ppu.register_status.bits() & 0b0010_0000, 0);
+
+        // Moving the sprites after the frame ran doesn't change what it drew
+        ppu.oam_data[0] = 100;
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        for n in 0..9 {
+            assert_eq!(frame.get_pixel(n * 16, 0), PALETTE[0]);
+            let drawn = if n < 8 { PALETTE[0x21] } else { PALETTE[0] };
+            assert_eq!(frame.get_pixel(n * 16, 1), drawn);
+            assert_eq!(frame.get_pixel(n * 16, 8), drawn);
+            assert_eq!(frame.get_pixel(n * 16, 9), PALETTE[0]);
+        }
+    }
+
+    #[test]
+    fn test_render_into_formats() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[16..24].fill(0xff);
+        let mut ppu = PPU::new(chr_rom, Mirroring::Horizontal);
+        ppu.palette_table[1] = 0x16;
+        ppu.vram[1] = 1;
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        for format in [
+            PixelFormat::Rgba8888,
+            PixelFormat::Rgb565,
+            PixelFormat::Xrgb8888,
+        ] {
+            let mut buffer = vec![0; format.frame_size()];
+            render_into(&ppu, format, &mut buffer);
+            let size = format.bytes_per_pixel();
+            for (x, y) in [(0, 0), (8, 0), (255, 239)] {
+                let index = y * format.stride() + x * size;
+                let expected = format.encode(frame.get_pixel(x, y));
+                assert_eq!(&buffer[index..index + size], &expected[..size]);
+            }
+        }
+        assert_eq!(
+            PixelFormat::Rgb565.encode((0xb4, 0x31, 0x20)),
+            [0x84, 0xb1, 0, 0]
+        );
+        assert_eq!(
+            u32::from_le_bytes(PixelFormat::Xrgb8888.encode((0xb4, 0x31, 0x20))),
+            0x00b4_3120
+        );
+    }
+
+    #[test]
+    fn test_composite_line() {
+        let background: Vec<u8> = (0..21).collect();
+        let sprites: Vec<u8> = (0..21)
+            .map(|i| if i % 3 == 0 { 0 } else { 0x80 | i })
+            .collect();
+        let mut line = vec![0; 21];
+        composite_line(&background, &sprites, &mut line);
+
+        let mut expected = vec![0; 21];
+        composite_scalar(&background, &sprites, &mut expected);
+        assert_eq!(line, expected);
+        assert_eq!(&line[..4], &[0, 0x81, 0x82, 3]);
+    }
+
+    #[test]
+    fn test_resolve_palette() {
+        let mut palette_table = [0; 32];
+        palette_table[1] = 0x16;
+        palette_table[2] = 0xd6;
+        let rgb = resolve_palette(&palette_table, 0, Region::Ntsc);
+        assert_eq!(
+            (rgb[0], rgb[1], rgb[2]),
+            (PALETTE[0], PALETTE[0x16], PALETTE[0x16])
+        );
+
+        let gray = resolve_palette(&palette_table, 0b0000_0001, Region::Ntsc);
+        assert_eq!(gray[1], PALETTE[0x10]);
+
+        // Emphasizing red dims green and blue, on PAL the same bit emphasizes green
+        let (r, g, b) = PALETTE[0x16];
+        let dim = |value: u8| (value as f32 * EMPHASIS_DIMMING) as u8;
+        let red = resolve_palette(&palette_table, 0b0010_0000, Region::Ntsc);
+        assert_eq!(red[1], (r, dim(g), dim(b)));
+        let green = resolve_palette(&palette_table, 0b0010_0000, Region::Pal);
+        assert_eq!(green[1], (dim(r), g, dim(b)));
+    }
+
+    #[test]
+    fn test_set_tile_row() {
+        let mut frame = Frame::new();
+        let rgb: [(u8, u8, u8); 8] = std::array::from_fn(|x| (x as u8 + 1, 0, 0));
+        frame.set_tile_row(8, 1, &rgb);
+        assert_eq!(frame.get_pixel(7, 1), (0, 0, 0));
+        assert_eq!(frame.get_pixel(8, 1), (1, 0, 0));
+        assert_eq!(frame.get_pixel(15, 1), (8, 0, 0));
+        assert_eq!(frame.get_pixel(16, 1), (0, 0, 0));
+
+        // Clipped at the right edge instead of wrapping to the next line
+        frame.set_tile_row(252, 1, &rgb);
+        assert_eq!(frame.get_pixel(255, 1), (4, 0, 0));
+        assert_eq!(frame.get_pixel(0, 2), (0, 0, 0));
+        frame.set_tile_row(0, 240, &rgb);
+        assert_eq!(frame.scanline_mut(1).len(), 256 * 3);
+    }
+
+    #[test]
+    fn test_decode_tile_row() {
+        assert_eq!(decode_tile_row(0, 0), [0; 8]);
+        assert_eq!(
+            decode_tile_row(0b1010_0001, 0b1100_0011),
+            [3, 2, 1, 0, 0, 0, 2, 3]
+        );
+        for plane_0 in 0..=255u8 {
+            for plane_1 in [0x00, 0x5a, 0xff] {
+                let row = decode_tile_row(plane_0, plane_1);
+                for (x, color) in row.into_iter().enumerate() {
+                    let bit = |plane: u8| (plane >> (7 - x)) & 1;
+                    assert_eq!(color, bit(plane_1) << 1 | bit(plane_0));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_channel_recycles() {
+        let (sender, receiver) = frame_channel();
+        let frame = sender.take();
+        let address = &*frame as *const Frame;
+        sender.send(frame).unwrap();
+
+        let shown = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        receiver.recycle(shown);
+        let frame = sender.take();
+        assert_eq!(&*frame as *const Frame, address);
+        // The frontend holds the only frame, so the next one is new
+        sender.send(frame).unwrap();
+        assert_ne!(&*sender.take() as *const Frame, address);
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, (0x12, 0x34, 0x56));
+        frame.set_pixel(255, 239, (0xff, 0x00, 0x80));
+
+        let png = frame.to_png();
+        let decoder = png::Decoder::new(png.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+
+        assert_eq!((info.width, info.height), (256, 240));
+        assert_eq!(&data[..], &frame.data[..]);
+    }
 }
@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+
+/// Zero runs shorter than this are cheaper to keep in a literal run.
+const MIN_ZERO_RUN: usize = 8;
+
+/// Recent save states, kept as the newest full state plus compressed deltas to go back in time.
+pub struct RewindBuffer {
+    capacity: usize,
+    latest: Vec<u8>,
+    /// Newest last, each delta turns a state into the one captured before it.
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    /// Keeps at most `capacity` states before the newest one.
+    pub fn new(capacity: usize) -> Self {
+        RewindBuffer {
+            capacity,
+            latest: vec![],
+            deltas: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Forgets all states, e.g. when another game is loaded.
+    pub fn clear(&mut self) {
+        self.latest.clear();
+        self.deltas.clear();
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.latest.len() == state.len() {
+            let xor: Vec<u8> = state
+                .iter()
+                .zip(&self.latest)
+                .map(|(new, old)| new ^ old)
+                .collect();
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+            self.deltas.push_back(encode(&xor));
+        } else {
+            self.deltas.clear();
+        }
+        self.latest = state;
+    }
+
+    /// The newest state, or the oldest one kept once rewinding has used up the others.
+    pub fn latest(&self) -> Option<&[u8]> {
+        if self.latest.is_empty() {
+            None
+        } else {
+            Some(&self.latest)
+        }
+    }
+
+    /// Steps back to the state captured before the newest one and returns it.
+    pub fn pop(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        apply(&mut self.latest, &delta);
+        Some(&self.latest)
+    }
+}
+
+/// Run-length encodes the zero runs of an XOR delta, as pairs of zero and literal run lengths
+/// followed by the literals.
+fn encode(xor: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut i = 0;
+    while i < xor.len() {
+        let zeros_start = i;
+        while i < xor.len() && xor[i] == 0 {
+            i += 1;
+        }
+        let literal_start = i;
+        while i < xor.len() {
+            let zeros = xor[i..].iter().take_while(|&&byte| byte == 0).count();
+            if zeros >= MIN_ZERO_RUN || i + zeros == xor.len() {
+                break;
+            }
+            i += zeros.max(1);
+        }
+
+        encoded.extend_from_slice(&((literal_start - zeros_start) as u32).to_le_bytes());
+        encoded.extend_from_slice(&((i - literal_start) as u32).to_le_bytes());
+        encoded.extend_from_slice(&xor[literal_start..i]);
+    }
+    encoded
+}
+
+/// XORs an encoded delta into the state.
+fn apply(state: &mut [u8], delta: &[u8]) {
+    let read_length = |position: usize| {
+        u32::from_le_bytes(delta[position..position + 4].try_into().unwrap()) as usize
+    };
+
+    let mut position = 0;
+    let mut offset = 0;
+    while position < delta.len() {
+        offset += read_length(position);
+        let literals = read_length(position + 4);
+        position += 8;
+        for (byte, xor) in state[offset..offset + literals]
+            .iter_mut()
+            .zip(&delta[position..position + literals])
+        {
+            *byte ^= xor;
+        }
+        offset += literals;
+        position += literals;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rewinds_in_order() {
+        let mut buffer = RewindBuffer::new(10);
+        let states: Vec<Vec<u8>> = (0..4u8)
+            .map(|i| {
+                let mut state = vec![0; 4096];
+                state[100] = i;
+                state[101..120].fill(i * 3);
+                state[4000] = 0xff - i;
+                state
+            })
+            .collect();
+        for state in &states {
+            buffer.push(state.clone());
+        }
+
+        assert_eq!(buffer.pop(), Some(states[2].as_slice()));
+        assert_eq!(buffer.pop(), Some(states[1].as_slice()));
+        buffer.push(states[3].clone());
+        assert_eq!(buffer.pop(), Some(states[1].as_slice()));
+        assert_eq!(buffer.pop(), Some(states[0].as_slice()));
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.latest(), Some(states[0].as_slice()));
+    }
+
+    #[test]
+    fn test_drops_oldest_state() {
+        let mut buffer = RewindBuffer::new(2);
+        for i in 0..5 {
+            buffer.push(vec![i; 16]);
+        }
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.pop(), Some([3; 16].as_slice()));
+        assert_eq!(buffer.pop(), Some([2; 16].as_slice()));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_small_changes_compress() {
+        let mut xor = vec![0; 4096];
+        xor[10] = 1;
+        xor[12] = 2;
+        xor[3000] = 3;
+        let encoded = encode(&xor);
+        assert!(encoded.len() < 32);
+
+        let mut state = vec![0; 4096];
+        apply(&mut state, &encoded);
+        assert_eq!(state, xor);
+    }
+}
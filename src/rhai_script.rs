@@ -0,0 +1,318 @@
+use crate::cpu::CPU;
+use crate::joypad::button_from_name;
+use crate::memory::MemoryRegion;
+use crate::osd::draw_text;
+use crate::render::Frame;
+use rhai::{Dynamic, Engine, FnPtr, FuncArgs, AST};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+/// Operations a single hook may run, so a hook that loops forever stops the script instead of
+/// the emulator.
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+const TEXT_COLOR: (u8, u8, u8) = (0xff, 0xff, 0xff);
+const SHADOW_COLOR: (u8, u8, u8) = (0x00, 0x00, 0x00);
+
+/// What hooks see and change. Reads come from a copy of the CPU address space taken before
+/// the hooks run, everything else is applied once they return.
+struct Host {
+    memory: Vec<Option<u8>>,
+    frame: u64,
+    buttons: u8,
+    writes: Vec<(u16, u8)>,
+    /// Buttons to hold from now on, as if pressed on the controller.
+    new_buttons: Option<u8>,
+    /// Text drawn over the frame when it ends.
+    texts: Vec<(i64, i64, String)>,
+    frame_start: Vec<FnPtr>,
+    frame_end: Vec<FnPtr>,
+    write_hooks: BTreeMap<u16, Vec<FnPtr>>,
+}
+
+impl Host {
+    fn new() -> Self {
+        Host {
+            memory: vec![None; 0x10000],
+            frame: 0,
+            buttons: 0,
+            writes: Vec::new(),
+            new_buttons: None,
+            texts: Vec::new(),
+            frame_start: Vec::new(),
+            frame_end: Vec::new(),
+            write_hooks: BTreeMap::new(),
+        }
+    }
+
+    fn buttons(&self) -> u8 {
+        self.new_buttons.unwrap_or(self.buttons)
+    }
+}
+
+/// A Rhai script that registers hooks from its top level, which runs when the first frame
+/// ends:
+///
+/// ```text
+/// let lives = 0;
+/// on_frame_end(|| text(8, 8, `lives ${read(0x75)}`));
+/// on_frame_start(|| if frame() % 60 == 0 { press("start") } else { release("start") });
+/// on_write(0x75, |address, value| { lives = value; print(`lost a life at ${frame()}`) });
+/// ```
+///
+/// Besides `read(address)`, `write(address, value)` and `frame()`, hooks can set the
+/// controller with `press(name)`, `release(name)`, `buttons()` and `set_buttons(bits)`, and
+/// draw with `text(x, y, text)`.
+pub struct RhaiScript {
+    engine: Engine,
+    ast: AST,
+    host: Rc<RefCell<Host>>,
+    started: bool,
+}
+
+impl RhaiScript {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let source = fs::read_to_string(path).map_err(|error| error.to_string())?;
+        Self::new(&source)
+    }
+
+    pub fn new(source: &str) -> Result<Self, String> {
+        let host = Rc::new(RefCell::new(Host::new()));
+        let engine = engine(&host);
+        let ast = engine.compile(source).map_err(|error| error.to_string())?;
+        Ok(RhaiScript {
+            engine,
+            ast,
+            host,
+            started: false,
+        })
+    }
+
+    /// Runs the frame start hooks, right before the next frame runs.
+    pub fn frame_start(&mut self, cpu: &mut CPU) -> Result<(), String> {
+        let hooks = self.host.borrow().frame_start.clone();
+        self.run(cpu, true, |script| script.call(&hooks, ()))
+    }
+
+    /// Runs the top level of the script the first time, then the frame end hooks, and draws
+    /// the text of the frame.
+    pub fn frame_end(&mut self, cpu: &mut CPU, frame: &mut Frame) -> Result<(), String> {
+        self.run(cpu, true, |script| {
+            if !script.started {
+                script.started = true;
+                script.engine.run_ast(&script.ast)?;
+            }
+            let hooks = script.host.borrow().frame_end.clone();
+            script.call(&hooks, ())
+        })?;
+        for (x, y, text) in self.host.borrow_mut().texts.drain(..) {
+            let (x, y) = (x.max(0) as usize, y.max(0) as usize);
+            draw_text(frame, x + 1, y + 1, &text, SHADOW_COLOR, 1.0);
+            draw_text(frame, x, y, &text, TEXT_COLOR, 1.0);
+        }
+        Ok(())
+    }
+
+    /// Runs the write hooks for the watched writes of the last instruction.
+    pub fn after_instruction(&mut self, cpu: &mut CPU) -> Result<(), String> {
+        let writes = cpu.bus.take_watched_writes();
+        if writes.is_empty() {
+            return Ok(());
+        }
+        // Only RAM can change between instructions, so only RAM is copied again
+        self.run(cpu, false, |script| {
+            for (address, value) in writes {
+                let hooks = script.host.borrow().write_hooks[&address].clone();
+                script.call(&hooks, (address as i64, value as i64))?;
+            }
+            Ok(())
+        })
+    }
+
+    fn run(
+        &mut self,
+        cpu: &mut CPU,
+        whole_memory: bool,
+        hooks: impl FnOnce(&mut Self) -> Result<(), Box<rhai::EvalAltResult>>,
+    ) -> Result<(), String> {
+        {
+            let mut host = self.host.borrow_mut();
+            let length = if whole_memory { 0x10000 } else { 0x2000 };
+            let memory = MemoryRegion::Cpu.peek_range(cpu, 0, length);
+            host.memory[..length].copy_from_slice(&memory);
+            host.frame = cpu.bus.frames();
+            host.buttons = cpu.bus.joypad_mut().buttons();
+        }
+
+        let result = hooks(self).map_err(|error| error.to_string());
+
+        let mut host = self.host.borrow_mut();
+        for (address, value) in host.writes.drain(..) {
+            MemoryRegion::Cpu.poke(cpu, address, value);
+        }
+        if let Some(buttons) = host.new_buttons.take() {
+            cpu.bus.joypad_mut().set_buttons(buttons);
+        }
+        for &address in host.write_hooks.keys() {
+            cpu.bus.watch_write(address, true);
+        }
+        result
+    }
+
+    fn call(
+        &self,
+        hooks: &[FnPtr],
+        args: impl FuncArgs + Clone,
+    ) -> Result<(), Box<rhai::EvalAltResult>> {
+        for hook in hooks {
+            // Whatever a hook returns is ignored
+            let _ = hook.call::<Dynamic>(&self.engine, &self.ast, args.clone())?;
+        }
+        Ok(())
+    }
+}
+
+/// An engine with the functions scripts use to reach the machine.
+fn engine(host: &Rc<RefCell<Host>>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+
+    let h = Rc::clone(host);
+    engine.register_fn("read", move |address: i64| {
+        h.borrow().memory[address as u16 as usize].unwrap_or(0) as i64
+    });
+    let h = Rc::clone(host);
+    engine.register_fn("write", move |address: i64, value: i64| {
+        let (address, value) = (address as u16, value as u8);
+        let mut host = h.borrow_mut();
+        // Later reads of the same hooks see the write
+        if let Some(byte @ Some(_)) = host.memory.get_mut(address as usize) {
+            *byte = Some(value);
+        }
+        host.writes.push((address, value));
+    });
+    let h = Rc::clone(host);
+    engine.register_fn("frame", move || h.borrow().frame as i64);
+
+    let h = Rc::clone(host);
+    engine.register_fn("buttons", move || h.borrow().buttons() as i64);
+    let h = Rc::clone(host);
+    engine.register_fn("set_buttons", move |buttons: i64| {
+        h.borrow_mut().new_buttons = Some(buttons as u8);
+    });
+    for (name, pressed) in [("press", true), ("release", false)] {
+        let h = Rc::clone(host);
+        engine.register_fn(name, move |button: &str| {
+            let button =
+                button_from_name(button).ok_or_else(|| format!("Unknown button {:?}", button))?;
+            let mut host = h.borrow_mut();
+            let buttons = host.buttons();
+            host.new_buttons = Some(if pressed {
+                buttons | button
+            } else {
+                buttons & !button
+            });
+            Ok::<_, Box<rhai::EvalAltResult>>(())
+        });
+    }
+
+    let h = Rc::clone(host);
+    engine.register_fn("text", move |x: i64, y: i64, text: &str| {
+        h.borrow_mut().texts.push((x, y, text.to_string()));
+    });
+
+    let h = Rc::clone(host);
+    engine.register_fn("on_frame_start", move |hook: FnPtr| {
+        h.borrow_mut().frame_start.push(hook);
+    });
+    let h = Rc::clone(host);
+    engine.register_fn("on_frame_end", move |hook: FnPtr| {
+        h.borrow_mut().frame_end.push(hook);
+    });
+    let h = Rc::clone(host);
+    engine.register_fn("on_write", move |address: i64, hook: FnPtr| {
+        let address = address as u16;
+        // Writes are reported without the RAM mirrors, as the bus collects them
+        let address = if address < 0x2000 {
+            address & 0x07ff
+        } else {
+            address
+        };
+        h.borrow_mut()
+            .write_hooks
+            .entry(address)
+            .or_default()
+            .push(hook);
+    });
+    engine
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::joypad::{JOYPAD_A, JOYPAD_START};
+
+    /// Writes the frame counter of $10 to $11 with STA $11 at $8004.
+    fn cpu() -> CPU<'static> {
+        let mut program = vec![0xa5, 0x10, 0x85, 0x11, 0x4c, 0x00, 0x80];
+        program.resize(2 * 0x4000 - 4, 0);
+        program.extend([0x00, 0x80, 0x00, 0x00]);
+        let mut cpu = CPU::new(Bus::new(test_rom(program), |_, _| {}));
+        cpu.power_cycle();
+        cpu
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut cpu = cpu();
+        let mut frame = Frame::new();
+        let mut script = RhaiScript::new(
+            r#"
+            let writes = [];
+            write(0x10, 7);
+            on_frame_start(|| { press("a"); press("start"); release("a") });
+            on_frame_end(|| text(0, 0, `${writes.len()}`));
+            on_write(0x0811, |address, value| writes.push(value));
+            "#,
+        )
+        .unwrap();
+
+        script.frame_end(&mut cpu, &mut frame).unwrap();
+        assert_eq!(cpu.bus.ram_mut()[0x10], 7);
+        script.frame_start(&mut cpu).unwrap();
+        assert_eq!(cpu.bus.joypad_mut().buttons(), JOYPAD_START);
+        assert_ne!(cpu.bus.joypad_mut().buttons() & JOYPAD_A, JOYPAD_A);
+
+        // LDA $10, STA $11
+        for _ in 0..2 {
+            cpu.step();
+            script.after_instruction(&mut cpu).unwrap();
+        }
+        let mut next = Frame::new();
+        script.frame_end(&mut cpu, &mut next).unwrap();
+        // One watched write, counted once the frame ends
+        let mut expected = Frame::new();
+        draw_text(&mut expected, 1, 1, "1", SHADOW_COLOR, 1.0);
+        draw_text(&mut expected, 0, 0, "1", TEXT_COLOR, 1.0);
+        assert_eq!(next.data, expected.data);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(RhaiScript::new("let = 1;").is_err());
+
+        let mut script = RhaiScript::new(r#"on_frame_start(|| press("jump"))"#).unwrap();
+        let mut cpu = cpu();
+        script.frame_end(&mut cpu, &mut Frame::new()).unwrap();
+        let error = script.frame_start(&mut cpu).unwrap_err();
+        assert!(error.contains("jump"), "{}", error);
+
+        let mut script = RhaiScript::new("loop {}").unwrap();
+        assert!(script.frame_end(&mut cpu, &mut Frame::new()).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+pub const FRAME_WIDTH: u32 = 256;
+pub const FRAME_HEIGHT: u32 = 240;
+
+/// Width of a NES pixel relative to its height on a CRT.
+const PIXEL_ASPECT_RATIO: f64 = 8.0 / 7.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleMode {
+    /// Largest whole multiple of the frame that fits in the window.
+    Integer,
+    /// Fill the whole window, ignoring the aspect ratio.
+    Stretch,
+    /// Keep the 8:7 pixel aspect ratio of a CRT and fit the window.
+    AspectCorrected,
+}
+
+impl ScaleMode {
+    pub fn next(self) -> ScaleMode {
+        match self {
+            ScaleMode::Integer => ScaleMode::Stretch,
+            ScaleMode::Stretch => ScaleMode::AspectCorrected,
+            ScaleMode::AspectCorrected => ScaleMode::Integer,
+        }
+    }
+}
+
+/// Area of the window that the frame is drawn to, the rest is letterboxed.
+#[derive(Debug, PartialEq)]
+pub struct Viewport {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Viewport {
+    fn centered(width: u32, height: u32, window_width: u32, window_height: u32) -> Self {
+        Viewport {
+            x: (window_width.saturating_sub(width) / 2) as i32,
+            y: (window_height.saturating_sub(height) / 2) as i32,
+            width,
+            height,
+        }
+    }
+}
+
+/// Calculates where the frame should be drawn in a window of the given size.
+pub fn viewport(mode: ScaleMode, window_width: u32, window_height: u32) -> Viewport {
+    match mode {
+        ScaleMode::Integer => {
+            let scale = (window_width / FRAME_WIDTH)
+                .min(window_height / FRAME_HEIGHT)
+                .max(1);
+            Viewport::centered(
+                FRAME_WIDTH * scale,
+                FRAME_HEIGHT * scale,
+                window_width,
+                window_height,
+            )
+        }
+        ScaleMode::Stretch => {
+            Viewport::centered(window_width, window_height, window_width, window_height)
+        }
+        ScaleMode::AspectCorrected => {
+            let frame_width = FRAME_WIDTH as f64 * PIXEL_ASPECT_RATIO;
+            let frame_height = FRAME_HEIGHT as f64;
+            let scale =
+                (window_width as f64 / frame_width).min(window_height as f64 / frame_height);
+            Viewport::centered(
+                (frame_width * scale).round() as u32,
+                (frame_height * scale).round() as u32,
+                window_width,
+                window_height,
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_integer_scaling() {
+        let viewport = viewport(ScaleMode::Integer, 800, 800);
+        assert_eq!(
+            viewport,
+            Viewport {
+                x: 16,
+                y: 40,
+                width: 768,
+                height: 720
+            }
+        );
+    }
+
+    #[test]
+    fn test_integer_scaling_small_window() {
+        let viewport = viewport(ScaleMode::Integer, 100, 100);
+        assert_eq!(viewport.width, FRAME_WIDTH);
+        assert_eq!(viewport.height, FRAME_HEIGHT);
+    }
+
+    #[test]
+    fn test_stretch() {
+        let viewport = viewport(ScaleMode::Stretch, 1000, 500);
+        assert_eq!(
+            viewport,
+            Viewport {
+                x: 0,
+                y: 0,
+                width: 1000,
+                height: 500
+            }
+        );
+    }
+
+    #[test]
+    fn test_aspect_corrected_letterbox() {
+        let viewport = viewport(ScaleMode::AspectCorrected, 1000, 480);
+        assert_eq!(viewport.height, 480);
+        assert_eq!(viewport.width, 585);
+        assert_eq!(viewport.x, (1000 - 585) / 2);
+        assert_eq!(viewport.y, 0);
+    }
+}
@@ -0,0 +1,265 @@
+use crate::bus::Bus;
+use crate::cartridge::{Mirroring, Region, Rom};
+use crate::cpu::{Mem, CPU};
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+/// Failures listed per opcode file, the rest are only counted.
+pub const FAILURES_SHOWN: usize = 3;
+
+/// Registers and the memory a case sets up or expects.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuState {
+    pub pc: u16,
+    pub s: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub ram: Vec<(u16, u8)>,
+}
+
+/// A single instruction of Tom Harte's single-step tests for the 6502.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestCase {
+    pub name: String,
+    pub initial: CpuState,
+    pub expected: CpuState,
+    /// Bus cycles the instruction takes. The CPU does not model what happens on each of them,
+    /// so only their number is compared.
+    pub cycles: usize,
+}
+
+/// Reads a file of test cases, e.g. `a9.json` with every case of LDA immediate.
+pub fn parse_cases(json: &str) -> Result<Vec<TestCase>, String> {
+    let cases: Value = serde_json::from_str(json).map_err(|error| error.to_string())?;
+    let cases = cases.as_array().ok_or("Expected a list of test cases")?;
+    cases
+        .iter()
+        .map(|case| {
+            Ok(TestCase {
+                name: case["name"].as_str().unwrap_or_default().to_string(),
+                initial: parse_state(&case["initial"])?,
+                expected: parse_state(&case["final"])?,
+                cycles: case["cycles"].as_array().map_or(0, Vec::len),
+            })
+        })
+        .collect()
+}
+
+fn parse_state(state: &Value) -> Result<CpuState, String> {
+    let number = |name: &str| {
+        state[name]
+            .as_u64()
+            .ok_or_else(|| format!("Missing {:?} in {}", name, state))
+    };
+    let ram = state["ram"]
+        .as_array()
+        .ok_or_else(|| format!("Missing \"ram\" in {}", state))?
+        .iter()
+        .map(|pair| match (pair[0].as_u64(), pair[1].as_u64()) {
+            (Some(address), Some(value)) => Ok((address as u16, value as u8)),
+            _ => Err(format!("Invalid RAM entry {}", pair)),
+        })
+        .collect::<Result<_, String>>()?;
+    Ok(CpuState {
+        pc: number("pc")? as u16,
+        s: number("s")? as u8,
+        a: number("a")? as u8,
+        x: number("x")? as u8,
+        y: number("y")? as u8,
+        p: number("p")? as u8,
+        ram,
+    })
+}
+
+/// A CPU on 64K of flat RAM, without the PPU or a cartridge in the way.
+fn flat_cpu() -> CPU<'static> {
+    let rom = Rom {
+        prg_rom: vec![0; 0x4000],
+        chr_rom: vec![0; 0x2000],
+        mapper_id: 0,
+        screen_mirroring: Mirroring::Horizontal,
+        region: Region::Ntsc,
+        crc: 0,
+    };
+    let mut cpu = CPU::new(Bus::new(rom, |_, _| {}));
+    cpu.bus.set_flat_memory(Some(vec![0; 0x10000]));
+    cpu
+}
+
+/// Runs the instruction of a case and describes every difference from the expected state.
+pub fn run_case(case: &TestCase) -> Result<(), String> {
+    let mut cpu = flat_cpu();
+    let initial = &case.initial;
+    for &(address, value) in &initial.ram {
+        cpu.write(address, value);
+    }
+    cpu.pc = initial.pc;
+    cpu.s = initial.s;
+    cpu.a = initial.a;
+    cpu.x = initial.x;
+    cpu.y = initial.y;
+    cpu.p = initial.p;
+
+    let start = cpu.bus.cycles();
+    cpu.step();
+    let cycles = (cpu.bus.cycles() - start) as usize;
+
+    let expected = &case.expected;
+    let mut differences = vec![];
+    let registers = [
+        ("PC", cpu.pc, expected.pc),
+        ("S", cpu.s as u16, expected.s as u16),
+        ("A", cpu.a as u16, expected.a as u16),
+        ("X", cpu.x as u16, expected.x as u16),
+        ("Y", cpu.y as u16, expected.y as u16),
+        ("P", cpu.p as u16, expected.p as u16),
+    ];
+    for (name, actual, expected) in registers {
+        if actual != expected {
+            differences.push(format!(
+                "{} {:02X}, expected {:02X}",
+                name, actual, expected
+            ));
+        }
+    }
+    for &(address, value) in &expected.ram {
+        let actual = cpu.read(address);
+        if actual != value {
+            differences.push(format!(
+                "${:04X} {:02X}, expected {:02X}",
+                address, actual, value
+            ));
+        }
+    }
+    if cycles != case.cycles {
+        differences.push(format!("{} cycles, expected {}", cycles, case.cycles));
+    }
+
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{}: {}", case.name, differences.join(", ")))
+    }
+}
+
+/// Results of the cases of one opcode file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileReport {
+    pub name: String,
+    pub cases: usize,
+    pub passed: usize,
+    /// The first few failures.
+    pub failures: Vec<String>,
+}
+
+impl fmt::Display for FileReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} of {} passed", self.name, self.passed, self.cases)?;
+        for failure in &self.failures {
+            write!(f, "\n  {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs every case of a file. An opcode the CPU does not implement panics, which fails the
+/// remaining cases of the file.
+pub fn run_file(path: &Path) -> io::Result<FileReport> {
+    let text = fs::read_to_string(path)?;
+    let cases =
+        parse_cases(&text).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+    let mut report = FileReport {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        cases: cases.len(),
+        passed: 0,
+        failures: vec![],
+    };
+    for case in &cases {
+        match panic::catch_unwind(AssertUnwindSafe(|| run_case(case))) {
+            Ok(Ok(())) => report.passed += 1,
+            Ok(Err(failure)) => {
+                if report.failures.len() < FAILURES_SHOWN {
+                    report.failures.push(failure);
+                }
+            }
+            Err(_) => {
+                report
+                    .failures
+                    .push(format!("{}: panicked, skipped the rest", case.name));
+                break;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Runs every `.json` file of a directory in name order, e.g. the `nes6502/v1` directory of
+/// the tests, which leaves out decimal mode like the NES does.
+pub fn run_dir(dir: &Path) -> io::Result<Vec<FileReport>> {
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .collect();
+    paths.sort();
+    paths.iter().map(|path| run_file(path)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// LDA #$42 and STA $10 in the format of the test files.
+    const CASES: &str = r#"[
+        {"name": "a9 42 00", "initial": {"pc": 1000, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+            "ram": [[1000, 169], [1001, 66]]},
+         "final": {"pc": 1002, "s": 253, "a": 66, "x": 0, "y": 0, "p": 36,
+            "ram": [[1000, 169], [1001, 66]]},
+         "cycles": [[1000, 169, "read"], [1001, 66, "read"]]},
+        {"name": "85 10 00", "initial": {"pc": 512, "s": 253, "a": 7, "x": 0, "y": 0, "p": 36,
+            "ram": [[512, 133], [513, 16], [16, 0]]},
+         "final": {"pc": 514, "s": 253, "a": 7, "x": 0, "y": 0, "p": 36,
+            "ram": [[512, 133], [513, 16], [16, 8]]},
+         "cycles": [[512, 133, "read"], [513, 16, "read"], [16, 7, "write"]]}
+    ]"#;
+
+    #[test]
+    fn test_run_case() {
+        let cases = parse_cases(CASES).unwrap();
+        assert_eq!(cases.len(), 2);
+        assert_eq!(cases[0].cycles, 2);
+        assert_eq!(cases[1].initial.ram[2], (16, 0));
+
+        assert_eq!(run_case(&cases[0]), Ok(()));
+        // The second case expects the wrong value on purpose
+        assert_eq!(
+            run_case(&cases[1]),
+            Err("85 10 00: $0010 07, expected 08".to_string())
+        );
+    }
+
+    #[test]
+    fn test_run_file() {
+        let path = std::env::temp_dir().join("nes_rust_test_single_step.json");
+        fs::write(&path, CASES).unwrap();
+        let report = run_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(report.passed, 1);
+        assert_eq!(report.failures.len(), 1);
+        assert!(report
+            .to_string()
+            .starts_with("nes_rust_test_single_step.json: 1 of 2 passed"));
+    }
+}
@@ -0,0 +1,168 @@
+//! Sprite evaluation the way the PPU does it for every scanline: secondary OAM is cleared to
+//! $FF, the first 8 sprites in range are copied into it and the rest only feed the overflow
+//! flag, then the patterns of those 8 are fetched for the line.
+
+use crate::ppu::PPU;
+use crate::render::decode_tile_row;
+
+/// Sprites the PPU can draw on one scanline.
+pub const SPRITES_PER_LINE: usize = 8;
+
+/// Secondary OAM after the evaluation of a scanline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpriteLine {
+    /// The sprites in range in OAM order, unused entries stay $FF.
+    pub entries: [[u8; 4]; SPRITES_PER_LINE],
+    pub count: usize,
+    /// Whether the evaluation set the sprite overflow flag of PPUSTATUS.
+    pub overflow: bool,
+}
+
+impl SpriteLine {
+    /// Secondary OAM as cleared, for lines nothing was evaluated for.
+    pub const EMPTY: SpriteLine = SpriteLine {
+        entries: [[0xff; 4]; SPRITES_PER_LINE],
+        count: 0,
+        overflow: false,
+    };
+
+    pub fn sprites(&self) -> &[[u8; 4]] {
+        &self.entries[..self.count]
+    }
+}
+
+fn in_range(y: u8, line: usize, height: usize) -> bool {
+    line.wrapping_sub(y as usize) < height
+}
+
+/// Finds the sprites on `line`, `height` is 8 or 16. Like the hardware, once secondary OAM is
+/// full the Y check also walks through the other bytes of each sprite, so the overflow flag
+/// misses some sprites and sees some that are not there.
+pub fn evaluate(oam: &[u8; 256], line: usize, height: usize) -> SpriteLine {
+    let mut sprites = SpriteLine::EMPTY;
+
+    let mut n = 0;
+    while n < 64 && sprites.count < SPRITES_PER_LINE {
+        let sprite = &oam[n * 4..n * 4 + 4];
+        if in_range(sprite[0], line, height) {
+            sprites.entries[sprites.count].copy_from_slice(sprite);
+            sprites.count += 1;
+        }
+        n += 1;
+    }
+
+    // The hardware bug: m should stay 0, but goes up along with n
+    let mut m = 0;
+    while n < 64 {
+        if in_range(oam[n * 4 + m], line, height) {
+            sprites.overflow = true;
+            break;
+        }
+        n += 1;
+        m = (m + 1) & 0b11;
+    }
+    sprites
+}
+
+/// Color indices 0-3 of a sprite from secondary OAM on `line`, flipped as its attributes say,
+/// as fetched at dots 257-320. 8x16 sprites take their pattern table from bit 0 of the tile
+/// number and the top half from the even tile.
+pub fn fetch_row(ppu: &PPU, sprite: &[u8; 4], line: usize, height: usize) -> [u8; 8] {
+    let attributes = sprite[2];
+    let mut row = line.wrapping_sub(sprite[0] as usize) % height;
+    if attributes & 0b1000_0000 != 0 {
+        row = height - 1 - row;
+    }
+
+    let tile = sprite[1] as usize;
+    let tile_start = if height == 16 {
+        (tile & 1) * 0x1000 + ((tile & !1) + row / 8) * 16
+    } else {
+        ppu.register_control.sprite_pattern_address() as usize + tile * 16
+    };
+    let mut pixels = decode_tile_row(
+        ppu.chr_rom[tile_start + row % 8],
+        ppu.chr_rom[tile_start + row % 8 + 8],
+    );
+    if attributes & 0b0100_0000 != 0 {
+        pixels.reverse();
+    }
+    pixels
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+
+    fn oam_with(sprites: &[(usize, [u8; 4])]) -> [u8; 256] {
+        let mut oam = [0xf0; 256];
+        for (index, sprite) in sprites {
+            oam[index * 4..index * 4 + 4].copy_from_slice(sprite);
+        }
+        oam
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let oam = oam_with(&[(3, [10, 1, 0, 0]), (7, [14, 2, 0, 0]), (9, [30, 3, 0, 0])]);
+        let line = evaluate(&oam, 17, 8);
+        assert_eq!(line.sprites(), [[10, 1, 0, 0], [14, 2, 0, 0]]);
+        assert_eq!(line.entries[2], [0xff; 4]);
+        assert!(!line.overflow);
+        // Taller sprites reach further down
+        assert_eq!(evaluate(&oam, 20, 16).count, 2);
+        assert_eq!(evaluate(&oam, 18, 8).sprites(), [[14, 2, 0, 0]]);
+    }
+
+    #[test]
+    fn test_overflow() {
+        // Nine sprites on the line, only eight fit
+        let sprites: Vec<(usize, [u8; 4])> = (0..9).map(|n| (n, [20, n as u8, 0, 0])).collect();
+        let line = evaluate(&oam_with(&sprites), 20, 8);
+        assert_eq!(line.count, 8);
+        assert_eq!(line.sprites()[7][1], 7);
+        assert!(line.overflow);
+
+        // The ninth sprite is looked at right, the tenth by its tile number
+        let mut sprites: Vec<(usize, [u8; 4])> = (0..8).map(|n| (n, [20, 0, 0, 0])).collect();
+        sprites.push((9, [0xf0, 20, 0, 0]));
+        assert!(evaluate(&oam_with(&sprites), 20, 8).overflow);
+        sprites[8] = (9, [20, 0xf0, 0, 0]);
+        assert!(!evaluate(&oam_with(&sprites), 20, 8).overflow);
+    }
+
+    #[test]
+    fn test_fetch_row_flips() {
+        let mut chr_rom = vec![0; 0x2000];
+        // Tile 1 has color 1 top left and color 2 bottom right
+        chr_rom[0x10] = 0b1000_0000;
+        chr_rom[0x10 + 7 + 8] = 0b0000_0001;
+        let ppu = PPU::new(chr_rom, Mirroring::Vertical);
+        let row = |attributes, line| fetch_row(&ppu, &[10, 1, attributes, 0], line, 8);
+        assert_eq!(row(0, 10), [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(row(0, 17), [0, 0, 0, 0, 0, 0, 0, 2]);
+        assert_eq!(row(0b0100_0000, 10), [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(row(0b1000_0000, 10), [0, 0, 0, 0, 0, 0, 0, 2]);
+        assert_eq!(row(0b1100_0000, 10), [2, 0, 0, 0, 0, 0, 0, 0]);
+        // The palette bits don't change the colors
+        assert_eq!(row(0b0000_0011, 10), row(0, 10));
+    }
+
+    #[test]
+    fn test_fetch_row_8x16() {
+        let mut chr_rom = vec![0; 0x2000];
+        // Tile $03 is $02 in the second pattern table, its top row all 1 and its bottom row
+        // all 2
+        chr_rom[0x1020] = 0xff;
+        chr_rom[0x1030 + 7 + 8] = 0xff;
+        let ppu = PPU::new(chr_rom, Mirroring::Vertical);
+        let row = |attributes, line| fetch_row(&ppu, &[10, 0x03, attributes, 0], line, 16);
+        assert_eq!(row(0, 10), [1; 8]);
+        assert_eq!(row(0, 25), [2; 8]);
+        assert_eq!(row(0, 17), [0; 8]);
+        // Flipped vertically the halves swap
+        assert_eq!(row(0b1000_0000, 10), [2; 8]);
+        assert_eq!(row(0b1000_0000, 25), [1; 8]);
+    }
+}
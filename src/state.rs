@@ -0,0 +1,262 @@
+use std::borrow::Cow;
+use std::io;
+
+/// Magic bytes at the start of every save state.
+pub const MAGIC: [u8; 4] = *b"NESS";
+/// Version 3 split the state into tagged chunks, version 2 was one flat list of values.
+pub const VERSION: u8 = 3;
+
+/// Enough room for a whole machine state, so writing one never reallocates.
+const INITIAL_CAPACITY: usize = 0x2000;
+
+/// Chunks of a version 2 state with their sizes, in the order they were written.
+const V2_CHUNKS: [([u8; 4], usize); 5] = [
+    (*b"ROM ", 4),
+    (*b"CPU ", 7),
+    (*b"RAM ", 0x800),
+    (*b"PPU ", 0x930),
+    (*b"JOY1", 3),
+];
+
+/// Collects the machine state as little endian values, in the order they are written.
+///
+/// Each component writes its values into its own chunk, a tag followed by the length, so a
+/// component can append new values and readers can skip chunks they do not know.
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut bytes = Vec::with_capacity(INITIAL_CAPACITY);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(VERSION);
+        StateWriter { bytes }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Writes the values written by `write` as one chunk.
+    pub fn write_chunk(&mut self, tag: [u8; 4], write: impl FnOnce(&mut StateWriter)) {
+        self.bytes.extend_from_slice(&tag);
+        let length_position = self.bytes.len();
+        self.write_u32(0);
+        write(self);
+
+        let length = (self.bytes.len() - length_position - 4) as u32;
+        self.bytes[length_position..length_position + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Brings a state written by an older version to the current layout.
+pub fn migrate(bytes: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    if bytes.len() < MAGIC.len() + 1 || bytes[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not a save state"));
+    }
+
+    match bytes[MAGIC.len()] {
+        VERSION => Ok(Cow::Borrowed(bytes)),
+        2 => {
+            let mut reader = StateReader::new(&bytes[MAGIC.len() + 1..]);
+            let mut writer = StateWriter::new();
+            for (tag, length) in V2_CHUNKS {
+                let values = reader.take(length)?;
+                writer.write_chunk(tag, |writer| writer.write_bytes(values));
+            }
+            Ok(Cow::Owned(writer.into_bytes()))
+        }
+        version => Err(invalid(&format!("unsupported version {}", version))),
+    }
+}
+
+/// The chunks of a save state in the current version.
+pub struct StateChunks<'a> {
+    chunks: Vec<([u8; 4], &'a [u8])>,
+}
+
+impl<'a> StateChunks<'a> {
+    /// Checks the header and splits the rest into chunks.
+    pub fn new(bytes: &'a [u8]) -> io::Result<Self> {
+        if bytes.len() < MAGIC.len() + 1 || bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a save state"));
+        }
+        let version = bytes[MAGIC.len()];
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+
+        let mut reader = StateReader::new(&bytes[MAGIC.len() + 1..]);
+        let mut chunks = vec![];
+        while reader.remaining() > 0 {
+            let mut tag = [0; 4];
+            reader.read_bytes(&mut tag)?;
+            let length = reader.read_u32()? as usize;
+            chunks.push((tag, reader.take(length)?));
+        }
+        Ok(StateChunks { chunks })
+    }
+
+    /// Every chunk with its tag, in the order they were written.
+    pub fn iter(&self) -> impl Iterator<Item = ([u8; 4], &'a [u8])> + '_ {
+        self.chunks.iter().copied()
+    }
+
+    /// Reader for the values of a chunk, other chunks are never looked at.
+    pub fn reader(&self, tag: [u8; 4]) -> io::Result<StateReader<'a>> {
+        self.chunks
+            .iter()
+            .find(|(chunk_tag, _)| *chunk_tag == tag)
+            .map(|(_, bytes)| StateReader::new(bytes))
+            .ok_or_else(|| {
+                invalid(&format!(
+                    "save state has no {} chunk",
+                    String::from_utf8_lossy(&tag).trim_end()
+                ))
+            })
+    }
+}
+
+/// Reads back the values in the order a `StateWriter` wrote them.
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        StateReader { bytes }
+    }
+
+    fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < length {
+            return Err(invalid("save state is truncated"));
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_bool(&mut self) -> io::Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    /// Fills the whole buffer, e.g. a RAM array.
+    pub fn read_bytes(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        buffer.copy_from_slice(self.take(buffer.len())?);
+        Ok(())
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_chunk(*b"TEST", |writer| {
+            writer.write_u8(0x12);
+            writer.write_u16(0x3456);
+            writer.write_u32(0x789a_bcde);
+            writer.write_bool(true);
+            writer.write_bytes(&[1, 2, 3]);
+        });
+        let bytes = writer.into_bytes();
+
+        let chunks = StateChunks::new(&bytes).unwrap();
+        let mut reader = chunks.reader(*b"TEST").unwrap();
+        assert_eq!(reader.read_u8().unwrap(), 0x12);
+        assert_eq!(reader.read_u16().unwrap(), 0x3456);
+        assert_eq!(reader.read_u32().unwrap(), 0x789a_bcde);
+        assert!(reader.read_bool().unwrap());
+        let mut buffer = [0; 3];
+        reader.read_bytes(&mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3]);
+        assert_eq!(reader.remaining(), 0);
+        assert!(reader.read_u8().is_err());
+    }
+
+    #[test]
+    fn test_skips_unknown_chunks() {
+        let mut writer = StateWriter::new();
+        writer.write_chunk(*b"NEW ", |writer| writer.write_u32(1));
+        writer.write_chunk(*b"OLD ", |writer| writer.write_u8(2));
+        let bytes = writer.into_bytes();
+
+        let chunks = StateChunks::new(&bytes).unwrap();
+        assert_eq!(chunks.reader(*b"OLD ").unwrap().read_u8().unwrap(), 2);
+        assert!(chunks.reader(*b"GONE").is_err());
+        assert!(StateChunks::new(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_migrates_version_2() {
+        let mut bytes = b"NESS\x02".to_vec();
+        for (i, (_, length)) in V2_CHUNKS.iter().enumerate() {
+            bytes.extend(std::iter::repeat_n(i as u8, *length));
+        }
+
+        let migrated = migrate(&bytes).unwrap();
+        let chunks = StateChunks::new(&migrated).unwrap();
+        let mut joypad = chunks.reader(*b"JOY1").unwrap();
+        assert_eq!(joypad.remaining(), 3);
+        assert_eq!(joypad.read_u8().unwrap(), 4);
+        assert!(migrate(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        assert!(migrate(b"NES\x1a").is_err());
+        assert!(migrate(b"NESS\x63").is_err());
+        assert!(StateChunks::new(b"NESS\x02").is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use crate::state::{self, StateChunks};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The values of a chunk in the order they are saved, a name with the size in bytes.
+type Layout = &'static [(&'static str, usize)];
+
+/// The chunks the tool knows. Values longer than two bytes are memory and compared byte by byte.
+const LAYOUTS: [([u8; 4], Layout); 5] = [
+    (
+        *b"CPU ",
+        &[("a", 1), ("x", 1), ("y", 1), ("p", 1), ("s", 1), ("pc", 2)],
+    ),
+    (*b"RAM ", &[("", 0x800)]),
+    (
+        *b"PPU ",
+        &[
+            ("palette", 32),
+            ("vram", 0x800),
+            ("oam", 256),
+            ("buffer", 1),
+            ("ctrl", 1),
+            ("mask", 1),
+            ("status", 1),
+            ("oam_addr", 1),
+            ("scroll_x", 1),
+            ("scroll_y", 1),
+            ("scroll_x_next", 1),
+            ("addr", 2),
+            ("addr_hi_next", 1),
+            ("scanline", 2),
+            ("cycles", 2),
+            ("nmi", 1),
+        ],
+    ),
+    (
+        *b"JOY1",
+        &[("strobe", 1), ("index", 1), ("buttons", 1), ("latched", 1)],
+    ),
+    (
+        *b"JOY2",
+        &[("strobe", 1), ("index", 1), ("buttons", 1), ("latched", 1)],
+    ),
+];
+
+/// A value that is not the same in both states.
+#[derive(Debug, PartialEq)]
+pub enum Difference {
+    /// A register or memory byte, memory is named by its offset.
+    Value {
+        name: String,
+        width: usize,
+        first: u16,
+        second: u16,
+    },
+    /// A chunk only one of the states has, e.g. from before the second controller was saved.
+    Missing { chunk: String, in_first: bool },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Difference::Value {
+                name,
+                width,
+                first,
+                second,
+            } => {
+                let digits = width * 2;
+                write!(
+                    f,
+                    "{}: ${:0digits$X} -> ${:0digits$X}",
+                    name,
+                    first,
+                    second,
+                    digits = digits
+                )
+            }
+            Difference::Missing { chunk, in_first } => write!(
+                f,
+                "{}: only in the {} state",
+                chunk,
+                if *in_first { "first" } else { "second" }
+            ),
+        }
+    }
+}
+
+/// Compares two states of the same game, as written by `CPU::state_to_bytes`. Chunks without
+/// a known layout, like the registers of a mapper once one saves them, are compared byte by
+/// byte.
+pub fn diff_states(first: &[u8], second: &[u8]) -> io::Result<Vec<Difference>> {
+    let first = state::migrate(first)?;
+    let second = state::migrate(second)?;
+    let first = StateChunks::new(&first)?;
+    let second = StateChunks::new(&second)?;
+    if first.reader(*b"ROM ")?.read_u32()? != second.reader(*b"ROM ")?.read_u32()? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "save states are from different games",
+        ));
+    }
+
+    let mut differences = vec![];
+    for (tag, bytes) in first.iter().filter(|(tag, _)| tag != b"ROM ") {
+        let chunk = String::from_utf8_lossy(&tag).trim_end().to_string();
+        match second.iter().find(|(other, _)| *other == tag) {
+            Some((_, other)) => diff_chunk(&chunk, layout(tag), bytes, other, &mut differences),
+            None => differences.push(Difference::Missing {
+                chunk,
+                in_first: true,
+            }),
+        }
+    }
+    for (tag, _) in second.iter() {
+        if first.iter().all(|(other, _)| other != tag) {
+            differences.push(Difference::Missing {
+                chunk: String::from_utf8_lossy(&tag).trim_end().to_string(),
+                in_first: false,
+            });
+        }
+    }
+    Ok(differences)
+}
+
+/// `diff_states` for two state files.
+pub fn diff_state_files(first: &Path, second: &Path) -> io::Result<Vec<Difference>> {
+    diff_states(&fs::read(first)?, &fs::read(second)?)
+}
+
+fn layout(tag: [u8; 4]) -> Layout {
+    LAYOUTS
+        .iter()
+        .find(|(layout_tag, _)| *layout_tag == tag)
+        .map_or(&[], |(_, fields)| fields)
+}
+
+fn diff_chunk(
+    chunk: &str,
+    fields: Layout,
+    first: &[u8],
+    second: &[u8],
+    differences: &mut Vec<Difference>,
+) {
+    let mut offset = 0;
+    for &(field, size) in fields {
+        if offset + size > first.len().min(second.len()) {
+            break;
+        }
+        let name = format!("{} {}", chunk, field);
+        if size <= 2 {
+            let value = |bytes: &[u8]| {
+                bytes[offset..offset + size]
+                    .iter()
+                    .rev()
+                    .fold(0, |value, byte| value << 8 | *byte as u16)
+            };
+            push_value(differences, name, size, value(first), value(second));
+        } else {
+            diff_bytes(&name, first, second, offset, offset + size, differences);
+        }
+        offset += size;
+    }
+
+    // Values appended by a newer version, or a chunk without a layout
+    let end = first.len().min(second.len());
+    diff_bytes(chunk, first, second, offset, end, differences);
+    if first.len() != second.len() {
+        push_value(
+            differences,
+            format!("{} length", chunk),
+            2,
+            first.len() as u16,
+            second.len() as u16,
+        );
+    }
+}
+
+fn diff_bytes(
+    name: &str,
+    first: &[u8],
+    second: &[u8],
+    start: usize,
+    end: usize,
+    differences: &mut Vec<Difference>,
+) {
+    for index in start..end {
+        push_value(
+            differences,
+            format!("{} ${:04X}", name.trim_end(), index - start),
+            1,
+            first[index] as u16,
+            second[index] as u16,
+        );
+    }
+}
+
+fn push_value(
+    differences: &mut Vec<Difference>,
+    name: String,
+    width: usize,
+    first: u16,
+    second: u16,
+) {
+    if first != second {
+        differences.push(Difference::Value {
+            name: name.trim_end().to_string(),
+            width,
+            first,
+            second,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::{Mem, CPU};
+
+    #[test]
+    fn test_diff_states() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        cpu.power_cycle();
+        let first = cpu.state_to_bytes();
+        assert_eq!(diff_states(&first, &first).unwrap(), []);
+
+        cpu.a = 0x42;
+        cpu.pc = 0x8123;
+        cpu.write(0x0812, 0x07);
+        cpu.bus.ppu.oam_data[5] = 0x99;
+        cpu.bus.ppu.scanline = 0x104;
+        let second = cpu.state_to_bytes();
+        let differences: Vec<String> = diff_states(&first, &second)
+            .unwrap()
+            .iter()
+            .map(|difference| difference.to_string())
+            .collect();
+        assert_eq!(
+            differences,
+            [
+                "CPU a: $00 -> $42",
+                format!("CPU pc: ${:04X} -> $8123", cpu.read_address(0xfffc)).as_str(),
+                // $0812 mirrors $0012
+                "RAM $0012: $00 -> $07",
+                "PPU oam $0005: $00 -> $99",
+                "PPU scanline: $0000 -> $0104",
+            ]
+        );
+
+        let mut other_game = second.clone();
+        other_game[13] ^= 1;
+        assert!(diff_states(&first, &other_game).is_err());
+    }
+}
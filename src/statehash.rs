@@ -0,0 +1,89 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Hash of a whole machine state, as written by `CPU::state_to_bytes`.
+pub fn hash_state(state: &[u8]) -> u32 {
+    crc32fast::hash(state)
+}
+
+/// The state hash of every frame of a run, to find where two runs of a game stop agreeing.
+#[derive(Debug, Default, PartialEq)]
+pub struct HashLog {
+    hashes: Vec<u32>,
+}
+
+impl HashLog {
+    pub fn new() -> Self {
+        HashLog { hashes: vec![] }
+    }
+
+    pub fn push(&mut self, hash: u32) {
+        self.hashes.push(hash);
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// First frame where the hashes differ, only the frames both runs got to are compared.
+    pub fn first_mismatch(&self, other: &HashLog) -> Option<usize> {
+        self.hashes
+            .iter()
+            .zip(&other.hashes)
+            .position(|(hash, other_hash)| hash != other_hash)
+    }
+
+    /// One hash per line in hex, so logs can also be compared with a text diff.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        for hash in &self.hashes {
+            writeln!(text, "{:08x}", hash).unwrap();
+        }
+        fs::write(path, text)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let hashes = fs::read_to_string(path)?
+            .lines()
+            .map(|line| {
+                u32::from_str_radix(line.trim(), 16)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not a state hash log"))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(HashLog { hashes })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn log(hashes: &[u32]) -> HashLog {
+        HashLog {
+            hashes: hashes.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_first_mismatch() {
+        assert_eq!(log(&[1, 2, 3]).first_mismatch(&log(&[1, 2, 3, 4])), None);
+        assert_eq!(log(&[1, 2, 3]).first_mismatch(&log(&[1, 5, 3])), Some(1));
+        assert_eq!(log(&[]).first_mismatch(&log(&[1])), None);
+    }
+
+    #[test]
+    fn test_save_load_round_trip() {
+        let path = std::env::temp_dir().join("nes_rust_test.hashes");
+        let saved = log(&[0, 0xdead_beef, 42]);
+        saved.save(&path).unwrap();
+        let loaded = HashLog::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, saved);
+    }
+}
@@ -0,0 +1,220 @@
+use crate::cpu::CPU;
+use std::fmt::Write as _;
+
+/// Names of the status flag bits, bit 0 first. Bit 5 is always set and has no name.
+const CPU_FLAGS: [(&str, u8); 7] = [
+    ("carry", 0),
+    ("zero", 1),
+    ("interrupt_disable", 2),
+    ("decimal", 3),
+    ("break", 4),
+    ("overflow", 6),
+    ("negative", 7),
+];
+const PPU_MASK_FLAGS: [(&str, u8); 8] = [
+    ("grayscale", 0),
+    ("show_background_left", 1),
+    ("show_sprites_left", 2),
+    ("show_background", 3),
+    ("show_sprites", 4),
+    ("emphasize_red", 5),
+    ("emphasize_green", 6),
+    ("emphasize_blue", 7),
+];
+const PPU_STATUS_FLAGS: [(&str, u8); 3] = [
+    ("sprite_overflow", 5),
+    ("sprite_zero_hit", 6),
+    ("vertical_blank", 7),
+];
+
+/// Just the values the export needs, written out with two spaces of indent per level.
+enum Json {
+    Number(u64),
+    Bool(bool),
+    Text(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    fn write(&self, text: &mut String, indent: usize) {
+        match self {
+            Json::Number(number) => write!(text, "{}", number).unwrap(),
+            Json::Bool(value) => write!(text, "{}", value).unwrap(),
+            Json::Text(value) => write!(text, "{:?}", value).unwrap(),
+            Json::Array(values) => {
+                let values: Vec<String> = values
+                    .iter()
+                    .map(|value| {
+                        let mut text = String::new();
+                        value.write(&mut text, indent);
+                        text
+                    })
+                    .collect();
+                write!(text, "[{}]", values.join(", ")).unwrap();
+            }
+            Json::Object(fields) => {
+                text.push_str("{\n");
+                for (index, (name, value)) in fields.iter().enumerate() {
+                    write!(text, "{:1$}\"{2}\": ", "", indent + 2, name).unwrap();
+                    value.write(text, indent + 2);
+                    text.push_str(if index + 1 < fields.len() {
+                        ",\n"
+                    } else {
+                        "\n"
+                    });
+                }
+                write!(text, "{:1$}}}", "", indent).unwrap();
+            }
+        }
+    }
+}
+
+fn number(value: impl Into<u64>) -> Json {
+    Json::Number(value.into())
+}
+
+fn flags(bits: u8, names: &[(&'static str, u8)]) -> Json {
+    Json::Object(
+        names
+            .iter()
+            .map(|&(name, bit)| (name, Json::Bool(bits & 1 << bit != 0)))
+            .collect(),
+    )
+}
+
+/// The whole machine as pretty JSON, for bug reports and to compare with the state dumps of
+/// other emulators. Numbers are decimal, registers come with their bits decoded next to them.
+pub fn machine_json(cpu: &CPU) -> String {
+    let ppu = &cpu.bus.ppu;
+    let control = ppu.register_control.bits();
+    let cpu_json = Json::Object(vec![
+        ("pc", number(cpu.pc)),
+        ("a", number(cpu.a)),
+        ("x", number(cpu.x)),
+        ("y", number(cpu.y)),
+        ("s", number(cpu.s)),
+        ("p", number(cpu.p)),
+        ("flags", flags(cpu.p, &CPU_FLAGS)),
+    ]);
+    let ppu_json = Json::Object(vec![
+        ("scanline", number(ppu.scanline)),
+        ("dot", number(ppu.cycles)),
+        ("ctrl", number(control)),
+        (
+            "ctrl_decoded",
+            Json::Object(vec![
+                (
+                    "nametable",
+                    number(0x2000 + (control as u16 & 0b11) * 0x400),
+                ),
+                (
+                    "vram_increment",
+                    number(ppu.register_control.get_address_increment()),
+                ),
+                (
+                    "sprite_pattern_table",
+                    number(ppu.register_control.sprite_pattern_address()),
+                ),
+                (
+                    "background_pattern_table",
+                    number(ppu.register_control.background_pattern_address()),
+                ),
+                (
+                    "sprite_height",
+                    number(ppu.register_control.sprite_height() as u64),
+                ),
+                (
+                    "nmi_on_vblank",
+                    Json::Bool(ppu.register_control.get_vertical_blank_nmi()),
+                ),
+            ]),
+        ),
+        ("mask", number(ppu.register_mask.bits())),
+        (
+            "mask_decoded",
+            flags(ppu.register_mask.bits(), &PPU_MASK_FLAGS),
+        ),
+        ("status", number(ppu.register_status.bits())),
+        (
+            "status_decoded",
+            flags(ppu.register_status.bits(), &PPU_STATUS_FLAGS),
+        ),
+        ("vram_address", number(ppu.register_address.get())),
+        (
+            "address_latch_high",
+            Json::Bool(ppu.register_address.hi_next()),
+        ),
+        ("scroll_x", number(ppu.register_scroll.x)),
+        ("scroll_y", number(ppu.register_scroll.y)),
+        ("scroll_latch_y", Json::Bool(ppu.register_scroll.x_next)),
+        ("oam_address", number(ppu.oam_address)),
+        ("read_buffer", number(ppu.buffer)),
+        ("nmi_pending", Json::Bool(ppu.nmi)),
+    ]);
+    // NROM is the only mapper, its banks never move
+    let mapper_json = Json::Object(vec![
+        ("number", number(0u8)),
+        ("name", Json::Text("NROM".to_string())),
+        (
+            "prg_banks",
+            Json::Array(vec![
+                number(cpu.bus.prg_bank(0x8000) as u64),
+                number(cpu.bus.prg_bank(0xc000) as u64),
+            ]),
+        ),
+        ("chr_bank", number(0u8)),
+        ("mirroring", Json::Text(format!("{:?}", ppu.mirroring))),
+    ]);
+    let timers_json = Json::Object(vec![
+        ("cpu_cycles", number(cpu.bus.cycles())),
+        ("frames", number(cpu.bus.frames())),
+        ("nmis", number(cpu.nmi_count)),
+        ("extra_scanlines", number(ppu.extra_scanlines)),
+        ("region", Json::Text(format!("{:?}", ppu.region))),
+    ]);
+
+    let mut text = String::new();
+    Json::Object(vec![
+        ("rom_crc", number(cpu.bus.rom_crc())),
+        ("cpu", cpu_json),
+        ("ppu", ppu_json),
+        ("mapper", mapper_json),
+        ("timers", timers_json),
+    ])
+    .write(&mut text, 0);
+    text.push('\n');
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_machine_json() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0; 0x8000]), |_, _| {}));
+        cpu.power_cycle();
+        cpu.a = 0x42;
+        cpu.p = 0b1000_0011;
+        cpu.write(0x2000, 0b1010_0001);
+        cpu.write(0x2001, 0b0001_1000);
+
+        let json = machine_json(&cpu);
+        assert!(json.starts_with("{\n  \"rom_crc\": "));
+        assert!(json.ends_with("\n}\n"));
+        assert!(json.contains("\n    \"a\": 66,\n"));
+        assert!(json.contains("\"flags\": {\n      \"carry\": true,\n      \"zero\": true,\n"));
+        assert!(json.contains("\"negative\": true\n    }\n  },\n  \"ppu\": {"));
+        assert!(json.contains("\"nametable\": 9216,"));
+        assert!(json.contains("\"sprite_height\": 16,"));
+        assert!(json.contains("\"nmi_on_vblank\": true\n"));
+        assert!(json.contains("\"show_background\": true,"));
+        assert!(json.contains("\"show_sprites_left\": false,"));
+        assert!(json.contains("\"prg_banks\": [0, 1],"));
+        assert!(json.contains("\"mirroring\": \"Vertical\""));
+    }
+}
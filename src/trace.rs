@@ -1,14 +1,151 @@
 use crate::cpu::{AddressingMode, Mem, CPU};
+use crate::disasm::decode;
+use crate::labels::Labels;
+use crate::memory::MemoryRegion;
 use crate::opcodes;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 
+/// How traced instructions are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    /// The columns of nestest.log, to diff against the reference log.
+    Nestest,
+    /// Mesen's trace logger columns, with operands shown by label.
+    Mesen,
+}
+
+impl TraceFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "nestest" => Some(TraceFormat::Nestest),
+            "mesen" => Some(TraceFormat::Mesen),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TraceFormat::Nestest => "nestest",
+            TraceFormat::Mesen => "mesen",
+        }
+    }
+}
+
+/// Where traced instructions go.
+pub enum TraceSink {
+    /// Keeps the most recent lines in memory, the oldest are dropped first.
+    Ring {
+        lines: VecDeque<String>,
+        capacity: usize,
+    },
+    File(BufWriter<File>),
+    Stdout,
+}
+
+/// Writes a line for every instruction the CPU runs while enabled, in the nestest.log format
+/// unless told otherwise.
+pub struct Tracer {
+    sink: TraceSink,
+    enabled: bool,
+    format: TraceFormat,
+    labels: Labels,
+}
+
+impl Tracer {
+    pub fn new(sink: TraceSink) -> Self {
+        Tracer {
+            sink,
+            enabled: true,
+            format: TraceFormat::Nestest,
+            labels: Labels::new(),
+        }
+    }
+
+    pub fn ring(capacity: usize) -> Self {
+        Self::new(TraceSink::Ring {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        })
+    }
+
+    pub fn file(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(TraceSink::File(BufWriter::new(File::create(
+            path,
+        )?))))
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(TraceSink::Stdout)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Changes the format of the following lines, the labels are only used by Mesen's.
+    pub fn set_format(&mut self, format: TraceFormat, labels: Labels) {
+        self.format = format;
+        self.labels = labels;
+    }
+
+    /// Traces the instruction the CPU is about to execute, unless disabled.
+    pub fn trace(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let line = match self.format {
+            TraceFormat::Nestest => trace(cpu),
+            TraceFormat::Mesen => trace_mesen(cpu, &self.labels),
+        };
+        match &mut self.sink {
+            TraceSink::Ring { lines, capacity } => {
+                lines.push_back(line);
+                // A ring of 0 keeps nothing
+                while lines.len() > *capacity {
+                    lines.pop_front();
+                }
+                Ok(())
+            }
+            TraceSink::File(writer) => writeln!(writer, "{}", line),
+            TraceSink::Stdout => writeln!(io::stdout().lock(), "{}", line),
+        }
+    }
+
+    /// The lines kept by a ring buffer, oldest first. Other sinks keep nothing.
+    pub fn lines(&self) -> String {
+        match &self.sink {
+            TraceSink::Ring { lines, .. } => {
+                lines.iter().map(|line| format!("{}\n", line)).collect()
+            }
+            _ => String::new(),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            TraceSink::File(writer) => writer.flush(),
+            TraceSink::Stdout => io::stdout().flush(),
+            TraceSink::Ring { .. } => Ok(()),
+        }
+    }
+}
+
+/// Formats the instruction at the program counter like nestest.log. Operands are read without
+/// side effects, so tracing does not change how a game runs; I/O registers read as 00.
 pub fn trace(cpu: &mut CPU) -> String {
-    let opcodes: &HashMap<u8, &'static opcodes::OpCode> = &(*opcodes::OPCODES_MAP);
+    let opcodes: &HashMap<u8, &'static opcodes::OpCode> = &opcodes::OPCODES_MAP;
 
     let code = cpu.read(cpu.pc);
     let opcode = opcodes
         .get(&code)
-        .expect(&*format!("OpCode {:#02x} was not found", code));
+        .unwrap_or_else(|| panic!("OpCode {:#02x} was not found", code));
 
     let begin = cpu.pc;
     let mut hex_dump = vec![];
@@ -18,7 +155,7 @@ pub fn trace(cpu: &mut CPU) -> String {
         AddressingMode::Immediate | AddressingMode::Implied => (0, 0),
         _ => {
             let (adr, _) = cpu.get_effective_address(&opcode.mode, begin + 1);
-            (adr, cpu.read(adr))
+            (adr, MemoryRegion::Cpu.peek(cpu, adr).unwrap_or(0))
         }
     };
 
@@ -116,12 +253,83 @@ pub fn trace(cpu: &mut CPU) -> String {
     .trim()
     .to_string();
     format!(
-        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} PPU:{:3},{:3}",
-        asm_str, cpu.a, cpu.x, cpu.y, cpu.p, cpu.s, cpu.bus.ppu.scanline, cpu.bus.ppu.cycles
+        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} PPU:{:3},{:3} CYC:{}",
+        asm_str,
+        cpu.a,
+        cpu.x,
+        cpu.y,
+        cpu.p,
+        cpu.s,
+        cpu.bus.ppu.scanline,
+        cpu.bus.ppu.cycles,
+        cpu.bus.cycles()
     )
     .to_ascii_uppercase()
 }
 
+/// Formats the instruction at the program counter like Mesen's trace logger does with
+///
+/// ```text
+/// [PC,h]  [Disassembly][EffectiveAddress] [MemoryValue,h][Align,48] A:[A,2h] X:[X,2h]
+/// Y:[Y,2h] P:[P,h] SP:[SP,2h] CYC:[Cycle,3] SL:[Scanline,3] FC:[FrameCount] CPU Cycle:[CycleCount]
+/// ```
+///
+/// on a single line. Addresses with a label are shown by name, and operands are read without
+/// side effects like in [`trace`].
+pub fn trace_mesen(cpu: &mut CPU, labels: &Labels) -> String {
+    let pc = cpu.pc;
+    let bytes: Vec<u8> = MemoryRegion::Cpu
+        .peek_range(cpu, pc, 3)
+        .into_iter()
+        .map(|byte| byte.unwrap_or(0))
+        .collect();
+    let mut text = labels.substitute(&decode(&bytes, pc).text);
+
+    if let Some(opcode) = opcodes::OPCODES_MAP.get(&bytes[0]) {
+        let adr = match opcode.mode {
+            AddressingMode::Immediate | AddressingMode::Implied => None,
+            // The operand of JMP and JSR is where they go, not memory they read
+            AddressingMode::Absolute if matches!(opcode.code, 0x20 | 0x4c) => None,
+            _ => Some(
+                cpu.get_effective_address(&opcode.mode, pc.wrapping_add(1))
+                    .0,
+            ),
+        };
+        match (adr, &opcode.mode) {
+            (Some(adr), AddressingMode::Indirect) => text.push_str(&format!(" @ ${:04X}", adr)),
+            (Some(adr), AddressingMode::ZeroPage | AddressingMode::Absolute) => {
+                let value = MemoryRegion::Cpu.peek(cpu, adr).unwrap_or(0);
+                text.push_str(&format!(" = ${:02X}", value));
+            }
+            (Some(adr), _) => {
+                let value = MemoryRegion::Cpu.peek(cpu, adr).unwrap_or(0);
+                text.push_str(&format!(" @ ${:04X} = ${:02X}", adr, value));
+            }
+            (None, _) => {}
+        }
+    }
+
+    // Mesen counts the pre-render scanline as -1
+    let scanline = match cpu.bus.ppu.scanline {
+        261 => -1,
+        scanline => scanline as i32,
+    };
+    let line = format!("{:04X}  {}", pc, text);
+    format!(
+        "{:48}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{:<3} SL:{:<3} FC:{} CPU Cycle:{}",
+        line,
+        cpu.a,
+        cpu.x,
+        cpu.y,
+        cpu.p,
+        cpu.s,
+        cpu.bus.ppu.cycles,
+        scanline,
+        cpu.bus.frames(),
+        cpu.bus.cycles()
+    )
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -129,7 +337,7 @@ mod test {
     use crate::cartridge::test::test_rom;
 
     /// Takes a vector of program memory and test it with trace starting from 0x8000.
-    fn test_cpu_trace(result: &mut Vec<String>, program: Vec<u8>) -> CPU {
+    fn test_cpu_trace(result: &mut Vec<String>, program: Vec<u8>) -> CPU<'_> {
         let program_size = program.len();
         let mut padded_program = program;
         padded_program.extend(vec![0; 2 * 0x4000 - program_size - 4]);
@@ -137,7 +345,7 @@ mod test {
 
         let bus = Bus::new(test_rom(padded_program), |_, _| {});
         let mut cpu = CPU::new(bus);
-        cpu.reset();
+        cpu.power_cycle();
         cpu.run_with_callback(
             |cpu| {
                 result.push(trace(cpu));
@@ -155,16 +363,87 @@ mod test {
         test_cpu_trace(&mut result, vec![0xa2, 0x01, 0xca, 0x88]);
 
         assert_eq!(
-            "8000  A2 01     LDX #$01                        A:00 X:00 Y:00 P:24 SP:FD",
+            "8000  A2 01     LDX #$01                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
             result[0]
         );
         assert_eq!(
-            "8002  CA        DEX                             A:00 X:01 Y:00 P:24 SP:FD",
+            "8002  CA        DEX                             A:00 X:01 Y:00 P:24 SP:FD PPU:  0, 27 CYC:9",
             result[1]
         );
         assert_eq!(
-            "8003  88        DEY                             A:00 X:00 Y:00 P:26 SP:FD",
+            "8003  88        DEY                             A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 33 CYC:11",
             result[2]
         );
     }
+
+    #[test]
+    fn test_mesen_trace() {
+        // LDA $0300,X, then STA $10
+        let program = vec![0xa2, 0x02, 0xbd, 0x00, 0x03, 0x85, 0x10];
+        let program_size = program.len();
+        let mut padded_program = program;
+        padded_program.resize(2 * 0x4000 - 4, 0);
+        padded_program.extend([0x00, 0x80, 0x00, 0x00]);
+        let mut cpu = CPU::new(Bus::new(test_rom(padded_program), |_, _| {}));
+        cpu.power_cycle();
+        cpu.write(0x0302, 0x7f);
+
+        let mut labels = Labels::new();
+        labels.insert(0x0010, "Lives");
+        let mut result = vec![];
+        cpu.run_with_callback(
+            |cpu| result.push(trace_mesen(cpu, &labels)),
+            true,
+            program_size as u64,
+        );
+
+        assert_eq!(
+            result[1],
+            "8002  LDA $0300,X @ $0302 = $7F                 \
+             A:00 X:02 Y:00 P:24 SP:FD CYC:27  SL:0   FC:0 CPU Cycle:9"
+        );
+        assert_eq!(
+            result[2],
+            "8005  STA Lives = $00                           \
+             A:7F X:02 Y:00 P:24 SP:FD CYC:39  SL:0   FC:0 CPU Cycle:13"
+        );
+    }
+
+    #[test]
+    fn test_tracer() {
+        // LDX #$01, DEX, DEY, then JMP $8000 forever
+        let mut program = vec![0xa2, 0x01, 0xca, 0x88, 0x4c, 0x00, 0x80];
+        program.resize(2 * 0x4000 - 4, 0);
+        program.extend([0x00, 0x80, 0x00, 0x00]);
+        let mut cpu = CPU::new(Bus::new(test_rom(program), |_, _| {}));
+        cpu.power_cycle();
+
+        cpu.tracer = Some(Tracer::ring(2));
+        for _ in 0..3 {
+            cpu.step();
+        }
+        let tracer = cpu.tracer.as_mut().unwrap();
+        let lines = tracer.lines();
+        assert_eq!(lines.lines().count(), 2);
+        assert!(lines.starts_with("8002  CA        DEX"));
+
+        tracer.set_enabled(false);
+        cpu.step();
+        assert!(cpu.tracer.as_ref().unwrap().lines().contains("8003  88"));
+        assert!(!cpu.tracer.as_ref().unwrap().lines().contains("8004"));
+
+        let path = std::env::temp_dir().join("nes_rust_test_trace.log");
+        cpu.tracer = Some(Tracer::file(&path).unwrap());
+        cpu.step();
+        cpu.tracer.as_mut().unwrap().flush().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(text.starts_with("8000  A2 01     LDX #$01"));
+
+        cpu.tracer = Some(Tracer::ring(0));
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.tracer.as_ref().unwrap().lines(), "");
+    }
 }
@@ -0,0 +1,336 @@
+use crate::debugger::{parse_command, DebugCommand, Registers};
+use crate::disasm::{disassemble_memory, Instruction};
+use crate::emulation::Command;
+use crate::labels::Labels;
+use crate::memory::MemoryRegion;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+/// Instructions listed from the PC onwards.
+const DISASSEMBLY_LINES: usize = 24;
+/// Bytes shown in the memory pane, 16 per row.
+const MEMORY_BYTES: usize = 0x100;
+/// Lines kept in the output pane.
+const OUTPUT_LINES: usize = 100;
+
+/// What the panes show, copied on the emulation thread.
+struct Snapshot {
+    registers: Registers,
+    disassembly: Vec<Instruction>,
+    memory: Vec<Option<u8>>,
+}
+
+/// Terminal debugger showing disassembly, registers, memory and a command line.
+struct Tui {
+    emulation: Sender<Command>,
+    labels: Labels,
+    stops: Receiver<Registers>,
+    snapshot_sender: Sender<Snapshot>,
+    snapshots: Receiver<Snapshot>,
+    /// Whether a snapshot was requested and has not arrived yet.
+    waiting: bool,
+    snapshot: Option<Snapshot>,
+    stopped: bool,
+    memory_address: u16,
+    input: String,
+    output: Vec<String>,
+}
+
+/// Runs the terminal debugger until it is quit, the emulation keeps running afterwards.
+///
+/// Takes the debugger console commands, plus `mem <address>` to show memory and `quit`.
+/// F5 continues, F6 breaks, F10 steps over, F11 steps into and Shift+F11 steps out.
+pub fn run(emulation: Sender<Command>, labels: Labels) -> io::Result<()> {
+    let (listener, stops) = mpsc::channel();
+    emulation
+        .send(Command::Debug(DebugCommand::Attach(listener)))
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "emulation has stopped"))?;
+    let (snapshot_sender, snapshots) = mpsc::channel();
+    let mut tui = Tui {
+        emulation,
+        labels,
+        stops,
+        snapshot_sender,
+        snapshots,
+        waiting: false,
+        snapshot: None,
+        stopped: false,
+        memory_address: 0,
+        input: String::new(),
+        output: vec!["Type help for the commands".to_string()],
+    };
+
+    let mut terminal = ratatui::init();
+    let result = tui.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl Tui {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            let stops: Vec<Registers> = self.stops.try_iter().collect();
+            for registers in stops {
+                self.stopped = true;
+                let text = self.labels.substitute(&format!("Break at {}", registers));
+                self.print(text);
+            }
+            if let Some(snapshot) = self.snapshots.try_iter().last() {
+                self.snapshot = Some(snapshot);
+                self.waiting = false;
+            }
+            if !self.waiting {
+                self.request_snapshot();
+            }
+
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if !event::poll(Duration::from_millis(50))? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let command = match key.code {
+                KeyCode::Esc => return Ok(()),
+                KeyCode::Enter => {
+                    let line = std::mem::take(&mut self.input);
+                    if !self.execute(&line) {
+                        return Ok(());
+                    }
+                    None
+                }
+                KeyCode::Backspace => {
+                    self.input.pop();
+                    None
+                }
+                KeyCode::Char(c) => {
+                    self.input.push(c);
+                    None
+                }
+                KeyCode::F(5) => Some(DebugCommand::Continue),
+                KeyCode::F(6) => Some(DebugCommand::Break),
+                KeyCode::F(10) => Some(DebugCommand::StepOver),
+                KeyCode::F(11) if key.modifiers.contains(KeyModifiers::SHIFT) => {
+                    Some(DebugCommand::StepOut)
+                }
+                KeyCode::F(11) => Some(DebugCommand::Step),
+                _ => None,
+            };
+            if let Some(command) = command {
+                self.send(command);
+            }
+        }
+    }
+
+    /// Asks the emulation thread for the state shown in the panes, it answers between
+    /// frames or right away while stopped.
+    fn request_snapshot(&mut self) {
+        let sender = self.snapshot_sender.clone();
+        let memory_address = self.memory_address;
+        let inspect = Command::Inspect(Box::new(move |cpu| {
+            let _ = sender.send(Snapshot {
+                registers: Registers::of(cpu),
+                disassembly: disassemble_memory(cpu, cpu.pc, DISASSEMBLY_LINES),
+                memory: MemoryRegion::Cpu.peek_range(cpu, memory_address, MEMORY_BYTES),
+            });
+        }));
+        self.waiting = self.emulation.send(inspect).is_ok();
+    }
+
+    fn send(&mut self, command: DebugCommand) {
+        if matches!(
+            command,
+            DebugCommand::Continue
+                | DebugCommand::Step
+                | DebugCommand::StepOver
+                | DebugCommand::StepOut
+        ) {
+            self.stopped = false;
+        }
+        if self.emulation.send(Command::Debug(command)).is_err() {
+            self.print("Emulation has stopped".to_string());
+        }
+    }
+
+    /// Runs a command line, returns false to quit.
+    fn execute(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        if line.is_empty() {
+            return true;
+        }
+        self.print(format!("> {}", line));
+
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("quit" | "q"), None) => return false,
+            (Some("help"), None) => self.print(
+                "break <address> [if <condition>], break nmi, break write <register>, \
+                 clear, pause, continue, step, next, finish, mem <address>, quit"
+                    .to_string(),
+            ),
+            (Some("mem" | "m"), Some(address)) => match self.labels.parse_address(address) {
+                Some(address) => self.memory_address = address & 0xfff0,
+                None => self.print(format!("Unknown address {:?}", address)),
+            },
+            _ => match parse_command(line, &self.labels) {
+                Ok(command) => self.send(command),
+                Err(error) => self.print(error),
+            },
+        }
+        true
+    }
+
+    fn print(&mut self, line: String) {
+        self.output.push(line);
+        if self.output.len() > OUTPUT_LINES {
+            self.output.remove(0);
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [top, memory, output, input] = Layout::vertical([
+            Constraint::Min(8),
+            Constraint::Length(MEMORY_BYTES as u16 / 16 + 2),
+            Constraint::Length(8),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+        let [disassembly, registers] =
+            Layout::horizontal([Constraint::Min(40), Constraint::Length(44)]).areas(top);
+
+        let state = if self.stopped { "Stopped" } else { "Running" };
+        frame.render_widget(
+            Paragraph::new(self.disassembly_lines())
+                .block(Block::bordered().title(format!("Disassembly ({})", state))),
+            disassembly,
+        );
+        frame.render_widget(
+            Paragraph::new(self.register_lines()).block(Block::bordered().title("Registers")),
+            registers,
+        );
+        frame.render_widget(
+            Paragraph::new(self.memory_lines()).block(Block::bordered().title("Memory")),
+            memory,
+        );
+
+        let visible = output.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = self.output[self.output.len().saturating_sub(visible)..]
+            .iter()
+            .map(|line| Line::raw(line.as_str()))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Output")),
+            output,
+        );
+        frame.render_widget(
+            Paragraph::new(format!("> {}", self.input)).block(Block::bordered()),
+            input,
+        );
+    }
+
+    fn disassembly_lines(&self) -> Vec<Line<'_>> {
+        let Some(snapshot) = &self.snapshot else {
+            return vec![];
+        };
+        snapshot
+            .disassembly
+            .iter()
+            .flat_map(|instruction| {
+                let mut lines = vec![];
+                if let Some(name) = self.labels.get(instruction.address) {
+                    lines.push(Line::raw(format!("{}:", name)));
+                }
+                let text = self.labels.substitute(&instruction.to_string());
+                if instruction.address == snapshot.registers.pc {
+                    lines.push(Line::styled(
+                        format!("> {}", text),
+                        Style::new().add_modifier(Modifier::REVERSED),
+                    ));
+                } else {
+                    lines.push(Line::raw(format!("  {}", text)));
+                }
+                lines
+            })
+            .collect()
+    }
+
+    fn register_lines(&self) -> Vec<Line<'_>> {
+        let Some(snapshot) = &self.snapshot else {
+            return vec![];
+        };
+        let registers = &snapshot.registers;
+        let mut lines = vec![
+            Line::raw(format!("PC {:04X}  SP {:02X}", registers.pc, registers.s)),
+            Line::raw(format!(
+                "A {:02X}  X {:02X}  Y {:02X}",
+                registers.a, registers.x, registers.y
+            )),
+            Line::raw(format!("P {:02X}  {}", registers.p, flags(registers.p))),
+            Line::raw(""),
+            Line::raw("Call stack"),
+        ];
+        for frame in registers.call_stack.iter().rev() {
+            lines.push(Line::raw(self.labels.substitute(&format!("  {}", frame))));
+        }
+        lines
+    }
+
+    fn memory_lines(&self) -> Vec<Line<'_>> {
+        let Some(snapshot) = &self.snapshot else {
+            return vec![];
+        };
+        snapshot
+            .memory
+            .chunks(16)
+            .enumerate()
+            .map(|(row, bytes)| {
+                let hex: Vec<String> = bytes
+                    .iter()
+                    .map(|byte| match byte {
+                        Some(byte) => format!("{:02X}", byte),
+                        None => "--".to_string(),
+                    })
+                    .collect();
+                let address = self.memory_address.wrapping_add(row as u16 * 16);
+                Line::raw(format!("{:04X}  {}", address, hex.join(" ")))
+            })
+            .collect()
+    }
+}
+
+/// The status flags as letters, upper case when set.
+fn flags(p: u8) -> String {
+    "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, flag)| {
+            if p & (0x80 >> i) != 0 {
+                flag
+            } else {
+                flag.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(flags(0x24), "nv-bdIzc");
+        assert_eq!(flags(0xc3), "NV-bdiZC");
+    }
+}
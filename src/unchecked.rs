@@ -0,0 +1,29 @@
+//! Indexing for the memory accesses of every instruction. With the `unchecked` feature the
+//! bounds checks are left out, callers mask or bound the index so it always fits. Debug builds
+//! check it either way.
+
+#[inline(always)]
+pub fn read(memory: &[u8], index: usize) -> u8 {
+    #[cfg(feature = "unchecked")]
+    {
+        debug_assert!(index < memory.len(), "{:#x} is out of bounds", index);
+        // SAFETY: callers keep the index within the memory, see the module docs
+        unsafe { *memory.get_unchecked(index) }
+    }
+    #[cfg(not(feature = "unchecked"))]
+    memory[index]
+}
+
+#[inline(always)]
+pub fn write(memory: &mut [u8], index: usize, value: u8) {
+    #[cfg(feature = "unchecked")]
+    {
+        debug_assert!(index < memory.len(), "{:#x} is out of bounds", index);
+        // SAFETY: callers keep the index within the memory, see the module docs
+        unsafe { *memory.get_unchecked_mut(index) = value }
+    }
+    #[cfg(not(feature = "unchecked"))]
+    {
+        memory[index] = value;
+    }
+}
@@ -0,0 +1,165 @@
+use rust_nes::cpu::CPU;
+use rust_nes::emulation::Command;
+use rust_nes::render::{render_nametables, render_pattern_tables};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::VideoSubsystem;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// Debug views that can be opened in their own window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum View {
+    Nametables,
+    PatternTables,
+}
+
+impl View {
+    fn title(self) -> &'static str {
+        match self {
+            View::Nametables => "Nametables",
+            View::PatternTables => "Pattern tables",
+        }
+    }
+
+    /// Size of the image in pixels, windows open at twice that.
+    fn size(self) -> (u32, u32) {
+        match self {
+            View::Nametables => (512, 480),
+            View::PatternTables => (256, 128),
+        }
+    }
+
+    /// Renders the view as RGB, runs on the emulation thread.
+    fn render(self, cpu: &mut CPU) -> Vec<u8> {
+        match self {
+            View::Nametables => render_nametables(&cpu.bus.ppu),
+            View::PatternTables => render_pattern_tables(&cpu.bus.ppu),
+        }
+    }
+}
+
+struct DebugWindow {
+    view: View,
+    canvas: Canvas<Window>,
+    image: Vec<u8>,
+    image_sender: Sender<Vec<u8>>,
+    images: Receiver<Vec<u8>>,
+}
+
+impl DebugWindow {
+    fn open(video: &VideoSubsystem, view: View) -> Self {
+        let (width, height) = view.size();
+        let window = video
+            .window(view.title(), width * 2, height * 2)
+            .resizable()
+            .build()
+            .unwrap();
+        let (image_sender, images) = mpsc::channel();
+
+        DebugWindow {
+            view,
+            canvas: window.into_canvas().build().unwrap(),
+            image: vec![0; (width * height * 3) as usize],
+            image_sender,
+            images,
+        }
+    }
+
+    fn id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    /// Asks the emulation thread for a new image, it arrives after the current frame.
+    fn request_image(&self, emulation: &Sender<Command>) {
+        let view = self.view;
+        let sender = self.image_sender.clone();
+        emulation
+            .send(Command::Inspect(Box::new(move |cpu| {
+                let _ = sender.send(view.render(cpu));
+            })))
+            .unwrap();
+    }
+
+    fn present(&mut self) {
+        if let Some(image) = self.images.try_iter().last() {
+            self.image = image;
+        }
+
+        let (width, height) = self.view.size();
+        let creator = self.canvas.texture_creator();
+        let mut texture = creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, width, height)
+            .unwrap();
+        texture
+            .update(None, &self.image, width as usize * 3)
+            .unwrap();
+
+        self.canvas.set_draw_color(Color::BLACK);
+        self.canvas.clear();
+        self.canvas.copy(&texture, None, None).unwrap();
+        self.canvas.present();
+    }
+}
+
+/// Debug windows that are open next to the main window, each with its own canvas.
+pub struct DebugWindows {
+    video: VideoSubsystem,
+    windows: Vec<DebugWindow>,
+}
+
+impl DebugWindows {
+    pub fn new(video: VideoSubsystem) -> Self {
+        DebugWindows {
+            video,
+            windows: vec![],
+        }
+    }
+
+    /// Opens the view, or closes it when it is already open.
+    pub fn toggle(&mut self, view: View) {
+        match self.windows.iter().position(|window| window.view == view) {
+            Some(i) => {
+                self.windows.remove(i);
+            }
+            None => self.windows.push(DebugWindow::open(&self.video, view)),
+        }
+    }
+
+    /// Handles the events of the debug windows, other events are given back.
+    pub fn route(&mut self, event: Event) -> Option<Event> {
+        let id = match event.get_window_id() {
+            Some(id) => id,
+            None => return Some(event),
+        };
+        let i = match self.windows.iter().position(|window| window.id() == id) {
+            Some(i) => i,
+            None => return Some(event),
+        };
+
+        match event {
+            Event::Window {
+                win_event: WindowEvent::Close,
+                ..
+            }
+            | Event::KeyDown {
+                keycode: Some(Keycode::Escape),
+                ..
+            } => {
+                self.windows.remove(i);
+            }
+            _ => { /* do nothing */ }
+        }
+        None
+    }
+
+    /// Shows the latest images and asks for the next ones.
+    pub fn update(&mut self, emulation: &Sender<Command>) {
+        for window in self.windows.iter_mut() {
+            window.present();
+            window.request_image(emulation);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use rust_nes::bus::Bus;
+use rust_nes::cartridge::Rom;
+use rust_nes::cpu::CPU;
+use rust_nes::joypad::{
+    JOYPAD_A, JOYPAD_B, JOYPAD_DOWN, JOYPAD_LEFT, JOYPAD_RIGHT, JOYPAD_SELECT, JOYPAD_START,
+    JOYPAD_UP,
+};
+use rust_nes::render::{self, PixelFormat};
+use rust_nes::scaling::{FRAME_HEIGHT, FRAME_WIDTH};
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::Clamped;
+use web_sys::{CanvasRenderingContext2d, ImageData};
+
+/// Button for a `KeyboardEvent.code`, the same keys as the SDL frontend.
+fn button_for_key(code: &str) -> Option<u8> {
+    match code {
+        "ArrowDown" => Some(JOYPAD_DOWN),
+        "ArrowUp" => Some(JOYPAD_UP),
+        "ArrowRight" => Some(JOYPAD_RIGHT),
+        "ArrowLeft" => Some(JOYPAD_LEFT),
+        "Space" => Some(JOYPAD_SELECT),
+        "Enter" => Some(JOYPAD_START),
+        "KeyA" => Some(JOYPAD_A),
+        "KeyS" => Some(JOYPAD_B),
+        _ => None,
+    }
+}
+
+/// The emulator as the page sees it. There are no threads in the browser, so the page runs
+/// frames from its animation loop and draws the latest one to a canvas.
+#[wasm_bindgen]
+pub struct WebNes {
+    cpu: CPU<'static>,
+    /// The frame as RGBA, which is what the canvas takes, rendered straight into.
+    pixels: Rc<RefCell<Vec<u8>>>,
+}
+
+#[wasm_bindgen]
+impl WebNes {
+    /// Powers on the console with the contents of an iNES file.
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: &[u8]) -> Result<WebNes, JsError> {
+        let rom = Rom::new(rom).map_err(|error| JsError::new(&error))?;
+        let pixels = Rc::new(RefCell::new(vec![0xff; PixelFormat::Rgba8888.frame_size()]));
+        let bus_pixels = Rc::clone(&pixels);
+        let bus = Bus::new(rom, move |ppu, _| {
+            render::render_into(ppu, PixelFormat::Rgba8888, &mut bus_pixels.borrow_mut());
+        });
+        let mut cpu = CPU::new(bus);
+        cpu.power_cycle();
+        Ok(WebNes { cpu, pixels })
+    }
+
+    /// Runs until the PPU finishes the next frame.
+    pub fn run_frame(&mut self) {
+        let end = self.cpu.bus.frames() + 1;
+        while self.cpu.bus.frames() < end {
+            self.cpu.step();
+        }
+    }
+
+    /// Draws the latest frame at the top left of the canvas.
+    pub fn draw(&mut self, context: &CanvasRenderingContext2d) -> Result<(), JsValue> {
+        let image = ImageData::new_with_u8_clamped_array_and_sh(
+            Clamped(&self.pixels.borrow()),
+            FRAME_WIDTH,
+            FRAME_HEIGHT,
+        )?;
+        context.put_image_data(&image, 0.0, 0.0)
+    }
+
+    /// Presses or releases the button of a key, returns false for keys without a button so
+    /// the page can leave them to the browser.
+    pub fn key(&mut self, code: &str, pressed: bool) -> bool {
+        match button_for_key(code) {
+            Some(button) => {
+                self.cpu
+                    .bus
+                    .joypad_mut()
+                    .set_button_pressed_status(button, pressed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+}